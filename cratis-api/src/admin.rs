@@ -0,0 +1,266 @@
+use cratis_core::{config::CratisServerSettings, tls::pinned_client, utils::{format_timestamp, parse_duration}, error::{CratisError, CratisResult, ErrorCode}};
+use crate::tls::certificate_fingerprint;
+use clap_derive::Subcommand;
+use reqwest::{Client, RequestBuilder, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Subcommand)]
+pub enum EnrollCommand {
+    // Create an enrollment code, it is only shown once
+    Create {
+        // Number of devices that can register with the code
+        #[arg(long, default_value_t = 1)]
+        uses: u32,
+        // Lifetime of the code, e.g. 30m, 12h or 7d
+        #[arg(long)]
+        expires_in: Option<String>,
+    },
+    // List enrollment codes that can still be used
+    List,
+    // Revoke an enrollment code by its id
+    Revoke {
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UsersCommand {
+    // Create a user account, the password is prompted for
+    Create {
+        username: String,
+    },
+    // List user accounts and the number of devices they own
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    // Create a token pair for a device with any scopes, including admin
+    Create {
+        #[arg(long)]
+        device: String,
+        // backup:write, restore:read or admin, can be repeated
+        #[arg(long = "scope", required = true)]
+        scopes: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DevicesCommand {
+    // Move the backups of a device registered by hostname to its new key-derived id
+    Rebind {
+        // Id the device was registered with before
+        from: String,
+        // Id the device registered with again
+        to: String,
+    },
+}
+
+// Response Structs
+#[derive(Deserialize)]
+struct CreatedCode {
+    id: String,
+    code: String,
+    expires_at: Option<u64>,
+    max_uses: u32,
+}
+
+#[derive(Deserialize)]
+struct CodeList {
+    codes: Vec<CodeEntry>,
+}
+
+#[derive(Deserialize)]
+struct UserList {
+    users: Vec<UserEntry>,
+}
+
+#[derive(Deserialize)]
+struct UserEntry {
+    username: String,
+    created_at: u64,
+    devices: usize,
+}
+
+#[derive(Deserialize)]
+struct CodeEntry {
+    id: String,
+    created_at: u64,
+    expires_at: Option<u64>,
+    max_uses: u32,
+    uses: u32,
+}
+
+/// Manages enrollment codes through the admin endpoints of a running server.
+///
+/// The server is reached on `127.0.0.1` and the configured port unless `server` is given,
+/// requests authenticate with `settings.admin_token`. With TLS enabled, only a server presenting
+/// the configured certificate is accepted.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The lines to show to the admin
+/// * `Err(CratisError)` - If the server is not reachable or rejected the request
+pub async fn enroll(settings: &CratisServerSettings, command: EnrollCommand, server: Option<&str>) -> CratisResult<Vec<String>> {
+    let url: String = format!("{}/admin/enrollment-codes", admin_base(settings, server)?);
+    let client: Client = admin_client(settings)?;
+
+    match command {
+        EnrollCommand::Create { uses, expires_in } => {
+            let expires_in: Option<u64> = expires_in.as_deref().map(parse_duration).transpose()?;
+            let body: Value = send(settings, client.post(&url).json(&json!({ "max_uses": uses, "expires_in": expires_in }))).await?;
+            let created: CreatedCode = serde_json::from_value(body).map_err(|_| CratisError::invalid_response("Invalid response"))?;
+
+            Ok(vec![
+                format!("Enrollment code: {}", created.code),
+                format!("Id: {}, uses: {}, expires: {}", created.id, created.max_uses, describe_expiry(created.expires_at)),
+                "The code is not shown again, register a device with `cratis register --code <code>`".to_string(),
+            ])
+        }
+        EnrollCommand::List => {
+            let body: Value = send(settings, client.get(&url)).await?;
+            let list: CodeList = serde_json::from_value(body).map_err(|_| CratisError::invalid_response("Invalid response"))?;
+
+            if list.codes.is_empty() {
+                return Ok(vec!["No usable enrollment codes".to_string()]);
+            }
+
+            Ok(list.codes.iter().map(|code| format!(
+                "{}  created {}  used {}/{}  expires {}",
+                code.id, format_timestamp(code.created_at), code.uses, code.max_uses, describe_expiry(code.expires_at),
+            )).collect())
+        }
+        EnrollCommand::Revoke { id } => {
+            send(settings, client.delete(format!("{}/{}", url, id))).await?;
+            Ok(vec![format!("Revoked enrollment code {}", id)])
+        }
+    }
+}
+
+/// Manages user accounts through the admin endpoints of a running server.
+///
+/// See [`enroll`] for how the server is reached.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The lines to show to the admin
+/// * `Err(CratisError)` - If the password cannot be read, the server is not reachable or rejected the request
+pub async fn users(settings: &CratisServerSettings, command: UsersCommand, server: Option<&str>) -> CratisResult<Vec<String>> {
+    let url: String = format!("{}/admin/users", admin_base(settings, server)?);
+    let client: Client = admin_client(settings)?;
+
+    match command {
+        UsersCommand::Create { username } => {
+            let password: String = rpassword::prompt_password(format!("Password for {}: ", username))?;
+            if rpassword::prompt_password("Repeat password: ")? != password {
+                return Err(CratisError::InvalidInput("The passwords do not match".to_string()));
+            }
+
+            send(settings, client.post(&url).json(&json!({ "username": username, "password": password }))).await?;
+            Ok(vec![format!("Created user {}, devices can now be registered with `cratis register --user {}`", username, username)])
+        }
+        UsersCommand::List => {
+            let body: Value = send(settings, client.get(&url)).await?;
+            let list: UserList = serde_json::from_value(body).map_err(|_| CratisError::invalid_response("Invalid response"))?;
+
+            if list.users.is_empty() {
+                return Ok(vec!["No users".to_string()]);
+            }
+
+            Ok(list.users.iter().map(|user| format!("{}  created {}  {} device(s)", user.username, format_timestamp(user.created_at), user.devices)).collect())
+        }
+    }
+}
+
+/// Issues tokens through the admin endpoints of a running server.
+///
+/// See [`enroll`] for how the server is reached.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The lines to show to the admin, containing the tokens
+/// * `Err(CratisError)` - If the server is not reachable or rejected the request
+pub async fn tokens(settings: &CratisServerSettings, command: TokenCommand, server: Option<&str>) -> CratisResult<Vec<String>> {
+    let url: String = format!("{}/admin/tokens", admin_base(settings, server)?);
+
+    match command {
+        TokenCommand::Create { device, scopes } => {
+            let body: Value = send(settings, admin_client(settings)?.post(&url).json(&json!({ "device_id": device, "scopes": scopes }))).await?;
+            let field = |key: &str| body.get(key).and_then(Value::as_str).map(str::to_string).ok_or(CratisError::invalid_response("Invalid response"));
+
+            Ok(vec![
+                format!("Device: {}, scopes: {}", device, scopes.join(", ")),
+                format!("Access token: {}", field("token")?),
+                format!("Refresh token: {}", field("refresh_token")?),
+            ])
+        }
+    }
+}
+
+/// Manages devices through the admin endpoints of a running server.
+///
+/// See [`enroll`] for how the server is reached.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The lines to show to the admin
+/// * `Err(CratisError)` - If the server is not reachable or rejected the request
+pub async fn devices(settings: &CratisServerSettings, command: DevicesCommand, server: Option<&str>) -> CratisResult<Vec<String>> {
+    let url: String = format!("{}/admin/devices", admin_base(settings, server)?);
+
+    match command {
+        DevicesCommand::Rebind { from, to } => {
+            let body: Value = send(settings, admin_client(settings)?.post(format!("{}/{}/rebind", url, from)).json(&json!({ "device_id": to }))).await?;
+            let moved: u64 = body.get("moved_versions").and_then(Value::as_u64).ok_or(CratisError::invalid_response("Invalid response"))?;
+
+            Ok(vec![format!("Moved {} version(s) from {} to {}, {} was removed", moved, from, to, from)])
+        }
+    }
+}
+
+/// Returns the base URL of the server's admin endpoints.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if no admin token is configured.
+fn admin_base(settings: &CratisServerSettings, server: Option<&str>) -> CratisResult<String> {
+    if settings.admin_token.is_empty() {
+        return Err(CratisError::ConfigError("settings.admin_token is not set, the admin endpoints are disabled".to_string()));
+    }
+
+    let scheme: &str = if settings.tls.enabled { "https" } else { "http" };
+    Ok(server.map(|s| s.trim_end_matches('/').to_string()).unwrap_or_else(|| format!("{}://127.0.0.1:{}", scheme, settings.port)))
+}
+
+/// Returns the HTTP client for the admin endpoints, pinned to the server's own certificate if
+/// TLS is enabled.
+fn admin_client(settings: &CratisServerSettings) -> CratisResult<Client> {
+    if !settings.tls.enabled {
+        return Ok(Client::new());
+    }
+
+    pinned_client(&certificate_fingerprint(&settings.tls)?)
+}
+
+async fn send(settings: &CratisServerSettings, request: RequestBuilder) -> CratisResult<Value> {
+    let response: Response = request
+        .bearer_auth(&settings.admin_token)
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    if response.status().is_success() {
+        return response.json::<Value>().await.map_err(|_| CratisError::invalid_response("Invalid response"));
+    }
+
+    // The server reports the admin token as an invalid access token, which it tried as well
+    match CratisError::from_response(response).await {
+        CratisError::Api { code: ErrorCode::Unauthorized, .. } => Err(CratisError::AuthFailure("The server rejected the admin token".to_string())),
+        error => Err(error),
+    }
+}
+
+fn describe_expiry(expires_at: Option<u64>) -> String {
+    expires_at.map(format_timestamp).unwrap_or_else(|| "never".to_string())
+}
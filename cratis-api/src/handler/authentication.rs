@@ -1,223 +1,752 @@
 #[allow(dead_code)]
-use cratis_core::{utils::generate_random_string, config::get_config_api, error::{display_msg, CratisError, CratisErrorLevel}};
-use axum::{Json, response::IntoResponse, http::StatusCode, middleware::Next, response::Response};
+use cratis_core::{utils::{format_timestamp, generate_random_string, timestamp_now}, error::{CratisError, CratisResult, ErrorCode}, identity::{challenge_message, device_id, registration_message}};
+use axum::{extract::State, Json, http::{HeaderMap, StatusCode}, middleware::Next, response::Response};
 use jsonwebtoken::{encode, EncodingKey, Header, decode, DecodingKey, Validation, Algorithm};
-use polodb_core::{CollectionT, bson::doc, Collection};
 use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
-use serde_json::{json};
+use serde_json::{json, Value};
 use http::Request;
-use uuid::Uuid;
-use crate::DB;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{Signature, VerifyingKey};
+use tracing::{warn, Span};
+use crate::handler::devices::cancel_data_deletion;
+use crate::handler::enrollment::{redeem_code, release_code};
+use crate::handler::rate_limit::authenticated;
+use crate::handler::scopes::{default_scopes, Scope, DEVICE_SCOPES};
+use crate::handler::users::user_from_headers;
+use crate::state::AppState;
+use crate::tls::{certificate_issued_at, certificate_public_key, issue_client_certificate, ClientCert};
 
 // Request Structs
 #[derive(Deserialize)]
 pub struct RegisterRequestData {
-    hostname: String,
+    // Base64 encoded Ed25519 public key, the device id is derived from it
+    public_key: String,
+    // Signature of `cratis-register:<public_key>:<timestamp>`, proving the device holds the private key
+    signature: String,
+    timestamp: u64,
+    // Display name of the device, usually its hostname
+    #[serde(alias = "hostname")]
+    label: String,
     os: String,
+    // Enrollment code issued by the admin, required unless settings.require_enrollment_code is false
+    #[serde(default)]
+    code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequestData {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChallengeRequestData {
+    device_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequestData {
+    device_id: String,
+    challenge: String,
+    // Signature of `cratis-login:<device_id>:<challenge>`
+    signature: String,
+    // Updates the display name of the device if set
+    #[serde(default)]
+    label: Option<String>,
 }
 
 // Collection Structs
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Device {
-    device_id: String,
+    pub device_id: String,
+    // Base64 encoded Ed25519 public key, empty for devices registered by hostname before
+    #[serde(default)]
+    pub public_key: String,
+    // Display name, can change without affecting the identity of the device
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub os: String,
+    // User owning the device, devices registered with an enrollment code only may have none
+    #[serde(default)]
+    pub user_id: Option<String>,
+    // Id of the enrollment code the device registered with
+    #[serde(default)]
+    pub enrolled_with: Option<String>,
+    // Unix timestamp of the registration, missing for devices registered before it was recorded
+    #[serde(default)]
+    pub registered_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub device_id: String,
+    // SHA-256 of the token, the token itself is only known to the device
+    pub token_hash: String,
+    // Unix timestamp after which the token is rejected
+    pub expires_at: u64,
+    // Set once the token was exchanged, presenting it again revokes all tokens of the device
+    pub used: bool,
+    // Scopes of the tokens issued in exchange
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Challenge {
+    pub device_id: String,
+    pub challenge: String,
+    // Unix timestamp after which the challenge can no longer be answered
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Revocation {
+    pub device_id: String,
+    // The revoked token, empty if every token of the device issued before `issued_before` is revoked
+    pub jti: String,
+    pub issued_before: u64,
+    // Unix timestamp after which all tokens covered by this entry have expired anyway
+    pub expires_at: u64,
 }
 
 // JWT Struct
 #[derive(Debug, Serialize, Deserialize, Clone)]
-struct Claims {
-    device_id: String,
-    jti: String,
+pub struct Claims {
+    pub device_id: String,
+    pub jti: String,
+    // Issued at and expiry as Unix timestamps
+    pub iat: u64,
+    pub exp: u64,
+    // Permissions of the token, checked per route by `require_scope`
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<Scope>,
+}
+
+/// An access token together with the refresh token to renew it.
+#[derive(Debug)]
+pub struct TokenPair {
+    // Kept as `token` so clients of earlier versions still find the access token
+    pub token: String,
+    pub refresh_token: String,
+    // Lifetime of the access token in seconds
+    pub expires_in: u64,
 }
 
+/// Length of generated refresh tokens.
+const REFRESH_TOKEN_LENGTH: usize = 48;
+
+/// Length of login challenges.
+const CHALLENGE_LENGTH: usize = 32;
+
+/// Seconds a login challenge can be answered in.
+const CHALLENGE_TTL_SECONDS: u64 = 60;
+
+/// Maximum difference in seconds between the timestamp of a registration and the server clock.
+const REGISTRATION_MAX_SKEW_SECONDS: u64 = 5 * 60;
+
 /// Handles device registration requests.
 ///
-/// This endpoint registers a new device by generating a unique device ID from the hostname
-/// and OS, checking for duplicates in the database, and creating a JWT token for authentication.
+/// This endpoint registers a new device by deriving its ID from its Ed25519 public key,
+/// verifying that the device holds the matching private key, checking for duplicates in the
+/// database, and creating a JWT token for authentication. The hostname is only stored as label.
+///
+/// With a user token from `/login` in the `Authorization` header, the device belongs to that
+/// user and no enrollment code is needed.
 ///
 /// # Arguments
 ///
-/// * `state` - Application state containing the database connection
-/// * `payload` - JSON payload containing hostname and OS information
+/// * `headers` - Request headers, optionally carrying a user token
+/// * `payload` - JSON payload containing the public key and its signature, the label, OS information and the enrollment code
 ///
 /// # Returns
 ///
-/// * `200 OK` with JWT token if registration is successful
-/// * `400 Bad Request` if hostname or OS is empty
-/// * `409 Conflict` if device already exists
-/// * `500 Internal Server Error` for database or JWT generation errors
+/// * `200 OK` with an access token and a refresh token if registration is successful, and a
+///   client certificate for the device key if `settings.tls.client_auth` is enabled
+/// * `400 Bad Request` if label or OS is empty or the public key is malformed
+/// * `401 Unauthorized` if the signature is invalid or the timestamp is too far off
+/// * `403 Forbidden` if the enrollment code is missing, unknown, expired or used up
+/// * `409 Conflict` with the device id and registration time if device already exists
+/// * `500 Internal Server Error` for database, JWT or certificate generation errors
 ///
 /// # Examples
 ///
 /// ```json
 /// // Request
 /// {
-///   "hostname": "my-laptop",
-///   "os": "linux"
+///   "public_key": "mC3r0Yc1...",
+///   "signature": "d4Fq9k2...",
+///   "timestamp": 1735862400,
+///   "label": "my-laptop",
+///   "os": "linux",
+///   "code": "7GQ2-XK4P-M9TD"
 /// }
 ///
 /// // Response
 /// {
 ///   "status": "ok",
-///   "token": "eyJ0eXAiOiJKV1QiLCJhbGciOiJIUzI1NiJ9..."
+///   "device_id": "6f1c2b1e-...",
+///   "token": "eyJ0eXAiOiJKV1QiLCJhbGciOiJIUzI1NiJ9...",
+///   "refresh_token": "Xq3...",
+///   "expires_in": 900
 /// }
 /// ```
-pub async fn register(Json(payload): Json<RegisterRequestData>) -> impl IntoResponse {
+pub async fn register(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<RegisterRequestData>) -> CratisResult<(StatusCode, Json<Value>)> {
     // Validate input
-    if payload.hostname.is_empty() || payload.os.is_empty() {
-        return (StatusCode::BAD_REQUEST, Json(json!({"error": "hostname and os are required"})));
+    if payload.label.is_empty() || payload.os.is_empty() {
+        return Err(CratisError::api(ErrorCode::InvalidRequest, "label and os are required"));
     }
 
-    // Generate device id from hostname and os
-    let device_id: String = generate_device_id(payload.hostname, payload.os);
+    let public_key: VerifyingKey = parse_public_key(&payload.public_key).ok_or_else(|| CratisError::api(ErrorCode::InvalidRequest, "Invalid public key"))?;
 
-    // Define collection and check if device id already exists in database
-    let collection: Collection<Device> = DB.collection::<Device>("devices");
-    let check_id: Result<Option<Device>, polodb_core::Error> = collection.find_one(doc! { "device_id": &device_id });
+    // The device has to prove it holds the private key, otherwise anyone could claim its identity
+    let now: u64 = timestamp_now()?;
+    let message: String = registration_message(&payload.public_key, payload.timestamp);
+    if now.abs_diff(payload.timestamp) > REGISTRATION_MAX_SKEW_SECONDS || !verify_signature(&public_key, &message, &payload.signature) {
+        return Err(CratisError::api(ErrorCode::Unauthorized, "Invalid signature"));
+    }
 
-    if let Ok(Some(_)) = check_id {
-        return (StatusCode::CONFLICT, Json(json!({ "error": "Device already exists" })))
+    // Generate device id from the public key
+    let device_id: String = device_id(public_key.as_bytes());
+
+    // Devices registered by a logged in user belong to them and need no enrollment code
+    let user_id: Option<String> = user_from_headers(&state, &headers);
+
+    let device = Device {
+        device_id: device_id.clone(),
+        public_key: payload.public_key,
+        label: payload.label,
+        os: payload.os,
+        user_id,
+        enrolled_with: None,
+        registered_at: Some(now),
+    };
+
+    let code: Option<String> = payload.code;
+    let (tokens, certificate): (TokenPair, Option<String>) = state.blocking(move |state| {
+        // Devices can authenticate with a certificate for their key instead of the tokens. It is
+        // issued first, so a device is not stored and its enrollment code not used up without one
+        let certificate: Option<String> = issue_client_certificate(&state.config.settings.tls, public_key.as_bytes(), state.config.settings.refresh_token_ttl_seconds)?;
+        Ok((add_device(state, device, code)?, certificate))
+    }).await?;
+
+    // Return if successful
+    let mut body = json!({ "status": "ok", "device_id": device_id, "token": tokens.token, "refresh_token": tokens.refresh_token, "expires_in": tokens.expires_in });
+    if let Some(certificate) = certificate {
+        body["client_certificate"] = json!(certificate);
     }
+    Ok((StatusCode::OK, Json(body)))
+}
+
+/// Stores a newly registered device and issues its first token pair.
+///
+/// The enrollment code is only redeemed once the device is known to be new, and given back if
+/// the device cannot be stored.
+///
+/// # Errors
+///
+/// Returns `CratisError::Api` if the device is already registered or the enrollment code is
+/// missing or invalid, and `CratisError` for database or JWT generation errors.
+fn add_device(state: &AppState, mut device: Device, code: Option<String>) -> CratisResult<TokenPair> {
+    let device_id: String = device.device_id.clone();
 
-    // Handle database error
-    if let Err(e) = check_id {
-        display_msg(Some(&CratisError::DatabaseError(e.to_string())), CratisErrorLevel::Warning, None);
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Internal Server Error" })))
+    // Check if device id already exists in database
+    let existing: Option<Device> = state.db.device(&device_id)?;
+
+    // The device proved it holds the key, so it may learn its id and sign in with /auth/token
+    if let Some(existing) = existing {
+        let message: String = match existing.registered_at {
+            Some(registered_at) => format!("Device already registered on {}", format_timestamp(registered_at)),
+            None => "Device already registered".to_string(),
+        };
+        return Err(CratisError::api(ErrorCode::AlreadyExists, message).with_details(json!({ "device_id": device_id, "registered_at": existing.registered_at })))
     }
 
-    // Generate new JWT for device
-    let jwt: String = match generate_jwt(device_id.clone()) {
-        Some(token) => token,
-        None => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Internal Server Error" })))
+    // Redeem the enrollment code only once the device is known to be new, so a conflict does not use it up
+    device.enrolled_with = match code.as_deref() {
+        _ if device.user_id.is_some() => None,
+        Some(code) => match redeem_code(state, code)? {
+            Some(id) => Some(id),
+            None => return Err(CratisError::api(ErrorCode::EnrollmentCodeInvalid, "Invalid or expired enrollment code")),
+        },
+        None if state.config.settings.require_enrollment_code => {
+            return Err(CratisError::api(ErrorCode::EnrollmentCodeInvalid, "An enrollment code is required"))
+        }
+        None => None,
     };
 
-    // Store device_id in db
-    let result = collection.insert_one(Device {device_id});
-    if let Err(e) = result {
-        display_msg(Some(&CratisError::DatabaseError(format!("Error inserting data: {}", e))), CratisErrorLevel::Warning, None);
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Internal Server Error" })))
+    // A device registering again keeps the data that was scheduled for deletion
+    let stored: CratisResult<bool> = cancel_data_deletion(state, &device_id).and_then(|_| state.db.insert_device(&device));
+
+    // The device was not registered, so the enrollment code is not used up by it
+    if !matches!(stored, Ok(true)) && let Some(code_id) = &device.enrolled_with && let Err(release) = release_code(state, code_id) {
+        warn!(code_id = %code_id, error = %release, "Unable to give back an enrollment code");
     }
 
-    // Return if successful
-    (StatusCode::OK, Json(json!({ "status": "ok", "token": jwt })))
+    match stored {
+        Ok(true) => issue_tokens(state, &device_id, DEVICE_SCOPES),
+        // Registered by a concurrent request since the check above
+        Ok(false) => Err(CratisError::api(ErrorCode::AlreadyExists, "Device already registered").with_details(json!({ "device_id": device_id }))),
+        Err(e) => Err(e),
+    }
 }
 
-pub async fn authenticate_middleware(mut req: Request<axum::body::Body>, next: Next) -> Result<Response, StatusCode> {
-    let auth_header = req.headers().get(http::header::AUTHORIZATION).and_then(|h| h.to_str().ok());
+/// Exchanges a refresh token for a new access token and a new refresh token.
+///
+/// Refresh tokens are single use. The presented token is marked as used and a new pair is
+/// issued. If a token that was already used is presented again, it has most likely been
+/// stolen, so all refresh tokens of the device are revoked and it has to register again.
+///
+/// # Returns
+///
+/// * `200 OK` with a new token pair
+/// * `401 Unauthorized` if the refresh token is unknown, expired or was already used
+/// * `500 Internal Server Error` for database or JWT generation errors
+///
+/// # Examples
+///
+/// ```json
+/// // Request
+/// { "refresh_token": "Xq3..." }
+///
+/// // Response
+/// { "status": "ok", "token": "eyJ0eXAi...", "refresh_token": "b7K...", "expires_in": 900 }
+/// ```
+pub async fn refresh(State(state): State<AppState>, Json(payload): Json<RefreshRequestData>) -> CratisResult<(StatusCode, Json<Value>)> {
+    match state.blocking(move |state| rotate_refresh_token(state, &payload.refresh_token)).await? {
+        Some(tokens) => Ok((StatusCode::OK, Json(json!({ "status": "ok", "token": tokens.token, "refresh_token": tokens.refresh_token, "expires_in": tokens.expires_in })))),
+        None => Err(CratisError::api(ErrorCode::Unauthorized, "Invalid refresh token")),
+    }
+}
+
+/// Hands out a challenge that a device signs with its private key to obtain new tokens.
+///
+/// Challenges are single use and expire after [`CHALLENGE_TTL_SECONDS`].
+///
+/// # Returns
+///
+/// * `200 OK` with the challenge
+/// * `404 Not Found` if the device is unknown or was registered without a key
+/// * `500 Internal Server Error` for database errors
+///
+/// # Examples
+///
+/// ```json
+/// // Request
+/// { "device_id": "6f1c2b1e-..." }
+///
+/// // Response
+/// { "status": "ok", "challenge": "p8Xn2...", "expires_in": 60 }
+/// ```
+pub async fn challenge(State(state): State<AppState>, Json(payload): Json<ChallengeRequestData>) -> CratisResult<(StatusCode, Json<Value>)> {
+    match state.blocking(move |state| create_challenge(state, &payload.device_id)).await? {
+        Some(challenge) => Ok((StatusCode::OK, Json(json!({ "status": "ok", "challenge": challenge, "expires_in": CHALLENGE_TTL_SECONDS })))),
+        None => Err(CratisError::api(ErrorCode::NotFound, "Device not found")),
+    }
+}
 
-    if let Some(auth_value) = auth_header {
-        if let Some(token) = auth_value.strip_prefix("Bearer ") {
-            match decode_token(token) {
-                Ok(claims) => {
-                    // Check if device_id is in db
-                    let collection: Collection<Device> = DB.collection::<Device>("devices");
-                    let result: Result<Option<Device>, polodb_core::Error> = collection.find_one(doc! { "device_id": &claims.device_id });
-
-                    // Handle database error
-                    if let Err(e) = result {
-                        display_msg(Some(&CratisError::DatabaseError(e.to_string())), CratisErrorLevel::Warning, None);
-                        return Err(StatusCode::INTERNAL_SERVER_ERROR)
-                    }
-
-                    if let Ok(Some(_)) = result {
-                        req.extensions_mut().insert(claims);
-                        return Ok(next.run(req).await);
-                    } else {
-                        return Err(StatusCode::UNAUTHORIZED)
-                    }
-                }
-                Err(_) => return Err(StatusCode::UNAUTHORIZED)
+/// Issues a new token pair to a device that signed a challenge from [`challenge`].
+///
+/// This lets a device obtain tokens again after its refresh token expired or was revoked,
+/// without registering again. The label of the device is updated if one is sent.
+///
+/// # Returns
+///
+/// * `200 OK` with a new token pair
+/// * `401 Unauthorized` if the challenge is unknown or expired, or the signature is invalid
+/// * `500 Internal Server Error` for database or JWT generation errors
+///
+/// # Examples
+///
+/// ```json
+/// // Request
+/// { "device_id": "6f1c2b1e-...", "challenge": "p8Xn2...", "signature": "Jc0w...", "label": "my-laptop" }
+///
+/// // Response
+/// { "status": "ok", "token": "eyJ0eXAi...", "refresh_token": "b7K...", "expires_in": 900 }
+/// ```
+pub async fn token(State(state): State<AppState>, Json(payload): Json<TokenRequestData>) -> CratisResult<(StatusCode, Json<Value>)> {
+    match state.blocking(move |state| answer_challenge(state, &payload)).await? {
+        Some((tokens, certificate)) => {
+            let mut body = json!({ "status": "ok", "token": tokens.token, "refresh_token": tokens.refresh_token, "expires_in": tokens.expires_in });
+            if let Some(certificate) = certificate {
+                body["client_certificate"] = json!(certificate);
             }
+            Ok((StatusCode::OK, Json(body)))
         }
+        None => Err(CratisError::api(ErrorCode::Unauthorized, "Invalid challenge or signature")),
+    }
+}
+
+/// Stores a new challenge for a device that has a public key.
+///
+/// # Returns
+///
+/// * `Ok(Some(String))` - The challenge
+/// * `Ok(None)` - If the device is unknown or has no public key
+/// * `Err(CratisError::DatabaseError)` - If the database cannot be queried or updated
+fn create_challenge(state: &AppState, device_id: &str) -> CratisResult<Option<String>> {
+    let device: Option<Device> = state.db.device(device_id)?;
+    if device.is_none_or(|device| device.public_key.is_empty()) {
+        return Ok(None);
     }
 
-    Err(StatusCode::UNAUTHORIZED)
+    let challenge: String = generate_random_string(CHALLENGE_LENGTH);
+    state.db.insert_challenge(&Challenge { device_id: device_id.to_string(), challenge: challenge.clone(), expires_at: timestamp_now()? + CHALLENGE_TTL_SECONDS })?;
+
+    Ok(Some(challenge))
 }
 
-/// Generates a unique device ID from hostname and OS information.
+/// Verifies a signed challenge and issues a new token pair, and a client certificate if enabled.
 ///
-/// Creates a deterministic UUID v5 by combining the hostname and OS into a string,
-/// hashing it with SHA-256, and generating a UUID using the URL namespace.
+/// # Returns
 ///
-/// # Arguments
+/// * `Ok(Some((TokenPair, Option<String>)))` - The new tokens and the PEM encoded client certificate
+/// * `Ok(None)` - If the challenge is unknown or expired, or the signature does not match the device's key
+/// * `Err(CratisError)` - For database or JWT generation errors
+fn answer_challenge(state: &AppState, payload: &TokenRequestData) -> CratisResult<Option<(TokenPair, Option<String>)>> {
+    // Challenges are removed before checking the signature, so each one can only be tried once
+    if !state.db.take_challenge(&payload.device_id, &payload.challenge, timestamp_now()?)? {
+        return Ok(None);
+    }
+
+    let device: Device = match state.db.device(&payload.device_id)? {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+
+    let public_key: VerifyingKey = match parse_public_key(&device.public_key) {
+        Some(public_key) => public_key,
+        None => return Ok(None),
+    };
+    if !verify_signature(&public_key, &challenge_message(&payload.device_id, &payload.challenge), &payload.signature) {
+        return Ok(None);
+    }
+
+    if let Some(label) = payload.label.as_deref().filter(|label| !label.is_empty() && *label != device.label) {
+        state.db.set_device_label(&payload.device_id, label)?;
+    }
+
+    let certificate: Option<String> = issue_client_certificate(&state.config.settings.tls, public_key.as_bytes(), state.config.settings.refresh_token_ttl_seconds)?;
+    let tokens: TokenPair = issue_tokens(state, &payload.device_id, DEVICE_SCOPES)?;
+    Ok(Some((tokens, certificate)))
+}
+
+/// Removes challenges that were not answered in time.
 ///
-/// * `hostname` - The device hostname
-/// * `os` - The operating system name
+/// # Returns
+///
+/// * `Ok(u64)` - The number of removed challenges
+/// * `Err(CratisError::DatabaseError)` - If the database cannot be updated
+pub fn prune_challenges(state: &AppState, now: u64) -> CratisResult<u64> {
+    state.db.prune_challenges(now)
+}
+
+/// Validates a refresh token and replaces it with a new token pair.
+///
+/// A refresh token can only be exchanged once. Presenting it again means it was stolen, so all
+/// tokens of the device are revoked, see [`revoke_device_tokens`].
 ///
 /// # Returns
 ///
-/// A string representation of the generated UUID
+/// * `Ok(Some(TokenPair))` - The new tokens
+/// * `Ok(None)` - If the token is unknown, expired or was already used
+/// * `Err(CratisError)` - For database or JWT generation errors
+fn rotate_refresh_token(state: &AppState, refresh_token: &str) -> CratisResult<Option<TokenPair>> {
+    let token_hash: String = hash_token(refresh_token);
+
+    let stored: RefreshToken = match state.db.refresh_token(&token_hash)? {
+        Some(stored) => stored,
+        None => return Ok(None),
+    };
+
+    // A used token is a reuse even after it expired
+    if !stored.used && stored.expires_at <= timestamp_now()? {
+        return Ok(None);
+    }
+
+    // Of several concurrent exchanges of the same token only the first one marks it, the others are reuses
+    if !state.db.mark_refresh_token_used(&token_hash)? {
+        revoke_device_tokens(state, &stored.device_id)?;
+        warn!(device_id = %stored.device_id, "Refresh token reuse detected, revoked all tokens of the device");
+        return Ok(None);
+    }
+
+    issue_tokens(state, &stored.device_id, &stored.scopes).map(Some)
+}
+
+/// Issues a new access token and a new refresh token for a device.
+///
+/// Only the hash of the refresh token is stored. Expired refresh tokens of the device are
+/// removed on the way.
 ///
-/// # Examples
+/// # Errors
 ///
-/// ```ignore
-/// let device_id = generate_device_id("laptop".to_string(), "linux".to_string());
-/// assert_eq!(device_id.len(), 36); // UUID string length
-/// ```
-fn generate_device_id(hostname: String, os: String) -> String {
-    let combined: String = format!("{}/{}", hostname, os);
-    let hash = Sha256::digest(combined);
-    Uuid::new_v5(&Uuid::NAMESPACE_URL, &hash).to_string()
+/// Returns `CratisError::TokenError` if the access token cannot be signed and
+/// `CratisError::DatabaseError` if the refresh token cannot be stored.
+pub fn issue_tokens(state: &AppState, device_id: &str, scopes: &[Scope]) -> CratisResult<TokenPair> {
+    let settings = &state.config.settings;
+    let now: u64 = timestamp_now()?;
+
+    let token: String = generate_jwt(state, device_id.to_string(), scopes.to_vec())
+        .ok_or_else(|| CratisError::TokenError("Unable to generate access token".to_string()))?;
+    let refresh_token: String = generate_random_string(REFRESH_TOKEN_LENGTH);
+
+    state.db.prune_refresh_tokens(device_id, now)?;
+    state.db.insert_refresh_token(&RefreshToken {
+        device_id: device_id.to_string(),
+        token_hash: hash_token(&refresh_token),
+        expires_at: now + settings.refresh_token_ttl_seconds,
+        used: false,
+        scopes: scopes.to_vec(),
+    })?;
+
+    Ok(TokenPair { token, refresh_token, expires_in: settings.access_token_ttl_seconds })
+}
+
+/// Returns the hex encoded SHA-256 hash of a refresh token, as stored in the database.
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Authenticates a request by its bearer token, or by the client certificate the device
+/// presented during the TLS handshake if it sent no token.
+///
+/// The claims of the device are added to the request. Devices authenticated by certificate get
+/// the scopes of a device token.
+///
+/// # Returns
+///
+/// * `401 Unauthorized` if neither a valid token nor a valid certificate was presented
+/// * `500 Internal Server Error` for database errors
+pub async fn authenticate_middleware(State(state): State<AppState>, mut req: Request<axum::body::Body>, next: Next) -> CratisResult<Response> {
+    let auth_header = req.headers().get(http::header::AUTHORIZATION).and_then(|h| h.to_str().ok());
+    let claims: Claims = match auth_header.and_then(extract_token) {
+        Some(token) => verify_token_blocking(&state, token).await?,
+        None => match req.extensions().get::<Option<ClientCert>>().cloned().flatten() {
+            Some(cert) => state.blocking(move |state| verify_certificate(state, &cert)).await?,
+            None => return Err(CratisError::api(ErrorCode::Unauthorized, "Missing access token")),
+        },
+    };
+
+    Span::current().record("device_id", claims.device_id.as_str());
+    req.extensions_mut().insert(claims);
+    Ok(authenticated(next.run(req).await))
+}
+
+/// Maps a client certificate to the device holding its key.
+///
+/// The certificate itself, including its validity period, was already verified against the
+/// server's CA during the handshake. Revoking all tokens of a device revokes the certificates
+/// issued to it so far as well.
+///
+/// # Returns
+///
+/// * `Ok(Claims)` - Claims for the device, valid for this request only
+/// * `Err(CratisError::Api)` - With `Unauthorized` if the certificate holds no device key, was revoked or the device was removed
+/// * `Err(CratisError::DatabaseError)` - If the database cannot be queried
+fn verify_certificate(state: &AppState, cert: &ClientCert) -> CratisResult<Claims> {
+    let invalid = || CratisError::api(ErrorCode::Unauthorized, "Invalid client certificate");
+    let public_key: [u8; 32] = certificate_public_key(cert).ok_or_else(invalid)?;
+    let device_id: String = device_id(&public_key);
+
+    let device: Device = state.db.device(&device_id)?.ok_or_else(|| CratisError::api(ErrorCode::Unauthorized, "Device not registered"))?;
+    if parse_public_key(&device.public_key).is_none_or(|key| *key.as_bytes() != public_key) {
+        return Err(invalid());
+    }
+
+    let issued_at: u64 = certificate_issued_at(cert).ok_or_else(invalid)?;
+    if state.db.is_revoked(&device_id, "", issued_at)? {
+        return Err(CratisError::api(ErrorCode::Unauthorized, "Client certificate revoked"));
+    }
+
+    let now: u64 = timestamp_now()?;
+    Ok(Claims { device_id, jti: String::new(), iat: now, exp: now, scopes: DEVICE_SCOPES.to_vec() })
+}
+
+/// Validates an access token: its signature and expiry, the revocation list, and that its device still exists.
+///
+/// # Returns
+///
+/// * `Ok(Claims)` - The claims of the valid token
+/// * `Err(CratisError::Api)` - With `Unauthorized` if the token is invalid, revoked or its device was removed
+/// * `Err(CratisError::DatabaseError)` - If the database cannot be queried
+pub(crate) fn verify_token(state: &AppState, token: &str) -> CratisResult<Claims> {
+    let claims: Claims = decode_token(state, token).map_err(|_| CratisError::api(ErrorCode::Unauthorized, "Invalid or expired access token"))?;
+
+    // Check the revocation list
+    if is_revoked(state, &claims)? {
+        return Err(CratisError::api(ErrorCode::Unauthorized, "Access token revoked"));
+    }
+
+    // Check if device_id is in db
+    match state.db.device(&claims.device_id)? {
+        Some(_) => Ok(claims),
+        None => Err(CratisError::api(ErrorCode::Unauthorized, "Device not registered")),
+    }
+}
+
+/// Runs [`verify_token`] on the blocking thread pool, for middlewares.
+pub(crate) async fn verify_token_blocking(state: &AppState, token: String) -> CratisResult<Claims> {
+    state.blocking(move |state| verify_token(state, &token)).await
+}
+
+/// Adds a single access token to the revocation list.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the revocation cannot be stored.
+pub fn revoke_token(state: &AppState, claims: &Claims) -> CratisResult<()> {
+    state.db.insert_revocation(&Revocation { device_id: claims.device_id.clone(), jti: claims.jti.clone(), issued_before: claims.iat + 1, expires_at: claims.exp })
+}
+
+/// Revokes every token issued to a device so far, including its refresh tokens and client certificates.
+///
+/// Tokens issued later, e.g. after the device registered again, are not affected.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be updated.
+pub fn revoke_device_tokens(state: &AppState, device_id: &str) -> CratisResult<()> {
+    let now: u64 = timestamp_now()?;
+
+    state.db.delete_refresh_tokens(device_id)?;
+
+    // Tokens issued in the current second are covered as well
+    state.db.insert_revocation(&Revocation {
+        device_id: device_id.to_string(),
+        jti: String::new(),
+        issued_before: now + 1,
+        // Client certificates are valid as long as refresh tokens, which outlive access tokens
+        expires_at: now + 1 + state.config.settings.refresh_token_ttl_seconds,
+    })
+}
+
+/// Checks whether a token is on the revocation list, either by its `jti` or because all
+/// tokens of its device issued before a certain time were revoked.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be queried.
+fn is_revoked(state: &AppState, claims: &Claims) -> CratisResult<bool> {
+    state.db.is_revoked(&claims.device_id, &claims.jti, claims.iat)
+}
+
+/// Removes revocations whose tokens have all expired, they are rejected by their `exp` anyway.
+///
+/// # Returns
+///
+/// * `Ok(u64)` - The number of removed entries
+/// * `Err(CratisError::DatabaseError)` - If the database cannot be updated
+pub fn prune_revocations(state: &AppState, now: u64) -> CratisResult<u64> {
+    state.db.prune_revocations(now)
+}
+
+/// Extracts the JWT from an `Authorization` header value.
+///
+/// Besides `Bearer <token>`, HTTP Basic credentials are accepted with the token as password
+/// and an arbitrary user name, since clients like WebDAV file managers only support Basic auth.
+///
+/// # Arguments
+///
+/// * `auth_value` - The value of the `Authorization` header
+///
+/// # Returns
+///
+/// * `Some(String)` - The token
+/// * `None` - If the header uses another scheme or is malformed
+pub(crate) fn extract_token(auth_value: &str) -> Option<String> {
+    if let Some(token) = auth_value.strip_prefix("Bearer ") {
+        return Some(token.to_string());
+    }
+
+    let encoded: &str = auth_value.strip_prefix("Basic ")?;
+    let decoded: Vec<u8> = BASE64_STANDARD.decode(encoded.trim()).ok()?;
+    let credentials: String = String::from_utf8(decoded).ok()?;
+    let (_, token) = credentials.split_once(':')?;
+
+    Some(token.to_string())
+}
+
+/// Decodes a base64 encoded Ed25519 public key.
+fn parse_public_key(encoded: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = BASE64_STANDARD.decode(encoded).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+/// Checks a base64 encoded Ed25519 signature of a message.
+fn verify_signature(public_key: &VerifyingKey, message: &str, signature: &str) -> bool {
+    let bytes: [u8; 64] = match BASE64_STANDARD.decode(signature).ok().and_then(|bytes| bytes.try_into().ok()) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
+
+    public_key.verify_strict(message.as_bytes(), &Signature::from_bytes(&bytes)).is_ok()
 }
 
 /// Generates a JWT token for device authentication.
 ///
-/// Creates a JSON Web Token containing the device ID as a claim, signed with
-/// the JWT_SECRET environment variable. Returns None if the secret is not set
-/// or token generation fails.
+/// Creates a JSON Web Token containing the device ID, its scopes, the issue time and an
+/// expiry `settings.access_token_ttl_seconds` in the future, signed with the configured
+/// secret. Returns None if the secret is not set or token generation fails.
 ///
 /// # Arguments
 ///
+/// * `state` - The server state holding the configured secret and token lifetime
 /// * `device_id` - The unique device identifier to include in the token
+/// * `scopes` - The permissions granted by the token
 ///
 /// # Returns
 ///
 /// * `Some(String)` - The generated JWT token
-/// * `None` - If JWT_SECRET is not set or token generation fails
-///
-/// # Environment Variables
-///
-/// * `JWT_SECRET` - Secret key used for signing the JWT token
+/// * `None` - If `settings.jwt` is not set or token generation fails
 ///
 /// # Examples
 ///
 /// ```ignore
-/// std::env::set_var("JWT_SECRET", "my-secret-key");
-/// let token = generate_jwt("device-123".to_string());
+/// let token = generate_jwt(&state, "device-123".to_string(), DEVICE_SCOPES.to_vec());
 /// assert!(token.is_some());
 /// ```
-fn generate_jwt(device_id: String) -> Option<String> {
-    let secret: String = get_config_api().settings.jwt.clone();
+fn generate_jwt(state: &AppState, device_id: String, scopes: Vec<Scope>) -> Option<String> {
+    let settings = &state.config.settings;
+    let secret: String = settings.jwt.clone();
 
     if secret.is_empty() {
-        display_msg(Some(&CratisError::TokenError("JWT Secret is empty!".to_string())), CratisErrorLevel::Warning, None);
+        warn!("JWT secret is empty");
         return None
     }
 
+    let iat: u64 = match timestamp_now() {
+        Ok(t) => t,
+        Err(e) => {
+            warn!(error = %e);
+            return None
+        }
+    };
+
     let encoding_key: EncodingKey = EncodingKey::from_secret(secret.as_bytes());
-    let jti: String = generate_random_string(6);
-    let claims = Claims { device_id, jti };
+    let jti: String = generate_random_string(16);
+    let claims = Claims { device_id, jti, iat, exp: iat + settings.access_token_ttl_seconds, scopes };
     match encode(&Header::default(), &claims, &encoding_key) {
         Ok(t) => Some(t),
         Err(e) => {
-            display_msg(Some(&CratisError::TokenError(e.to_string())), CratisErrorLevel::Warning, None);
+            warn!(error = %CratisError::TokenError(e.to_string()));
             None
         }
     }
 }
 
-fn decode_token(token: &str) ->  Result<Claims, jsonwebtoken::errors::Error> {
-    let secret: String = get_config_api().settings.jwt.clone();
+fn decode_token(state: &AppState, token: &str) ->  Result<Claims, jsonwebtoken::errors::Error> {
+    let secret: &str = &state.config.settings.jwt;
 
     if secret.is_empty() {
-        display_msg(Some(&CratisError::TokenError("JWT Secret is empty!".to_string())), CratisErrorLevel::Warning, None);
+        warn!("JWT secret is empty");
         return Err(jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat));
     }
 
+    // Validates `exp`, tokens without it are rejected
     let mut validation = Validation::default();
-    validation.validate_exp = false;
     validation.algorithms = vec![Algorithm::HS256];
-    validation.required_spec_claims.remove("exp");
 
     let token_data = decode::<Claims>(
         token,
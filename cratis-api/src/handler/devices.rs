@@ -0,0 +1,194 @@
+use cratis_core::{utils::timestamp_now, error::{CratisError, CratisResult, ErrorCode}};
+use axum::{extract::{Path, Query, State}, http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use std::time::Duration;
+use crate::handler::authentication::{prune_challenges, prune_revocations, revoke_device_tokens, revoke_token, Claims};
+use crate::handler::enrollment::prune_codes;
+use crate::handler::file_management::delete_device_files;
+use crate::state::AppState;
+
+// Request Structs
+#[derive(Deserialize)]
+pub struct DeleteDeviceQuery {
+    // Also delete the backed up data of the device once the grace period has passed
+    #[serde(default)]
+    delete_data: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RebindDeviceRequest {
+    // Device the backup history is moved to
+    device_id: String,
+}
+
+// Collection Structs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DataDeletion {
+    pub device_id: String,
+    // Unix timestamp after which the data is deleted
+    pub delete_after: u64,
+}
+
+/// Outcome of removing a device.
+enum Removal {
+    NotFound,
+    // Holds the time the data will be deleted at, if requested
+    Removed(Option<u64>),
+}
+
+/// Interval in which scheduled data deletions, expired revocations and enrollment codes are processed.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Removes a device, revoking all of its tokens.
+///
+/// A device can only remove itself. With `delete_data=true` the backed up data of the device
+/// is deleted once `settings.delete_data_after_days` have passed; registering the device again
+/// before that cancels the deletion.
+///
+/// # Returns
+///
+/// * `200 OK` with the time the data will be deleted at, if requested
+/// * `403 Forbidden` if the token belongs to another device
+/// * `404 Not Found` if the device does not exist
+/// * `500 Internal Server Error` for database errors
+pub async fn delete_device(State(state): State<AppState>, Extension(claims): Extension<Claims>, Path(device_id): Path<String>, Query(query): Query<DeleteDeviceQuery>) -> CratisResult<(StatusCode, Json<Value>)> {
+    if claims.device_id != device_id {
+        return Err(CratisError::api(ErrorCode::Forbidden, "Devices can only remove themselves"))
+    }
+
+    match state.blocking(move |state| remove_device(state, &claims, query.delete_data)).await? {
+        Removal::Removed(Some(delete_after)) => Ok((StatusCode::OK, Json(json!({ "status": "ok", "data_deleted_after": delete_after })))),
+        Removal::Removed(None) => Ok((StatusCode::OK, Json(json!({ "status": "ok" })))),
+        Removal::NotFound => Err(CratisError::api(ErrorCode::NotFound, "Device not found")),
+    }
+}
+
+/// Deletes the device record, revokes its tokens and schedules the deletion of its data.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be updated.
+fn remove_device(state: &AppState, claims: &Claims, delete_data: bool) -> CratisResult<Removal> {
+    if !state.db.delete_device(&claims.device_id)? {
+        return Ok(Removal::NotFound);
+    }
+
+    revoke_token(state, claims)?;
+    revoke_device_tokens(state, &claims.device_id)?;
+    state.metrics.forget_device(&claims.device_id);
+
+    if !delete_data {
+        return Ok(Removal::Removed(None));
+    }
+
+    let delete_after: u64 = timestamp_now()? + state.config.settings.delete_data_after_days as u64 * 24 * 60 * 60;
+    state.db.schedule_data_deletion(&DataDeletion { device_id: claims.device_id.clone(), delete_after })?;
+
+    Ok(Removal::Removed(Some(delete_after)))
+}
+
+/// Moves the backup history of a device to another device, for use behind `admin_middleware`.
+///
+/// Devices registered by hostname, before devices were identified by their key, register again
+/// under a key-derived id. Rebinding the old id makes their earlier backups part of the new
+/// device's history. The old device record is removed and its tokens are revoked.
+///
+/// # Returns
+///
+/// * `200 OK` with the number of moved versions
+/// * `404 Not Found` if either device does not exist
+/// * `400 Bad Request` if both ids are the same
+/// * `500 Internal Server Error` for database errors
+pub async fn rebind_device(State(state): State<AppState>, Path(device_id): Path<String>, Json(payload): Json<RebindDeviceRequest>) -> CratisResult<(StatusCode, Json<Value>)> {
+    if device_id == payload.device_id {
+        return Err(CratisError::api(ErrorCode::InvalidRequest, "A device cannot be rebound to itself"));
+    }
+
+    let (from, to): (String, String) = (device_id.clone(), payload.device_id.clone());
+    match state.blocking(move |state| move_device(state, &from, &to)).await? {
+        Some(moved) => {
+            info!(from = %device_id, to = %payload.device_id, moved, "Rebound a device");
+            Ok((StatusCode::OK, Json(json!({ "status": "ok", "moved_versions": moved }))))
+        }
+        None => Err(CratisError::api(ErrorCode::NotFound, "Device not found")),
+    }
+}
+
+/// Moves the versions of a device to another one and removes the first device.
+///
+/// # Returns
+///
+/// * `Ok(Some(u64))` - The number of moved versions
+/// * `Ok(None)` - If either device does not exist
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be updated.
+fn move_device(state: &AppState, from: &str, to: &str) -> CratisResult<Option<u64>> {
+    if state.db.device(from)?.is_none() || state.db.device(to)?.is_none() {
+        return Ok(None);
+    }
+
+    let moved: u64 = state.db.move_device_versions(from, to)?;
+    state.db.delete_device(from)?;
+    state.db.cancel_data_deletion(from)?;
+    revoke_device_tokens(state, from)?;
+    state.metrics.forget_device(from);
+
+    Ok(Some(moved))
+}
+
+/// Cancels a scheduled deletion of a device's data.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be updated.
+pub fn cancel_data_deletion(state: &AppState, device_id: &str) -> CratisResult<()> {
+    state.db.cancel_data_deletion(device_id)
+}
+
+/// Deletes the data of all devices whose scheduled deletion is due, prunes expired revocations
+/// and challenges, and removes enrollment codes that can no longer be used.
+///
+/// # Errors
+///
+/// Returns `CratisError` if the database cannot be queried or updated, or an object cannot be removed.
+pub fn run_maintenance(state: &AppState) -> CratisResult<()> {
+    let now: u64 = timestamp_now()?;
+    for deletion in state.db.due_data_deletions(now)? {
+        let removed: usize = delete_device_files(state, &deletion.device_id)?;
+        state.db.cancel_data_deletion(&deletion.device_id)?;
+
+        info!(device_id = %deletion.device_id, removed, "Deleted the file versions of a removed device");
+    }
+
+    prune_revocations(state, now)?;
+    prune_challenges(state, now)?;
+    prune_codes(state, now)?;
+
+    Ok(())
+}
+
+/// Runs [`run_maintenance`] once an hour, starting immediately, until the server shuts down.
+///
+/// A run that already started is finished before returning, so the database is not closed
+/// in the middle of it.
+pub async fn maintenance_loop(state: AppState) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+
+        let task_state: AppState = state.clone();
+        match tokio::task::spawn_blocking(move || run_maintenance(&task_state)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(error = %e, "Maintenance failed"),
+            Err(e) => warn!(error = %e, "Maintenance task failed"),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use cratis_core::{utils::{generate_random_string, timestamp_now}, error::{CratisError, CratisResult, ErrorCode}};
+use axum::{extract::{Path, State}, middleware::Next, response::Response, http::StatusCode, Json};
+use http::Request;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Sha256, Digest};
+use crate::handler::authentication::verify_token_blocking;
+use crate::handler::rate_limit::authenticated;
+use crate::handler::scopes::{missing_scope, Scope};
+use crate::state::AppState;
+
+// Request Structs
+#[derive(Deserialize)]
+pub struct CreateCodeRequest {
+    // Number of registrations the code allows, defaults to 1
+    max_uses: Option<u32>,
+    // Lifetime of the code in seconds, unlimited if not set
+    expires_in: Option<u64>,
+}
+
+// Collection Structs
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EnrollmentCode {
+    // Public identifier used to list and revoke the code
+    pub id: String,
+    // SHA-256 of the code, the code itself is only shown once when it is created
+    pub code_hash: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub max_uses: u32,
+    pub uses: u32,
+}
+
+/// Length of the random part of enrollment codes, shown in groups of four.
+const CODE_LENGTH: usize = 12;
+
+/// Only lets requests through that carry `settings.admin_token`, or an access token with the
+/// `admin` scope, as bearer token.
+///
+/// # Returns
+///
+/// * `404 Not Found` if no admin token is configured, the admin endpoints are disabled then
+/// * `401 Unauthorized` if the token is missing or wrong
+/// * `403 Forbidden` if an access token without the `admin` scope is presented
+pub async fn admin_middleware(State(state): State<AppState>, req: Request<axum::body::Body>, next: Next) -> CratisResult<Response> {
+    let admin_token: &str = &state.config.settings.admin_token;
+    if admin_token.is_empty() {
+        return Err(CratisError::api(ErrorCode::NotFound, "The admin endpoints are disabled"));
+    }
+
+    let provided: &str = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or_else(|| CratisError::api(ErrorCode::Unauthorized, "Missing admin token"))?;
+
+    // Comparing hashes keeps the comparison time independent of the secret
+    if Sha256::digest(provided.as_bytes()) == Sha256::digest(admin_token.as_bytes()) {
+        return Ok(authenticated(next.run(req).await));
+    }
+
+    match verify_token_blocking(&state, provided.to_string()).await? {
+        claims if claims.has_scope(Scope::Admin) => Ok(authenticated(next.run(req).await)),
+        _ => Err(missing_scope(Scope::Admin)),
+    }
+}
+
+/// Creates an enrollment code.
+///
+/// The code is only part of this response, the server keeps a hash of it.
+///
+/// # Returns
+///
+/// * `200 OK` with the code, its id, expiry and number of allowed uses
+/// * `400 Bad Request` if `max_uses` is 0
+/// * `500 Internal Server Error` for database errors
+///
+/// # Examples
+///
+/// ```json
+/// // Request
+/// { "max_uses": 1, "expires_in": 86400 }
+///
+/// // Response
+/// { "status": "ok", "id": "k2Jd9aQe", "code": "7GQ2-XK4P-M9TD", "expires_at": 1735948800, "max_uses": 1 }
+/// ```
+pub async fn create_code(State(state): State<AppState>, Json(payload): Json<CreateCodeRequest>) -> CratisResult<(StatusCode, Json<Value>)> {
+    let max_uses: u32 = payload.max_uses.unwrap_or(1);
+    if max_uses == 0 {
+        return Err(CratisError::api(ErrorCode::InvalidRequest, "max_uses must be at least 1"))
+    }
+
+    let (code, entry) = state.blocking(move |state| insert_code(state, max_uses, payload.expires_in)).await?;
+    Ok((StatusCode::OK, Json(json!({
+        "status": "ok",
+        "id": entry.id,
+        "code": code,
+        "expires_at": entry.expires_at,
+        "max_uses": entry.max_uses,
+    }))))
+}
+
+/// Lists all enrollment codes that can still be used.
+///
+/// # Returns
+///
+/// * `200 OK` with the codes, without the codes themselves
+/// * `500 Internal Server Error` for database errors
+pub async fn list_codes(State(state): State<AppState>) -> CratisResult<(StatusCode, Json<Value>)> {
+    Ok((StatusCode::OK, Json(json!({ "status": "ok", "codes": state.blocking(usable_codes).await? }))))
+}
+
+/// Revokes an enrollment code by its id.
+///
+/// # Returns
+///
+/// * `200 OK` if the code was revoked
+/// * `404 Not Found` if no code with this id exists
+/// * `500 Internal Server Error` for database errors
+pub async fn revoke_code(State(state): State<AppState>, Path(id): Path<String>) -> CratisResult<(StatusCode, Json<Value>)> {
+    if state.blocking(move |state| state.db.delete_code(&id)).await? {
+        Ok((StatusCode::OK, Json(json!({ "status": "ok" }))))
+    } else {
+        Err(CratisError::api(ErrorCode::NotFound, "Enrollment code not found"))
+    }
+}
+
+/// Generates a new enrollment code and stores its hash.
+///
+/// # Returns
+///
+/// * `Ok((String, EnrollmentCode))` - The code and its stored entry
+/// * `Err(CratisError::DatabaseError)` - If the code cannot be stored
+fn insert_code(state: &AppState, max_uses: u32, expires_in: Option<u64>) -> CratisResult<(String, EnrollmentCode)> {
+    let now: u64 = timestamp_now()?;
+    let raw: String = generate_random_string(CODE_LENGTH).to_uppercase();
+    let code: String = raw.as_bytes().chunks(4).map(|c| String::from_utf8_lossy(c).to_string()).collect::<Vec<String>>().join("-");
+
+    let entry = EnrollmentCode {
+        id: generate_random_string(8),
+        code_hash: hash_code(&code),
+        created_at: now,
+        expires_at: expires_in.map(|seconds| now + seconds),
+        max_uses,
+        uses: 0,
+    };
+
+    state.db.insert_code(&entry)?;
+
+    Ok((code, entry))
+}
+
+fn usable_codes(state: &AppState) -> CratisResult<Vec<EnrollmentCode>> {
+    let now: u64 = timestamp_now()?;
+
+    let codes: Vec<EnrollmentCode> = state.db.codes()?;
+    Ok(codes.into_iter().filter(|code| code.is_usable(now)).collect())
+}
+
+impl EnrollmentCode {
+    /// Checks whether the code allows another registration at `now`.
+    pub fn is_usable(&self, now: u64) -> bool {
+        self.uses < self.max_uses && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// Redeems an enrollment code for one registration.
+///
+/// Codes are compared case-insensitively and with or without dashes.
+///
+/// # Returns
+///
+/// * `Ok(Some(String))` - The id of the redeemed code, pass it to [`release_code`] if the registration fails
+/// * `Ok(None)` - If the code is unknown, expired or used up
+/// * `Err(CratisError::DatabaseError)` - If the database cannot be queried or updated
+pub fn redeem_code(state: &AppState, code: &str) -> CratisResult<Option<String>> {
+    let entry: EnrollmentCode = match state.db.code_by_hash(&hash_code(code))? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    match state.db.try_redeem_code(&entry.id, timestamp_now()?)? {
+        true => Ok(Some(entry.id)),
+        false => Ok(None),
+    }
+}
+
+/// Gives back a registration redeemed with [`redeem_code`] that did not complete.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be updated.
+pub fn release_code(state: &AppState, id: &str) -> CratisResult<()> {
+    state.db.release_code(id)
+}
+
+/// Removes enrollment codes that expired or are used up.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of removed codes
+/// * `Err(CratisError::DatabaseError)` - If the database cannot be queried or updated
+pub fn prune_codes(state: &AppState, now: u64) -> CratisResult<usize> {
+    let mut removed: usize = 0;
+    for code in state.db.codes()? {
+        if code.is_usable(now) {
+            continue;
+        }
+
+        state.db.delete_code(&code.id)?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// Normalizes a code and returns its hex encoded SHA-256 hash, as stored in the database.
+fn hash_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| *c != '-' && !c.is_whitespace()).collect::<String>().to_uppercase();
+    Sha256::digest(normalized.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
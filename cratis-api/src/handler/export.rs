@@ -0,0 +1,229 @@
+use cratis_core::{utils::timestamp_now, error::{CratisError, CratisResult, ErrorCode}};
+use axum::{body::{Body, Bytes}, extract::{Query, State}, http::{header, StatusCode}, response::Response, Extension};
+use serde::Deserialize;
+use tracing::{warn, Span};
+use std::io::{self, BufWriter, Write};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+use crate::handler::authentication::Claims;
+use crate::handler::file_management::{snapshot_files, File};
+use crate::handler::users::can_access_device;
+use crate::state::AppState;
+use crate::storage::{PinnedObjects, Storage};
+
+// Request Structs
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    // Unix timestamp of the snapshot, defaults to now
+    as_of: Option<u64>,
+    // Only export files whose original path starts with this prefix
+    prefix: Option<String>,
+    format: Option<ArchiveFormat>,
+    // Export a snapshot of another device of the same user, defaults to the authenticated device
+    device: Option<String>,
+}
+
+impl ArchiveFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarZst => "application/zstd",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+/// Number of archive chunks that may be queued before the archive writer waits for the client.
+const CHANNEL_CAPACITY: usize = 16;
+/// Size of the buffer collecting small writes into larger body chunks.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Exports a snapshot of the authenticated device, or another device of the same user, as an archive download.
+///
+/// The archive is written on a blocking thread and streamed to the client chunk by chunk,
+/// so memory usage stays constant regardless of the snapshot size.
+///
+/// # Query Parameters
+///
+/// * `as_of` - Unix timestamp of the snapshot, defaults to the current time
+/// * `prefix` - Only include files whose original path starts with this prefix
+/// * `format` - `tar` (default), `tar.zst` or `zip`
+/// * `device` - Id of the device to export, defaults to the authenticated device
+///
+/// # Returns
+///
+/// * `200 OK` with the archive as body
+/// * `403 Forbidden` if the device belongs to another user
+/// * `404 Not Found` if the snapshot contains no files
+/// * `500 Internal Server Error` for database errors
+pub async fn export(State(state): State<AppState>, Extension(claims): Extension<Claims>, Query(query): Query<ExportQuery>) -> CratisResult<Response> {
+    let as_of: u64 = match query.as_of {
+        Some(t) => t,
+        None => timestamp_now()?,
+    };
+    let format: ArchiveFormat = query.format.unwrap_or(ArchiveFormat::Tar);
+
+    let device_id: String = query.device.unwrap_or_else(|| claims.device_id.clone());
+    // Retention or the deletion of a device's data could remove objects during a long download otherwise
+    let (files, pins): (Vec<File>, PinnedObjects) = state.blocking(move |state| {
+        if !can_access_device(state, &claims, &device_id)? {
+            return Err(CratisError::api(ErrorCode::Forbidden, "The device belongs to another user"))
+        }
+        state.storage.pin_objects(|| snapshot_files(state, &device_id, as_of, query.prefix.as_deref()), |file| &file.hash)
+    }).await?;
+    if files.is_empty() {
+        return Err(CratisError::api(ErrorCode::NotFound, "No files found for this snapshot"))
+    }
+
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(CHANNEL_CAPACITY);
+
+    let span: Span = Span::current();
+    let storage: Storage = state.storage.clone();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let _pins: PinnedObjects = pins;
+        let mut writer = BufWriter::with_capacity(CHUNK_SIZE, ChannelWriter { tx: tx.clone() });
+        let result: CratisResult<()> = write_archive(&storage, format, &files, &mut writer).and_then(|_| Ok(writer.flush()?));
+
+        if let Err(e) = result {
+            warn!(error = %e, "Export aborted");
+            // Abort the response so the client does not mistake a truncated archive for a complete one
+            let _ = tx.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+    });
+
+    let file_name: String = format!("snapshot-{}.{}", as_of, format.extension());
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name))
+        .body(Body::from_stream(ReceiverStream::new(rx)))
+        .map_err(|e| CratisError::Internal(e.to_string()))
+}
+
+/// Forwards everything written to it as body chunks over a channel.
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the given file versions as an archive of the requested format.
+///
+/// # Arguments
+///
+/// * `storage` - The object store the content of the files is read from
+/// * `format` - The archive format to produce
+/// * `files` - The file versions to include
+/// * `out` - The writer receiving the archive
+///
+/// # Errors
+///
+/// Returns `CratisError::IoError` if an object cannot be read or the archive cannot be written.
+pub fn write_archive<W: Write>(storage: &Storage, format: ArchiveFormat, files: &[File], out: W) -> CratisResult<()> {
+    match format {
+        ArchiveFormat::Tar => {
+            write_tar(storage, files, out)?;
+        }
+        ArchiveFormat::TarZst => {
+            let encoder = zstd::Encoder::new(out, 0)?;
+            write_tar(storage, files, encoder)?.finish()?;
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new_stream(out);
+
+            for file in files {
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .large_file(file.size >= u32::MAX as u64);
+
+                zip.start_file(archive_path(&file.path), options).map_err(io::Error::from)?;
+                io::copy(&mut storage.open_object(&file.hash)?, &mut zip)?;
+            }
+
+            zip.finish().map_err(io::Error::from)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_tar<W: Write>(storage: &Storage, files: &[File], out: W) -> CratisResult<W> {
+    let mut builder = tar::Builder::new(out);
+
+    for file in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(file.size);
+        header.set_mode(0o644);
+        header.set_mtime(file.timestamp);
+
+        builder.append_data(&mut header, archive_path(&file.path), storage.open_object(&file.hash)?)?;
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+/// Turns an original device path into a relative path inside an archive.
+///
+/// Leading slashes, drive colons and `..` components are removed and Windows separators
+/// are normalized, e.g. `C:\Users\me\a.txt` becomes `C/Users/me/a.txt`.
+pub fn archive_path(path: &str) -> String {
+    path.replace('\\', "/")
+        .split('/')
+        .map(|component| component.trim_end_matches(':'))
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_paths_are_relative() {
+        assert_eq!(archive_path("/home/me/a.txt"), "home/me/a.txt");
+        assert_eq!(archive_path("home/me/a.txt"), "home/me/a.txt");
+        assert_eq!(archive_path("C:\\Users\\me\\a.txt"), "C/Users/me/a.txt");
+        assert_eq!(archive_path("C:/Users/me/a.txt"), "C/Users/me/a.txt");
+        assert_eq!(archive_path("//server/share/./a.txt"), "server/share/a.txt");
+    }
+
+    #[test]
+    fn archive_paths_stay_inside_the_archive() {
+        assert_eq!(archive_path("/home/../../etc/passwd"), "home/etc/passwd");
+        assert_eq!(archive_path("..\\..\\a.txt"), "a.txt");
+        assert_eq!(archive_path("/../"), "");
+    }
+}
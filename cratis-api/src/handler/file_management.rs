@@ -1,17 +1,435 @@
-use axum::{extract::Multipart, response::IntoResponse, http::StatusCode};
-use polodb_core::{CollectionT, bson::doc, Collection};
+use cratis_core::{config::{Compression, RetentionConfig}, utils::timestamp_now, error::{CratisError, CratisResult, ErrorCode}};
+use axum::{body::Bytes, extract::{Multipart, Query, State, multipart::Field}, http::StatusCode, Extension, Json};
 use serde::{Deserialize, Serialize};
-use tokio::fs::File as TokioFile;
-use tokio::io::AsyncWriteExt;
+use serde_json::{json, Value};
+use tracing::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use tokio::sync::mpsc;
+use crate::handler::authentication::Claims;
+use crate::handler::scopes::Scope;
+use crate::state::AppState;
+use crate::storage::{ObjectWriter, Storage, StoredObject};
 
 // Collection Structs
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct File {
-    device_id: String,
+    pub device_id: String,
+    // Path of the file on the device it was backed up from
+    pub path: String,
+    // BLAKE3 hash of the content, used as key in the object store
+    pub hash: String,
+    pub size: u64,
+    // Unix timestamp of the backup that produced this version
+    pub timestamp: u64,
+    // Name of the backup job that produced this version, None for imports
+    #[serde(default)]
+    pub job: Option<String>,
 }
 
-pub async fn backup(mut multipart: Multipart) -> impl IntoResponse {
-    // file_name, file_path, file_size
-    let mut metadata: Vec<(String, String, u32)> = Vec::new();
-    
-}
\ No newline at end of file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetentionPolicy {
+    pub device_id: String,
+    pub job: String,
+    pub keep_versions: Option<u32>,
+    pub keep_days: Option<u32>,
+}
+
+// Request Structs
+#[derive(Deserialize)]
+pub struct BackupQuery {
+    // Name of the backup job the upload belongs to
+    job: Option<String>,
+    // Compression applied to every uploaded file by the client
+    #[serde(default)]
+    compression: Compression,
+    keep_versions: Option<u32>,
+    keep_days: Option<u32>,
+}
+
+/// Number of seconds in a day, used for `keep_days` retention.
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Number of received chunks of an upload that may wait to be written to the object store.
+const UPLOAD_QUEUE_CHUNKS: usize = 8;
+
+/// Handles file uploads from a device.
+///
+/// Expects a multipart body in which every `files` part is followed by a `paths` text field
+/// holding the original path of that file on the device. The content of every file is streamed
+/// into the object store, identical content is only stored once. A new version is recorded
+/// for each path whose content changed since its last backup.
+///
+/// # Query Parameters
+///
+/// * `job` - Name of the backup job, recorded with every new version
+/// * `compression` - `none` (default) or `zstd` if the client compressed the files
+/// * `keep_versions`, `keep_days` - Retention policy of the job, applied to its versions after the upload.
+///   The first policy sent for a job is stored, later requests can only loosen it unless the token
+///   has the `admin` scope, see [`retention_policy`].
+///
+/// # Returns
+///
+/// * `200 OK` with the number of received, changed and pruned files
+/// * `400 Bad Request` if the multipart body is malformed or files and paths do not match up
+/// * `500 Internal Server Error` for storage or database errors
+pub async fn backup(State(state): State<AppState>, Extension(claims): Extension<Claims>, Query(query): Query<BackupQuery>, mut multipart: Multipart) -> CratisResult<(StatusCode, Json<Value>)> {
+    let mut objects: Vec<StoredObject> = Vec::new();
+    let mut paths: Vec<String> = Vec::new();
+    let malformed = |_| CratisError::api(ErrorCode::InvalidRequest, "Malformed multipart body");
+
+    while let Some(field) = multipart.next_field().await.map_err(malformed)? {
+        match field.name() {
+            Some("files") => objects.push(store_field(&state, field, query.compression).await?),
+            Some("paths") => paths.push(field.text().await.map_err(malformed)?),
+            _ => continue,
+        }
+    }
+
+    if objects.len() != paths.len() {
+        return Err(CratisError::api(ErrorCode::InvalidRequest, "Every file requires a matching path"));
+    }
+
+    let timestamp: u64 = timestamp_now()?;
+    let files: usize = objects.len();
+    let ingested: u64 = objects.iter().map(|object| object.size).sum();
+
+    // The objects stay pinned until their versions are recorded
+    let changed: usize = state.blocking({
+        let device_id: String = claims.device_id.clone();
+        let job: Option<String> = query.job.clone();
+        move |state| {
+            let mut changed: usize = 0;
+            for (object, path) in objects.iter().zip(paths.iter()) {
+                if record_version(state, &device_id, path, object, timestamp, job.as_deref())? {
+                    changed += 1;
+                }
+            }
+            Ok(changed)
+        }
+    }).await?;
+
+    state.metrics.record_ingest(&claims.device_id, ingested);
+
+    let requested = RetentionConfig { keep_versions: query.keep_versions, keep_days: query.keep_days };
+    let mut pruned: usize = 0;
+    if let Some(job) = query.job {
+        let device_id: String = claims.device_id.clone();
+        let admin: bool = claims.has_scope(Scope::Admin);
+
+        let result: CratisResult<usize> = state.blocking(move |state| {
+            let retention: RetentionConfig = retention_policy(state, &device_id, &job, requested, admin)?;
+            apply_retention(state, &device_id, &job, retention, timestamp)
+        }).await;
+
+        match result {
+            Ok(removed) => pruned = removed,
+            // The backup itself succeeded, pruning is retried with the next backup
+            Err(e) => warn!(error = %e),
+        }
+    }
+
+    Ok((StatusCode::OK, Json(json!({ "status": "ok", "files": files, "changed": changed, "pruned": pruned }))))
+}
+
+/// Streams a single uploaded file into the object store, decompressing it if needed.
+///
+/// Objects are always stored and hashed uncompressed, so deduplication works across jobs
+/// with different compression settings. The chunks are written by a blocking task, see
+/// [`write_object`].
+async fn store_field(state: &AppState, mut field: Field<'_>, compression: Compression) -> CratisResult<StoredObject> {
+    let (chunks, mut received) = mpsc::channel::<Option<Bytes>>(UPLOAD_QUEUE_CHUNKS);
+    let storage: Storage = state.storage.clone();
+    let task = tokio::task::spawn_blocking(move || write_object(&storage, &mut received, compression));
+
+    loop {
+        let chunk: Option<Bytes> = field.chunk().await.map_err(|e| CratisError::InvalidInput(format!("Upload interrupted: {}", e)))?;
+        let last: bool = chunk.is_none();
+        // The task only stops receiving if writing failed, its error is returned below
+        if chunks.send(chunk).await.is_err() || last {
+            break;
+        }
+    }
+
+    task.await.map_err(|e| CratisError::Internal(format!("Upload task failed: {}", e)))?
+}
+
+/// Writes the chunks of an upload into the object store, decompressing them if needed.
+///
+/// `None` marks the end of the upload. If the channel closes before, the upload was
+/// interrupted and nothing is stored.
+fn write_object(storage: &Storage, received: &mut mpsc::Receiver<Option<Bytes>>, compression: Compression) -> CratisResult<StoredObject> {
+    let writer: ObjectWriter = storage.writer()?;
+
+    match compression {
+        Compression::None => {
+            let mut writer: ObjectWriter = writer;
+            write_chunks(received, &mut writer)?;
+            writer.finish()
+        }
+        Compression::Zstd => {
+            let mut decoder = zstd::stream::write::Decoder::new(writer)?;
+            write_chunks(received, &mut decoder)?;
+            decoder.flush()?;
+            decoder.into_inner().finish()
+        }
+    }
+}
+
+fn write_chunks(received: &mut mpsc::Receiver<Option<Bytes>>, out: &mut impl Write) -> CratisResult<()> {
+    loop {
+        match received.blocking_recv() {
+            Some(Some(chunk)) => out.write_all(&chunk)?,
+            Some(None) => return Ok(()),
+            None => return Err(CratisError::InvalidInput("Upload interrupted".to_string())),
+        }
+    }
+}
+
+/// Records a new version of a file unless its content is unchanged.
+///
+/// The content is compared against the latest version of the same path that is not newer
+/// than `timestamp`, so repeated backups of unchanged files do not create new versions.
+///
+/// # Arguments
+///
+/// * `device_id` - The device the file belongs to
+/// * `path` - Path of the file on the device
+/// * `object` - The stored content of the file
+/// * `timestamp` - Unix timestamp the version is recorded at
+/// * `job` - Name of the backup job producing the version, if any
+///
+/// # Returns
+///
+/// * `Ok(true)` - If a new version was recorded
+/// * `Ok(false)` - If the content did not change
+/// * `Err(CratisError::DatabaseError)` - If the database could not be queried or updated
+pub fn record_version(state: &AppState, device_id: &str, path: &str, object: &StoredObject, timestamp: u64, job: Option<&str>) -> CratisResult<bool> {
+    let latest: Option<File> = state.db.latest_version(device_id, path, timestamp)?;
+    if latest.is_some_and(|l| l.hash == object.hash) {
+        return Ok(false);
+    }
+
+    state.db.insert_version(&File {
+        device_id: device_id.to_string(),
+        path: path.to_string(),
+        hash: object.hash.clone(),
+        size: object.size,
+        timestamp,
+        job: job.map(str::to_string),
+    })?;
+
+    Ok(true)
+}
+
+/// Resolves the state of a device's files at a point in time.
+///
+/// For every path the latest version that is not newer than `as_of` is selected.
+///
+/// # Arguments
+///
+/// * `device_id` - The device to resolve the snapshot for
+/// * `as_of` - Unix timestamp of the snapshot
+/// * `prefix` - Only include paths starting with this prefix, if given
+///
+/// # Returns
+///
+/// * `Ok(Vec<File>)` - The versions making up the snapshot, ordered by path
+/// * `Err(CratisError::DatabaseError)` - If the database could not be queried
+pub fn snapshot_files(state: &AppState, device_id: &str, as_of: u64, prefix: Option<&str>) -> CratisResult<Vec<File>> {
+    state.db.snapshot(device_id, as_of, prefix)
+}
+
+/// Lists the points in time at which a device has snapshots.
+///
+/// Every backup or import that recorded at least one version creates a snapshot.
+///
+/// # Arguments
+///
+/// * `device_id` - The device to list the snapshots of
+///
+/// # Returns
+///
+/// * `Ok(Vec<u64>)` - Unix timestamps of all snapshots in ascending order
+/// * `Err(CratisError::DatabaseError)` - If the database could not be queried
+pub fn snapshot_times(state: &AppState, device_id: &str) -> CratisResult<Vec<u64>> {
+    state.db.snapshot_times(device_id)
+}
+
+/// Size of the stored content, as exported by `/metrics`.
+pub struct StorageStats {
+    // Distinct objects in the store
+    pub objects: u64,
+    // Bytes the objects take up, every content counted once
+    pub stored_bytes: u64,
+    // Bytes of all recorded versions, as if nothing was deduplicated
+    pub referenced_bytes: u64,
+}
+
+/// Sums up the objects referenced by the recorded versions of all devices.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database could not be queried.
+pub fn storage_stats(state: &AppState) -> CratisResult<StorageStats> {
+    state.db.storage_stats()
+}
+
+/// Returns the retention policy to apply to a backup job, storing the requested one if allowed.
+///
+/// Clients send their policy with every upload. The first policy of a job is stored on the server,
+/// afterwards a request may keep more versions or keep them longer, but only a token with the
+/// `admin` scope can make the policy stricter. A leaked device token therefore cannot be used to
+/// prune a job's history.
+///
+/// # Arguments
+///
+/// * `device_id` - The device the job belongs to
+/// * `job` - Name of the backup job
+/// * `requested` - The policy sent by the client
+/// * `admin` - Whether the request is allowed to tighten the stored policy
+///
+/// # Returns
+///
+/// * `Ok(RetentionConfig)` - The stored policy after the request
+/// * `Err(CratisError)` - If the database could not be queried or updated
+pub fn retention_policy(state: &AppState, device_id: &str, job: &str, requested: RetentionConfig, admin: bool) -> CratisResult<RetentionConfig> {
+    let stored: Option<RetentionConfig> = state.db.retention_policy(device_id, job)?
+        .map(|policy| RetentionConfig { keep_versions: policy.keep_versions, keep_days: policy.keep_days });
+
+    if stored == Some(requested) {
+        return Ok(requested);
+    }
+    if let Some(stored) = stored && !admin && !loosens(stored, requested) {
+        warn!(device_id, job, "Ignoring a stricter retention policy, it requires the admin scope");
+        return Ok(stored);
+    }
+
+    state.db.set_retention_policy(&RetentionPolicy {
+        device_id: device_id.to_string(),
+        job: job.to_string(),
+        keep_versions: requested.keep_versions,
+        keep_days: requested.keep_days,
+    })?;
+    Ok(requested)
+}
+
+/// Checks whether `requested` keeps at least every version `stored` keeps.
+fn loosens(stored: RetentionConfig, requested: RetentionConfig) -> bool {
+    let keeps = |stored: Option<u32>, requested: Option<u32>| match (stored, requested) {
+        (_, None) => true,
+        (Some(stored), Some(requested)) => requested >= stored,
+        (None, Some(_)) => false,
+    };
+    keeps(stored.keep_versions, requested.keep_versions) && keeps(stored.keep_days, requested.keep_days)
+}
+
+/// Removes versions produced by a backup job that fall outside its retention policy.
+///
+/// Versions are grouped by path. A version is removed if it is older than the `keep_versions`
+/// newest versions of its path or older than `keep_days`. The newest version of every path is
+/// always kept, so the latest snapshot stays complete. Objects no longer referenced by any
+/// version are removed from the store.
+///
+/// Blocks on the database and the file system, call it from a blocking task.
+///
+/// # Arguments
+///
+/// * `device_id` - The device the job belongs to
+/// * `job` - Name of the backup job
+/// * `retention` - The retention policy of the job
+/// * `now` - Unix timestamp `keep_days` is measured from
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of removed versions
+/// * `Err(CratisError)` - If the database could not be queried or updated, or an object could not be removed
+pub fn apply_retention(state: &AppState, device_id: &str, job: &str, retention: RetentionConfig, now: u64) -> CratisResult<usize> {
+    if retention.keep_versions.is_none() && retention.keep_days.is_none() {
+        return Ok(0);
+    }
+
+    let mut by_path: BTreeMap<String, Vec<File>> = BTreeMap::new();
+    for version in state.db.job_versions(device_id, job)? {
+        by_path.entry(version.path.clone()).or_default().push(version);
+    }
+
+    let cutoff: Option<u64> = retention.keep_days.map(|days| now.saturating_sub(days as u64 * SECONDS_PER_DAY));
+    let mut removed: usize = 0;
+    let mut released: BTreeSet<String> = BTreeSet::new();
+
+    for mut versions in by_path.into_values() {
+        versions.sort_by_key(|v| std::cmp::Reverse(v.timestamp));
+
+        for (index, version) in versions.into_iter().enumerate().skip(1) {
+            let too_many: bool = retention.keep_versions.is_some_and(|keep| index >= keep as usize);
+            let too_old: bool = cutoff.is_some_and(|cutoff| version.timestamp < cutoff);
+            if !too_many && !too_old {
+                continue;
+            }
+
+            state.db.delete_version(device_id, &version.path, version.timestamp)?;
+            removed += 1;
+            released.insert(version.hash);
+        }
+    }
+
+    release_objects(state, released)?;
+
+    Ok(removed)
+}
+
+/// Removes all file versions of a device along with objects no other device references.
+///
+/// Objects a running upload of another device deduplicated against are kept, see
+/// [`crate::storage::Storage::release_object`]. Blocks on the database and the file system.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of removed versions
+/// * `Err(CratisError)` - If the database could not be queried or updated, or an object could not be removed
+pub fn delete_device_files(state: &AppState, device_id: &str) -> CratisResult<usize> {
+    let versions: Vec<File> = state.db.delete_device_versions(device_id)?;
+    let removed: usize = versions.len();
+
+    release_objects(state, versions.into_iter().map(|version| version.hash).collect())?;
+
+    Ok(removed)
+}
+
+/// Removes the given objects from the store unless a file version or a running upload still uses them.
+fn release_objects(state: &AppState, hashes: BTreeSet<String>) -> CratisResult<()> {
+    for hash in hashes {
+        state.storage.release_object(&hash, || state.db.is_referenced(&hash))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(keep_versions: Option<u32>, keep_days: Option<u32>) -> RetentionConfig {
+        RetentionConfig { keep_versions, keep_days }
+    }
+
+    #[test]
+    fn loosening_keeps_at_least_as_many_versions() {
+        let stored: RetentionConfig = policy(Some(5), Some(30));
+
+        assert!(loosens(stored, policy(Some(5), Some(30))));
+        assert!(loosens(stored, policy(Some(10), None)));
+        assert!(loosens(stored, policy(None, None)));
+        assert!(!loosens(stored, policy(Some(1), Some(30))));
+        assert!(!loosens(stored, policy(Some(5), Some(1))));
+    }
+
+    #[test]
+    fn limiting_an_unlimited_policy_tightens_it() {
+        let stored: RetentionConfig = policy(None, Some(30));
+
+        assert!(!loosens(stored, policy(Some(100), Some(30))));
+        assert!(loosens(stored, policy(None, Some(60))));
+    }
+}
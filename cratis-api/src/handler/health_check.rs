@@ -1,5 +1,103 @@
+use cratis_core::error::CratisError;
+use axum::{extract::State, Json};
 use http::StatusCode;
+use serde_json::{json, Value};
+use tracing::warn;
+use crate::state::AppState;
+
+const MEGABYTE: u64 = 1024 * 1024;
 
 pub async fn health_check() -> StatusCode {
     StatusCode::OK
-}
\ No newline at end of file
+}
+
+/// Reports that the server process is running, without checking its dependencies.
+///
+/// # Returns
+///
+/// * `200 OK` with the version and uptime
+///
+/// # Examples
+///
+/// ```json
+/// // Response
+/// { "status": "ok", "version": "0.1.0", "uptime_seconds": 3600 }
+/// ```
+pub async fn live(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    (StatusCode::OK, Json(json!({ "status": "ok", "version": env!("CARGO_PKG_VERSION"), "uptime_seconds": state.started_at.elapsed().as_secs() })))
+}
+
+/// Reports whether the server can handle backups.
+///
+/// Checks that the database answers a query, that a file can be written to the storage
+/// directory and that the storage has at least `settings.health.min_free_disk_mb` free.
+/// Failures are logged with their cause, the response only names the failed check. While the
+/// server shuts down the `shutdown` check fails, so load balancers stop sending requests.
+///
+/// # Returns
+///
+/// * `200 OK` with the results of the checks, the version and uptime if all checks passed
+/// * `503 Service Unavailable` with the same body if any check failed
+///
+/// # Examples
+///
+/// ```json
+/// // Response
+/// {
+///   "status": "ok",
+///   "version": "0.1.0",
+///   "uptime_seconds": 3600,
+///   "checks": {
+///     "database": { "ok": true },
+///     "storage": { "ok": true },
+///     "disk": { "ok": true, "free_bytes": 52428800000, "min_free_bytes": 1073741824 },
+///     "shutdown": { "ok": true }
+///   }
+/// }
+/// ```
+pub async fn ready(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    // The checks block on the database and the file system, so they run on the blocking pool
+    let (database, storage, disk) = tokio::join!(
+        state.blocking(|state| state.db.count_devices()),
+        state.blocking(|state| state.storage.probe_writable()),
+        state.blocking(|state| state.storage.free_space()),
+    );
+
+    let database: Value = match database {
+        Ok(_) => json!({ "ok": true }),
+        Err(e) => failed(e, "Database query failed"),
+    };
+
+    let storage: Value = match storage {
+        Ok(()) => json!({ "ok": true }),
+        Err(e) => failed(e, "Storage is not writable"),
+    };
+
+    let min_free_bytes: u64 = state.config.settings.health.min_free_disk_mb.saturating_mul(MEGABYTE);
+    let disk: Value = match disk {
+        Ok(Some(free_bytes)) => json!({ "ok": free_bytes >= min_free_bytes, "free_bytes": free_bytes, "min_free_bytes": min_free_bytes }),
+        // The free space is unknown, which should not take the server out of rotation
+        Ok(None) => json!({ "ok": true }),
+        Err(e) => failed(e, "Free space could not be determined"),
+    };
+
+    let shutdown: Value = match state.is_shutting_down() {
+        true => json!({ "ok": false, "error": "Server is shutting down" }),
+        false => json!({ "ok": true }),
+    };
+
+    let is_ready: bool = [&database, &storage, &disk, &shutdown].iter().all(|check| check["ok"] == true);
+    let status: StatusCode = if is_ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    (status, Json(json!({
+        "status": if is_ready { "ok" } else { "unavailable" },
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_seconds": state.started_at.elapsed().as_secs(),
+        "checks": { "database": database, "storage": storage, "disk": disk, "shutdown": shutdown },
+    })))
+}
+
+fn failed(error: CratisError, reason: &str) -> Value {
+    warn!(error = %error, "{}", reason);
+    json!({ "ok": false, "error": reason })
+}
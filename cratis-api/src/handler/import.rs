@@ -0,0 +1,170 @@
+use cratis_core::error::{CratisError, CratisResult, ErrorCode};
+use axum::{body::Body, extract::{Query, State}, http::StatusCode, Extension, Json};
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read};
+use tokio_stream::StreamExt;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use crate::handler::authentication::Claims;
+use crate::handler::file_management::record_version;
+use crate::state::AppState;
+use crate::storage::{ObjectWriter, StoredObject};
+
+// Request Structs
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    // Unix timestamp the archive content is recorded at
+    as_of: u64,
+    // Directory on the device the archive was taken from, archive paths are placed below it
+    root: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    files: u64,
+    versions: u64,
+    bytes: u64,
+    // Bytes that were already stored and did not need to be written again
+    deduplicated_bytes: u64,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Imports a tar archive into the history of the authenticated device.
+///
+/// The request body is the raw archive, optionally gzip or zstd compressed. Compression is
+/// detected from the first bytes of the body. Every regular file in the archive is stored
+/// as a version at `as_of`, as if it had been backed up at that time. Content that is already
+/// stored is deduplicated.
+///
+/// # Query Parameters
+///
+/// * `as_of` - Unix timestamp the files are recorded at
+/// * `root` - Directory the archive paths are relative to, defaults to `/`
+///
+/// # Returns
+///
+/// * `200 OK` with a summary of the imported files
+/// * `400 Bad Request` if the body is not a valid tar archive
+/// * `500 Internal Server Error` for storage or database errors
+pub async fn import(State(state): State<AppState>, Extension(claims): Extension<Claims>, Query(query): Query<ImportQuery>, body: Body) -> CratisResult<(StatusCode, Json<Value>)> {
+    let stream = body.into_data_stream().map(|chunk| chunk.map_err(io::Error::other));
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+    let root: String = query.root.unwrap_or_else(|| "/".to_string());
+    let device_id: String = claims.device_id.clone();
+
+    let result: CratisResult<ImportSummary> = state.blocking(move |state| {
+        import_archive(state, BufReader::new(reader), &claims.device_id, &root, query.as_of)
+    }).await;
+
+    match result {
+        Ok(summary) => {
+            state.metrics.record_ingest(&device_id, summary.bytes);
+            Ok((StatusCode::OK, Json(json!({ "status": "ok", "summary": summary }))))
+        }
+        Err(CratisError::IoError(e)) if e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(CratisError::api(ErrorCode::InvalidRequest, "Invalid tar archive"))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads a possibly compressed tar archive and records its files as versions.
+fn import_archive<R: BufRead>(state: &AppState, mut reader: R, device_id: &str, root: &str, as_of: u64) -> CratisResult<ImportSummary> {
+    let magic: &[u8] = reader.fill_buf()?;
+
+    if magic.starts_with(&GZIP_MAGIC) {
+        read_tar(state, MultiGzDecoder::new(reader), device_id, root, as_of)
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        read_tar(state, zstd::Decoder::with_buffer(reader)?, device_id, root, as_of)
+    } else {
+        read_tar(state, reader, device_id, root, as_of)
+    }
+}
+
+fn read_tar<R: Read>(state: &AppState, reader: R, device_id: &str, root: &str, as_of: u64) -> CratisResult<ImportSummary> {
+    let mut archive = tar::Archive::new(reader);
+    let mut summary = ImportSummary::default();
+    // tar reports malformed headers as `ErrorKind::Other`, they are answered as invalid archives
+    let invalid = |e: io::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path: String = match device_path(root, &entry.path().map_err(invalid)?.to_string_lossy()) {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let mut writer: ObjectWriter = state.storage.writer()?;
+        io::copy(&mut entry, &mut writer)?;
+        let object: StoredObject = writer.finish()?;
+
+        summary.files += 1;
+        summary.bytes += object.size;
+        if !object.is_new {
+            summary.deduplicated_bytes += object.size;
+        }
+        if record_version(state, device_id, &path, &object, as_of, None)? {
+            summary.versions += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Maps a path inside an archive to the path it had on the device.
+///
+/// Returns `None` for paths that would escape `root` or are empty.
+fn device_path(root: &str, archive_path: &str) -> Option<String> {
+    let components: Vec<&str> = archive_path
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+
+    if components.is_empty() || components.contains(&"..") {
+        return None;
+    }
+
+    Some(format!("{}/{}", root.trim_end_matches('/'), components.join("/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_paths_are_placed_below_the_root() {
+        assert_eq!(device_path("/", "home/me/a.txt").as_deref(), Some("/home/me/a.txt"));
+        assert_eq!(device_path("/home/me/", "./docs//a.txt").as_deref(), Some("/home/me/docs/a.txt"));
+        assert_eq!(device_path("/srv", "/etc/passwd").as_deref(), Some("/srv/etc/passwd"));
+    }
+
+    #[test]
+    fn archive_paths_do_not_escape_the_root() {
+        assert_eq!(device_path("/srv", "../etc/passwd"), None);
+        assert_eq!(device_path("/srv", "docs/../../etc/passwd"), None);
+        assert_eq!(device_path("/srv", "./"), None);
+        assert_eq!(device_path("/srv", ""), None);
+    }
+
+    #[test]
+    fn compression_is_detected_from_the_magic_bytes() {
+        let gzip: Vec<u8> = {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, b"tar").unwrap();
+            encoder.finish().unwrap()
+        };
+        let zstd: Vec<u8> = zstd::encode_all(&b"tar"[..], 0).unwrap();
+
+        assert!(gzip.starts_with(&GZIP_MAGIC));
+        assert!(zstd.starts_with(&ZSTD_MAGIC));
+        assert!(!gzip.starts_with(&ZSTD_MAGIC) && !zstd.starts_with(&GZIP_MAGIC));
+    }
+}
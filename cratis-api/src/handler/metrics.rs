@@ -0,0 +1,176 @@
+use cratis_core::{error::{CratisError, CratisResult, ErrorCode}};
+use axum::{extract::{MatchedPath, Request, State}, http::{header, HeaderMap, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::handler::file_management::{storage_stats, StorageStats};
+use crate::state::AppState;
+
+/// Route label of requests that did not match any route, so unknown paths do not create new series.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Time the storage gauges are reused for, computing them reads every stored version.
+const STORAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Metrics of a server, exported in the Prometheus text format by [`metrics`].
+///
+/// Every [`AppState`] has its own, so servers in one process do not share counters. Counters
+/// start at zero when the server starts. The gauges describing the stored data are read from
+/// the database on a scrape, at most every [`STORAGE_REFRESH_INTERVAL`].
+pub struct Metrics {
+    registry: Registry,
+    // Handled requests by method, route and status
+    requests: IntCounterVec,
+    // Time until the response was ready by method and route, streamed bodies are not included
+    latency: HistogramVec,
+    // Uncompressed bytes received in backups and imports by device
+    ingested_bytes: IntCounterVec,
+    // Requests answered with 401 Unauthorized by route
+    auth_failures: IntCounterVec,
+    devices: IntGauge,
+    objects: IntGauge,
+    stored_bytes: IntGauge,
+    deduplicated_bytes: IntGauge,
+    // Time the storage gauges were last read, None before the first scrape
+    storage_read_at: Mutex<Option<Instant>>,
+}
+
+impl Metrics {
+    /// Creates the metrics and registers them with a registry of their own.
+    ///
+    /// # Errors
+    ///
+    /// Returns `prometheus::Error` if a metric name is invalid or used twice.
+    pub fn new() -> prometheus::Result<Self> {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("cratis".to_string()), None)?,
+            requests: IntCounterVec::new(Opts::new("http_requests_total", "Handled HTTP requests"), &["method", "route", "status"])?,
+            latency: HistogramVec::new(HistogramOpts::new("http_request_duration_seconds", "Time until the response was ready"), &["method", "route"])?,
+            ingested_bytes: IntCounterVec::new(Opts::new("ingested_bytes_total", "Bytes received in backups and imports"), &["device_id"])?,
+            auth_failures: IntCounterVec::new(Opts::new("auth_failures_total", "Requests rejected with 401 Unauthorized"), &["route"])?,
+            devices: IntGauge::new("devices", "Registered devices")?,
+            objects: IntGauge::new("stored_objects", "Distinct objects in the store")?,
+            stored_bytes: IntGauge::new("stored_bytes", "Bytes taken up by the stored objects")?,
+            deduplicated_bytes: IntGauge::new("deduplicated_bytes", "Bytes saved by storing identical content once")?,
+            storage_read_at: Mutex::new(None),
+        };
+
+        metrics.registry.register(Box::new(metrics.requests.clone()))?;
+        metrics.registry.register(Box::new(metrics.latency.clone()))?;
+        metrics.registry.register(Box::new(metrics.ingested_bytes.clone()))?;
+        metrics.registry.register(Box::new(metrics.auth_failures.clone()))?;
+        metrics.registry.register(Box::new(metrics.devices.clone()))?;
+        metrics.registry.register(Box::new(metrics.objects.clone()))?;
+        metrics.registry.register(Box::new(metrics.stored_bytes.clone()))?;
+        metrics.registry.register(Box::new(metrics.deduplicated_bytes.clone()))?;
+
+        Ok(metrics)
+    }
+
+    /// Counts the bytes a device uploaded, called once the upload was recorded.
+    pub fn record_ingest(&self, device_id: &str, bytes: u64) {
+        self.ingested_bytes.with_label_values(&[device_id]).inc_by(bytes);
+    }
+
+    /// Removes the series of a device that no longer exists.
+    pub fn forget_device(&self, device_id: &str) {
+        // Fails if the device uploaded nothing since the server started, there is nothing to remove then
+        let _ = self.ingested_bytes.remove_label_values(&[device_id]);
+    }
+
+    /// Reads the number of devices, and the size of the store if the last reading is older than
+    /// [`STORAGE_REFRESH_INTERVAL`], from the database.
+    fn refresh_storage(&self, state: &AppState) -> CratisResult<()> {
+        self.devices.set(state.db.count_devices()? as i64);
+
+        let mut read_at = self.storage_read_at.lock().unwrap_or_else(|e| e.into_inner());
+        if read_at.is_some_and(|read_at| read_at.elapsed() < STORAGE_REFRESH_INTERVAL) {
+            return Ok(());
+        }
+
+        let stats: StorageStats = storage_stats(state)?;
+        *read_at = Some(Instant::now());
+
+        self.objects.set(stats.objects as i64);
+        self.stored_bytes.set(stats.stored_bytes as i64);
+        self.deduplicated_bytes.set(stats.referenced_bytes.saturating_sub(stats.stored_bytes) as i64);
+        Ok(())
+    }
+}
+
+/// Middleware counting requests and measuring their latency per route, applied to every route.
+///
+/// Routes are labelled with their pattern, e.g. `/devices/{id}`, so the number of series stays
+/// bounded. Requests rejected by the rate limiter are counted as well.
+///
+/// # Examples
+///
+/// ```ignore
+/// let app = Router::new().merge(routes).layer(middleware::from_fn_with_state(state.clone(), track_metrics));
+/// ```
+pub async fn track_metrics(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let route: String = req.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string()).unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let method: String = req.method().to_string();
+    let started: Instant = Instant::now();
+
+    let response: Response = next.run(req).await;
+
+    let metrics: &Metrics = &state.metrics;
+    let status: StatusCode = response.status();
+    metrics.requests.with_label_values(&[method.as_str(), route.as_str(), status.as_str()]).inc();
+    metrics.latency.with_label_values(&[method.as_str(), route.as_str()]).observe(started.elapsed().as_secs_f64());
+    if status == StatusCode::UNAUTHORIZED {
+        metrics.auth_failures.with_label_values(&[route.as_str()]).inc();
+    }
+
+    response
+}
+
+/// Exports the metrics in the Prometheus text format.
+///
+/// Disabled unless `settings.metrics.enabled` is set. If `settings.metrics.token` is set, it
+/// has to be sent as bearer token, e.g. with `authorization: { credentials: <token> }` in the
+/// scrape config.
+///
+/// # Returns
+///
+/// * `200 OK` with the metrics
+/// * `401 Unauthorized` if the token is missing or wrong
+/// * `404 Not Found` if metrics are disabled
+/// * `500 Internal Server Error` for database errors
+///
+/// # Examples
+///
+/// ```text
+/// cratis_http_requests_total{method="POST",route="/backup",status="200"} 42
+/// cratis_ingested_bytes_total{device_id="6f1c2b1e-..."} 1073741824
+/// cratis_stored_objects 1234
+/// ```
+pub async fn metrics(State(state): State<AppState>, headers: HeaderMap) -> CratisResult<Response> {
+    let settings = &state.config.settings.metrics;
+    if !settings.enabled {
+        return Err(CratisError::api(ErrorCode::NotFound, "Metrics are disabled"));
+    }
+
+    if !settings.token.is_empty() {
+        let provided: &str = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        // Comparing hashes keeps the comparison time independent of the secret
+        if Sha256::digest(provided.as_bytes()) != Sha256::digest(settings.token.as_bytes()) {
+            return Err(CratisError::api(ErrorCode::Unauthorized, "Invalid metrics token"));
+        }
+    }
+
+    state.blocking(|state| state.metrics.refresh_storage(state)).await?;
+
+    let encoder = TextEncoder::new();
+    let mut body: Vec<u8> = Vec::new();
+    encoder.encode(&state.metrics.registry.gather(), &mut body).map_err(|e| CratisError::Internal(e.to_string()))?;
+
+    Ok(([(header::CONTENT_TYPE, encoder.format_type().to_string())], body).into_response())
+}
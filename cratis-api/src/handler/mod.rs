@@ -1,3 +1,14 @@
 pub mod authentication;
 pub mod health_check;
-pub mod file_management;
\ No newline at end of file
+pub mod file_management;
+pub mod export;
+pub mod import;
+pub mod webdav;
+pub mod devices;
+pub mod enrollment;
+pub mod users;
+pub mod scopes;
+pub mod rate_limit;
+pub mod trace;
+pub mod metrics;
+pub mod shutdown;
\ No newline at end of file
@@ -0,0 +1,266 @@
+use cratis_core::{config::RateLimitSettings, error::{CratisError, ErrorCode}};
+use axum::{extract::{ConnectInfo, Request, State}, http::{header, StatusCode}, middleware::Next, response::{IntoResponse, Response}, Extension};
+use serde_json::json;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::handler::authentication::Claims;
+use crate::state::AppState;
+
+/// Number of tracked clients above which idle entries are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Request budget of a single client, refilled continuously up to the per minute limit.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Bucket { tokens: f64::MAX, updated: now }
+    }
+
+    /// Takes one request from the budget.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the request is within the limit
+    /// * `Err(u64)` - Seconds until the next request is allowed
+    fn take(&mut self, per_minute: u32, now: Instant) -> Result<(), u64> {
+        let capacity: f64 = per_minute as f64;
+        let rate: f64 = capacity / 60.0;
+
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(capacity);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / rate).ceil() as u64)
+        }
+    }
+
+    /// Whether the budget is full again, so the entry carries no state worth keeping.
+    fn idle(&self, per_minute: u32, now: Instant) -> bool {
+        now.duration_since(self.updated) >= Duration::from_secs(60) || self.tokens >= per_minute as f64
+    }
+}
+
+/// Failed authentication attempts of a single IP address.
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Marks the response to a request that authenticated successfully.
+///
+/// Only such responses reset the failed attempts of the client's IP address in [`limit_ip`], a
+/// successful request to a public route like `/ping` does not.
+#[derive(Clone, Copy, Debug)]
+pub struct Authenticated;
+
+/// Marks the response as authenticated, see [`Authenticated`].
+pub fn authenticated(mut response: Response) -> Response {
+    response.extensions_mut().insert(Authenticated);
+    response
+}
+
+/// Marks successful responses of routes that check credentials themselves, like `/login`.
+///
+/// # Examples
+///
+/// ```ignore
+/// .route("/login", post(login))
+/// .route_layer(middleware::map_response(mark_authenticated))
+/// ```
+pub async fn mark_authenticated(response: Response) -> Response {
+    match response.status().is_success() {
+        true => authenticated(response),
+        false => response,
+    }
+}
+
+/// In-memory state of the rate limiting layers, shared by all requests.
+///
+/// Limits apply per server process, they are reset on restart.
+#[derive(Default)]
+pub struct RateLimiter {
+    ips: Mutex<HashMap<IpAddr, Bucket>>,
+    devices: Mutex<HashMap<String, Bucket>>,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(RateLimiter::default())
+    }
+
+    /// Returns the remaining lockout of an IP address in seconds, if it is locked out.
+    fn locked(&self, ip: IpAddr, now: Instant) -> Option<u64> {
+        // A poisoned lock must not lift the lockout
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let until: Instant = failures.get(&ip)?.locked_until.filter(|until| *until > now)?;
+
+        Some(until.duration_since(now).as_secs().max(1))
+    }
+
+    /// Records a rejected authentication attempt, locking the IP address out once
+    /// `lockout_after_failures` is reached. Every further failure doubles the lockout.
+    fn record_failure(&self, ip: IpAddr, limits: &RateLimitSettings, now: Instant) {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        if failures.len() > PRUNE_THRESHOLD {
+            failures.retain(|_, f| f.locked_until.is_some_and(|until| until > now));
+        }
+
+        let entry: &mut Failures = failures.entry(ip).or_insert(Failures { count: 0, locked_until: None });
+        entry.count = entry.count.saturating_add(1);
+
+        if entry.count >= limits.lockout_after_failures {
+            let exponent: u32 = (entry.count - limits.lockout_after_failures).min(31);
+            let seconds: u64 = limits.lockout_base_seconds.saturating_mul(1 << exponent).min(limits.lockout_max_seconds);
+            entry.locked_until = Some(now + Duration::from_secs(seconds));
+        }
+    }
+
+    /// Forgets the failed attempts of an IP address after it authenticated successfully.
+    fn clear_failures(&self, ip: IpAddr) {
+        self.failures.lock().unwrap_or_else(|e| e.into_inner()).remove(&ip);
+    }
+}
+
+/// Takes a request from the bucket of `key`, dropping idle buckets once too many are tracked.
+fn take<K: Eq + Hash>(buckets: &Mutex<HashMap<K, Bucket>>, key: K, per_minute: u32, now: Instant) -> Result<(), u64> {
+    // A poisoned lock only means another request panicked, the counters are still usable
+    let mut buckets = buckets.lock().unwrap_or_else(|e| e.into_inner());
+    if buckets.len() > PRUNE_THRESHOLD {
+        buckets.retain(|_, bucket| !bucket.idle(per_minute, now));
+    }
+
+    buckets.entry(key).or_insert_with(|| Bucket::new(now)).take(per_minute, now)
+}
+
+fn too_many_requests(retry_after: u64) -> Response {
+    (
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        CratisError::api(ErrorCode::RateLimited, "Too many requests").with_details(json!({ "retry_after": retry_after })),
+    )
+        .into_response()
+}
+
+/// Middleware limiting the requests per client IP address, applied to every route.
+///
+/// IP addresses that keep failing authentication (`401 Unauthorized` from any route, e.g. a
+/// wrong password, signature or token) are locked out with an exponentially growing lockout,
+/// see `settings.rate_limit`. Only a request that authenticated successfully resets the count,
+/// see [`Authenticated`].
+///
+/// # Examples
+///
+/// ```ignore
+/// let app = Router::new().merge(routes).layer(middleware::from_fn_with_state(state.clone(), limit_ip));
+/// ```
+///
+/// # Returns
+///
+/// * `429 Too Many Requests` with a `Retry-After` header if the IP address is over its limit or locked out
+pub async fn limit_ip(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, req: Request, next: Next) -> Response {
+    let limits: &RateLimitSettings = &state.config.settings.rate_limit;
+    if !limits.enabled {
+        return next.run(req).await;
+    }
+    let limiter: &RateLimiter = &state.limiter;
+
+    let ip: IpAddr = addr.ip().to_canonical();
+    let now: Instant = Instant::now();
+
+    if let Some(retry_after) = limiter.locked(ip, now) {
+        return too_many_requests(retry_after);
+    }
+    if let Err(retry_after) = take(&limiter.ips, ip, limits.requests_per_minute_ip, now) {
+        return too_many_requests(retry_after);
+    }
+
+    let response: Response = next.run(req).await;
+    match response.status() {
+        StatusCode::UNAUTHORIZED => limiter.record_failure(ip, limits, Instant::now()),
+        _ if response.extensions().get::<Authenticated>().is_some() => limiter.clear_failures(ip),
+        _ => {}
+    }
+
+    response
+}
+
+/// Middleware limiting the requests per device, applied after `authenticate_middleware`.
+///
+/// Keeps a single device from using up the server even when its requests come from many
+/// IP addresses.
+///
+/// # Returns
+///
+/// * `429 Too Many Requests` with a `Retry-After` header if the device is over its limit
+pub async fn limit_device(State(state): State<AppState>, Extension(claims): Extension<Claims>, req: Request, next: Next) -> Response {
+    let limits: &RateLimitSettings = &state.config.settings.rate_limit;
+    if !limits.enabled {
+        return next.run(req).await;
+    }
+
+    if let Err(retry_after) = take(&state.limiter.devices, claims.device_id, limits.requests_per_minute_device, Instant::now()) {
+        return too_many_requests(retry_after);
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn lock_out(limiter: &RateLimiter, now: Instant) {
+        let limits = RateLimitSettings { lockout_after_failures: 2, ..RateLimitSettings::default() };
+        limiter.record_failure(IP, &limits, now);
+        assert!(limiter.locked(IP, now).is_none());
+        limiter.record_failure(IP, &limits, now);
+    }
+
+    #[test]
+    fn failures_lock_out_until_cleared() {
+        let limiter = RateLimiter::default();
+        let now: Instant = Instant::now();
+
+        lock_out(&limiter, now);
+        assert!(limiter.locked(IP, now).is_some());
+
+        limiter.clear_failures(IP);
+        assert!(limiter.locked(IP, now).is_none());
+    }
+
+    #[test]
+    fn poisoned_lock_keeps_the_lockout() {
+        let limiter = Arc::new(RateLimiter::default());
+        let now: Instant = Instant::now();
+        lock_out(&limiter, now);
+
+        let poisoner = limiter.clone();
+        let _ = std::thread::spawn(move || {
+            let _failures = poisoner.failures.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+
+        assert!(limiter.failures.is_poisoned());
+        assert!(limiter.locked(IP, now).is_some());
+    }
+
+    #[test]
+    fn only_authenticated_responses_are_marked() {
+        let ok: Response = StatusCode::OK.into_response();
+        assert!(ok.extensions().get::<Authenticated>().is_none());
+        assert!(authenticated(ok).extensions().get::<Authenticated>().is_some());
+    }
+}
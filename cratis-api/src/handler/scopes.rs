@@ -0,0 +1,143 @@
+use cratis_core::error::{CratisError, CratisResult, ErrorCode};
+use axum::{extract::State, middleware::Next, response::{IntoResponse, Response}, http::StatusCode, Extension, Json};
+use http::Request;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::handler::authentication::{issue_tokens, Claims, TokenPair};
+use crate::state::AppState;
+
+// Request Structs
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    scopes: Vec<Scope>,
+}
+
+#[derive(Deserialize)]
+pub struct AdminTokenRequest {
+    device_id: String,
+    scopes: Vec<Scope>,
+}
+
+/// Permissions carried by access tokens and checked per route.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    // Upload backups and imports, and remove the device
+    #[serde(rename = "backup:write")]
+    BackupWrite,
+    // Read backups through export, WebDAV and the device list
+    #[serde(rename = "restore:read")]
+    RestoreRead,
+    // Everything, including the backups of all devices and the admin endpoints
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+/// Scopes of tokens issued to devices on registration and login.
+pub const DEVICE_SCOPES: &[Scope] = &[Scope::BackupWrite, Scope::RestoreRead];
+
+/// Tokens issued before scopes existed were device tokens.
+pub fn default_scopes() -> Vec<Scope> {
+    DEVICE_SCOPES.to_vec()
+}
+
+impl Claims {
+    /// Checks whether the token grants a scope, `admin` grants every scope.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|s| *s == scope || *s == Scope::Admin)
+    }
+}
+
+/// Rejects requests whose token lacks the scope given as state.
+///
+/// Has to run after `authenticate_middleware`, which provides the claims.
+///
+/// # Examples
+///
+/// ```ignore
+/// .route("/backup", post(backup).layer(middleware::from_fn_with_state(Scope::BackupWrite, require_scope)))
+/// ```
+///
+/// # Returns
+///
+/// * `403 Forbidden` with the missing scope if the token does not grant it
+pub async fn require_scope(State(scope): State<Scope>, Extension(claims): Extension<Claims>, req: Request<axum::body::Body>, next: Next) -> Response {
+    if claims.has_scope(scope) {
+        return next.run(req).await;
+    }
+
+    missing_scope(scope).into_response()
+}
+
+/// Returns the error for a token that does not grant a scope.
+pub(crate) fn missing_scope(scope: Scope) -> CratisError {
+    CratisError::api(ErrorCode::MissingScope, "Missing scope").with_details(json!({ "scope": scope }))
+}
+
+/// Issues a token pair with fewer scopes for the authenticated device.
+///
+/// Meant for tools that only need part of the device's permissions, e.g. a restore-only token
+/// for a helpdesk tool. The requested scopes must be granted by the presented token.
+///
+/// # Returns
+///
+/// * `200 OK` with the new token pair
+/// * `400 Bad Request` if no scope is requested
+/// * `403 Forbidden` if a requested scope is not granted by the presented token
+/// * `500 Internal Server Error` for database or JWT generation errors
+///
+/// # Examples
+///
+/// ```json
+/// // Request
+/// { "scopes": ["restore:read"] }
+///
+/// // Response
+/// { "status": "ok", "token": "eyJ0eXAi...", "refresh_token": "b7K...", "expires_in": 900, "scopes": ["restore:read"] }
+/// ```
+pub async fn create_token(State(state): State<AppState>, Extension(claims): Extension<Claims>, Json(payload): Json<CreateTokenRequest>) -> CratisResult<(StatusCode, Json<Value>)> {
+    if payload.scopes.is_empty() {
+        return Err(CratisError::api(ErrorCode::InvalidRequest, "At least one scope is required"))
+    }
+    if let Some(scope) = payload.scopes.iter().find(|scope| !claims.has_scope(**scope)) {
+        return Err(missing_scope(*scope))
+    }
+
+    let tokens: TokenPair = state.blocking({
+        let scopes: Vec<Scope> = payload.scopes.clone();
+        move |state| issue_tokens(state, &claims.device_id, &scopes)
+    }).await?;
+
+    Ok(token_response(tokens, &payload.scopes))
+}
+
+/// Issues a token pair with any scopes for any device, for use behind `admin_middleware`.
+///
+/// # Returns
+///
+/// * `200 OK` with the new token pair
+/// * `400 Bad Request` if no scope is requested
+/// * `404 Not Found` if the device does not exist
+/// * `500 Internal Server Error` for database or JWT generation errors
+pub async fn create_admin_token(State(state): State<AppState>, Json(payload): Json<AdminTokenRequest>) -> CratisResult<(StatusCode, Json<Value>)> {
+    if payload.scopes.is_empty() {
+        return Err(CratisError::api(ErrorCode::InvalidRequest, "At least one scope is required"))
+    }
+
+    let scopes: Vec<Scope> = payload.scopes.clone();
+    let tokens: TokenPair = state.blocking(move |state| match state.db.device(&payload.device_id)? {
+        Some(_) => issue_tokens(state, &payload.device_id, &scopes),
+        None => Err(CratisError::api(ErrorCode::NotFound, "Device not found")),
+    }).await?;
+
+    Ok(token_response(tokens, &payload.scopes))
+}
+
+fn token_response(tokens: TokenPair, scopes: &[Scope]) -> (StatusCode, Json<Value>) {
+    (StatusCode::OK, Json(json!({
+        "status": "ok",
+        "token": tokens.token,
+        "refresh_token": tokens.refresh_token,
+        "expires_in": tokens.expires_in,
+        "scopes": scopes,
+    })))
+}
@@ -0,0 +1,59 @@
+use cratis_core::error::{CratisError, CratisResult, ErrorCode};
+use axum::{extract::{Request, State}, middleware::Next, response::Response};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use crate::state::AppState;
+
+/// Waits for SIGTERM or Ctrl-C and starts the shutdown by cancelling `shutdown`.
+///
+/// Returns once a signal arrived, or once the shutdown was started elsewhere.
+pub async fn wait_for_signal(shutdown: CancellationToken) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!(error = %e, "Unable to listen for Ctrl-C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!(error = %e, "Unable to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl-C, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+        _ = shutdown.cancelled() => {}
+    }
+    shutdown.cancel();
+}
+
+/// Middleware refusing new uploads once the shutdown started, applied to `/backup` and `/import`.
+///
+/// Uploads that were already running are not affected, they get the drain timeout to finish.
+///
+/// # Returns
+///
+/// * `503 Service Unavailable` with the code `unavailable` while the server shuts down
+///
+/// # Examples
+///
+/// ```ignore
+/// .route("/backup", post(backup).layer(middleware::from_fn_with_state(state.clone(), refuse_during_shutdown)))
+/// ```
+pub async fn refuse_during_shutdown(State(state): State<AppState>, req: Request, next: Next) -> CratisResult<Response> {
+    if state.is_shutting_down() {
+        return Err(CratisError::api(ErrorCode::Unavailable, "The server is shutting down, try again later"));
+    }
+    Ok(next.run(req).await)
+}
@@ -0,0 +1,51 @@
+use axum::{extract::Request, http::{HeaderName, HeaderValue}, middleware::Next, response::Response};
+use std::time::Instant;
+use tracing::{field, info, info_span, Instrument, Span};
+use uuid::Uuid;
+
+/// Header carrying the id of a request, taken from the client if present and always returned.
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id accepted from a client, longer ids are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Middleware running every request in its own span, applied outermost to every route.
+///
+/// The span carries the method, path and request id, and the device id once
+/// `authenticate_middleware` has verified the credentials, so every event logged while
+/// handling the request can be attributed to it. A summary with status and duration is
+/// logged when the response is ready.
+///
+/// The request id is taken from the `X-Request-Id` header if the client sent a sane one,
+/// otherwise a new UUID is generated. It is returned in the same header.
+pub async fn trace_request(req: Request, next: Next) -> Response {
+    let request_id: String = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span: Span = info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.uri().path(),
+        request_id = %request_id,
+        device_id = field::Empty,
+    );
+
+    async move {
+        let started: Instant = Instant::now();
+        let mut response: Response = next.run(req).await;
+
+        info!(status = response.status().as_u16(), duration_ms = started.elapsed().as_millis() as u64, "Request finished");
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(REQUEST_ID_HEADER.clone(), value);
+        }
+        response
+    }
+    .instrument(span)
+    .await
+}
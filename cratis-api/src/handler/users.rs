@@ -0,0 +1,303 @@
+use cratis_core::{utils::{generate_random_string, timestamp_now}, error::{CratisError, CratisResult, ErrorCode}};
+use axum::{extract::State, http::{HeaderMap, StatusCode}, Extension, Json};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use jsonwebtoken::{encode, EncodingKey, Header, decode, DecodingKey, Validation, Algorithm};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::handler::authentication::{extract_token, Claims, Device};
+use crate::handler::scopes::Scope;
+use crate::state::AppState;
+
+// Request Structs
+#[derive(Deserialize)]
+pub struct CreateUserRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequestData {
+    username: String,
+    password: String,
+}
+
+// Collection Structs
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct User {
+    pub user_id: String,
+    pub username: String,
+    // Argon2id hash in PHC string format, including its salt and parameters
+    pub password_hash: String,
+    pub created_at: u64,
+}
+
+// JWT Struct
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserClaims {
+    pub user_id: String,
+    pub jti: String,
+    // Issued at and expiry as Unix timestamps
+    pub iat: u64,
+    pub exp: u64,
+}
+
+/// Minimum length of user passwords.
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Lifetime of user session tokens, they are only needed to register devices.
+const USER_TOKEN_TTL_SECONDS: u64 = 15 * 60;
+
+// Verified against when a username is unknown, so that the response time does not reveal which users exist
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| hash_password(&generate_random_string(32)).unwrap_or_default());
+
+/// Creates a user account.
+///
+/// # Returns
+///
+/// * `200 OK` with the id of the new user
+/// * `400 Bad Request` if the username is empty or the password is too short
+/// * `409 Conflict` if the username is taken
+/// * `500 Internal Server Error` for database or hashing errors
+///
+/// # Examples
+///
+/// ```json
+/// // Request
+/// { "username": "alice", "password": "correct horse battery staple" }
+///
+/// // Response
+/// { "status": "ok", "user_id": "Vb81kQz0x2LmPw4T" }
+/// ```
+pub async fn create_user(State(state): State<AppState>, Json(payload): Json<CreateUserRequest>) -> CratisResult<(StatusCode, Json<Value>)> {
+    let username: String = payload.username.trim().to_string();
+    if username.is_empty() {
+        return Err(CratisError::api(ErrorCode::InvalidRequest, "username is required"))
+    }
+    if payload.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(CratisError::api(ErrorCode::InvalidRequest, format!("password must have at least {} characters", MIN_PASSWORD_LENGTH)))
+    }
+
+    let result: CratisResult<Option<User>> = tokio::task::spawn_blocking(move || insert_user(&state, &username, &payload.password))
+        .await
+        .unwrap_or(Err(CratisError::Internal("Hashing task failed".to_string())));
+
+    match result? {
+        Some(user) => Ok((StatusCode::OK, Json(json!({ "status": "ok", "user_id": user.user_id })))),
+        None => Err(CratisError::api(ErrorCode::AlreadyExists, "User already exists")),
+    }
+}
+
+/// Lists all user accounts along with the number of devices they own.
+///
+/// # Returns
+///
+/// * `200 OK` with the users, without password hashes
+/// * `500 Internal Server Error` for database errors
+pub async fn list_users(State(state): State<AppState>) -> CratisResult<(StatusCode, Json<Value>)> {
+    let users: Vec<Value> = state.blocking(|state| {
+        state.db.users()?
+            .into_iter()
+            .map(|user| {
+                let devices: Vec<Device> = state.db.user_devices(&user.user_id)?;
+                Ok(json!({ "user_id": user.user_id, "username": user.username, "created_at": user.created_at, "devices": devices.len() }))
+            })
+            .collect::<CratisResult<_>>()
+    }).await?;
+
+    Ok((StatusCode::OK, Json(json!({ "status": "ok", "users": users }))))
+}
+
+/// Logs a user in with username and password.
+///
+/// The returned token is short-lived and only accepted by `/register`, devices registered
+/// with it belong to the user.
+///
+/// # Returns
+///
+/// * `200 OK` with a user token
+/// * `401 Unauthorized` if username or password are wrong
+/// * `500 Internal Server Error` for database, hashing or JWT generation errors
+///
+/// # Examples
+///
+/// ```json
+/// // Request
+/// { "username": "alice", "password": "correct horse battery staple" }
+///
+/// // Response
+/// { "status": "ok", "token": "eyJ0eXAi...", "expires_in": 900 }
+/// ```
+pub async fn login(State(state): State<AppState>, Json(payload): Json<LoginRequestData>) -> CratisResult<(StatusCode, Json<Value>)> {
+    let result: CratisResult<Option<User>> = tokio::task::spawn_blocking({
+        let state: AppState = state.clone();
+        move || check_credentials(&state, &payload.username, &payload.password)
+    })
+        .await
+        .unwrap_or(Err(CratisError::Internal("Hashing task failed".to_string())));
+
+    let user: User = result?.ok_or_else(|| CratisError::api(ErrorCode::Unauthorized, "Invalid username or password"))?;
+    let token: String = generate_user_jwt(&state, &user.user_id)?;
+
+    Ok((StatusCode::OK, Json(json!({ "status": "ok", "token": token, "expires_in": USER_TOKEN_TTL_SECONDS }))))
+}
+
+/// Lists the devices of the user owning the requesting device.
+///
+/// Devices without an owner only see themselves, tokens with the `admin` scope see every device.
+///
+/// # Returns
+///
+/// * `200 OK` with the devices
+/// * `500 Internal Server Error` for database errors
+///
+/// # Examples
+///
+/// ```json
+/// // Response
+/// { "status": "ok", "devices": [{ "device_id": "6f1c2b1e-...", "label": "my-laptop", "os": "linux", "current": true }] }
+/// ```
+pub async fn list_devices(State(state): State<AppState>, Extension(claims): Extension<Claims>) -> CratisResult<(StatusCode, Json<Value>)> {
+    let devices: Vec<Device> = state.blocking({
+        let claims: Claims = claims.clone();
+        move |state| readable_devices(state, &claims)
+    }).await?;
+
+    let devices: Vec<Value> = devices
+        .iter()
+        .map(|device| json!({ "device_id": device.device_id, "label": device.label, "os": device.os, "current": device.device_id == claims.device_id }))
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!({ "status": "ok", "devices": devices }))))
+}
+
+/// Returns the devices whose backups a token may read: its own device and all devices of the
+/// same user, or every device for tokens with the `admin` scope.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be queried.
+pub fn readable_devices(state: &AppState, claims: &Claims) -> CratisResult<Vec<Device>> {
+    if claims.has_scope(Scope::Admin) {
+        return state.db.devices();
+    }
+
+    let device: Option<Device> = state.db.device(&claims.device_id)?;
+
+    match device {
+        Some(device) => match device.user_id.as_deref() {
+            Some(user_id) => state.db.user_devices(user_id),
+            None => Ok(vec![device]),
+        },
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Checks whether a token may read the backups of a device, see [`readable_devices`].
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be queried.
+pub fn can_access_device(state: &AppState, claims: &Claims, target: &str) -> CratisResult<bool> {
+    if claims.device_id == target {
+        return Ok(true);
+    }
+
+    Ok(readable_devices(state, claims)?.iter().any(|device| device.device_id == target))
+}
+
+/// Reads the user id from a user token in the `Authorization` header.
+///
+/// # Returns
+///
+/// * `Some(String)` - If the header carries a valid user token
+/// * `None` - If there is no header, or it holds something else, e.g. a device token
+pub fn user_from_headers(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    let auth_value: &str = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let token: String = extract_token(auth_value)?;
+    decode_user_token(state, &token).ok().map(|claims| claims.user_id)
+}
+
+/// Stores a new user with a hashed password.
+///
+/// # Returns
+///
+/// * `Ok(Some(User))` - The new user
+/// * `Ok(None)` - If the username is taken
+/// * `Err(CratisError)` - For database or hashing errors
+fn insert_user(state: &AppState, username: &str, password: &str) -> CratisResult<Option<User>> {
+    // Checked before hashing the password, the store checks again when inserting
+    if state.db.user_by_name(username)?.is_some() {
+        return Ok(None);
+    }
+
+    let user = User {
+        user_id: generate_random_string(16),
+        username: username.to_string(),
+        password_hash: hash_password(password)?,
+        created_at: timestamp_now()?,
+    };
+    if !state.db.insert_user(&user)? {
+        return Ok(None);
+    }
+
+    Ok(Some(user))
+}
+
+/// Looks up a user and verifies the password.
+///
+/// # Returns
+///
+/// * `Ok(Some(User))` - If username and password match
+/// * `Ok(None)` - If the user does not exist or the password is wrong
+/// * `Err(CratisError::DatabaseError)` - If the database cannot be queried
+fn check_credentials(state: &AppState, username: &str, password: &str) -> CratisResult<Option<User>> {
+    let user: Option<User> = state.db.user_by_name(username.trim())?;
+
+    let hash: &str = user.as_ref().map_or(DUMMY_HASH.as_str(), |user| user.password_hash.as_str());
+    let valid: bool = PasswordHash::new(hash)
+        .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+        .unwrap_or(false);
+
+    Ok(user.filter(|_| valid))
+}
+
+/// Hashes a password with Argon2id and a random salt.
+///
+/// # Errors
+///
+/// Returns `CratisError::Internal` if hashing fails.
+fn hash_password(password: &str) -> CratisResult<String> {
+    let salt: SaltString = SaltString::encode_b64(generate_random_string(16).as_bytes())
+        .map_err(|_| CratisError::Internal("Unable to generate password salt".to_string()))?;
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| CratisError::Internal("Unable to hash password".to_string()))
+}
+
+fn generate_user_jwt(state: &AppState, user_id: &str) -> CratisResult<String> {
+    let secret: &str = &state.config.settings.jwt;
+    if secret.is_empty() {
+        return Err(CratisError::TokenError("JWT Secret is empty!".to_string()));
+    }
+
+    let iat: u64 = timestamp_now()?;
+    let claims = UserClaims { user_id: user_id.to_string(), jti: generate_random_string(16), iat, exp: iat + USER_TOKEN_TTL_SECONDS };
+
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes()))
+        .map_err(|e| CratisError::TokenError(e.to_string()))
+}
+
+fn decode_user_token(state: &AppState, token: &str) -> Result<UserClaims, jsonwebtoken::errors::Error> {
+    let secret: &str = &state.config.settings.jwt;
+    if secret.is_empty() {
+        return Err(jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat));
+    }
+
+    let mut validation = Validation::default();
+    validation.algorithms = vec![Algorithm::HS256];
+
+    decode::<UserClaims>(token, &DecodingKey::from_secret(secret.as_bytes()), &validation).map(|data| data.claims)
+}
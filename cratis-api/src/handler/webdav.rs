@@ -0,0 +1,427 @@
+use cratis_core::error::CratisResult;
+use axum::{body::Body, extract::{Path, State}, http::{header, HeaderMap, HeaderValue, Method, StatusCode}, response::{IntoResponse, Response}, Extension};
+use chrono::{DateTime, NaiveDateTime};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tracing::warn;
+use std::collections::BTreeMap;
+use tokio_util::io::ReaderStream;
+use crate::handler::authentication::Claims;
+use crate::handler::export::archive_path;
+use crate::handler::file_management::{snapshot_times, File};
+use crate::handler::users::readable_devices;
+use crate::state::AppState;
+
+/// Path the WebDAV tree is mounted at.
+pub const DAV_ROOT: &str = "/dav";
+
+/// Directory name format of snapshots, chosen to be valid on every common file system.
+const SNAPSHOT_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+/// Characters left unencoded in hrefs, everything else is percent-encoded.
+const HREF_ENCODE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+/// A file or collection in the WebDAV tree.
+struct Resource {
+    href: String,
+    name: String,
+    // Unix timestamp of the last change
+    modified: u64,
+    // None for collections
+    file: Option<File>,
+}
+
+/// What a request path resolves to.
+enum Target {
+    // A file that is served without listing its directory
+    File(File),
+    Resource(Resource, Vec<Resource>),
+    NotFound,
+}
+
+/// Serves the root of the WebDAV tree.
+pub async fn dav_root(State(state): State<AppState>, Extension(claims): Extension<Claims>, method: Method, headers: HeaderMap) -> Response {
+    handle(&state, &claims, method, &headers, "").await
+}
+
+/// Serves a read-only WebDAV view of the backup history of the authenticated device and the
+/// other devices of the same user.
+///
+/// The tree is laid out as `/dav/<device>/<snapshot-time>/<original path>`, where snapshot times
+/// are formatted as `YYYY-MM-DD_HH-MM-SS` (UTC). Any point in time in that format can be opened,
+/// not just the listed snapshots. Only `OPTIONS`, `GET`, `HEAD` and `PROPFIND` are supported,
+/// every other method is answered with `405 Method Not Allowed`.
+///
+/// # Returns
+///
+/// * `200 OK` with the file content for `GET` and `HEAD`
+/// * `207 Multi-Status` for `PROPFIND`
+/// * `404 Not Found` if the path does not exist in the snapshot
+/// * `405 Method Not Allowed` for write methods
+/// * `500 Internal Server Error` for database or storage errors
+pub async fn dav(State(state): State<AppState>, Extension(claims): Extension<Claims>, method: Method, headers: HeaderMap, Path(path): Path<String>) -> Response {
+    handle(&state, &claims, method, &headers, &path).await
+}
+
+/// Adds an HTTP Basic challenge to `401` responses, so that file managers prompt for credentials.
+pub async fn basic_challenge(mut response: Response) -> Response {
+    if response.status() == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"Cratis\""));
+    }
+    response
+}
+
+async fn handle(state: &AppState, claims: &Claims, method: Method, headers: &HeaderMap, path: &str) -> Response {
+    if method == Method::OPTIONS {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header("DAV", "1")
+            .header(header::ALLOW, ALLOWED_METHODS)
+            .body(Body::empty())
+            .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    if method != Method::GET && method != Method::HEAD && method.as_str() != "PROPFIND" {
+        return (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOWED_METHODS)]).into_response();
+    }
+
+    let segments: Vec<String> = path.split('/').filter(|s| !s.is_empty()).map(String::from).collect();
+    let propfind: bool = method.as_str() == "PROPFIND";
+
+    let claims: Claims = claims.clone();
+    let (resource, children) = match state.blocking(move |state| lookup(state, &claims, &segments, propfind)).await {
+        Ok(Target::File(file)) => return serve_file(state, &file, method == Method::HEAD).await,
+        Ok(Target::Resource(resource, children)) => (resource, children),
+        Ok(Target::NotFound) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            warn!(error = %e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    };
+
+    if propfind {
+        let depth_zero: bool = headers.get("Depth").and_then(|h| h.to_str().ok()) == Some("0");
+        let listed: &[Resource] = if depth_zero { &[] } else { &children };
+        return multistatus(&resource, listed);
+    }
+
+    match &resource.file {
+        Some(file) => serve_file(state, file, method == Method::HEAD).await,
+        None => (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, "OPTIONS, PROPFIND")]).into_response(),
+    }
+}
+
+/// Looks up a request path among the devices the claims can read.
+///
+/// Files are looked up directly unless they are listed, so downloads do not read the snapshot
+/// of their directory.
+fn lookup(state: &AppState, claims: &Claims, segments: &[String], propfind: bool) -> CratisResult<Target> {
+    let devices: Vec<String> = readable_devices(state, claims)?.into_iter().map(|device| device.device_id).collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    if !propfind && let Some(file) = resolve_file(state, &devices, &segments)? {
+        return Ok(Target::File(file));
+    }
+
+    match resolve(state, &devices, &segments)? {
+        Some((resource, children)) => Ok(Target::Resource(resource, children)),
+        None => Ok(Target::NotFound),
+    }
+}
+
+/// Looks up the resource at the given path segments along with its children.
+///
+/// # Returns
+///
+/// * `Ok(Some((resource, children)))` - If the path exists
+/// * `Ok(None)` - If the path does not exist or belongs to a device not in `devices`
+/// * `Err(CratisError::DatabaseError)` - If the database could not be queried
+fn resolve(state: &AppState, devices: &[String], segments: &[&str]) -> CratisResult<Option<(Resource, Vec<Resource>)>> {
+    let device_id: &str = match segments.first() {
+        None => {
+            let mut children: Vec<Resource> = Vec::new();
+            for device_id in devices {
+                let latest: u64 = snapshot_times(state, device_id)?.last().copied().unwrap_or(0);
+                children.push(Resource { href: href(&[device_id], true), name: device_id.to_string(), modified: latest, file: None });
+            }
+
+            let modified: u64 = children.iter().map(|child| child.modified).max().unwrap_or(0);
+            let root = Resource { href: href(&[], true), name: String::new(), modified, file: None };
+            return Ok(Some((root, children)));
+        }
+        Some(device) => match devices.iter().find(|d| d == device) {
+            Some(device_id) => device_id,
+            None => return Ok(None),
+        },
+    };
+
+    let times: Vec<u64> = snapshot_times(state, device_id)?;
+    let latest: u64 = times.last().copied().unwrap_or(0);
+
+    let (snapshot, rest) = match segments {
+        [] => return Ok(None),
+        [_] => {
+            let snapshots: Vec<Resource> = times
+                .iter()
+                .filter_map(|t| {
+                    let name: String = format_snapshot_time(*t)?;
+                    Some(Resource { href: href(&[device_id, &name], true), name, modified: *t, file: None })
+                })
+                .collect();
+
+            let resource = Resource { href: href(&[device_id], true), name: device_id.to_string(), modified: latest, file: None };
+            return Ok(Some((resource, snapshots)));
+        }
+        [_, snapshot, rest @ ..] => (*snapshot, rest),
+    };
+
+    let as_of: u64 = match parse_snapshot_time(snapshot) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+
+    // Collect the direct children of the requested directory from all paths in the snapshot
+    let mut directories: BTreeMap<String, u64> = BTreeMap::new();
+    let mut files: BTreeMap<String, File> = BTreeMap::new();
+    let mut this_file: Option<File> = None;
+    let mut modified: u64 = 0;
+
+    // Only the paths below the requested directory are read, the whole snapshot only for its root
+    let mut versions: Vec<File> = Vec::new();
+    match rest {
+        [] => versions = state.db.snapshot(device_id, as_of, None)?,
+        _ => for prefix in device_paths(rest) {
+            versions.extend(state.db.snapshot(device_id, as_of, Some(&prefix))?);
+        },
+    }
+
+    for file in versions {
+        let path: String = archive_path(&file.path);
+        let components: Vec<&str> = path.split('/').collect();
+
+        if !components.starts_with(rest) {
+            continue;
+        }
+
+        modified = modified.max(file.timestamp);
+
+        match &components[rest.len()..] {
+            [] => this_file = Some(file),
+            [name] => { files.insert(name.to_string(), file); }
+            [name, ..] => {
+                let entry = directories.entry(name.to_string()).or_insert(0);
+                *entry = (*entry).max(file.timestamp);
+            }
+        }
+    }
+
+    let mut base: Vec<&str> = vec![device_id, snapshot];
+    base.extend_from_slice(rest);
+    let name: String = base.last().map(|s| s.to_string()).unwrap_or_default();
+
+    if let Some(file) = this_file {
+        let resource = Resource { href: href(&base, false), name, modified: file.timestamp, file: Some(file) };
+        return Ok(Some((resource, Vec::new())));
+    }
+
+    // Empty snapshot roots still exist, empty directories below them do not
+    if !rest.is_empty() && directories.is_empty() && files.is_empty() {
+        return Ok(None);
+    }
+
+    let mut children: Vec<Resource> = Vec::new();
+    for (child, child_modified) in directories {
+        let mut segments: Vec<&str> = base.clone();
+        segments.push(&child);
+        children.push(Resource { href: href(&segments, true), name: child.clone(), modified: child_modified, file: None });
+    }
+    for (child, file) in files {
+        let mut segments: Vec<&str> = base.clone();
+        segments.push(&child);
+        children.push(Resource { href: href(&segments, false), name: child.clone(), modified: file.timestamp, file: Some(file) });
+    }
+
+    let resource = Resource { href: href(&base, true), name, modified, file: None };
+    Ok(Some((resource, children)))
+}
+
+/// Looks up the file at the given path segments, `/<device>/<snapshot>/<path...>`.
+///
+/// Only the versions of the few device paths the segments can stand for are read.
+///
+/// # Returns
+///
+/// * `Ok(Some(File))` - The version of the file in the snapshot
+/// * `Ok(None)` - If the path is no file, e.g. a directory, or does not exist
+/// * `Err(CratisError::DatabaseError)` - If the database could not be queried
+fn resolve_file(state: &AppState, devices: &[String], segments: &[&str]) -> CratisResult<Option<File>> {
+    let (device_id, snapshot, rest) = match segments {
+        [device_id, snapshot, rest @ ..] if !rest.is_empty() => (*device_id, *snapshot, rest),
+        _ => return Ok(None),
+    };
+    if !devices.iter().any(|d| d == device_id) {
+        return Ok(None);
+    }
+    let as_of: u64 = match parse_snapshot_time(snapshot) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+
+    let wanted: String = rest.join("/");
+    let mut found: Option<File> = None;
+    for path in device_paths(rest) {
+        let file: Option<File> = state.db.latest_version(device_id, &path, as_of)?;
+        if let Some(file) = file.filter(|file| archive_path(&file.path) == wanted)
+            && found.as_ref().is_none_or(|f| file.timestamp >= f.timestamp)
+        {
+            found = Some(file);
+        }
+    }
+    Ok(found)
+}
+
+/// Returns the device paths that [`archive_path`] turns into the given components.
+///
+/// Covers absolute and relative Unix paths and Windows paths with either separator, e.g.
+/// `C/Users` stands for `/C/Users`, `C/Users`, `C:\Users` and `C:/Users`. Also usable as
+/// prefixes, paths that merely start with the same characters have to be filtered out.
+fn device_paths(components: &[&str]) -> Vec<String> {
+    let unix: String = components.join("/");
+    let mut paths: Vec<String> = vec![format!("/{}", unix), unix];
+
+    match components {
+        [drive] => paths.push(format!("{}:", drive)),
+        [drive, rest @ ..] => {
+            paths.push(format!("{}:\\{}", drive, rest.join("\\")));
+            paths.push(format!("{}:/{}", drive, rest.join("/")));
+        }
+        [] => {}
+    }
+    paths
+}
+
+async fn serve_file(state: &AppState, file: &File, head_only: bool) -> Response {
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, file.size)
+        .header(header::ETAG, format!("\"{}\"", file.hash));
+
+    if let Some(modified) = format_http_date(file.timestamp) {
+        response = response.header(header::LAST_MODIFIED, modified);
+    }
+
+    let body: Body = if head_only {
+        Body::empty()
+    } else {
+        match tokio::fs::File::open(state.storage.object_path(&file.hash)).await {
+            Ok(f) => Body::from_stream(ReaderStream::new(f)),
+            Err(e) => {
+                warn!(error = %e, hash = %file.hash, "Unable to open stored object");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    };
+
+    response.body(body).unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+/// Builds a `207 Multi-Status` response describing the resource and the listed children.
+fn multistatus(resource: &Resource, children: &[Resource]) -> Response {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+
+    for entry in std::iter::once(resource).chain(children.iter()) {
+        xml.push_str("<D:response><D:href>");
+        xml.push_str(&escape_xml(&entry.href));
+        xml.push_str("</D:href><D:propstat><D:prop>");
+        xml.push_str(&format!("<D:displayname>{}</D:displayname>", escape_xml(&entry.name)));
+
+        if let Some(modified) = format_http_date(entry.modified) {
+            xml.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", modified));
+        }
+
+        match &entry.file {
+            Some(file) => {
+                xml.push_str("<D:resourcetype/>");
+                xml.push_str(&format!("<D:getcontentlength>{}</D:getcontentlength>", file.size));
+                xml.push_str("<D:getcontenttype>application/octet-stream</D:getcontenttype>");
+                xml.push_str(&format!("<D:getetag>\"{}\"</D:getetag>", file.hash));
+            }
+            None => xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>"),
+        }
+
+        xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n");
+    }
+    xml.push_str("</D:multistatus>\n");
+
+    Response::builder()
+        .status(StatusCode::MULTI_STATUS)
+        .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(Body::from(xml))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+fn href(segments: &[&str], collection: bool) -> String {
+    let mut href = String::from(DAV_ROOT);
+    for segment in segments {
+        href.push('/');
+        href.extend(utf8_percent_encode(segment, HREF_ENCODE));
+    }
+    if collection {
+        href.push('/');
+    }
+    href
+}
+
+fn parse_snapshot_time(name: &str) -> Option<u64> {
+    NaiveDateTime::parse_from_str(name, SNAPSHOT_FORMAT).ok().and_then(|t| u64::try_from(t.and_utc().timestamp()).ok())
+}
+
+fn format_snapshot_time(timestamp: u64) -> Option<String> {
+    DateTime::from_timestamp(i64::try_from(timestamp).ok()?, 0).map(|t| t.format(SNAPSHOT_FORMAT).to_string())
+}
+
+fn format_http_date(timestamp: u64) -> Option<String> {
+    DateTime::from_timestamp(i64::try_from(timestamp).ok()?, 0).map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_times_round_trip() {
+        let name: String = format_snapshot_time(1_700_000_000).unwrap();
+        assert_eq!(name, "2023-11-14_22-13-20");
+        assert_eq!(parse_snapshot_time(&name), Some(1_700_000_000));
+    }
+
+    #[test]
+    fn malformed_snapshot_times_are_rejected() {
+        for name in ["", "..", "latest", "1700000000", "2023-11-14", "2023-11-14_22-13", "2023-11-14 22:13:20", "2023-13-01_00-00-00", "2023-11-14_22-13-20x", "1969-12-31_23-59-59"] {
+            assert_eq!(parse_snapshot_time(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn components_stand_for_every_path_style() {
+        assert_eq!(device_paths(&["home", "me"]), ["/home/me", "home/me", "home:\\me", "home:/me"]);
+        assert_eq!(device_paths(&["C"]), ["/C", "C", "C:"]);
+        assert_eq!(device_paths(&[]), ["/", ""]);
+    }
+
+    #[test]
+    fn hrefs_are_percent_encoded() {
+        assert_eq!(href(&[], true), "/dav/");
+        assert_eq!(href(&["my laptop", "2023-11-14_22-13-20", "a&b.txt"], false), "/dav/my%20laptop/2023-11-14_22-13-20/a%26b.txt");
+    }
+}
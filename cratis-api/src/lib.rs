@@ -0,0 +1,507 @@
+use crate::handler::{authentication::{authenticate_middleware, register, refresh, challenge, token}, health_check::{health_check, live, ready}, file_management::backup, export::export, import::import, webdav::{dav, dav_root, basic_challenge}, devices::{delete_device, rebind_device}, enrollment::{admin_middleware, create_code, list_codes, revoke_code}, users::{create_user, list_users, list_devices, login}, scopes::{require_scope, create_token, create_admin_token, Scope}, rate_limit::{limit_ip, limit_device, mark_authenticated}, trace::trace_request, metrics::{metrics, track_metrics}, shutdown::refuse_during_shutdown};
+use axum::{Router, routing::post, routing::get, routing::any, routing::delete, middleware, extract::DefaultBodyLimit};
+
+// This is for the test endpoint only:
+// use http::StatusCode;
+
+pub mod admin;
+pub mod handler;
+pub mod state;
+pub mod storage;
+pub mod store;
+pub mod tls;
+
+pub use state::AppState;
+
+/// Builds the router serving the whole API on the given state.
+///
+/// The rate limiter needs the address of the client, so the router has to be served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`. Tests calling it directly add the
+/// address with axum's `MockConnectInfo` layer instead. Background maintenance is not part of
+/// the router, run `handler::devices::maintenance_loop` next to it.
+///
+/// # Examples
+///
+/// ```ignore
+/// let state = AppState::new(config)?;
+/// let app = cratis_api::build_router(state.clone()).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+/// let response = app.oneshot(Request::get("/health/live").body(Body::empty())?).await?;
+/// ```
+pub fn build_router(state: AppState) -> Router {
+    let auth_routes = Router::new()
+        // Put any routes that need authentication here
+        // .route("/test", get(test))
+        // Each route checks the scope it needs, after the token itself was validated
+        .route("/backup", post(backup).layer(DefaultBodyLimit::disable()).layer(middleware::from_fn_with_state(state.clone(), refuse_during_shutdown)).layer(middleware::from_fn_with_state(Scope::BackupWrite, require_scope)))
+        .route("/export", get(export).layer(middleware::from_fn_with_state(Scope::RestoreRead, require_scope)))
+        .route("/import", post(import).layer(DefaultBodyLimit::disable()).layer(middleware::from_fn_with_state(state.clone(), refuse_during_shutdown)).layer(middleware::from_fn_with_state(Scope::BackupWrite, require_scope)))
+        .route("/devices", get(list_devices).layer(middleware::from_fn_with_state(Scope::RestoreRead, require_scope)))
+        .route("/devices/{id}", delete(delete_device).layer(middleware::from_fn_with_state(Scope::BackupWrite, require_scope)))
+        .route("/tokens", post(create_token))
+        .route_layer(middleware::from_fn_with_state(state.clone(), limit_device))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate_middleware));
+
+    // Read-only WebDAV view of the backup history, challenges for Basic auth so file managers prompt for the token
+    let dav_routes = Router::new()
+        .route("/dav", any(dav_root))
+        .route("/dav/", any(dav_root))
+        .route("/dav/{*path}", any(dav))
+        .route_layer(middleware::from_fn_with_state(Scope::RestoreRead, require_scope))
+        .route_layer(middleware::from_fn_with_state(state.clone(), limit_device))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate_middleware))
+        .layer(middleware::map_response(basic_challenge));
+
+    // These routes check credentials themselves, a successful response resets the failed attempts
+    let login_routes = Router::new()
+        .route("/login", post(login))
+        .route("/token/refresh", post(refresh))
+        .route("/auth/token", post(token))
+        .route_layer(middleware::map_response(mark_authenticated));
+
+    let public_routes = Router::new()
+        .route("/register", post(register))
+        .route("/auth/challenge", post(challenge))
+        .route("/ping", get(health_check))
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        // Disabled with 404 unless settings.metrics.enabled is set
+        .route("/metrics", get(metrics));
+
+    // Disabled with 404 unless settings.admin_token is set
+    let admin_routes = Router::new()
+        .route("/admin/enrollment-codes", post(create_code).get(list_codes))
+        .route("/admin/enrollment-codes/{id}", delete(revoke_code))
+        .route("/admin/users", post(create_user).get(list_users))
+        .route("/admin/tokens", post(create_admin_token))
+        .route("/admin/devices/{id}/rebind", post(rebind_device))
+        .route_layer(middleware::from_fn_with_state(state.clone(), admin_middleware));
+
+    Router::new()
+        .merge(public_routes)
+        .merge(login_routes)
+        .merge(auth_routes)
+        .merge(dav_routes)
+        .merge(admin_routes)
+        // Locked out clients are rejected before any other work is done
+        .layer(middleware::from_fn_with_state(state.clone(), limit_ip))
+        // Counts every request per route, including rate limited ones
+        .layer(middleware::from_fn_with_state(state.clone(), track_metrics))
+        // Every request runs in a span with its request id, including rejected ones
+        .layer(middleware::from_fn(trace_request))
+        .with_state(state)
+}
+
+// This is a temporary test endpoint to test the authentication system
+// Remove this endpoint when it is no longer needed
+// pub async fn test() -> StatusCode {
+//     StatusCode::IM_A_TEAPOT
+// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::http::{header, Request, StatusCode};
+    use cratis_core::{config::CratisServerConfig, utils::timestamp_now};
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use tower::ServiceExt;
+    use crate::handler::authentication::{issue_tokens, Device};
+    use crate::handler::file_management::File;
+    use crate::handler::scopes::DEVICE_SCOPES;
+
+    const ADMIN_TOKEN: &str = "router-test-admin";
+
+    /// A server with an SQLite database in a temp directory of its own.
+    struct TestServer {
+        state: AppState,
+        router: Router,
+        dir: PathBuf,
+    }
+
+    impl TestServer {
+        fn new(name: &str, rate_limit: Value) -> Self {
+            Self::with_settings(name, json!({ "rate_limit": rate_limit }))
+        }
+
+        /// Starts a server with the given entries added to its settings.
+        fn with_settings(name: &str, settings: Value) -> Self {
+            let dir: PathBuf = std::env::temp_dir().join(format!("cratis-router-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let mut config: Value = json!({
+                "settings": {
+                    "db": dir.join("cratis.db"),
+                    "db_backend": "sqlite",
+                    "jwt": "router-test-secret",
+                    "admin_token": ADMIN_TOKEN,
+                    "metrics": { "enabled": true },
+                    "storage": dir.join("storage"),
+                }
+            });
+            if let Value::Object(entries) = settings {
+                config["settings"].as_object_mut().unwrap().extend(entries);
+            }
+            let config: CratisServerConfig = serde_json::from_value(config).unwrap();
+
+            let state: AppState = AppState::new(config).unwrap();
+            let router: Router = build_router(state.clone()).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+            TestServer { state, router, dir }
+        }
+
+        async fn send(&self, request: Request<Body>) -> (StatusCode, Value) {
+            let response = self.router.clone().oneshot(request).await.unwrap();
+            let status: StatusCode = response.status();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+        }
+
+        async fn post_json(&self, uri: &str, body: Value) -> (StatusCode, Value) {
+            let request = Request::post(uri).header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())).unwrap();
+            self.send(request).await
+        }
+
+        async fn get(&self, uri: &str, token: &str) -> StatusCode {
+            let request = Request::get(uri).header(header::AUTHORIZATION, format!("Bearer {}", token)).body(Body::empty()).unwrap();
+            self.send(request).await.0
+        }
+
+        /// Sends a request and returns the raw response body.
+        async fn download(&self, request: Request<Body>) -> (StatusCode, Vec<u8>) {
+            let response = self.router.clone().oneshot(request).await.unwrap();
+            let status: StatusCode = response.status();
+            (status, to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+        }
+
+        /// Backs up a file through `/backup` as the device the token belongs to.
+        async fn backup(&self, token: &str, path: &str, content: &str) -> StatusCode {
+            let body: String = format!(
+                "--X\r\nContent-Disposition: form-data; name=\"files\"; filename=\"file\"\r\n\r\n{}\r\n\
+                --X\r\nContent-Disposition: form-data; name=\"paths\"\r\n\r\n{}\r\n--X--\r\n",
+                content, path
+            );
+            let request = Request::post("/backup")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+                .body(Body::from(body))
+                .unwrap();
+            self.send(request).await.0
+        }
+
+        /// Returns the metrics in the Prometheus text format.
+        async fn scrape(&self) -> String {
+            let response = self.router.clone().oneshot(Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+            String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+        }
+
+        /// Registers a device directly in the database and returns an access token for it.
+        fn device_token(&self, device_id: &str) -> String {
+            self.state.db.insert_device(&Device {
+                device_id: device_id.to_string(),
+                public_key: String::new(),
+                label: "test".to_string(),
+                os: "linux".to_string(),
+                user_id: None,
+                enrolled_with: None,
+                registered_at: None,
+            })
+            .unwrap();
+            issue_tokens(&self.state, device_id, DEVICE_SCOPES).unwrap().token
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.state.db.close();
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn health_check_answers() {
+        let server = TestServer::new("health", json!({}));
+        assert_eq!(server.get("/health/live", "").await, StatusCode::OK);
+
+        let (_, response) = server.send(Request::get("/health/ready").body(Body::empty()).unwrap()).await;
+        assert_eq!(response["checks"]["database"]["ok"], true);
+        assert_eq!(response["checks"]["storage"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn backup_stores_uploaded_files() {
+        let server = TestServer::new("backup", json!({}));
+        let token: String = server.device_token("laptop");
+
+        let body: &str = "--X\r\n\
+            Content-Disposition: form-data; name=\"files\"; filename=\"notes.txt\"\r\n\r\n\
+            hello\r\n\
+            --X\r\n\
+            Content-Disposition: form-data; name=\"paths\"\r\n\r\n\
+            /home/me/notes.txt\r\n\
+            --X--\r\n";
+        let request = Request::post("/backup?job=home")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+            .body(Body::from(body))
+            .unwrap();
+
+        let (status, response) = server.send(request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((response["files"].as_u64(), response["changed"].as_u64()), (Some(1), Some(1)));
+
+        let version = server.state.db.latest_version("laptop", "/home/me/notes.txt", timestamp_now().unwrap()).unwrap().unwrap();
+        assert_eq!(version.size, 5);
+        assert!(server.state.storage.object_path(&version.hash).exists());
+    }
+
+    #[tokio::test]
+    async fn public_requests_do_not_reset_failed_attempts() {
+        let server = TestServer::new("lockout", json!({ "lockout_after_failures": 3 }));
+
+        for _ in 0..2 {
+            assert_eq!(server.get("/devices", "wrong").await, StatusCode::UNAUTHORIZED);
+            assert_eq!(server.get("/ping", "").await, StatusCode::OK);
+        }
+        assert_eq!(server.get("/devices", "wrong").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.get("/ping", "").await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn authenticated_requests_reset_failed_attempts() {
+        let server = TestServer::new("reset", json!({ "lockout_after_failures": 2 }));
+        let token: String = server.device_token("laptop");
+
+        assert_eq!(server.get("/devices", "wrong").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.get("/devices", &token).await, StatusCode::OK);
+        assert_eq!(server.get("/devices", "wrong").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.get("/ping", "").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_get_an_error_body() {
+        let server = TestServer::new("unauthorized", json!({}));
+
+        let (status, response) = server.send(Request::get("/devices").header(header::AUTHORIZATION, "Bearer wrong").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response["error"]["code"], "unauthorized");
+        assert_eq!(response["error"]["message"], "Invalid or expired access token");
+
+        let (status, response) = server.send(Request::get("/devices").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response["error"]["message"], "Missing access token");
+
+        let token: String = server.device_token("laptop");
+        let (status, response) = server.send(Request::get("/admin/users").header(header::AUTHORIZATION, format!("Bearer {}", token)).body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(response["error"]["details"]["scope"], "admin");
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_rotated() {
+        let server = TestServer::new("refresh", json!({}));
+        server.device_token("laptop");
+        let issued = issue_tokens(&server.state, "laptop", DEVICE_SCOPES).unwrap();
+
+        let (status, response) = server.post_json("/token/refresh", json!({ "refresh_token": issued.refresh_token })).await;
+        assert_eq!(status, StatusCode::OK);
+        let refresh_token: &str = response["refresh_token"].as_str().unwrap();
+        assert_ne!(refresh_token, issued.refresh_token);
+        assert_eq!(server.get("/devices", response["token"].as_str().unwrap()).await, StatusCode::OK);
+
+        let (status, _) = server.post_json("/token/refresh", json!({ "refresh_token": refresh_token })).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_tokens_revoke_the_device() {
+        let server = TestServer::new("refresh-reuse", json!({}));
+        server.device_token("laptop");
+        let issued = issue_tokens(&server.state, "laptop", DEVICE_SCOPES).unwrap();
+
+        let (_, rotated) = server.post_json("/token/refresh", json!({ "refresh_token": issued.refresh_token })).await;
+        let (status, response) = server.post_json("/token/refresh", json!({ "refresh_token": issued.refresh_token })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response["error"]["message"], "Invalid refresh token");
+
+        // The pair issued to whoever presented the token first is revoked as well
+        let (status, _) = server.post_json("/token/refresh", json!({ "refresh_token": rotated["refresh_token"] })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.get("/devices", rotated["token"].as_str().unwrap()).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn clients_refresh_expiring_access_tokens() {
+        // Access tokens expiring within a minute are refreshed, so the client refreshes right away
+        let server = TestServer::with_settings("client-refresh", json!({ "access_token_ttl_seconds": 30 }));
+        server.device_token("laptop");
+        let issued = issue_tokens(&server.state, "laptop", DEVICE_SCOPES).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+        let serving = tokio::spawn(axum::serve(listener, server.router.clone()).into_future());
+
+        let config_path: PathBuf = server.dir.join("cratis.yml");
+        let config: String = format!(
+            "version: 3\nclient:\n  name: laptop\nbackup:\n  watch_directories: []\nserver:\n  address: http://{}\n  auth_token: {}\n  refresh_token: {}\n",
+            address, issued.token, issued.refresh_token
+        );
+        std::fs::write(&config_path, config).unwrap();
+        cratis_core::config::load_config(&config_path, false).unwrap();
+
+        let access_token: String = cratis_core::auth::access_token().await.unwrap();
+        assert_ne!(access_token, issued.token);
+        assert_eq!(server.get("/devices", &access_token).await, StatusCode::OK);
+
+        // The new pair is saved, so the next run does not present the used refresh token again
+        let saved: String = std::fs::read_to_string(&config_path).unwrap();
+        assert!(saved.contains(&access_token) && !saved.contains(&issued.refresh_token));
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn restricted_tokens_keep_their_scopes() {
+        let server = TestServer::new("scopes", json!({}));
+        let device: String = server.device_token("laptop");
+
+        let create_token = |token: &str, scopes: Value| Request::post("/tokens")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "scopes": scopes }).to_string()))
+            .unwrap();
+
+        let (status, restricted) = server.send(create_token(&device, json!(["restore:read"]))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(restricted["scopes"], json!(["restore:read"]));
+        let token: &str = restricted["token"].as_str().unwrap();
+
+        assert_eq!(server.get("/devices", token).await, StatusCode::OK);
+        let (status, response) = server.send(Request::post("/backup").header(header::AUTHORIZATION, format!("Bearer {}", token)).body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(response["error"]["details"]["scope"], "backup:write");
+
+        // Neither a restricted token nor a device token can grant more than it has
+        let (status, response) = server.send(create_token(token, json!(["restore:read", "backup:write"]))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(response["error"]["details"]["scope"], "backup:write");
+        let (status, response) = server.send(create_token(&device, json!(["admin"]))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(response["error"]["details"]["scope"], "admin");
+
+        // Refreshing keeps the scopes of the original token
+        let (_, refreshed) = server.post_json("/token/refresh", json!({ "refresh_token": restricted["refresh_token"] })).await;
+        let (status, _) = server.send(Request::post("/backup").header(header::AUTHORIZATION, format!("Bearer {}", refreshed["token"].as_str().unwrap())).body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn rebinding_moves_the_history_of_a_device() {
+        let server = TestServer::new("rebind", json!({}));
+        server.device_token("laptop");
+        let token: String = server.device_token("ed25519-laptop");
+        server.state.db.insert_version(&File { device_id: "laptop".to_string(), path: "/a".to_string(), hash: "a1".to_string(), size: 1, timestamp: 10, job: None }).unwrap();
+
+        let rebind = |id: &str, to: &str| Request::post(format!("/admin/devices/{}/rebind", id))
+            .header(header::AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "device_id": to }).to_string()))
+            .unwrap();
+
+        let (status, response) = server.send(rebind("laptop", "ed25519-laptop")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["moved_versions"].as_u64(), Some(1));
+        assert!(server.state.db.device("laptop").unwrap().is_none());
+        assert_eq!(server.state.db.latest_version("ed25519-laptop", "/a", 100).unwrap().unwrap().hash, "a1");
+        assert_eq!(server.get("/devices", &token).await, StatusCode::OK);
+
+        assert_eq!(server.send(rebind("laptop", "ed25519-laptop")).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(server.send(rebind("ed25519-laptop", "ed25519-laptop")).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn exported_snapshots_import_into_another_device() {
+        let server = TestServer::new("round-trip", json!({}));
+        let laptop: String = server.device_token("laptop");
+        let desktop: String = server.device_token("desktop");
+        assert_eq!(server.backup(&laptop, "/home/me/notes.txt", "hello").await, StatusCode::OK);
+        assert_eq!(server.backup(&laptop, "C:\\Users\\me\\a.txt", "world").await, StatusCode::OK);
+
+        let request = Request::get("/export?format=tar").header(header::AUTHORIZATION, format!("Bearer {}", laptop)).body(Body::empty()).unwrap();
+        let (status, archive) = server.download(request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let gzip: Vec<u8> = {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, &archive).unwrap();
+            encoder.finish().unwrap()
+        };
+        let zstd: Vec<u8> = zstd::encode_all(&archive[..], 0).unwrap();
+
+        for (as_of, body) in [(100, archive.clone()), (200, gzip), (300, zstd)] {
+            let request = Request::post(format!("/import?as_of={}&root=/restored", as_of))
+                .header(header::AUTHORIZATION, format!("Bearer {}", desktop))
+                .body(Body::from(body))
+                .unwrap();
+            let (status, response) = server.send(request).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(response["summary"]["files"].as_u64(), Some(2));
+            assert_eq!(response["summary"]["deduplicated_bytes"].as_u64(), Some(10));
+        }
+
+        let notes = server.state.db.latest_version("desktop", "/restored/home/me/notes.txt", 300).unwrap().unwrap();
+        let windows = server.state.db.latest_version("desktop", "/restored/C/Users/me/a.txt", 300).unwrap().unwrap();
+        assert_eq!((notes.timestamp, notes.size, windows.size), (100, 5, 5));
+        assert_eq!(std::fs::read(server.state.storage.object_path(&notes.hash)).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn webdav_paths_outside_the_snapshots_are_not_found() {
+        let server = TestServer::new("dav-paths", json!({}));
+        let token: String = server.device_token("laptop");
+        server.device_token("someone-else");
+        assert_eq!(server.backup(&token, "/home/me/notes.txt", "hello").await, StatusCode::OK);
+
+        let snapshot: String = chrono::DateTime::from_timestamp(timestamp_now().unwrap() as i64 + 1, 0).unwrap().format("%Y-%m-%d_%H-%M-%S").to_string();
+        let (status, content) = server.download(Request::get(format!("/dav/laptop/{}/home/me/notes.txt", snapshot)).header(header::AUTHORIZATION, format!("Bearer {}", token)).body(Body::empty()).unwrap()).await;
+        assert_eq!((status, content.as_slice()), (StatusCode::OK, &b"hello"[..]));
+
+        for path in [
+            "/dav/laptop/latest/home/me/notes.txt".to_string(),
+            "/dav/laptop/2023-11-14/home/me/notes.txt".to_string(),
+            "/dav/laptop/1969-12-31_23-59-59/".to_string(),
+            format!("/dav/laptop/{}/../{}/home/me/notes.txt", snapshot, snapshot),
+            format!("/dav/laptop/{}/home/me/../me/notes.txt", snapshot),
+            format!("/dav/laptop/{}/home/nobody/", snapshot),
+            format!("/dav/someone-else/{}/", snapshot),
+            format!("/dav/unknown/{}/", snapshot),
+        ] {
+            assert_eq!(server.get(&path, &token).await, StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_archives_are_rejected() {
+        let server = TestServer::new("malformed-import", json!({}));
+        let token: String = server.device_token("laptop");
+
+        let request = Request::post("/import?as_of=100")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(vec![b'x'; 1024]))
+            .unwrap();
+        let (status, response) = server.send(request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response["error"]["message"], "Invalid tar archive");
+    }
+
+    #[tokio::test]
+    async fn servers_count_their_own_requests() {
+        let first = TestServer::new("metrics-first", json!({}));
+        let second = TestServer::new("metrics-second", json!({}));
+
+        assert_eq!(first.get("/ping", "").await, StatusCode::OK);
+
+        assert!(first.scrape().await.contains("cratis_http_requests_total{method=\"GET\",route=\"/ping\",status=\"200\"} 1"));
+        assert!(!second.scrape().await.contains("route=\"/ping\""));
+    }
+}
@@ -1,46 +1,235 @@
-#[allow(unused_imports)]
-use crate::handler::{authentication::{authenticate_middleware, register}, health_check::health_check};
-use cratis_core::{config::{get_config_api, load_config, TEMP_API_CONFIG_PATH}};
-use axum::{Router, routing::post, routing::get, middleware};
-use polodb_core::Database;
-use once_cell::sync::Lazy;
+use cratis_api::{build_router, tls, AppState};
+use cratis_api::admin::{devices, enroll, tokens, users, DevicesCommand, EnrollCommand, TokenCommand, UsersCommand};
+use cratis_api::handler::{devices::maintenance_loop, shutdown::wait_for_signal};
+use cratis_api::tls::ClientCertAcceptor;
+use cratis_core::{config::{CratisServerConfig, read_config, find_config, default_init_path, write_starter_config, render_config, migrate_config_file}, logging::init_logging, error::CratisError, validation::{check_config, ConfigIssue}};
+use clap::Parser;
+use clap_derive::{Parser, Subcommand};
+use axum_server::Handle;
+use tracing::{error, info, warn};
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::time::Duration;
 
-// This is for the test endpoint only:
-// use http::StatusCode;
+#[derive(Parser)]
+#[command(name = "cratis-api")]
+#[command(about = "Cratis backup server", long_about = None)]
+struct Args {
+    // Path of the config file, takes precedence over CRATIS_CONFIG and the default locations
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-mod handler;
+#[derive(Subcommand)]
+enum Command {
+    // Write a starter config file
+    Init {
+        #[arg(long)]
+        force: bool,
+    },
+    // Print the effective configuration with secrets redacted
+    ShowConfig,
+    // Validate the config file and report every problem, exits non-zero if there are any
+    CheckConfig,
+    // Manage the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    // Manage enrollment codes of the running server
+    Enroll {
+        // Address of the server, defaults to the configured port on 127.0.0.1
+        #[arg(long)]
+        server: Option<String>,
+        #[command(subcommand)]
+        command: EnrollCommand,
+    },
+    // Manage user accounts of the running server
+    Users {
+        // Address of the server, defaults to the configured port on 127.0.0.1
+        #[arg(long)]
+        server: Option<String>,
+        #[command(subcommand)]
+        command: UsersCommand,
+    },
+    // Issue tokens through the running server
+    Token {
+        // Address of the server, defaults to the configured port on 127.0.0.1
+        #[arg(long)]
+        server: Option<String>,
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+    // Manage devices of the running server
+    Devices {
+        // Address of the server, defaults to the configured port on 127.0.0.1
+        #[arg(long)]
+        server: Option<String>,
+        #[command(subcommand)]
+        command: DevicesCommand,
+    },
+    // Print the fingerprint of the TLS certificate, to compare with the one `cratis register` shows
+    Fingerprint,
+}
 
-// Database:
-pub static DB: Lazy<Arc<Database>> = Lazy::new(|| { Arc::new(Database::open_path(PathBuf::from(get_config_api().settings.db.clone())).expect("Failed to open DB")) });
+#[derive(Subcommand)]
+enum ConfigCommand {
+    // Upgrade the config file to the current format, the original is kept as a backup
+    Migrate,
+}
 
 #[tokio::main]
 async fn main() {
-    // Load config
-    load_config(TEMP_API_CONFIG_PATH, true);
+    let args = Args::parse();
+
+    // Commands before logging is set up print to stderr
+    if let Some(Command::Init { force }) = args.command {
+        match default_init_path(args.config.as_deref(), true).and_then(|path| write_starter_config(&path, true, force).map(|_| path)) {
+            Ok(path) => eprintln!("Created config at {}", path.display()),
+            Err(e) => fatal(&e),
+        }
+        return;
+    }
+
+    // Validate and load config, the server refuses to start with an invalid config
+    let config_path: PathBuf = find_config(args.config.as_deref(), true).unwrap_or_else(|e| fatal(&e));
+
+    if let Some(Command::Config { command: ConfigCommand::Migrate }) = args.command {
+        match migrate_config_file(&config_path, true) {
+            Ok(report) => {
+                for line in report.messages(&config_path) {
+                    eprintln!("{}", line);
+                }
+            }
+            Err(e) => fatal(&e),
+        }
+        return;
+    }
+
+    // show-config stays usable with an invalid config, so problems can be inspected
+    let validate: bool = !matches!(args.command, Some(Command::ShowConfig));
+    match check_config(&config_path, true) {
+        _ if !validate => {}
+        Ok(issues) if issues.is_empty() => {
+            if matches!(args.command, Some(Command::CheckConfig)) {
+                eprintln!("{} is valid", config_path.display());
+                return;
+            }
+        }
+        Ok(issues) => report_issues(&config_path, &issues),
+        Err(e) => fatal(&e),
+    }
+
+    let config: CratisServerConfig = read_config(&config_path, true).unwrap_or_else(|e| fatal(&e));
+
+    // Admin commands print their results, only the server itself logs
+    if args.command.is_none() && let Err(e) = init_logging(&config.settings.log) {
+        fatal(&e);
+    }
 
-    // Router
-    // let auth_routes = Router::new()
-    //     // Put any routes that need authentication here
-    //     // .route("/test", get(test))
-    //     .route_layer(middleware::from_fn(authenticate_middleware));
+    if let Some(Command::ShowConfig) = args.command {
+        match render_config(&config) {
+            Ok(rendered) => println!("# Loaded from {}\n{}", config_path.display(), rendered),
+            Err(e) => fatal(&e),
+        }
+        return;
+    }
 
-    let public_routes = Router::new()
-        .route("/register", post(register))
-        .route("/ping", get(health_check));
+    // The only TLS backend compiled in, used by the server and the admin commands
+    let _ = rustls::crypto::ring::default_provider().install_default();
 
-    let app = Router::new()
-        .merge(public_routes);
-        // .merge(auth_routes);
+    if let Some(Command::Fingerprint) = args.command {
+        match tls::certificate_fingerprint(&config.settings.tls) {
+            Ok(fingerprint) => println!("{}", fingerprint),
+            Err(e) => fatal(&e),
+        }
+        return;
+    }
+
+    let admin_result = match args.command {
+        Some(Command::Enroll { server, command }) => Some(enroll(&config.settings, command, server.as_deref()).await),
+        Some(Command::Users { server, command }) => Some(users(&config.settings, command, server.as_deref()).await),
+        Some(Command::Token { server, command }) => Some(tokens(&config.settings, command, server.as_deref()).await),
+        Some(Command::Devices { server, command }) => Some(devices(&config.settings, command, server.as_deref()).await),
+        _ => None,
+    };
+    if let Some(result) = admin_result {
+        match result {
+            Ok(lines) => lines.into_iter().for_each(|line| println!("{}", line)),
+            Err(e) => fatal(&e),
+        }
+        return;
+    }
+
+    // Opens the database, a server already running on it is reported here rather than on the first request
+    let state: AppState = AppState::new(config).unwrap_or_else(|e| fatal(&e));
+    let settings = &state.config.settings;
+
+    // Uploads cut off by a crash or an expired drain timeout leave their temporary files behind
+    match state.storage.clean_temp_files() {
+        Ok(0) => {}
+        Ok(removed) => info!(removed, "Removed temporary files of incomplete uploads"),
+        Err(e) => warn!(error = %e, "Unable to remove temporary files of incomplete uploads"),
+    }
+
+    // Process scheduled data deletions and expired revocations in the background
+    let maintenance = tokio::spawn(maintenance_loop(state.clone()));
+
+    // On SIGTERM or Ctrl-C stop accepting connections and give running requests time to finish
+    let handle: Handle = Handle::new();
+    let drain: Duration = Duration::from_secs(settings.drain_timeout_seconds);
+    tokio::spawn({
+        let handle: Handle = handle.clone();
+        let shutdown = state.shutdown.clone();
+        async move {
+            wait_for_signal(shutdown).await;
+            info!(drain_timeout_seconds = drain.as_secs(), "Waiting for running requests to finish");
+            handle.graceful_shutdown(Some(drain));
+        }
+    });
 
     // Start server
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", get_config_api().settings.port)).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let app = build_router(state.clone());
+    let addr: SocketAddr = SocketAddr::from(([0, 0, 0, 0], settings.port));
+    let served: std::io::Result<()> = if settings.tls.enabled {
+        let (tls_config, fingerprint) = tls::server_config(&settings.tls).await.unwrap_or_else(|e| fatal(&e));
+        info!(port = settings.port, fingerprint = %fingerprint, "Serving HTTPS");
+        axum_server::bind(addr).handle(handle).acceptor(ClientCertAcceptor::new(tls_config)).serve(app.into_make_service_with_connect_info::<SocketAddr>()).await
+    } else {
+        info!(port = settings.port, "Serving HTTP");
+        axum_server::bind(addr).handle(handle).serve(app.into_make_service_with_connect_info::<SocketAddr>()).await
+    };
+    if let Err(e) = served {
+        fatal(&e.into());
+    }
+
+    // A maintenance run in progress finishes before the database is closed
+    if let Err(e) = maintenance.await {
+        warn!(error = %e, "Maintenance task failed");
+    }
+    state.db.close();
+    info!("Shut down");
+}
+
+/// Prints every config issue and exits with a non-zero status.
+fn report_issues(path: &std::path::Path, issues: &[ConfigIssue]) -> ! {
+    for issue in issues {
+        eprintln!("Warning: {}", CratisError::ConfigError(issue.to_string()));
+    }
+    let summary = CratisError::ConfigError(format!("{} problem(s) found in {}", issues.len(), path.display()));
+    fatal(&summary)
 }
 
-// This is a temporary test endpoint to test the authentication system
-// Remove this endpoint when it is no longer needed
-// pub async fn test() -> StatusCode {
-//     StatusCode::IM_A_TEAPOT
-// }
\ No newline at end of file
+/// Reports a fatal error and exits, the library itself never terminates the process.
+///
+/// Only the server logs, errors of the other commands and of the startup are printed to stderr.
+fn fatal(error: &CratisError) -> ! {
+    if tracing::dispatcher::has_been_set() {
+        error!("{}", error);
+    } else {
+        eprintln!("Fatal error: {}", error);
+    }
+    std::process::exit(1)
+}
@@ -0,0 +1,93 @@
+use cratis_core::{config::CratisServerConfig, error::{CratisError, CratisResult}};
+use tracing::warn;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_util::sync::CancellationToken;
+use crate::handler::metrics::Metrics;
+use crate::handler::rate_limit::RateLimiter;
+use crate::storage::Storage;
+use crate::store::{self, MetadataStore};
+
+/// Everything the handlers share, passed to them through axum's `State`.
+///
+/// Cheap to clone, every clone refers to the same database, store and counters. Several
+/// servers with their own state can run in one process, e.g. in tests against a temp directory.
+///
+/// # Examples
+///
+/// ```ignore
+/// let config: CratisServerConfig = read_config(&path, true)?;
+/// let state = AppState::new(config)?;
+/// let app = cratis_api::build_router(state.clone());
+/// ```
+#[derive(Clone)]
+pub struct AppState {
+    // Metadata database, polodb or SQLite depending on `settings.db_backend`
+    pub db: Arc<dyn MetadataStore>,
+    pub config: Arc<CratisServerConfig>,
+    pub storage: Storage,
+    // Request budgets and failed logins per client, see `limit_ip` and `limit_device`
+    pub limiter: Arc<RateLimiter>,
+    // Exported at /metrics, see `Metrics`
+    pub metrics: Arc<Metrics>,
+    // Cancelled to shut the server down, new uploads are refused from then on
+    pub shutdown: CancellationToken,
+    // Reported as uptime by the health checks
+    pub started_at: Instant,
+}
+
+impl AppState {
+    /// Opens the metadata database and the object store named in the config.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::DatabaseError` if the database cannot be opened, e.g. because
+    /// another server holds its lock.
+    pub fn new(config: CratisServerConfig) -> CratisResult<Self> {
+        let db: Arc<dyn MetadataStore> = store::open(&config.settings)?;
+        let storage: Storage = Storage::new(&config.settings.storage);
+        let metrics: Metrics = Metrics::new().map_err(|e| CratisError::Internal(format!("Unable to create the metrics: {}", e)))?;
+
+        Ok(AppState {
+            db,
+            config: Arc::new(config),
+            storage,
+            limiter: RateLimiter::new(),
+            metrics: Arc::new(metrics),
+            shutdown: CancellationToken::new(),
+            started_at: Instant::now(),
+        })
+    }
+
+    /// Runs work that accesses the metadata database on the blocking thread pool.
+    ///
+    /// The store methods block until the database answered, so async handlers must not call
+    /// them directly: a slow query, or a request waiting for the SQLite connection, would stall
+    /// every other request scheduled on the same worker.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the work, or `CratisError::Internal` if the task panicked.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let device: Option<Device> = state.blocking(move |state| state.db.device(&device_id)).await?;
+    /// ```
+    pub async fn blocking<T, F>(&self, work: F) -> CratisResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&AppState) -> CratisResult<T> + Send + 'static,
+    {
+        let state: AppState = self.clone();
+        tokio::task::spawn_blocking(move || work(&state)).await.unwrap_or_else(|e| {
+            warn!(error = %e, "Database task failed");
+            Err(CratisError::Internal(e.to_string()))
+        })
+    }
+
+    /// Whether the server is draining requests before it exits.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+}
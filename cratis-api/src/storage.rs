@@ -0,0 +1,427 @@
+use cratis_core::{error::CratisResult, utils::generate_random_string};
+use blake3::Hasher;
+use sysinfo::Disks;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Number of pins per object hash, see [`StoredObject`].
+type Pins = Arc<Mutex<HashMap<String, usize>>>;
+
+/// Result of writing an object into the content-addressed store.
+///
+/// The object is pinned as long as the value or a clone of it is alive, so it cannot be released
+/// before the file version referencing it is recorded. Drop it once the version is recorded.
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub hash: String,
+    pub size: u64,
+    // false if identical content was already stored
+    pub is_new: bool,
+    // Keeps [`Storage::release_object`] from removing the object until dropped
+    _pin: Arc<ObjectPin>,
+}
+
+/// Objects pinned by a lookup, see [`Storage::pin_objects`].
+///
+/// The objects cannot be released as long as the value is alive.
+#[derive(Debug)]
+pub struct PinnedObjects {
+    _pins: Vec<ObjectPin>,
+}
+
+#[derive(Debug)]
+struct ObjectPin {
+    pins: Pins,
+    hash: String,
+}
+
+impl Drop for ObjectPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = pins.get_mut(&self.hash) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.hash);
+            }
+        }
+    }
+}
+
+/// The content-addressed object store below the directory configured in `settings.storage`.
+///
+/// Cheap to clone, every clone refers to the same directory and shares the pinned objects.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    root: PathBuf,
+    // Objects written by uploads whose file versions are not recorded yet
+    pins: Pins,
+}
+
+impl Storage {
+    /// Creates a store in the given directory, which is created on the first write.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Storage { root: root.into(), pins: Arc::default() }
+    }
+
+    /// Returns the root directory of the store.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the location of the object with the given content hash.
+    ///
+    /// Objects are sharded into sub directories by the first two characters of their hash
+    /// to keep directory sizes manageable.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - The BLAKE3 hash of the object in hexadecimal form
+    ///
+    /// # Returns
+    ///
+    /// The path `<storage>/objects/<hash[..2]>/<hash>`
+    pub fn object_path(&self, hash: &str) -> PathBuf {
+        self.root.join("objects").join(&hash[..2]).join(hash)
+    }
+
+    /// Opens the stored object with the given content hash for reading.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::IoError` if the object does not exist or cannot be opened.
+    pub fn open_object(&self, hash: &str) -> CratisResult<File> {
+        Ok(File::open(self.object_path(hash))?)
+    }
+
+    /// Looks up file versions and pins the objects they reference until the returned
+    /// [`PinnedObjects`] are dropped.
+    ///
+    /// The lookup runs while releases are held back, so an object cannot be removed between
+    /// reading a version and pinning its object. Meant for downloads that read the objects
+    /// long after looking up the versions.
+    ///
+    /// # Arguments
+    ///
+    /// * `lookup` - Reads the versions from the database
+    /// * `hash` - Returns the hash of the object a version references
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let (files, pins) = state.storage.pin_objects(|| snapshot_files(state, &device_id, as_of, None), |file| &file.hash)?;
+    /// ```
+    pub fn pin_objects<T>(&self, lookup: impl FnOnce() -> CratisResult<Vec<T>>, hash: impl Fn(&T) -> &str) -> CratisResult<(Vec<T>, PinnedObjects)> {
+        let mut pins = self.pins.lock().unwrap_or_else(|e| e.into_inner());
+        let items: Vec<T> = lookup()?;
+
+        let hashes: BTreeSet<String> = items.iter().map(|item| hash(item).to_string()).collect();
+        let pinned: Vec<ObjectPin> = hashes
+            .into_iter()
+            .map(|hash| {
+                *pins.entry(hash.clone()).or_insert(0) += 1;
+                ObjectPin { pins: self.pins.clone(), hash }
+            })
+            .collect();
+
+        Ok((items, PinnedObjects { _pins: pinned }))
+    }
+
+    /// Removes an object that is neither pinned by a running upload nor referenced by a file version.
+    ///
+    /// `referenced` is asked while new objects are held back, so an upload cannot deduplicate
+    /// against the object between the check and its removal.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - The BLAKE3 hash of the object in hexadecimal form
+    /// * `referenced` - Checks whether a file version references the object
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the object was removed, or did not exist
+    /// * `Ok(false)` - If the object is still in use
+    /// * `Err(CratisError)` - If `referenced` failed or the object cannot be removed
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// state.storage.release_object(&hash, || state.db.is_referenced(&hash))?;
+    /// ```
+    pub fn release_object(&self, hash: &str, referenced: impl FnOnce() -> CratisResult<bool>) -> CratisResult<bool> {
+        let pins = self.pins.lock().unwrap_or_else(|e| e.into_inner());
+        if pins.contains_key(hash) || referenced()? {
+            return Ok(false);
+        }
+
+        self.remove_object(hash)?;
+        Ok(true)
+    }
+
+    /// Removes the object with the given content hash, removing a missing object is not an error.
+    fn remove_object(&self, hash: &str) -> CratisResult<()> {
+        match fs::remove_file(self.object_path(hash)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts writing a new object, see [`ObjectWriter`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::IoError` if the temporary directory or file cannot be created.
+    pub fn writer(&self) -> CratisResult<ObjectWriter> {
+        ObjectWriter::new(self.clone())
+    }
+
+    /// Removes the temporary files of uploads that were cut off, called on start before serving.
+    ///
+    /// Objects are only moved into the store once they are complete, so anything left below
+    /// `<storage>/tmp` belongs to an upload that was interrupted, e.g. by a crash or a shutdown
+    /// whose drain timeout ran out.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(usize)` - The number of files removed
+    /// * `Err(CratisError::IoError)` - If the temporary directory or a file in it cannot be removed
+    pub fn clean_temp_files(&self) -> CratisResult<usize> {
+        let entries = match fs::read_dir(self.root.join("tmp")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut removed: usize = 0;
+        for entry in entries {
+            let path: PathBuf = entry?.path();
+            if path.is_file() {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Checks that new objects can be stored, by writing a temporary file and discarding it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::IoError` if the temporary file cannot be created or written.
+    pub fn probe_writable(&self) -> CratisResult<()> {
+        let mut writer: ObjectWriter = self.writer()?;
+        writer.write_all(b"cratis")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Returns the free space of the file system the store is on.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(u64))` - The bytes available to the server
+    /// * `Ok(None)` - If the file system is not among the mounted disks, e.g. in some containers
+    /// * `Err(CratisError::IoError)` - If the storage directory does not exist
+    pub fn free_space(&self) -> CratisResult<Option<u64>> {
+        let root: PathBuf = self.root.canonicalize()?;
+        let disks: Disks = Disks::new_with_refreshed_list();
+
+        // The disk mounted deepest above the storage directory holds it
+        Ok(disks
+            .list()
+            .iter()
+            .filter(|disk| root.starts_with(disk.mount_point()))
+            .max_by_key(|disk| disk.mount_point().as_os_str().len())
+            .map(|disk| disk.available_space()))
+    }
+}
+
+/// Streams content into the object store while hashing it.
+///
+/// Data is written into a temporary file below `<storage>/tmp` first. Calling [`ObjectWriter::finish`]
+/// moves it to its content-addressed location, or discards it if identical content is already stored.
+/// Dropping the writer without finishing removes the temporary file.
+///
+/// # Examples
+///
+/// ```ignore
+/// let mut writer = state.storage.writer()?;
+/// writer.write_all(b"hello world")?;
+/// let object = writer.finish()?;
+/// println!("{} ({} bytes)", object.hash, object.size);
+/// ```
+pub struct ObjectWriter {
+    storage: Storage,
+    temp_path: PathBuf,
+    file: Option<File>,
+    hasher: Hasher,
+    size: u64,
+}
+
+impl ObjectWriter {
+    /// Creates a new writer backed by a fresh temporary file in the given store.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::IoError` if the temporary directory or file cannot be created.
+    fn new(storage: Storage) -> CratisResult<Self> {
+        let temp_dir: PathBuf = storage.root.join("tmp");
+        fs::create_dir_all(&temp_dir)?;
+
+        let temp_path: PathBuf = temp_dir.join(generate_random_string(16));
+        let file: File = File::create(&temp_path)?;
+
+        Ok(Self { storage, temp_path, file: Some(file), hasher: Hasher::new(), size: 0 })
+    }
+
+    /// Flushes the written content and commits it to the object store.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(StoredObject)` - Hash and size of the content, and whether it was newly stored
+    /// * `Err(CratisError)` - If the content could not be flushed or moved into place
+    pub fn finish(mut self) -> CratisResult<StoredObject> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+
+        let hash: String = self.hasher.finalize().to_hex().to_string();
+
+        // The object is pinned before it is looked up, and both happen while releases are held
+        // back, so a release cannot remove existing content this upload deduplicates against
+        let placed: CratisResult<bool> = {
+            let mut pins = self.storage.pins.lock().unwrap_or_else(|e| e.into_inner());
+            *pins.entry(hash.clone()).or_insert(0) += 1;
+            self.place(&hash)
+        };
+        let pin = ObjectPin { pins: self.storage.pins.clone(), hash: hash.clone() };
+
+        Ok(StoredObject { hash, size: self.size, is_new: placed?, _pin: Arc::new(pin) })
+    }
+
+    /// Moves the temporary file to the location of the given hash.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(false)` - If the content was already stored, the temp file is removed on drop
+    /// * `Ok(true)` - If the content was moved into the store
+    fn place(&self, hash: &str) -> CratisResult<bool> {
+        let target: PathBuf = self.storage.object_path(hash);
+        if target.exists() {
+            return Ok(false);
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&self.temp_path, &target)?;
+        Ok(true)
+    }
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let file = self.file.as_mut().ok_or_else(|| io::Error::other("Object writer already finished"))?;
+        let written: usize = file.write(buf)?;
+
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ObjectWriter {
+    fn drop(&mut self) {
+        self.file.take();
+        // Either already renamed into the store or left over from an aborted upload
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cratis_core::error::CratisError;
+
+    /// Returns a store in an empty directory of its own.
+    fn storage(name: &str) -> Storage {
+        let root: PathBuf = std::env::temp_dir().join(format!("cratis-storage-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        Storage::new(root)
+    }
+
+    fn store(storage: &Storage, content: &[u8]) -> StoredObject {
+        let mut writer: ObjectWriter = storage.writer().unwrap();
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn pinned_objects_are_not_released() {
+        let storage: Storage = storage("pinned");
+        let object: StoredObject = store(&storage, b"content");
+        let hash: String = object.hash.clone();
+
+        assert!(!storage.release_object(&hash, || Ok(false)).unwrap());
+        assert!(storage.object_path(&hash).exists());
+
+        drop(object);
+        assert!(storage.release_object(&hash, || Ok(false)).unwrap());
+        assert!(!storage.object_path(&hash).exists());
+        fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn looked_up_objects_are_pinned() {
+        let storage: Storage = storage("looked-up");
+        let hash: String = store(&storage, b"content").hash;
+
+        let (hashes, pins) = storage.pin_objects(|| Ok(vec![hash.clone(), hash.clone()]), |hash| hash).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert!(!storage.release_object(&hash, || Ok(false)).unwrap());
+
+        drop(pins);
+        assert!(storage.release_object(&hash, || Ok(false)).unwrap());
+        assert!(storage.pin_objects::<String>(|| Err(CratisError::DatabaseError("down".to_string())), |hash| hash).is_err());
+        fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn deduplicated_objects_are_pinned() {
+        let storage: Storage = storage("deduplicated");
+        let hash: String = store(&storage, b"content").hash;
+
+        // A second upload of the same content pins the existing object
+        let object: StoredObject = store(&storage, b"content");
+        assert!(!object.is_new);
+        let clone: StoredObject = object.clone();
+        drop(object);
+        assert!(!storage.release_object(&hash, || Ok(false)).unwrap());
+
+        drop(clone);
+        assert!(storage.release_object(&hash, || Ok(false)).unwrap());
+        fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn referenced_objects_are_not_released() {
+        let storage: Storage = storage("referenced");
+        let hash: String = store(&storage, b"content").hash;
+
+        assert!(!storage.release_object(&hash, || Ok(true)).unwrap());
+        assert!(storage.object_path(&hash).exists());
+        assert!(storage.release_object(&hash, || Err(CratisError::DatabaseError("down".to_string()))).is_err());
+        assert!(storage.object_path(&hash).exists());
+        fs::remove_dir_all(storage.root()).unwrap();
+    }
+}
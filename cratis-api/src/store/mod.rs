@@ -0,0 +1,453 @@
+use cratis_core::{config::{CratisServerSettings, DbBackend}, error::CratisResult};
+use std::path::Path;
+use std::sync::Arc;
+use crate::handler::authentication::{Challenge, Device, RefreshToken, Revocation};
+use crate::handler::devices::DataDeletion;
+use crate::handler::enrollment::EnrollmentCode;
+use crate::handler::file_management::{File, RetentionPolicy, StorageStats};
+use crate::handler::users::User;
+
+pub mod polodb;
+pub mod sqlite;
+
+/// Storage of the server's metadata: devices, the file versions making up their snapshots,
+/// tokens, users and enrollment codes.
+///
+/// Handlers only access the metadata through this trait, so the database engine can be
+/// chosen with `settings.db_backend` without touching them. File contents are not part of
+/// the metadata, they live in the object store, see [`crate::storage::Storage`].
+///
+/// All methods block until the database answered, async code calls them through
+/// [`AppState::blocking`](crate::state::AppState::blocking). Every error is reported as
+/// `CratisError::DatabaseError`.
+pub trait MetadataStore: Send + Sync {
+    // Devices
+
+    /// Returns the device with the given id.
+    fn device(&self, device_id: &str) -> CratisResult<Option<Device>>;
+
+    /// Returns all registered devices.
+    fn devices(&self) -> CratisResult<Vec<Device>>;
+
+    /// Returns the devices belonging to a user.
+    fn user_devices(&self, user_id: &str) -> CratisResult<Vec<Device>>;
+
+    /// Returns the number of registered devices.
+    fn count_devices(&self) -> CratisResult<u64>;
+
+    /// Stores a new device.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(false)` - If a device with the same id exists already
+    fn insert_device(&self, device: &Device) -> CratisResult<bool>;
+
+    /// Changes the display name of a device.
+    fn set_device_label(&self, device_id: &str, label: &str) -> CratisResult<()>;
+
+    /// Removes a device, its file versions are kept.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the device existed
+    fn delete_device(&self, device_id: &str) -> CratisResult<bool>;
+
+    // File versions and snapshots
+
+    fn insert_version(&self, version: &File) -> CratisResult<()>;
+
+    /// Returns the latest version of a path that is not newer than `as_of`.
+    fn latest_version(&self, device_id: &str, path: &str, as_of: u64) -> CratisResult<Option<File>>;
+
+    /// Returns the latest version of every path that is not newer than `as_of`, ordered by path.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - Only include paths starting with this prefix, if given
+    fn snapshot(&self, device_id: &str, as_of: u64, prefix: Option<&str>) -> CratisResult<Vec<File>>;
+
+    /// Returns the distinct timestamps of a device's versions in ascending order.
+    fn snapshot_times(&self, device_id: &str) -> CratisResult<Vec<u64>>;
+
+    /// Returns all versions a backup job of a device produced.
+    fn job_versions(&self, device_id: &str, job: &str) -> CratisResult<Vec<File>>;
+
+    /// Removes a single version, identified by its path and timestamp.
+    fn delete_version(&self, device_id: &str, path: &str, timestamp: u64) -> CratisResult<()>;
+
+    /// Removes all versions of a device.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<File>)` - The removed versions
+    fn delete_device_versions(&self, device_id: &str) -> CratisResult<Vec<File>>;
+
+    /// Moves all versions and retention policies of a device to another device.
+    ///
+    /// Retention policies the other device has already are kept.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The number of moved versions
+    fn move_device_versions(&self, from: &str, to: &str) -> CratisResult<u64>;
+
+    /// Checks whether any version of any device references an object.
+    fn is_referenced(&self, hash: &str) -> CratisResult<bool>;
+
+    /// Sums up the objects referenced by the versions of all devices.
+    fn storage_stats(&self) -> CratisResult<StorageStats>;
+
+    // Retention policies
+
+    /// Returns the retention policy stored for a backup job of a device.
+    fn retention_policy(&self, device_id: &str, job: &str) -> CratisResult<Option<RetentionPolicy>>;
+
+    /// Stores the retention policy of a backup job, replacing the previous one.
+    fn set_retention_policy(&self, policy: &RetentionPolicy) -> CratisResult<()>;
+
+    // Tokens
+
+    fn insert_refresh_token(&self, token: &RefreshToken) -> CratisResult<()>;
+
+    /// Returns the refresh token with the given hash.
+    fn refresh_token(&self, token_hash: &str) -> CratisResult<Option<RefreshToken>>;
+
+    /// Marks a refresh token as exchanged, see [`RefreshToken::used`].
+    ///
+    /// Checking and marking the token is a single update, so only one of several concurrent
+    /// exchanges of the same token succeeds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the token existed and was not used before
+    fn mark_refresh_token_used(&self, token_hash: &str) -> CratisResult<bool>;
+
+    /// Removes all refresh tokens of a device.
+    fn delete_refresh_tokens(&self, device_id: &str) -> CratisResult<()>;
+
+    /// Removes the refresh tokens of a device that expired at `now`.
+    fn prune_refresh_tokens(&self, device_id: &str, now: u64) -> CratisResult<()>;
+
+    fn insert_revocation(&self, revocation: &Revocation) -> CratisResult<()>;
+
+    /// Checks whether a token is revoked, either by its `jti` or because all tokens of the
+    /// device issued before a later point in time were revoked.
+    ///
+    /// An empty `jti` only checks the revocations of all tokens, e.g. for client certificates.
+    fn is_revoked(&self, device_id: &str, jti: &str, issued_at: u64) -> CratisResult<bool>;
+
+    /// Removes revocations of tokens that expired at `now`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The number of removed revocations
+    fn prune_revocations(&self, now: u64) -> CratisResult<u64>;
+
+    // Login challenges
+
+    fn insert_challenge(&self, challenge: &Challenge) -> CratisResult<()>;
+
+    /// Removes a challenge issued to a device, so it can only be answered once.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the challenge existed and had not expired at `now`
+    fn take_challenge(&self, device_id: &str, challenge: &str, now: u64) -> CratisResult<bool>;
+
+    /// Removes challenges that expired at `now`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The number of removed challenges
+    fn prune_challenges(&self, now: u64) -> CratisResult<u64>;
+
+    // Enrollment codes
+
+    fn insert_code(&self, code: &EnrollmentCode) -> CratisResult<()>;
+
+    /// Returns all enrollment codes, including used up and expired ones.
+    fn codes(&self) -> CratisResult<Vec<EnrollmentCode>>;
+
+    /// Returns the enrollment code with the given hash.
+    fn code_by_hash(&self, code_hash: &str) -> CratisResult<Option<EnrollmentCode>>;
+
+    /// Counts a registration with an enrollment code if the code is still usable at `now`.
+    ///
+    /// Checking and counting is a single update, so a code cannot be used more often than
+    /// allowed by concurrent registrations.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the registration was counted
+    /// * `Ok(false)` - If the code does not exist, expired or is used up
+    fn try_redeem_code(&self, id: &str, now: u64) -> CratisResult<bool>;
+
+    /// Takes back a registration counted by [`MetadataStore::try_redeem_code`].
+    fn release_code(&self, id: &str) -> CratisResult<()>;
+
+    /// Removes an enrollment code.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the code existed
+    fn delete_code(&self, id: &str) -> CratisResult<bool>;
+
+    // Users
+
+    /// Stores a new user.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(false)` - If a user with the same name exists already
+    fn insert_user(&self, user: &User) -> CratisResult<bool>;
+
+    /// Returns the user with the given name.
+    fn user_by_name(&self, username: &str) -> CratisResult<Option<User>>;
+
+    /// Returns all users.
+    fn users(&self) -> CratisResult<Vec<User>>;
+
+    // Scheduled deletions of device data
+
+    fn schedule_data_deletion(&self, deletion: &DataDeletion) -> CratisResult<()>;
+
+    /// Cancels all scheduled deletions of a device's data.
+    fn cancel_data_deletion(&self, device_id: &str) -> CratisResult<()>;
+
+    /// Returns the deletions due at `now`.
+    fn due_data_deletions(&self, now: u64) -> CratisResult<Vec<DataDeletion>>;
+
+    /// Flushes and closes the database, later calls fail.
+    ///
+    /// Blocks until pending writes are on disk.
+    fn close(&self);
+}
+
+/// Opens the metadata database named in the settings with the configured backend.
+///
+/// The database is created if it does not exist, missing tables and indexes are added.
+///
+/// # Errors
+///
+/// Returns `CratisError::DatabaseError` if the database cannot be opened, e.g. because
+/// another process holds its lock.
+///
+/// # Examples
+///
+/// ```ignore
+/// let db: Arc<dyn MetadataStore> = store::open(&config.settings)?;
+/// let device: Option<Device> = db.device(&device_id)?;
+/// ```
+pub fn open(settings: &CratisServerSettings) -> CratisResult<Arc<dyn MetadataStore>> {
+    let path: &Path = Path::new(&settings.db);
+
+    match settings.db_backend {
+        DbBackend::Polodb => Ok(Arc::new(polodb::PolodbStore::open(path)?)),
+        DbBackend::Sqlite => Ok(Arc::new(sqlite::SqliteStore::open(path)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::handler::scopes::DEVICE_SCOPES;
+
+    /// Runs every check against both backends, each with a database of its own in a temp directory.
+    macro_rules! backend_tests {
+        ($($check:ident),* $(,)?) => {
+            mod sqlite_backend {
+                $(#[test] fn $check() { super::$check(&super::open_store(stringify!($check), super::DbBackend::Sqlite)) })*
+            }
+
+            mod polodb_backend {
+                $(#[test] fn $check() { super::$check(&super::open_store(stringify!($check), super::DbBackend::Polodb)) })*
+            }
+        };
+    }
+
+    backend_tests!(
+        versions_are_looked_up_by_time,
+        removing_the_latest_version_restores_the_previous_one,
+        refresh_tokens_are_used_once,
+        codes_are_redeemed_up_to_their_limit,
+        device_revocations_ignore_single_tokens,
+        retention_policies_are_replaced,
+        challenges_are_taken_once,
+        versions_move_to_another_device,
+        users_and_devices_are_unique,
+    );
+
+    fn open_store(name: &str, backend: DbBackend) -> Arc<dyn MetadataStore> {
+        let dir: PathBuf = std::env::temp_dir().join(format!("cratis-store-{}-{:?}-{}", std::process::id(), backend, name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        match backend {
+            DbBackend::Polodb => Arc::new(polodb::PolodbStore::open(&dir.join("cratis.db")).unwrap()),
+            DbBackend::Sqlite => Arc::new(sqlite::SqliteStore::open(&dir.join("cratis.db")).unwrap()),
+        }
+    }
+
+    fn version(device_id: &str, path: &str, hash: &str, timestamp: u64) -> File {
+        File { device_id: device_id.to_string(), path: path.to_string(), hash: hash.to_string(), size: 1, timestamp, job: Some("home".to_string()) }
+    }
+
+    fn hashes(files: &[File]) -> Vec<&str> {
+        files.iter().map(|file| file.hash.as_str()).collect()
+    }
+
+    fn versions_are_looked_up_by_time(store: &Arc<dyn MetadataStore>) {
+        for file in [
+            version("laptop", "/a", "a1", 10),
+            version("laptop", "/a", "a2", 20),
+            version("laptop", "/b", "b1", 15),
+            version("laptop", "/docs/c", "c1", 5),
+            version("phone", "/a", "p1", 30),
+        ] {
+            store.insert_version(&file).unwrap();
+        }
+
+        let latest = |path: &str, as_of: u64| store.latest_version("laptop", path, as_of).unwrap().map(|file| file.hash);
+        assert_eq!(latest("/a", 25).as_deref(), Some("a2"));
+        assert_eq!(latest("/a", 20).as_deref(), Some("a2"));
+        assert_eq!(latest("/a", 15).as_deref(), Some("a1"));
+        assert_eq!(latest("/a", 5), None);
+        assert_eq!(latest("/missing", 25), None);
+
+        assert_eq!(hashes(&store.snapshot("laptop", 25, None).unwrap()), ["a2", "b1", "c1"]);
+        assert_eq!(hashes(&store.snapshot("laptop", 12, None).unwrap()), ["a1", "c1"]);
+        assert_eq!(hashes(&store.snapshot("laptop", 25, Some("/docs")).unwrap()), ["c1"]);
+        assert!(store.snapshot("laptop", 1, None).unwrap().is_empty());
+
+        assert_eq!(store.snapshot_times("laptop").unwrap(), [5, 10, 15, 20]);
+        assert_eq!(store.job_versions("laptop", "home").unwrap().len(), 4);
+        assert!(store.job_versions("laptop", "work").unwrap().is_empty());
+
+        assert_eq!(store.delete_device_versions("laptop").unwrap().len(), 4);
+        assert!(store.snapshot("laptop", 25, None).unwrap().is_empty());
+        assert_eq!(latest("/a", 25), None);
+        assert_eq!(hashes(&store.snapshot("phone", 30, None).unwrap()), ["p1"]);
+    }
+
+    fn removing_the_latest_version_restores_the_previous_one(store: &Arc<dyn MetadataStore>) {
+        store.insert_version(&version("laptop", "/a", "a1", 10)).unwrap();
+        store.insert_version(&version("laptop", "/a", "a2", 20)).unwrap();
+
+        store.delete_version("laptop", "/a", 20).unwrap();
+        assert_eq!(store.latest_version("laptop", "/a", 30).unwrap().map(|file| file.hash).as_deref(), Some("a1"));
+        assert_eq!(hashes(&store.snapshot("laptop", 30, None).unwrap()), ["a1"]);
+
+        store.delete_version("laptop", "/a", 10).unwrap();
+        assert!(store.latest_version("laptop", "/a", 30).unwrap().is_none());
+        assert!(!store.is_referenced("a1").unwrap());
+    }
+
+    fn refresh_tokens_are_used_once(store: &Arc<dyn MetadataStore>) {
+        store.insert_refresh_token(&RefreshToken {
+            device_id: "laptop".to_string(),
+            token_hash: "hash".to_string(),
+            expires_at: 100,
+            used: false,
+            scopes: DEVICE_SCOPES.to_vec(),
+        }).unwrap();
+
+        assert!(store.mark_refresh_token_used("hash").unwrap());
+        assert!(!store.mark_refresh_token_used("hash").unwrap());
+        assert!(store.refresh_token("hash").unwrap().unwrap().used);
+        assert!(!store.mark_refresh_token_used("unknown").unwrap());
+    }
+
+    fn codes_are_redeemed_up_to_their_limit(store: &Arc<dyn MetadataStore>) {
+        store.insert_code(&EnrollmentCode { id: "code".to_string(), code_hash: "hash".to_string(), created_at: 0, expires_at: Some(100), max_uses: 2, uses: 0 }).unwrap();
+
+        assert!(store.try_redeem_code("code", 50).unwrap());
+        assert!(store.try_redeem_code("code", 50).unwrap());
+        assert!(!store.try_redeem_code("code", 50).unwrap());
+
+        store.release_code("code").unwrap();
+        assert!(!store.try_redeem_code("code", 100).unwrap());
+        assert!(store.try_redeem_code("code", 50).unwrap());
+
+        for _ in 0..3 {
+            store.release_code("code").unwrap();
+        }
+        assert_eq!(store.code_by_hash("hash").unwrap().unwrap().uses, 0);
+        assert!(!store.try_redeem_code("unknown", 50).unwrap());
+    }
+
+    fn device_revocations_ignore_single_tokens(store: &Arc<dyn MetadataStore>) {
+        store.insert_revocation(&Revocation { device_id: "laptop".to_string(), jti: "token".to_string(), issued_before: 200, expires_at: 300 }).unwrap();
+        assert!(store.is_revoked("laptop", "token", 150).unwrap());
+        assert!(!store.is_revoked("laptop", "other", 150).unwrap());
+        assert!(!store.is_revoked("laptop", "", 150).unwrap());
+
+        store.insert_revocation(&Revocation { device_id: "laptop".to_string(), jti: String::new(), issued_before: 100, expires_at: 300 }).unwrap();
+        assert!(store.is_revoked("laptop", "", 50).unwrap());
+        assert!(store.is_revoked("laptop", "other", 50).unwrap());
+        assert!(!store.is_revoked("laptop", "", 150).unwrap());
+        assert!(!store.is_revoked("phone", "", 50).unwrap());
+    }
+
+    fn retention_policies_are_replaced(store: &Arc<dyn MetadataStore>) {
+        let policy = |job: &str, keep_versions: Option<u32>| RetentionPolicy { device_id: "laptop".to_string(), job: job.to_string(), keep_versions, keep_days: None };
+
+        store.set_retention_policy(&policy("home", Some(3))).unwrap();
+        store.set_retention_policy(&policy("work", Some(5))).unwrap();
+        store.set_retention_policy(&policy("home", None)).unwrap();
+
+        assert_eq!(store.retention_policy("laptop", "home").unwrap().unwrap().keep_versions, None);
+        assert_eq!(store.retention_policy("laptop", "work").unwrap().unwrap().keep_versions, Some(5));
+        assert!(store.retention_policy("laptop", "other").unwrap().is_none());
+        assert!(store.retention_policy("phone", "home").unwrap().is_none());
+    }
+
+    fn versions_move_to_another_device(store: &Arc<dyn MetadataStore>) {
+        store.insert_version(&version("laptop", "/a", "a1", 10)).unwrap();
+        store.insert_version(&version("laptop", "/b", "b1", 10)).unwrap();
+        store.insert_version(&version("ed25519-laptop", "/a", "a2", 20)).unwrap();
+        let policy = |device_id: &str, job: &str, keep_versions: Option<u32>| RetentionPolicy { device_id: device_id.to_string(), job: job.to_string(), keep_versions, keep_days: None };
+        store.set_retention_policy(&policy("laptop", "home", Some(3))).unwrap();
+        store.set_retention_policy(&policy("laptop", "work", Some(5))).unwrap();
+        store.set_retention_policy(&policy("ed25519-laptop", "home", Some(7))).unwrap();
+
+        assert_eq!(store.move_device_versions("laptop", "ed25519-laptop").unwrap(), 2);
+
+        assert!(store.snapshot("laptop", 100, None).unwrap().is_empty());
+        assert_eq!(hashes(&store.snapshot("ed25519-laptop", 100, None).unwrap()), ["a2", "b1"]);
+        assert_eq!(hashes(&store.snapshot("ed25519-laptop", 15, None).unwrap()), ["a1", "b1"]);
+        assert_eq!(store.snapshot_times("ed25519-laptop").unwrap(), [10, 20]);
+        assert_eq!(store.retention_policy("ed25519-laptop", "home").unwrap().unwrap().keep_versions, Some(7));
+        assert_eq!(store.retention_policy("ed25519-laptop", "work").unwrap().unwrap().keep_versions, Some(5));
+        assert!(store.retention_policy("laptop", "work").unwrap().is_none());
+    }
+
+    fn users_and_devices_are_unique(store: &Arc<dyn MetadataStore>) {
+        let user = |user_id: &str, username: &str| User { user_id: user_id.to_string(), username: username.to_string(), password_hash: String::new(), created_at: 1 };
+        assert!(store.insert_user(&user("1", "alice")).unwrap());
+        assert!(!store.insert_user(&user("2", "alice")).unwrap());
+        assert!(store.insert_user(&user("3", "bob")).unwrap());
+        assert_eq!(store.users().unwrap().len(), 2);
+
+        let device = |label: &str| Device { device_id: "laptop".to_string(), public_key: String::new(), label: label.to_string(), os: "linux".to_string(), user_id: None, enrolled_with: None, registered_at: None };
+        assert!(store.insert_device(&device("first")).unwrap());
+        assert!(!store.insert_device(&device("second")).unwrap());
+        assert_eq!(store.device("laptop").unwrap().unwrap().label, "first");
+
+        // A removed device can register again
+        assert!(store.delete_device("laptop").unwrap());
+        assert!(store.insert_device(&device("third")).unwrap());
+    }
+
+    fn challenges_are_taken_once(store: &Arc<dyn MetadataStore>) {
+        let challenge = |challenge: &str| Challenge { device_id: "laptop".to_string(), challenge: challenge.to_string(), expires_at: 100 };
+        store.insert_challenge(&challenge("first")).unwrap();
+        store.insert_challenge(&challenge("second")).unwrap();
+
+        assert!(!store.take_challenge("phone", "first", 50).unwrap());
+        assert!(store.take_challenge("laptop", "first", 50).unwrap());
+        assert!(!store.take_challenge("laptop", "first", 50).unwrap());
+        assert!(!store.take_challenge("laptop", "second", 100).unwrap());
+        assert_eq!(store.prune_challenges(100).unwrap(), 1);
+    }
+}
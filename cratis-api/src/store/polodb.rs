@@ -0,0 +1,529 @@
+use cratis_core::error::{CratisError, CratisResult};
+use polodb_core::{bson::{doc, to_document, Document}, Collection, CollectionT, Database, IndexModel};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, RwLock};
+use crate::handler::authentication::{Challenge, Device, RefreshToken, Revocation};
+use crate::handler::devices::DataDeletion;
+use crate::handler::enrollment::EnrollmentCode;
+use crate::handler::file_management::{File, RetentionPolicy, StorageStats};
+use crate::handler::users::User;
+use crate::store::MetadataStore;
+
+/// Fields looked up by every collection, indexed when the database is opened.
+///
+/// polodb only supports indexes on a single field. A read whose filter contains an indexed
+/// field stops at the first indexed document that differs in any other field of the filter,
+/// and compares those fields by equality only. Reads of these collections therefore filter on
+/// a single indexed field and check everything else on the documents they return. Updates and
+/// deletes do not use the indexes and can filter on anything.
+const INDEXES: &[(&str, &str)] = &[
+    ("devices", "device_id"),
+    ("devices", "user_id"),
+    ("files", "device_id"),
+    ("files", "hash"),
+    ("latest_versions", "key"),
+    ("latest_versions", "device_id"),
+    ("refresh_tokens", "token_hash"),
+    ("refresh_tokens", "device_id"),
+    ("revocations", "jti"),
+    ("revocations", "device_id"),
+    ("challenges", "device_id"),
+    ("enrollment_codes", "code_hash"),
+    ("users", "username"),
+    ("data_deletions", "device_id"),
+    ("retention_policies", "device_id"),
+];
+
+/// The newest version of a path, kept next to the history in `files`.
+///
+/// Looking up the latest version of a path, as every backup does for every file, and listing
+/// the current snapshot of a device then do not read the history of the device.
+#[derive(Serialize, Deserialize)]
+struct LatestVersion {
+    // Device id and path, see `latest_key`
+    key: String,
+    device_id: String,
+    version: File,
+}
+
+/// Metadata store kept in a polodb database, chosen with `settings.db_backend: polodb`.
+///
+/// Only a single field of a document can be indexed, so listing snapshot times and the versions
+/// of a job, checking device revocations and computing storage statistics read the whole history
+/// of a device or of all devices. This is fine for small installations, larger ones should use
+/// SQLite.
+///
+/// polodb flushes its data when the last handle of a database is dropped. The handle is kept
+/// here rather than in a plain static, so [`MetadataStore::close`] can drop it before the
+/// process exits.
+pub struct PolodbStore {
+    // None once the database was closed
+    database: RwLock<Option<Database>>,
+    // Held while versions are added or removed, polodb cannot upsert into `latest_versions`
+    latest: Mutex<()>,
+    // Held while users and devices are checked for duplicates and inserted. Unique indexes
+    // cannot be used, polodb checks them on deletes as well and refuses to remove the document
+    unique: Mutex<()>,
+}
+
+impl PolodbStore {
+    /// Opens the database at the given path, creating it and its indexes if they do not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::DatabaseError` if the database cannot be opened, e.g. because
+    /// another process holds its lock.
+    pub fn open(path: &Path) -> CratisResult<Self> {
+        let database: Database = Database::open_path(path).map_err(|e| CratisError::DatabaseError(format!("Unable to open {}: {}", path.display(), e)))?;
+
+        for (collection, field) in INDEXES {
+            database
+                .collection::<Document>(collection)
+                .create_index(IndexModel { keys: doc! { *field: 1 }, options: None })
+                .map_err(|e| CratisError::DatabaseError(format!("Unable to index {}.{}: {}", collection, field, e)))?;
+        }
+
+        let store = PolodbStore { database: RwLock::new(Some(database)), latest: Mutex::new(()), unique: Mutex::new(()) };
+        store.index_latest_versions()?;
+
+        Ok(store)
+    }
+
+    fn collection<T: Send + Sync>(&self, name: &str) -> CratisResult<Collection<T>> {
+        let database = self.database.read().unwrap_or_else(|e| e.into_inner());
+        database
+            .as_ref()
+            .map(|database| database.collection::<T>(name))
+            .ok_or_else(|| CratisError::DatabaseError("The database is closed".to_string()))
+    }
+
+    /// Fills `latest_versions` from the history of a database created before it existed.
+    fn index_latest_versions(&self) -> CratisResult<()> {
+        let collection: Collection<LatestVersion> = self.collection::<LatestVersion>("latest_versions")?;
+        if collection.count_documents().map_err(|e| CratisError::DatabaseError(e.to_string()))? > 0 {
+            return Ok(());
+        }
+
+        let mut latest: BTreeMap<String, File> = BTreeMap::new();
+        for version in find_all(&self.collection::<File>("files")?, doc! {})? {
+            let key: String = latest_key(&version.device_id, &version.path);
+            if latest.get(&key).is_none_or(|existing| version.timestamp >= existing.timestamp) {
+                latest.insert(key, version);
+            }
+        }
+
+        for (key, version) in latest {
+            insert(&collection, &LatestVersion { key, device_id: version.device_id.clone(), version })?;
+        }
+        Ok(())
+    }
+
+    /// Serializes changes of `latest_versions`, which are read first and written afterwards.
+    fn lock_latest(&self) -> MutexGuard<'_, ()> {
+        self.latest.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records a version as the latest of its path, unless a newer one is recorded already.
+    ///
+    /// The caller holds [`PolodbStore::lock_latest`].
+    fn record_latest(&self, version: &File) -> CratisResult<()> {
+        let collection: Collection<LatestVersion> = self.collection::<LatestVersion>("latest_versions")?;
+        let key: String = latest_key(&version.device_id, &version.path);
+
+        match find_one(&collection, doc! { "key": &key })? {
+            Some(latest) if latest.version.timestamp > version.timestamp => Ok(()),
+            Some(_) => {
+                let document: Document = to_document(version).map_err(|e| CratisError::DatabaseError(format!("Error updating data: {}", e)))?;
+                update(&collection, doc! { "key": &key }, doc! { "$set": { "version": document } })
+            }
+            None => insert(&collection, &LatestVersion { key, device_id: version.device_id.clone(), version: version.clone() }),
+        }
+    }
+
+    /// Reads all versions of a device's path.
+    ///
+    /// polodb cannot look up a path within a device, so the history of the device is read.
+    fn path_history(&self, device_id: &str, path: &str) -> CratisResult<Vec<File>> {
+        let versions: Vec<File> = find_all(&self.collection::<File>("files")?, doc! { "device_id": device_id })?;
+        Ok(versions.into_iter().filter(|version| version.path == path).collect())
+    }
+}
+
+/// Key of a path in `latest_versions`. Paths cannot contain a NUL character on any platform.
+fn latest_key(device_id: &str, path: &str) -> String {
+    format!("{}\0{}", device_id, path)
+}
+
+/// Picks the newest version of every path among the versions that existed at `as_of`.
+fn newest_by_path(versions: impl IntoIterator<Item = File>, as_of: u64) -> BTreeMap<String, File> {
+    let mut newest: BTreeMap<String, File> = BTreeMap::new();
+    for version in versions {
+        if version.timestamp <= as_of && newest.get(&version.path).is_none_or(|existing| version.timestamp >= existing.timestamp) {
+            newest.insert(version.path.clone(), version);
+        }
+    }
+    newest
+}
+
+/// Runs a query and collects all matching documents.
+fn find_all<T: DeserializeOwned + Send + Sync>(collection: &Collection<T>, filter: Document) -> CratisResult<Vec<T>> {
+    collection
+        .find(filter)
+        .run()
+        .map_err(|e| CratisError::DatabaseError(e.to_string()))?
+        .collect::<Result<Vec<T>, polodb_core::Error>>()
+        .map_err(|e| CratisError::DatabaseError(e.to_string()))
+}
+
+fn find_one<T: DeserializeOwned + Send + Sync>(collection: &Collection<T>, filter: Document) -> CratisResult<Option<T>> {
+    collection.find_one(filter).map_err(|e| CratisError::DatabaseError(e.to_string()))
+}
+
+fn insert<T: serde::Serialize + Send + Sync>(collection: &Collection<T>, document: &T) -> CratisResult<()> {
+    collection.insert_one(document).map_err(|e| CratisError::DatabaseError(format!("Error inserting data: {}", e)))?;
+    Ok(())
+}
+
+fn update<T: Send + Sync>(collection: &Collection<T>, filter: Document, update: Document) -> CratisResult<()> {
+    collection.update_one(filter, update).map_err(|e| CratisError::DatabaseError(format!("Error updating data: {}", e)))?;
+    Ok(())
+}
+
+fn delete<T: Send + Sync>(collection: &Collection<T>, filter: Document) -> CratisResult<u64> {
+    collection
+        .delete_many(filter)
+        .map(|result| result.deleted_count)
+        .map_err(|e| CratisError::DatabaseError(format!("Error deleting data: {}", e)))
+}
+
+impl MetadataStore for PolodbStore {
+    fn device(&self, device_id: &str) -> CratisResult<Option<Device>> {
+        find_one(&self.collection::<Device>("devices")?, doc! { "device_id": device_id })
+    }
+
+    fn devices(&self) -> CratisResult<Vec<Device>> {
+        find_all(&self.collection::<Device>("devices")?, doc! {})
+    }
+
+    fn user_devices(&self, user_id: &str) -> CratisResult<Vec<Device>> {
+        find_all(&self.collection::<Device>("devices")?, doc! { "user_id": user_id })
+    }
+
+    fn count_devices(&self) -> CratisResult<u64> {
+        self.collection::<Device>("devices")?.count_documents().map_err(|e| CratisError::DatabaseError(e.to_string()))
+    }
+
+    fn insert_device(&self, device: &Device) -> CratisResult<bool> {
+        let _unique = self.unique.lock().unwrap_or_else(|e| e.into_inner());
+        let collection: Collection<Device> = self.collection::<Device>("devices")?;
+        if find_one(&collection, doc! { "device_id": &device.device_id })?.is_some() {
+            return Ok(false);
+        }
+
+        insert(&collection, device)?;
+        Ok(true)
+    }
+
+    fn set_device_label(&self, device_id: &str, label: &str) -> CratisResult<()> {
+        update(&self.collection::<Device>("devices")?, doc! { "device_id": device_id }, doc! { "$set": { "label": label } })
+    }
+
+    fn delete_device(&self, device_id: &str) -> CratisResult<bool> {
+        let deleted: u64 = self.collection::<Device>("devices")?
+            .delete_one(doc! { "device_id": device_id })
+            .map_err(|e| CratisError::DatabaseError(format!("Error deleting data: {}", e)))?
+            .deleted_count;
+        Ok(deleted > 0)
+    }
+
+    fn insert_version(&self, version: &File) -> CratisResult<()> {
+        let _latest = self.lock_latest();
+        insert(&self.collection::<File>("files")?, version)?;
+        self.record_latest(version)
+    }
+
+    fn latest_version(&self, device_id: &str, path: &str, as_of: u64) -> CratisResult<Option<File>> {
+        let latest: Option<LatestVersion> = find_one(&self.collection::<LatestVersion>("latest_versions")?, doc! { "key": latest_key(device_id, path) })?;
+
+        match latest {
+            Some(latest) if latest.version.timestamp <= as_of => Ok(Some(latest.version)),
+            // The path changed after `as_of`, the version back then is part of its history
+            Some(_) => Ok(newest_by_path(self.path_history(device_id, path)?, as_of).remove(path)),
+            None => Ok(None),
+        }
+    }
+
+    fn snapshot(&self, device_id: &str, as_of: u64, prefix: Option<&str>) -> CratisResult<Vec<File>> {
+        let latest: Vec<LatestVersion> = find_all(&self.collection::<LatestVersion>("latest_versions")?, doc! { "device_id": device_id })?;
+
+        let mut snapshot: BTreeMap<String, File> = BTreeMap::new();
+        let mut changed: BTreeSet<String> = BTreeSet::new();
+        for LatestVersion { version, .. } in latest {
+            if prefix.is_some_and(|p| !version.path.starts_with(p)) {
+                continue;
+            }
+
+            if version.timestamp <= as_of {
+                snapshot.insert(version.path.clone(), version);
+            } else {
+                changed.insert(version.path);
+            }
+        }
+
+        // Only snapshots of the past read the history, for the paths that changed since
+        if !changed.is_empty() {
+            let versions: Vec<File> = find_all(&self.collection::<File>("files")?, doc! { "device_id": device_id })?;
+            snapshot.extend(newest_by_path(versions.into_iter().filter(|version| changed.contains(&version.path)), as_of));
+        }
+
+        Ok(snapshot.into_values().collect())
+    }
+
+    fn snapshot_times(&self, device_id: &str) -> CratisResult<Vec<u64>> {
+        let versions: Vec<File> = find_all(&self.collection::<File>("files")?, doc! { "device_id": device_id })?;
+        let times: BTreeSet<u64> = versions.into_iter().map(|version| version.timestamp).collect();
+        Ok(times.into_iter().collect())
+    }
+
+    fn job_versions(&self, device_id: &str, job: &str) -> CratisResult<Vec<File>> {
+        let versions: Vec<File> = find_all(&self.collection::<File>("files")?, doc! { "device_id": device_id })?;
+        Ok(versions.into_iter().filter(|version| version.job.as_deref() == Some(job)).collect())
+    }
+
+    fn delete_version(&self, device_id: &str, path: &str, timestamp: u64) -> CratisResult<()> {
+        let _latest = self.lock_latest();
+        self.collection::<File>("files")?
+            .delete_one(doc! { "device_id": device_id, "path": path, "timestamp": timestamp as i64 })
+            .map_err(|e| CratisError::DatabaseError(format!("Error deleting data: {}", e)))?;
+
+        let collection: Collection<LatestVersion> = self.collection::<LatestVersion>("latest_versions")?;
+        let key: String = latest_key(device_id, path);
+        let latest: Option<LatestVersion> = find_one(&collection, doc! { "key": &key })?;
+        if latest.is_none_or(|latest| latest.version.timestamp != timestamp) {
+            return Ok(());
+        }
+
+        // The latest version was removed, the newest remaining one takes its place
+        delete(&collection, doc! { "key": &key })?;
+        match newest_by_path(self.path_history(device_id, path)?, u64::MAX).remove(path) {
+            Some(version) => insert(&collection, &LatestVersion { key, device_id: device_id.to_string(), version }),
+            None => Ok(()),
+        }
+    }
+
+    fn delete_device_versions(&self, device_id: &str) -> CratisResult<Vec<File>> {
+        let _latest = self.lock_latest();
+        let collection: Collection<File> = self.collection::<File>("files")?;
+        let versions: Vec<File> = find_all(&collection, doc! { "device_id": device_id })?;
+        delete(&collection, doc! { "device_id": device_id })?;
+        delete(&self.collection::<LatestVersion>("latest_versions")?, doc! { "device_id": device_id })?;
+        Ok(versions)
+    }
+
+    fn move_device_versions(&self, from: &str, to: &str) -> CratisResult<u64> {
+        let _latest = self.lock_latest();
+        let files: Collection<File> = self.collection::<File>("files")?;
+        let moved: u64 = files
+            .update_many(doc! { "device_id": from }, doc! { "$set": { "device_id": to } })
+            .map_err(|e| CratisError::DatabaseError(format!("Error updating data: {}", e)))?
+            .modified_count;
+
+        let policies: Collection<RetentionPolicy> = self.collection::<RetentionPolicy>("retention_policies")?;
+        for policy in find_all(&policies, doc! { "device_id": from })? {
+            if self.retention_policy(to, &policy.job)?.is_none() {
+                insert(&policies, &RetentionPolicy { device_id: to.to_string(), ..policy })?;
+            }
+        }
+        delete(&policies, doc! { "device_id": from })?;
+
+        // Both histories are merged, so the latest versions of the device are recorded again
+        let latest: Collection<LatestVersion> = self.collection::<LatestVersion>("latest_versions")?;
+        delete(&latest, doc! { "device_id": from })?;
+        delete(&latest, doc! { "device_id": to })?;
+        for (path, version) in newest_by_path(find_all(&files, doc! { "device_id": to })?, u64::MAX) {
+            insert(&latest, &LatestVersion { key: latest_key(to, &path), device_id: to.to_string(), version })?;
+        }
+        Ok(moved)
+    }
+
+    fn is_referenced(&self, hash: &str) -> CratisResult<bool> {
+        Ok(find_one(&self.collection::<File>("files")?, doc! { "hash": hash })?.is_some())
+    }
+
+    fn storage_stats(&self) -> CratisResult<StorageStats> {
+        let versions = self.collection::<File>("files")?
+            .find(doc! {})
+            .run()
+            .map_err(|e| CratisError::DatabaseError(e.to_string()))?;
+
+        let mut objects: HashMap<String, u64> = HashMap::new();
+        let mut referenced_bytes: u64 = 0;
+        for version in versions {
+            let version: File = version.map_err(|e| CratisError::DatabaseError(e.to_string()))?;
+            referenced_bytes += version.size;
+            objects.insert(version.hash, version.size);
+        }
+
+        Ok(StorageStats { objects: objects.len() as u64, stored_bytes: objects.values().sum(), referenced_bytes })
+    }
+
+    fn retention_policy(&self, device_id: &str, job: &str) -> CratisResult<Option<RetentionPolicy>> {
+        let policies: Vec<RetentionPolicy> = find_all(&self.collection::<RetentionPolicy>("retention_policies")?, doc! { "device_id": device_id })?;
+        Ok(policies.into_iter().find(|policy| policy.job == job))
+    }
+
+    fn set_retention_policy(&self, policy: &RetentionPolicy) -> CratisResult<()> {
+        let collection: Collection<RetentionPolicy> = self.collection::<RetentionPolicy>("retention_policies")?;
+        let update: Document = doc! { "$set": { "keep_versions": policy.keep_versions, "keep_days": policy.keep_days } };
+
+        let matched: u64 = collection
+            .update_one(doc! { "device_id": &policy.device_id, "job": &policy.job }, update)
+            .map_err(|e| CratisError::DatabaseError(format!("Error updating data: {}", e)))?
+            .matched_count;
+        if matched == 0 {
+            insert(&collection, policy)?;
+        }
+        Ok(())
+    }
+
+    fn insert_refresh_token(&self, token: &RefreshToken) -> CratisResult<()> {
+        insert(&self.collection::<RefreshToken>("refresh_tokens")?, token)
+    }
+
+    fn refresh_token(&self, token_hash: &str) -> CratisResult<Option<RefreshToken>> {
+        find_one(&self.collection::<RefreshToken>("refresh_tokens")?, doc! { "token_hash": token_hash })
+    }
+
+    fn mark_refresh_token_used(&self, token_hash: &str) -> CratisResult<bool> {
+        let modified: u64 = self.collection::<RefreshToken>("refresh_tokens")?
+            .update_one(doc! { "token_hash": token_hash, "used": false }, doc! { "$set": { "used": true } })
+            .map_err(|e| CratisError::DatabaseError(format!("Error updating data: {}", e)))?
+            .modified_count;
+        Ok(modified > 0)
+    }
+
+    fn delete_refresh_tokens(&self, device_id: &str) -> CratisResult<()> {
+        delete(&self.collection::<RefreshToken>("refresh_tokens")?, doc! { "device_id": device_id })?;
+        Ok(())
+    }
+
+    fn prune_refresh_tokens(&self, device_id: &str, now: u64) -> CratisResult<()> {
+        delete(&self.collection::<RefreshToken>("refresh_tokens")?, doc! { "device_id": device_id, "expires_at": { "$lte": now as i64 } })?;
+        Ok(())
+    }
+
+    fn insert_revocation(&self, revocation: &Revocation) -> CratisResult<()> {
+        insert(&self.collection::<Revocation>("revocations")?, revocation)
+    }
+
+    fn is_revoked(&self, device_id: &str, jti: &str, issued_at: u64) -> CratisResult<bool> {
+        let collection: Collection<Revocation> = self.collection::<Revocation>("revocations")?;
+
+        if !jti.is_empty() && find_one(&collection, doc! { "jti": jti })?.is_some() {
+            return Ok(true);
+        }
+
+        let by_device: Vec<Revocation> = find_all(&collection, doc! { "device_id": device_id })?;
+        Ok(by_device.iter().any(|revocation| revocation.jti.is_empty() && revocation.issued_before > issued_at))
+    }
+
+    fn prune_revocations(&self, now: u64) -> CratisResult<u64> {
+        delete(&self.collection::<Revocation>("revocations")?, doc! { "expires_at": { "$lte": now as i64 } })
+    }
+
+    fn insert_challenge(&self, challenge: &Challenge) -> CratisResult<()> {
+        insert(&self.collection::<Challenge>("challenges")?, challenge)
+    }
+
+    fn take_challenge(&self, device_id: &str, challenge: &str, now: u64) -> CratisResult<bool> {
+        let removed: u64 = delete(
+            &self.collection::<Challenge>("challenges")?,
+            doc! { "device_id": device_id, "challenge": challenge, "expires_at": { "$gt": now as i64 } },
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn prune_challenges(&self, now: u64) -> CratisResult<u64> {
+        delete(&self.collection::<Challenge>("challenges")?, doc! { "expires_at": { "$lte": now as i64 } })
+    }
+
+    fn insert_code(&self, code: &EnrollmentCode) -> CratisResult<()> {
+        insert(&self.collection::<EnrollmentCode>("enrollment_codes")?, code)
+    }
+
+    fn codes(&self) -> CratisResult<Vec<EnrollmentCode>> {
+        find_all(&self.collection::<EnrollmentCode>("enrollment_codes")?, doc! {})
+    }
+
+    fn code_by_hash(&self, code_hash: &str) -> CratisResult<Option<EnrollmentCode>> {
+        find_one(&self.collection::<EnrollmentCode>("enrollment_codes")?, doc! { "code_hash": code_hash })
+    }
+
+    fn try_redeem_code(&self, id: &str, now: u64) -> CratisResult<bool> {
+        let collection: Collection<EnrollmentCode> = self.collection::<EnrollmentCode>("enrollment_codes")?;
+
+        // polodb cannot compare two fields of a document, so the count is only increased if it
+        // did not change since it was read, and read again otherwise
+        loop {
+            let code: EnrollmentCode = match find_one(&collection, doc! { "id": id })? {
+                Some(code) if code.is_usable(now) => code,
+                _ => return Ok(false),
+            };
+
+            let modified: u64 = collection
+                .update_one(doc! { "id": id, "uses": code.uses as i64 }, doc! { "$set": { "uses": (code.uses + 1) as i64 } })
+                .map_err(|e| CratisError::DatabaseError(format!("Error updating data: {}", e)))?
+                .modified_count;
+            if modified > 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn release_code(&self, id: &str) -> CratisResult<()> {
+        update(&self.collection::<EnrollmentCode>("enrollment_codes")?, doc! { "id": id, "uses": { "$gt": 0 } }, doc! { "$inc": { "uses": -1 } })
+    }
+
+    fn delete_code(&self, id: &str) -> CratisResult<bool> {
+        Ok(delete(&self.collection::<EnrollmentCode>("enrollment_codes")?, doc! { "id": id })? > 0)
+    }
+
+    fn insert_user(&self, user: &User) -> CratisResult<bool> {
+        let _unique = self.unique.lock().unwrap_or_else(|e| e.into_inner());
+        let collection: Collection<User> = self.collection::<User>("users")?;
+        if find_one(&collection, doc! { "username": &user.username })?.is_some() {
+            return Ok(false);
+        }
+
+        insert(&collection, user)?;
+        Ok(true)
+    }
+
+    fn user_by_name(&self, username: &str) -> CratisResult<Option<User>> {
+        find_one(&self.collection::<User>("users")?, doc! { "username": username })
+    }
+
+    fn users(&self) -> CratisResult<Vec<User>> {
+        find_all(&self.collection::<User>("users")?, doc! {})
+    }
+
+    fn schedule_data_deletion(&self, deletion: &DataDeletion) -> CratisResult<()> {
+        insert(&self.collection::<DataDeletion>("data_deletions")?, deletion)
+    }
+
+    fn cancel_data_deletion(&self, device_id: &str) -> CratisResult<()> {
+        delete(&self.collection::<DataDeletion>("data_deletions")?, doc! { "device_id": device_id })?;
+        Ok(())
+    }
+
+    fn due_data_deletions(&self, now: u64) -> CratisResult<Vec<DataDeletion>> {
+        find_all(&self.collection::<DataDeletion>("data_deletions")?, doc! { "delete_after": { "$lte": now as i64 } })
+    }
+
+    fn close(&self) {
+        // Dropping the last handle flushes the data and closes the database
+        let _ = self.database.write().unwrap_or_else(|e| e.into_inner()).take();
+    }
+}
@@ -0,0 +1,496 @@
+use cratis_core::error::{CratisError, CratisResult};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::Path;
+use std::sync::Mutex;
+use crate::handler::authentication::{Challenge, Device, RefreshToken, Revocation};
+use crate::handler::devices::DataDeletion;
+use crate::handler::enrollment::EnrollmentCode;
+use crate::handler::file_management::{File, RetentionPolicy, StorageStats};
+use crate::handler::scopes::Scope;
+use crate::handler::users::User;
+use crate::store::MetadataStore;
+
+/// Tables and indexes, created when the database is opened.
+///
+/// Listing snapshots walks the versions of a device by path and timestamp, so `files` is indexed
+/// on all three. Timestamps are stored as integers and must fit into an `i64`.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS devices (
+    device_id TEXT PRIMARY KEY NOT NULL,
+    public_key TEXT NOT NULL,
+    label TEXT NOT NULL,
+    os TEXT NOT NULL,
+    user_id TEXT,
+    enrolled_with TEXT,
+    registered_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS devices_user_id ON devices (user_id);
+
+CREATE TABLE IF NOT EXISTS files (
+    device_id TEXT NOT NULL,
+    path TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    job TEXT
+);
+CREATE INDEX IF NOT EXISTS files_device_path_timestamp ON files (device_id, path, timestamp);
+CREATE INDEX IF NOT EXISTS files_device_timestamp ON files (device_id, timestamp);
+CREATE INDEX IF NOT EXISTS files_device_job ON files (device_id, job);
+CREATE INDEX IF NOT EXISTS files_hash ON files (hash);
+
+CREATE TABLE IF NOT EXISTS retention_policies (
+    device_id TEXT NOT NULL,
+    job TEXT NOT NULL,
+    keep_versions INTEGER,
+    keep_days INTEGER,
+    PRIMARY KEY (device_id, job)
+);
+
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    token_hash TEXT PRIMARY KEY NOT NULL,
+    device_id TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    used INTEGER NOT NULL,
+    scopes TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS refresh_tokens_device_id ON refresh_tokens (device_id, expires_at);
+
+CREATE TABLE IF NOT EXISTS revocations (
+    device_id TEXT NOT NULL,
+    jti TEXT NOT NULL,
+    issued_before INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS revocations_jti ON revocations (jti);
+CREATE INDEX IF NOT EXISTS revocations_device_id ON revocations (device_id, jti, issued_before);
+CREATE INDEX IF NOT EXISTS revocations_expires_at ON revocations (expires_at);
+
+CREATE TABLE IF NOT EXISTS challenges (
+    device_id TEXT NOT NULL,
+    challenge TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS challenges_device_id ON challenges (device_id, challenge);
+CREATE INDEX IF NOT EXISTS challenges_expires_at ON challenges (expires_at);
+
+CREATE TABLE IF NOT EXISTS enrollment_codes (
+    id TEXT PRIMARY KEY NOT NULL,
+    code_hash TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    max_uses INTEGER NOT NULL,
+    uses INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS enrollment_codes_code_hash ON enrollment_codes (code_hash);
+
+CREATE TABLE IF NOT EXISTS users (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS data_deletions (
+    device_id TEXT NOT NULL,
+    delete_after INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS data_deletions_device_id ON data_deletions (device_id);
+CREATE INDEX IF NOT EXISTS data_deletions_delete_after ON data_deletions (delete_after);
+";
+
+const DEVICE_COLUMNS: &str = "device_id, public_key, label, os, user_id, enrolled_with, registered_at";
+const FILE_COLUMNS: &str = "device_id, path, hash, size, timestamp, job";
+const CODE_COLUMNS: &str = "id, code_hash, created_at, expires_at, max_uses, uses";
+const USER_COLUMNS: &str = "user_id, username, password_hash, created_at";
+
+/// Metadata store kept in an SQLite database, chosen with `settings.db_backend: sqlite`.
+///
+/// All queries share one connection, they run one after another. A caller waits for the
+/// queries of all others, so it has to run on a blocking thread, see
+/// [`AppState::blocking`](crate::state::AppState::blocking).
+pub struct SqliteStore {
+    // None once the database was closed
+    connection: Mutex<Option<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens the database at the given path, creating it, its tables and indexes if they do not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::DatabaseError` if the database cannot be opened or its schema cannot be created.
+    pub fn open(path: &Path) -> CratisResult<Self> {
+        let unable = |e: rusqlite::Error| CratisError::DatabaseError(format!("Unable to open {}: {}", path.display(), e));
+
+        let connection: Connection = Connection::open(path).map_err(unable)?;
+        // Readers do not block the writer, and a crash does not lose committed data
+        connection.pragma_update(None, "journal_mode", "WAL").map_err(unable)?;
+        connection.pragma_update(None, "synchronous", "NORMAL").map_err(unable)?;
+        connection.execute_batch(SCHEMA).map_err(unable)?;
+
+        Ok(SqliteStore { connection: Mutex::new(Some(connection)) })
+    }
+
+    /// Runs queries on the connection, mapping errors to `CratisError::DatabaseError`.
+    fn with<T>(&self, query: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> CratisResult<T> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        match connection.as_ref() {
+            Some(connection) => query(connection).map_err(|e| CratisError::DatabaseError(e.to_string())),
+            None => Err(CratisError::DatabaseError("The database is closed".to_string())),
+        }
+    }
+
+    /// Runs a query and collects all rows.
+    fn rows<T>(&self, sql: &str, params: impl rusqlite::Params, map: fn(&Row) -> rusqlite::Result<T>) -> CratisResult<Vec<T>> {
+        self.with(|connection| connection.prepare_cached(sql)?.query_map(params, map)?.collect())
+    }
+
+    /// Runs a query and returns its first row.
+    fn row<T>(&self, sql: &str, params: impl rusqlite::Params, map: fn(&Row) -> rusqlite::Result<T>) -> CratisResult<Option<T>> {
+        self.with(|connection| connection.prepare_cached(sql)?.query_row(params, map).optional())
+    }
+
+    /// Runs a statement and returns the number of changed rows.
+    fn execute(&self, sql: &str, params: impl rusqlite::Params) -> CratisResult<usize> {
+        self.with(|connection| connection.prepare_cached(sql)?.execute(params))
+    }
+}
+
+fn device_from_row(row: &Row) -> rusqlite::Result<Device> {
+    Ok(Device {
+        device_id: row.get(0)?,
+        public_key: row.get(1)?,
+        label: row.get(2)?,
+        os: row.get(3)?,
+        user_id: row.get(4)?,
+        enrolled_with: row.get(5)?,
+        registered_at: row.get(6)?,
+    })
+}
+
+fn file_from_row(row: &Row) -> rusqlite::Result<File> {
+    Ok(File {
+        device_id: row.get(0)?,
+        path: row.get(1)?,
+        hash: row.get(2)?,
+        size: row.get(3)?,
+        timestamp: row.get(4)?,
+        job: row.get(5)?,
+    })
+}
+
+fn refresh_token_from_row(row: &Row) -> rusqlite::Result<RefreshToken> {
+    let scopes: String = row.get(4)?;
+    Ok(RefreshToken {
+        token_hash: row.get(0)?,
+        device_id: row.get(1)?,
+        expires_at: row.get(2)?,
+        used: row.get(3)?,
+        scopes: serde_json::from_str::<Vec<Scope>>(&scopes).map_err(|e| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, Box::new(e)))?,
+    })
+}
+
+fn code_from_row(row: &Row) -> rusqlite::Result<EnrollmentCode> {
+    Ok(EnrollmentCode {
+        id: row.get(0)?,
+        code_hash: row.get(1)?,
+        created_at: row.get(2)?,
+        expires_at: row.get(3)?,
+        max_uses: row.get(4)?,
+        uses: row.get(5)?,
+    })
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        user_id: row.get(0)?,
+        username: row.get(1)?,
+        password_hash: row.get(2)?,
+        created_at: row.get(3)?,
+    })
+}
+
+impl MetadataStore for SqliteStore {
+    fn device(&self, device_id: &str) -> CratisResult<Option<Device>> {
+        self.row(&format!("SELECT {} FROM devices WHERE device_id = ?1", DEVICE_COLUMNS), [device_id], device_from_row)
+    }
+
+    fn devices(&self) -> CratisResult<Vec<Device>> {
+        self.rows(&format!("SELECT {} FROM devices", DEVICE_COLUMNS), [], device_from_row)
+    }
+
+    fn user_devices(&self, user_id: &str) -> CratisResult<Vec<Device>> {
+        self.rows(&format!("SELECT {} FROM devices WHERE user_id = ?1", DEVICE_COLUMNS), [user_id], device_from_row)
+    }
+
+    fn count_devices(&self) -> CratisResult<u64> {
+        self.with(|connection| connection.query_row("SELECT COUNT(*) FROM devices", [], |row| row.get(0)))
+    }
+
+    fn insert_device(&self, device: &Device) -> CratisResult<bool> {
+        let inserted: usize = self.execute(
+            &format!("INSERT INTO devices ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) ON CONFLICT DO NOTHING", DEVICE_COLUMNS),
+            params![device.device_id, device.public_key, device.label, device.os, device.user_id, device.enrolled_with, device.registered_at],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    fn set_device_label(&self, device_id: &str, label: &str) -> CratisResult<()> {
+        self.execute("UPDATE devices SET label = ?2 WHERE device_id = ?1", [device_id, label])?;
+        Ok(())
+    }
+
+    fn delete_device(&self, device_id: &str) -> CratisResult<bool> {
+        Ok(self.execute("DELETE FROM devices WHERE device_id = ?1", [device_id])? > 0)
+    }
+
+    fn insert_version(&self, version: &File) -> CratisResult<()> {
+        self.execute(
+            &format!("INSERT INTO files ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", FILE_COLUMNS),
+            params![version.device_id, version.path, version.hash, version.size, version.timestamp, version.job],
+        )?;
+        Ok(())
+    }
+
+    fn latest_version(&self, device_id: &str, path: &str, as_of: u64) -> CratisResult<Option<File>> {
+        self.row(
+            &format!("SELECT {} FROM files WHERE device_id = ?1 AND path = ?2 AND timestamp <= ?3 ORDER BY timestamp DESC, rowid DESC LIMIT 1", FILE_COLUMNS),
+            params![device_id, path, as_of],
+            file_from_row,
+        )
+    }
+
+    fn snapshot(&self, device_id: &str, as_of: u64, prefix: Option<&str>) -> CratisResult<Vec<File>> {
+        // With MAX() SQLite takes the other columns from the row holding the maximum
+        self.rows(
+            "SELECT device_id, path, hash, size, MAX(timestamp), job FROM files
+             WHERE device_id = ?1 AND timestamp <= ?2 AND (?3 IS NULL OR substr(path, 1, length(?3)) = ?3)
+             GROUP BY path ORDER BY path",
+            params![device_id, as_of, prefix],
+            file_from_row,
+        )
+    }
+
+    fn snapshot_times(&self, device_id: &str) -> CratisResult<Vec<u64>> {
+        self.rows("SELECT DISTINCT timestamp FROM files WHERE device_id = ?1 ORDER BY timestamp", [device_id], |row| row.get(0))
+    }
+
+    fn job_versions(&self, device_id: &str, job: &str) -> CratisResult<Vec<File>> {
+        self.rows(&format!("SELECT {} FROM files WHERE device_id = ?1 AND job = ?2", FILE_COLUMNS), [device_id, job], file_from_row)
+    }
+
+    fn delete_version(&self, device_id: &str, path: &str, timestamp: u64) -> CratisResult<()> {
+        self.execute(
+            "DELETE FROM files WHERE rowid = (SELECT rowid FROM files WHERE device_id = ?1 AND path = ?2 AND timestamp = ?3 LIMIT 1)",
+            params![device_id, path, timestamp],
+        )?;
+        Ok(())
+    }
+
+    fn delete_device_versions(&self, device_id: &str) -> CratisResult<Vec<File>> {
+        self.with(|connection| {
+            let transaction = connection.unchecked_transaction()?;
+            let versions: Vec<File> = transaction
+                .prepare_cached(&format!("SELECT {} FROM files WHERE device_id = ?1", FILE_COLUMNS))?
+                .query_map([device_id], file_from_row)?
+                .collect::<rusqlite::Result<Vec<File>>>()?;
+            transaction.execute("DELETE FROM files WHERE device_id = ?1", [device_id])?;
+            transaction.commit()?;
+            Ok(versions)
+        })
+    }
+
+    fn move_device_versions(&self, from: &str, to: &str) -> CratisResult<u64> {
+        self.with(|connection| {
+            let transaction = connection.unchecked_transaction()?;
+            let moved: usize = transaction.execute("UPDATE files SET device_id = ?2 WHERE device_id = ?1", [from, to])?;
+            transaction.execute("UPDATE OR IGNORE retention_policies SET device_id = ?2 WHERE device_id = ?1", [from, to])?;
+            transaction.execute("DELETE FROM retention_policies WHERE device_id = ?1", [from])?;
+            transaction.commit()?;
+            Ok(moved as u64)
+        })
+    }
+
+    fn is_referenced(&self, hash: &str) -> CratisResult<bool> {
+        self.with(|connection| connection.query_row("SELECT EXISTS (SELECT 1 FROM files WHERE hash = ?1)", [hash], |row| row.get(0)))
+    }
+
+    fn storage_stats(&self) -> CratisResult<StorageStats> {
+        self.with(|connection| {
+            connection.query_row(
+                "SELECT COUNT(*), COALESCE(SUM(size), 0), (SELECT COALESCE(SUM(size), 0) FROM files)
+                 FROM (SELECT hash, MAX(size) AS size FROM files GROUP BY hash)",
+                [],
+                |row| Ok(StorageStats { objects: row.get(0)?, stored_bytes: row.get(1)?, referenced_bytes: row.get(2)? }),
+            )
+        })
+    }
+
+    fn retention_policy(&self, device_id: &str, job: &str) -> CratisResult<Option<RetentionPolicy>> {
+        self.row(
+            "SELECT device_id, job, keep_versions, keep_days FROM retention_policies WHERE device_id = ?1 AND job = ?2",
+            params![device_id, job],
+            |row| Ok(RetentionPolicy { device_id: row.get(0)?, job: row.get(1)?, keep_versions: row.get(2)?, keep_days: row.get(3)? }),
+        )
+    }
+
+    fn set_retention_policy(&self, policy: &RetentionPolicy) -> CratisResult<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO retention_policies (device_id, job, keep_versions, keep_days) VALUES (?1, ?2, ?3, ?4)",
+            params![policy.device_id, policy.job, policy.keep_versions, policy.keep_days],
+        )?;
+        Ok(())
+    }
+
+    fn insert_refresh_token(&self, token: &RefreshToken) -> CratisResult<()> {
+        let scopes: String = serde_json::to_string(&token.scopes).map_err(|e| CratisError::DatabaseError(e.to_string()))?;
+        self.execute(
+            "INSERT INTO refresh_tokens (token_hash, device_id, expires_at, used, scopes) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![token.token_hash, token.device_id, token.expires_at, token.used, scopes],
+        )?;
+        Ok(())
+    }
+
+    fn refresh_token(&self, token_hash: &str) -> CratisResult<Option<RefreshToken>> {
+        self.row(
+            "SELECT token_hash, device_id, expires_at, used, scopes FROM refresh_tokens WHERE token_hash = ?1",
+            [token_hash],
+            refresh_token_from_row,
+        )
+    }
+
+    fn mark_refresh_token_used(&self, token_hash: &str) -> CratisResult<bool> {
+        Ok(self.execute("UPDATE refresh_tokens SET used = 1 WHERE token_hash = ?1 AND used = 0", [token_hash])? > 0)
+    }
+
+    fn delete_refresh_tokens(&self, device_id: &str) -> CratisResult<()> {
+        self.execute("DELETE FROM refresh_tokens WHERE device_id = ?1", [device_id])?;
+        Ok(())
+    }
+
+    fn prune_refresh_tokens(&self, device_id: &str, now: u64) -> CratisResult<()> {
+        self.execute("DELETE FROM refresh_tokens WHERE device_id = ?1 AND expires_at <= ?2", params![device_id, now])?;
+        Ok(())
+    }
+
+    fn insert_revocation(&self, revocation: &Revocation) -> CratisResult<()> {
+        self.execute(
+            "INSERT INTO revocations (device_id, jti, issued_before, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![revocation.device_id, revocation.jti, revocation.issued_before, revocation.expires_at],
+        )?;
+        Ok(())
+    }
+
+    fn is_revoked(&self, device_id: &str, jti: &str, issued_at: u64) -> CratisResult<bool> {
+        self.with(|connection| {
+            connection.query_row(
+                "SELECT EXISTS (SELECT 1 FROM revocations WHERE jti = ?2 AND ?2 <> '')
+                     OR EXISTS (SELECT 1 FROM revocations WHERE device_id = ?1 AND jti = '' AND issued_before > ?3)",
+                params![device_id, jti, issued_at],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    fn prune_revocations(&self, now: u64) -> CratisResult<u64> {
+        Ok(self.execute("DELETE FROM revocations WHERE expires_at <= ?1", [now])? as u64)
+    }
+
+    fn insert_challenge(&self, challenge: &Challenge) -> CratisResult<()> {
+        self.execute(
+            "INSERT INTO challenges (device_id, challenge, expires_at) VALUES (?1, ?2, ?3)",
+            params![challenge.device_id, challenge.challenge, challenge.expires_at],
+        )?;
+        Ok(())
+    }
+
+    fn take_challenge(&self, device_id: &str, challenge: &str, now: u64) -> CratisResult<bool> {
+        let removed: usize = self.execute(
+            "DELETE FROM challenges WHERE device_id = ?1 AND challenge = ?2 AND expires_at > ?3",
+            params![device_id, challenge, now],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn prune_challenges(&self, now: u64) -> CratisResult<u64> {
+        Ok(self.execute("DELETE FROM challenges WHERE expires_at <= ?1", [now])? as u64)
+    }
+
+    fn insert_code(&self, code: &EnrollmentCode) -> CratisResult<()> {
+        self.execute(
+            &format!("INSERT INTO enrollment_codes ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", CODE_COLUMNS),
+            params![code.id, code.code_hash, code.created_at, code.expires_at, code.max_uses, code.uses],
+        )?;
+        Ok(())
+    }
+
+    fn codes(&self) -> CratisResult<Vec<EnrollmentCode>> {
+        self.rows(&format!("SELECT {} FROM enrollment_codes ORDER BY created_at", CODE_COLUMNS), [], code_from_row)
+    }
+
+    fn code_by_hash(&self, code_hash: &str) -> CratisResult<Option<EnrollmentCode>> {
+        self.row(&format!("SELECT {} FROM enrollment_codes WHERE code_hash = ?1", CODE_COLUMNS), [code_hash], code_from_row)
+    }
+
+    fn try_redeem_code(&self, id: &str, now: u64) -> CratisResult<bool> {
+        let changed: usize = self.execute(
+            "UPDATE enrollment_codes SET uses = uses + 1 WHERE id = ?1 AND uses < max_uses AND (expires_at IS NULL OR expires_at > ?2)",
+            params![id, now],
+        )?;
+        Ok(changed > 0)
+    }
+
+    fn release_code(&self, id: &str) -> CratisResult<()> {
+        self.execute("UPDATE enrollment_codes SET uses = uses - 1 WHERE id = ?1 AND uses > 0", [id])?;
+        Ok(())
+    }
+
+    fn delete_code(&self, id: &str) -> CratisResult<bool> {
+        Ok(self.execute("DELETE FROM enrollment_codes WHERE id = ?1", [id])? > 0)
+    }
+
+    fn insert_user(&self, user: &User) -> CratisResult<bool> {
+        let inserted: usize = self.execute(
+            &format!("INSERT INTO users ({}) VALUES (?1, ?2, ?3, ?4) ON CONFLICT DO NOTHING", USER_COLUMNS),
+            params![user.user_id, user.username, user.password_hash, user.created_at],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    fn user_by_name(&self, username: &str) -> CratisResult<Option<User>> {
+        self.row(&format!("SELECT {} FROM users WHERE username = ?1", USER_COLUMNS), [username], user_from_row)
+    }
+
+    fn users(&self) -> CratisResult<Vec<User>> {
+        self.rows(&format!("SELECT {} FROM users ORDER BY created_at", USER_COLUMNS), [], user_from_row)
+    }
+
+    fn schedule_data_deletion(&self, deletion: &DataDeletion) -> CratisResult<()> {
+        self.execute("INSERT INTO data_deletions (device_id, delete_after) VALUES (?1, ?2)", params![deletion.device_id, deletion.delete_after])?;
+        Ok(())
+    }
+
+    fn cancel_data_deletion(&self, device_id: &str) -> CratisResult<()> {
+        self.execute("DELETE FROM data_deletions WHERE device_id = ?1", [device_id])?;
+        Ok(())
+    }
+
+    fn due_data_deletions(&self, now: u64) -> CratisResult<Vec<DataDeletion>> {
+        self.rows(
+            "SELECT device_id, delete_after FROM data_deletions WHERE delete_after <= ?1",
+            [now],
+            |row| Ok(DataDeletion { device_id: row.get(0)?, delete_after: row.get(1)? }),
+        )
+    }
+
+    fn close(&self) {
+        let connection: Option<Connection> = self.connection.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(connection) = connection {
+            // Dropping the connection closes it as well, closing explicitly reports failures
+            if let Err((_, e)) = connection.close() {
+                tracing::warn!(error = %CratisError::DatabaseError(e.to_string()), "Unable to close the database");
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use cratis_core::{config::TlsSettings, error::{CratisError, CratisResult}, identity::device_id, tls::fingerprint, utils::timestamp_now};
+use axum::Extension;
+use axum_server::{accept::{Accept, DefaultAcceptor}, tls_rustls::{RustlsAcceptor, RustlsConfig}};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, PublicKeyData, SignatureAlgorithm, PKCS_ED25519};
+use rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+
+/// Names the self-signed certificate is always valid for.
+const DEFAULT_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// The client certificate a device presented during the TLS handshake.
+///
+/// Added to every request of the connection by [`ClientCertAcceptor`], `None` if the client
+/// presented no certificate. Only certificates issued by the server's CA get this far.
+#[derive(Clone, Debug)]
+pub struct ClientCert(pub CertificateDer<'static>);
+
+/// Loads the configured certificate and key, generating a self-signed pair first if enabled and
+/// none exists yet.
+///
+/// With `client_auth`, devices may additionally present a certificate issued by the server's CA,
+/// which is generated on first start as well. Clients without a certificate are still accepted
+/// and authenticate with a token.
+///
+/// # Returns
+///
+/// * `Ok((RustlsConfig, String))` - The TLS config to serve with and the certificate fingerprint
+/// * `Err(CratisError::TlsError)` - If the certificate or key are missing or invalid
+pub async fn server_config(tls: &TlsSettings) -> CratisResult<(RustlsConfig, String)> {
+    ensure_certificate(tls)?;
+
+    let config: RustlsConfig = if tls.client_auth {
+        ensure_ca(tls)?;
+        RustlsConfig::from_config(Arc::new(client_auth_config(tls)?))
+    } else {
+        RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+            .await
+            .map_err(|e| CratisError::TlsError(format!("Unable to load {} and {}: {}", tls.cert, tls.key, e)))?
+    };
+
+    Ok((config, certificate_fingerprint(tls)?))
+}
+
+/// Returns the SHA-256 fingerprint of the configured certificate, as clients pin it.
+///
+/// # Errors
+///
+/// Returns `CratisError::TlsError` if the certificate cannot be read.
+pub fn certificate_fingerprint(tls: &TlsSettings) -> CratisResult<String> {
+    let path: &str = &tls.cert;
+    let cert: CertificateDer = CertificateDer::from_pem_file(path)
+        .map_err(|e| CratisError::TlsError(format!("Unable to read certificate {}: {}", path, e)))?;
+
+    Ok(fingerprint(cert.as_ref()))
+}
+
+/// Generates a self-signed certificate if `self_signed` is set and the certificate or key do
+/// not exist yet. Existing files are never replaced, so the fingerprint clients pinned stays valid.
+fn ensure_certificate(tls: &TlsSettings) -> CratisResult<()> {
+    let (cert_path, key_path) = (Path::new(&tls.cert), Path::new(&tls.key));
+    if !tls.self_signed || (cert_path.exists() && key_path.exists()) {
+        return Ok(());
+    }
+
+    let names: Vec<String> = DEFAULT_NAMES.iter().map(|name| name.to_string()).chain(tls.names.iter().cloned()).collect();
+    let mut params: CertificateParams = CertificateParams::new(names).map_err(|e| CratisError::TlsError(e.to_string()))?;
+    params.distinguished_name.push(DnType::CommonName, "Cratis server");
+
+    let key: KeyPair = KeyPair::generate().map_err(|e| CratisError::TlsError(e.to_string()))?;
+    let cert: Certificate = params.self_signed(&key).map_err(|e| CratisError::TlsError(e.to_string()))?;
+
+    write_pair(cert_path, &cert.pem(), key_path, &key.serialize_pem())
+}
+
+/// Builds a server config that asks clients for a certificate issued by the server's CA.
+fn client_auth_config(tls: &TlsSettings) -> CratisResult<ServerConfig> {
+    let tls_error = |path: &str, e: &dyn std::fmt::Display| CratisError::TlsError(format!("Unable to read {}: {}", path, e));
+
+    let certs: Vec<CertificateDer<'static>> = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| tls_error(&tls.cert, &e))?;
+    let key: PrivateKeyDer<'static> = PrivateKeyDer::from_pem_file(&tls.key).map_err(|e| tls_error(&tls.key, &e))?;
+
+    let mut roots: RootCertStore = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(&tls.ca_cert).map_err(|e| tls_error(&tls.ca_cert, &e))?)
+        .map_err(|e| tls_error(&tls.ca_cert, &e))?;
+
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()
+        .map_err(|e| CratisError::TlsError(e.to_string()))?;
+
+    let mut config: ServerConfig = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| CratisError::TlsError(e.to_string()))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Generates the CA signing client certificates if its certificate or key do not exist yet.
+fn ensure_ca(tls: &TlsSettings) -> CratisResult<()> {
+    let (cert_path, key_path) = (Path::new(&tls.ca_cert), Path::new(&tls.ca_key));
+    if cert_path.exists() && key_path.exists() {
+        return Ok(());
+    }
+
+    let mut params: CertificateParams = CertificateParams::new(Vec::new()).map_err(|e| CratisError::TlsError(e.to_string()))?;
+    params.distinguished_name.push(DnType::CommonName, "Cratis device CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+
+    let key: KeyPair = KeyPair::generate().map_err(|e| CratisError::TlsError(e.to_string()))?;
+    let cert: Certificate = params.self_signed(&key).map_err(|e| CratisError::TlsError(e.to_string()))?;
+
+    write_pair(cert_path, &cert.pem(), key_path, &key.serialize_pem())
+}
+
+/// The Ed25519 public key of a device, as the subject of its client certificate.
+struct DevicePublicKey<'a>(&'a [u8; 32]);
+
+impl PublicKeyData for DevicePublicKey<'_> {
+    fn der_bytes(&self) -> &[u8] {
+        self.0
+    }
+
+    fn algorithm(&self) -> &SignatureAlgorithm {
+        &PKCS_ED25519
+    }
+}
+
+/// Issues a client certificate for a device key, signed by the server's CA.
+///
+/// The certificate binds the device's existing Ed25519 key, so the private key never leaves the
+/// device and the device ID follows from the certificate like it does from the key. It is valid
+/// from now on for `validity_seconds`, devices get a new one with every login.
+///
+/// # Returns
+///
+/// * `Ok(Some(String))` - The PEM encoded certificate
+/// * `Ok(None)` - If `client_auth` is disabled
+/// * `Err(CratisError::TlsError)` - If the CA cannot be loaded or the certificate cannot be signed
+pub fn issue_client_certificate(tls: &TlsSettings, public_key: &[u8; 32], validity_seconds: u64) -> CratisResult<Option<String>> {
+    if !tls.client_auth {
+        return Ok(None);
+    }
+
+    let ca_pem: String = fs::read_to_string(&tls.ca_cert)?;
+    let ca_key: KeyPair = KeyPair::from_pem(&fs::read_to_string(&tls.ca_key)?).map_err(|e| CratisError::TlsError(e.to_string()))?;
+    // Only the name, key identifier and key of the issuer are used for signing
+    let ca: Certificate = CertificateParams::from_ca_cert_pem(&ca_pem)
+        .and_then(|params| params.self_signed(&ca_key))
+        .map_err(|e| CratisError::TlsError(e.to_string()))?;
+
+    let mut params: CertificateParams = CertificateParams::new(Vec::new()).map_err(|e| CratisError::TlsError(e.to_string()))?;
+    params.distinguished_name.push(DnType::CommonName, device_id(public_key));
+    params.is_ca = IsCa::ExplicitNoCa;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    // The start of the validity doubles as issue time when checking for revoked device tokens
+    params.not_before = OffsetDateTime::from_unix_timestamp(timestamp_now()? as i64).map_err(|e| CratisError::TlsError(e.to_string()))?;
+    params.not_after = params.not_before + Duration::seconds(validity_seconds as i64);
+
+    let cert: Certificate = params
+        .signed_by(&DevicePublicKey(public_key), &ca, &ca_key)
+        .map_err(|e| CratisError::TlsError(e.to_string()))?;
+
+    Ok(Some(cert.pem()))
+}
+
+/// Returns the Ed25519 public key of a client certificate, `None` for any other key type.
+pub fn certificate_public_key(cert: &ClientCert) -> Option<[u8; 32]> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert.0.as_ref()).ok()?;
+    let spki = parsed.public_key();
+    if spki.algorithm.algorithm.to_id_string() != "1.3.101.112" {
+        return None;
+    }
+
+    spki.subject_public_key.data.as_ref().try_into().ok()
+}
+
+/// Returns the start of a client certificate's validity as Unix timestamp.
+pub fn certificate_issued_at(cert: &ClientCert) -> Option<u64> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert.0.as_ref()).ok()?;
+    u64::try_from(parsed.validity().not_before.timestamp()).ok()
+}
+
+fn write_pair(cert_path: &Path, cert: &str, key_path: &Path, key: &str) -> CratisResult<()> {
+    for path in [cert_path, key_path] {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    fs::write(cert_path, cert)?;
+    fs::write(key_path, key)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(key_path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    Ok(())
+}
+
+/// Performs the TLS handshake and makes the client certificate available to the handlers as
+/// `Extension<Option<ClientCert>>`.
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        ClientCertAcceptor { inner: RustlsAcceptor::new(config) }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = <Extension<Option<ClientCert>> as Layer<S>>::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor: RustlsAcceptor<DefaultAcceptor> = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let cert: Option<ClientCert> = stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()).map(|cert| ClientCert(cert.clone().into_owned()));
+
+            Ok((stream, Extension(cert).layer(service)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn client_certificates_expire_after_the_given_validity() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("cratis-tls-{}", std::process::id()));
+        let tls = TlsSettings {
+            client_auth: true,
+            ca_cert: dir.join("ca.crt").to_string_lossy().to_string(),
+            ca_key: dir.join("ca.key").to_string_lossy().to_string(),
+            ..TlsSettings::default()
+        };
+        ensure_ca(&tls).unwrap();
+
+        let public_key: [u8; 32] = [7; 32];
+        let pem: String = issue_client_certificate(&tls, &public_key, 3600).unwrap().unwrap();
+        let cert = ClientCert(CertificateDer::from_pem_slice(pem.as_bytes()).unwrap());
+
+        let (_, parsed) = x509_parser::parse_x509_certificate(cert.0.as_ref()).unwrap();
+        let validity = parsed.validity();
+        assert_eq!(validity.not_after.timestamp() - validity.not_before.timestamp(), 3600);
+        assert!(certificate_issued_at(&cert).unwrap().abs_diff(timestamp_now().unwrap()) <= 1);
+        assert_eq!(certificate_public_key(&cert), Some(public_key));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,26 +1,67 @@
 use clap_derive::{Parser, Subcommand};
+use cratis_core::auth::{access_token, authorize, login, token_device_id, Tokens};
 use cratis_core::backup::backup;
-use cratis_core::config::get_config_cli;
-use cratis_core::error::{CratisError, CratisErrorLevel, CratisResult, display_msg};
+use cratis_core::config::{get_config_cli, AuthMethod, BackupJob};
+use cratis_core::error::{CratisError, CratisResult, ErrorCode};
+use cratis_core::identity::{key_path, load_or_create_key, registration_message, save_certificate, DeviceKey};
+use cratis_core::tls::{client, fetch_fingerprint, normalize_fingerprint, pin};
+use cratis_core::utils::{format_duration, format_timestamp, parse_timestamp, timestamp_now, to_human_readable_size};
 use reqwest::{Client, Response, StatusCode};
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::{json, Map, Value};
 use sysinfo::System;
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
 
 #[derive(Parser)]
 #[command(name = "cratis.db")]
 # [command(about = "Manage your backups", long_about = None)]
 pub struct Cli {
+    // Path of the config file, takes precedence over CRATIS_CONFIG and the default locations
+    #[arg(short, long, global = true)]
+    pub config: Option<String>,
     #[command(subcommand)]
     pub command: Commands,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
+    // Write a starter config file
+    Init {
+        #[arg(long)]
+        force: bool,
+    },
     // Registers device on server
-    Register,
-    // Immediately trigger a backup based on the current configuration
-    BackupNow,
+    Register {
+        // Enrollment code issued by the server admin
+        #[arg(long)]
+        code: Option<String>,
+        // Register the device for this user, the password is prompted for
+        #[arg(long, conflicts_with = "code")]
+        user: Option<String>,
+        // Expected fingerprint of the server certificate, as printed by `cratis-api fingerprint`
+        #[arg(long)]
+        fingerprint: Option<String>,
+    },
+    // List the devices of the user owning this device
+    Devices,
+    // Issue tokens with limited scopes for other tools
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+    // Removes this device from the server and revokes its tokens
+    Unregister {
+        // Also delete the backed up data after the server's grace period
+        #[arg(long)]
+        delete_data: bool,
+    },
+    // Immediately trigger a backup based on the current configuration, runs every job unless one is named
+    BackupNow {
+        #[arg(long)]
+        job: Option<String>,
+    },
     // Restore a specific snapshot for a given file path
     RestoreSnapshot {
         #[arg(short, long)]
@@ -33,33 +74,87 @@ pub enum Commands {
         #[arg(short, long)]
         file: String,
     },
-    // Print the currently loaded configuration
+    // Print the currently loaded configuration with secrets redacted
     ShowConfig,
-    // Send a test request to verify server connectivity and token validity
+    // Validate the config file and report every problem, exits non-zero if there are any
+    CheckConfig,
+    // Check that the server is reachable and ready, showing its version, uptime and free disk space
     PingServer,
+    // Manage the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    // Export a snapshot of this device, or another device of the same user, as a tar, tar.zst or zip archive
+    Export {
+        #[arg(short, long)]
+        output: String,
+        #[arg(long)]
+        as_of: Option<String>,
+        #[arg(long)]
+        prefix: Option<String>,
+        #[arg(long, default_value = "tar")]
+        format: String,
+        // Id of the device to export, see `cratis devices`
+        #[arg(long)]
+        device: Option<String>,
+    },
+    // Import an existing tar archive (optionally gzip or zstd compressed) as a historical snapshot
+    Import {
+        #[arg(long)]
+        tar: String,
+        #[arg(long)]
+        as_of: String,
+        #[arg(long, default_value = "/")]
+        root: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    // Create a token pair with the given scopes, e.g. a restore-only token for a helpdesk tool
+    Create {
+        // backup:write or restore:read, can be repeated
+        #[arg(long = "scope", required = true)]
+        scopes: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    // Upgrade the config file to the current format, the original is kept as a backup
+    Migrate,
 }
 
 /// Registers the current device with the Cratis server.
 ///
 /// This function collects system information (hostname and OS) and sends a registration
-/// request to the server. Upon successful registration, it returns an authentication token
-/// that can be used for subsequent API calls.
+/// request to the server, together with the enrollment code if one is given. With a user, the
+/// password is prompted for and the device is registered for that user instead. The device is
+/// identified by an Ed25519 keypair that is created on first use and kept in the key file; the
+/// hostname is only its display name. Upon successful registration, it returns a short-lived
+/// access token for subsequent API calls and a refresh token to renew it. With
+/// `server.auth: certificate`, the client certificate issued by the server is saved as well.
+///
+/// If the key is already registered, the device signs in with it instead.
 ///
 /// # Returns
 ///
-/// * `Ok(String)` - The authentication token received from the server
+/// * `Ok(Tokens)` - The access and refresh token received from the server
 /// * `Err(CratisError)` - If registration fails due to:
 ///   - Network connectivity issues
 ///   - Server not found (404)
-///   - Device already registered (409)
+///   - Missing, invalid or expired enrollment code (403)
+///   - Wrong username or password
+///   - Rejected key signature (401)
 ///   - Invalid server response
 ///   - Unable to retrieve system information
 ///
 /// # Examples
 ///
 /// ```ignore
-/// match register().await {
-///     Ok(token) => println!("Registration successful! Token: {}", token),
+/// match register(Some("7GQ2-XK4P-M9TD"), None).await {
+///     Ok(tokens) => println!("Registration successful! Token: {}", tokens.access_token),
 ///     Err(e) => eprintln!("Registration failed: {}", e),
 /// }
 /// ```
@@ -74,74 +169,478 @@ pub enum Commands {
 ///
 /// * Hostname - Retrieved from system information
 /// * Operating System - Retrieved from system information
-pub async fn register() -> CratisResult<String> {
+/// * Public key - Of the device key, the private key never leaves the device
+pub async fn register(code: Option<&str>, user: Option<&str>) -> CratisResult<Tokens> {
     let hostname: String = System::host_name().ok_or(CratisError::Unknown)?;
     let os: String = System::name().ok_or(CratisError::Unknown)?;
 
-    let mut device_info: HashMap<String, String> = HashMap::new();
-    (&mut device_info).insert("hostname".to_string(), hostname);
-    (&mut device_info).insert("os".to_string(), os);
+    // The key is kept across registrations, so the device keeps its id and its backups
+    let key: DeviceKey = load_or_create_key()?;
+    let public_key: String = key.public_key();
+    let timestamp: u64 = timestamp_now()?;
 
-    let client: Client = Client::new();
-    let response: Response = client
-        .post(format!("{}/register", get_config_cli().server.address))
-        .json(&device_info)
+    let device_info: Value = json!({
+        "public_key": public_key,
+        "signature": key.sign(registration_message(&public_key, timestamp).as_bytes()),
+        "timestamp": timestamp,
+        "label": &hostname,
+        "os": os,
+        "code": code,
+    });
+
+    let client: Client = client()?;
+    let mut request = client.post(format!("{}/register", get_config_cli()?.server.address)).json(&device_info);
+    if let Some(user) = user {
+        request = request.bearer_auth(user_login(&client, user).await?);
+    }
+    let response: Response = request
         .send()
         .await
-        .map_err(|_| CratisError::RequestError("Unable to send request"))?;
+        .map_err(CratisError::connection)?;
 
     let status: StatusCode = response.status();
     let response_body: String = response
         .text()
         .await
-        .map_err(|_| CratisError::RequestError("Invalid response"))?;
+        .map_err(|_| CratisError::invalid_response("Invalid response"))?;
 
     if status.is_success() {
         let json_value: Value = serde_json::from_str(&response_body)
-            .map_err(|_| CratisError::RequestError("Invalid response"))?;
+            .map_err(|_| CratisError::invalid_response("Invalid response"))?;
+
+        let token: Option<&str> = json_value.get("token").and_then(|v| v.as_str());
+        let refresh_token: Option<&str> = json_value.get("refresh_token").and_then(|v| v.as_str());
 
-        if let Some(token) = json_value.get("token").and_then(|v| v.as_str()) {
-            Ok(token.to_string())
+        // Devices authenticating with a certificate use it instead of the tokens from now on
+        let certificate: Option<&str> = json_value.get("client_certificate").and_then(|v| v.as_str());
+        match certificate {
+            Some(certificate) if get_config_cli()?.server.auth == AuthMethod::Certificate => save_certificate(certificate)?,
+            None if get_config_cli()?.server.auth == AuthMethod::Certificate => {
+                return Err(CratisError::AuthFailure("The server does not issue client certificates, set server.auth to token".to_string()))
+            }
+            _ => {}
+        }
+
+        if let (Some(token), Some(refresh_token)) = (token, refresh_token) {
+            Ok(Tokens { access_token: token.to_string(), refresh_token: refresh_token.to_string() })
         } else {
-            Err(CratisError::RequestError(
-                "Invalid response: Token missing!",
-            ))
+            Err(CratisError::invalid_response("Invalid response: Token missing!"))
         }
-    } else if status == StatusCode::NOT_FOUND {
-        Err(CratisError::RequestError("Server not found"))
-    } else if status == StatusCode::CONFLICT {
-        Err(CratisError::RequestError("Device already registered"))
     } else {
-        Err(CratisError::RequestError("Invalid response"))
+        let error: CratisError = CratisError::from_body(status, response_body.as_bytes());
+
+        // Already registered with this key, sign in with it instead
+        if let CratisError::Api { code: ErrorCode::AlreadyExists, details, .. } = &error
+            && let Some(device_id) = details.get("device_id").and_then(Value::as_str)
+        {
+            return login(device_id, Some(&hostname)).await;
+        }
+
+        match error.code() {
+            ErrorCode::EnrollmentCodeInvalid => Err(CratisError::AuthFailure(format!("{}, ask the server admin for one and pass it with --code", error))),
+            ErrorCode::Unauthorized => Err(CratisError::AuthFailure(format!("{}, check that the clocks of client and server are in sync", error))),
+            _ => Err(error),
+        }
+    }
+}
+
+/// Pins the certificate of an HTTPS server before registering with it.
+///
+/// The fingerprint of the certificate the server presents is compared to `expected`, or shown
+/// to the user for confirmation if there is none. If `server.tls_fingerprint` is already set,
+/// the presented certificate has to match it. The accepted fingerprint is pinned for the rest of
+/// this run.
+///
+/// # Arguments
+///
+/// * `expected` - Fingerprint the certificate has to have, as printed by `cratis-api fingerprint`
+///
+/// # Returns
+///
+/// * `Ok(Some(String))` - The newly pinned fingerprint, to be stored in the config
+/// * `Ok(None)` - If the server is not reached over HTTPS or its certificate was pinned before
+/// * `Err(CratisError)` - If the server is not reachable, the fingerprint does not match or the
+///   user does not trust the certificate
+pub async fn pin_server_certificate(expected: Option<&str>) -> CratisResult<Option<String>> {
+    let server = &get_config_cli()?.server;
+    if !server.address.starts_with("https://") {
+        if expected.is_some() {
+            return Err(CratisError::InvalidInput("--fingerprint needs an https:// server address".to_string()));
+        }
+        return Ok(None);
+    }
+
+    let presented: String = fetch_fingerprint(&server.address).await?;
+    if !server.tls_fingerprint.is_empty() {
+        if normalize_fingerprint(&server.tls_fingerprint)? != presented {
+            return Err(CratisError::TlsError(format!(
+                "The server presented the certificate {}, which does not match server.tls_fingerprint. \
+                 If the certificate was replaced on purpose, clear server.tls_fingerprint and register again",
+                presented
+            )));
+        }
+        return Ok(None);
+    }
+
+    match expected {
+        Some(expected) if normalize_fingerprint(expected)? != presented => {
+            return Err(CratisError::TlsError(format!("The server presented the certificate {}, which is not the expected one", presented)));
+        }
+        Some(_) => {}
+        None => {
+            println!("The server presented a certificate with the fingerprint\n  {}", presented);
+            println!("Compare it with the output of `cratis-api fingerprint` on the server.");
+            print!("Trust this certificate? [y/N] ");
+            std::io::stdout().flush()?;
+
+            let mut answer: String = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                return Err(CratisError::AuthFailure("The server certificate was not trusted".to_string()));
+            }
+        }
+    }
+
+    pin(presented.clone());
+    Ok(Some(presented))
+}
+
+/// Removes this device from the Cratis server.
+///
+/// All tokens of the device are revoked by the server and its client certificate is no longer
+/// accepted. The caller is expected to clear the tokens from the config afterwards.
+///
+/// # Arguments
+///
+/// * `delete_data` - Also delete the backed up data once the server's grace period has passed
+///
+/// # Returns
+///
+/// * `Ok(String)` - A summary message, including when the data will be deleted
+/// * `Err(CratisError)` - If the device is not registered or the request fails
+pub async fn unregister(delete_data: bool) -> CratisResult<String> {
+    let device_id: String = match get_config_cli()?.server.auth {
+        AuthMethod::Certificate => DeviceKey::load(&key_path()?)?.ok_or(CratisError::AuthFailure("This device is not registered".to_string()))?.device_id(),
+        AuthMethod::Token => token_device_id(&access_token().await?).ok_or(CratisError::AuthFailure("This device is not registered".to_string()))?,
+    };
+
+    let client: Client = client()?;
+    let response: Response = authorize(client.delete(format!("{}/devices/{}", get_config_cli()?.server.address, device_id))).await?
+        .query(&[("delete_data", delete_data)])
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    match response.status() {
+        s if s.is_success() => {}
+        _ => return Err(CratisError::from_response(response).await),
+    }
+
+    let body: Value = response.json().await.map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    match body.get("data_deleted_after").and_then(Value::as_u64) {
+        Some(delete_after) => Ok(format!("Device {} removed, its data will be deleted after {}", device_id, format_timestamp(delete_after))),
+        None => Ok(format!("Device {} removed", device_id)),
+    }
+}
+
+/// Logs a user in, prompting for the password.
+///
+/// # Returns
+///
+/// * `Ok(String)` - A short-lived user token
+/// * `Err(CratisError)` - If the password cannot be read, the credentials are wrong or the server is not reachable
+async fn user_login(client: &Client, username: &str) -> CratisResult<String> {
+    let password: String = rpassword::prompt_password(format!("Password for {}: ", username))?;
+
+    let response: Response = client
+        .post(format!("{}/login", get_config_cli()?.server.address))
+        .json(&json!({ "username": username, "password": password }))
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    match response.status() {
+        s if s.is_success() => {}
+        _ => return Err(CratisError::from_response(response).await),
+    }
+
+    let body: Value = response.json().await.map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    body.get("token")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or(CratisError::invalid_response("Invalid response: Token missing!"))
+}
+
+/// Lists the devices of the user owning this device.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - One line per device, the current device is marked with `*`
+/// * `Err(CratisError)` - If the device is not registered or the request fails
+pub async fn list_devices() -> CratisResult<Vec<String>> {
+    let client: Client = client()?;
+    let response: Response = authorize(client.get(format!("{}/devices", get_config_cli()?.server.address))).await?
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    match response.status() {
+        s if s.is_success() => {}
+        _ => return Err(CratisError::from_response(response).await),
     }
+
+    let body: Value = response.json().await.map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    let devices: &Vec<Value> = body.get("devices").and_then(Value::as_array).ok_or(CratisError::invalid_response("Invalid response"))?;
+
+    Ok(devices
+        .iter()
+        .map(|device| {
+            let marker: &str = if device.get("current").and_then(Value::as_bool).unwrap_or(false) { "*" } else { " " };
+            let field = |key: &str| device.get(key).and_then(Value::as_str).unwrap_or("").to_string();
+            format!("{} {}  {}  {}", marker, field("device_id"), field("label"), field("os"))
+        })
+        .collect())
+}
+
+/// Requests a token pair for this device that only grants the given scopes.
+///
+/// The tokens are not stored in the config, they are meant to be handed to another tool.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The lines to show, containing the access and the refresh token
+/// * `Err(CratisError)` - If a scope is not granted to this device or the request fails
+pub async fn create_token(scopes: &[String]) -> CratisResult<Vec<String>> {
+    let client: Client = client()?;
+    let response: Response = authorize(client.post(format!("{}/tokens", get_config_cli()?.server.address))).await?
+        .json(&json!({ "scopes": scopes }))
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    if !response.status().is_success() {
+        return Err(match CratisError::from_response(response).await {
+            CratisError::Api { code: ErrorCode::MissingScope, details, .. } => CratisError::AuthFailure(format!(
+                "This device may not grant the {} scope",
+                details.get("scope").and_then(Value::as_str).unwrap_or("requested"),
+            )),
+            // Unknown scopes are rejected while parsing the request, before the handler could report an error
+            error @ CratisError::RequestError { .. } if error.code() == ErrorCode::InvalidRequest => {
+                CratisError::InvalidInput("Unknown scope, use backup:write or restore:read".to_string())
+            }
+            error => error,
+        });
+    }
+
+    let body: Value = response.json().await.map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    let field = |key: &str| body.get(key).and_then(Value::as_str).map(str::to_string).ok_or(CratisError::invalid_response("Invalid response: Token missing!"));
+
+    Ok(vec![
+        format!("Scopes: {}", scopes.join(", ")),
+        format!("Access token: {}", field("token")?),
+        format!("Refresh token: {}", field("refresh_token")?),
+    ])
 }
 
-pub async fn ping_server() -> CratisResult<String> {
-    let client: Client = Client::new();
+/// Checks that the server is reachable and ready to take backups.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The version and uptime of the server and the result of every check
+/// * `Err(CratisError::RequestError)` - If the server is not ready, naming the failed checks
+/// * `Err(CratisError)` - If the server is not reachable
+pub async fn ping_server() -> CratisResult<Vec<String>> {
+    let client: Client = client()?;
     let response: Response = client
-        .get(format!("{}/ping", get_config_cli().server.address))
+        .get(format!("{}/health/ready", get_config_cli()?.server.address))
         .send()
         .await
-        .map_err(|_| {
-            CratisError::ConnectionIssue("Unable to send request, server is not reachable!")
-        })?;
+        .map_err(CratisError::connection)?;
 
+    // Not ready is reported with 503 and the same body
     let status: StatusCode = response.status();
+    if status != StatusCode::OK && status != StatusCode::SERVICE_UNAVAILABLE {
+        return Err(CratisError::from_response(response).await);
+    }
 
-    if status == StatusCode::OK {
-        Ok("Server is reachable!".to_string())
-    } else {
-        Err(CratisError::Unknown)
+    let body: Value = response.json().await.map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    let version: &str = body.get("version").and_then(Value::as_str).unwrap_or("unknown");
+    let uptime: u64 = body.get("uptime_seconds").and_then(Value::as_u64).unwrap_or_default();
+    let checks: &Map<String, Value> = body.get("checks").and_then(Value::as_object).ok_or(CratisError::invalid_response("Invalid response: Checks missing!"))?;
+
+    let mut failed: Vec<String> = Vec::new();
+    let mut lines: Vec<String> = vec![format!("Server is ready (version {}, up {})", version, format_duration(uptime))];
+    for (name, check) in checks {
+        let mut result: String = match check.get("ok").and_then(Value::as_bool) {
+            Some(true) => "ok".to_string(),
+            _ => {
+                let reason: &str = check.get("error").and_then(Value::as_str).unwrap_or("failed");
+                failed.push(format!("{}: {}", name, reason));
+                reason.to_string()
+            }
+        };
+        if let Some(free_bytes) = check.get("free_bytes").and_then(Value::as_u64) {
+            result.push_str(&format!(", {} free", to_human_readable_size(free_bytes as f64)));
+        }
+        lines.push(format!("  {:<10}{}", name, result));
+    }
+
+    if status == StatusCode::SERVICE_UNAVAILABLE || !failed.is_empty() {
+        return Err(CratisError::request(status, format!("Server (version {}) is not ready: {}", version, failed.join(", "))));
+    }
+    Ok(lines)
+}
+
+/// Runs backup jobs immediately.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job to run, all configured jobs are run if `None`
+///
+/// # Returns
+///
+/// * `Ok(String)` - A summary message naming the jobs that ran
+/// * `Err(CratisError)` - If the job does not exist or the server rejects an upload, jobs
+///   after a failed one are not run
+pub async fn backup_now(job: Option<&str>) -> CratisResult<String> {
+    let backup_config = &get_config_cli()?.backup;
+    let jobs: Vec<BackupJob> = match job {
+        Some(name) => vec![backup_config.job(name)?],
+        None => backup_config.effective_jobs(),
+    };
+
+    for job in &jobs {
+        // Rejected uploads are returned as error with the reason the server gave
+        backup(job).await?;
     }
+
+    let names: Vec<&str> = jobs.iter().map(|job| job.name.as_str()).collect();
+    Ok(format!("Files backed up successfully! Jobs: {}", names.join(", ")))
+}
+
+/// Downloads a snapshot of this device, or another device of the same user, from the server as an archive.
+///
+/// The archive is streamed directly into the output file, so it is never held in memory
+/// as a whole. A partially written file is removed if the download fails.
+///
+/// # Arguments
+///
+/// * `output` - Path of the archive file to create
+/// * `as_of` - Point in time of the snapshot (Unix timestamp, `YYYY-MM-DD` or RFC 3339), defaults to now
+/// * `prefix` - Only export files whose original path starts with this prefix
+/// * `format` - Archive format: `tar`, `tar.zst` or `zip`
+/// * `device` - Id of the device to export, defaults to this device
+///
+/// # Returns
+///
+/// * `Ok(String)` - A summary message containing the output path and archive size
+/// * `Err(CratisError)` - If the request fails, the snapshot is empty or the file cannot be written
+pub async fn export_snapshot(output: &str, as_of: Option<String>, prefix: Option<String>, format: &str, device: Option<String>) -> CratisResult<String> {
+    let mut query: Vec<(&str, String)> = vec![("format", format.to_string())];
+    if let Some(device) = device {
+        query.push(("device", device));
+    }
+    if let Some(as_of) = as_of {
+        query.push(("as_of", parse_timestamp(&as_of)?.to_string()));
+    }
+    if let Some(prefix) = prefix {
+        query.push(("prefix", prefix));
+    }
+
+    let client: Client = client()?;
+    let config = get_config_cli()?;
+    let mut response: Response = authorize(client.get(format!("{}/export", config.server.address))).await?
+        .query(&query)
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    if !response.status().is_success() {
+        return Err(CratisError::from_response(response).await);
+    }
+
+    let mut file = tokio::fs::File::create(output).await?;
+    let mut size: u64 = 0;
+
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                drop(file);
+                let _ = tokio::fs::remove_file(output).await;
+                return Err(CratisError::connection(e));
+            }
+        };
+
+        if let Err(e) = file.write_all(&chunk).await {
+            drop(file);
+            let _ = tokio::fs::remove_file(output).await;
+            return Err(e.into());
+        }
+        size += chunk.len() as u64;
+    }
+    if let Err(e) = file.flush().await {
+        drop(file);
+        let _ = tokio::fs::remove_file(output).await;
+        return Err(e.into());
+    }
+
+    Ok(format!("Exported snapshot to {} ({})", output, to_human_readable_size(size as f64)))
 }
 
-pub async fn backup_now() -> CratisResult<String> {
-    let status: http::status::StatusCode = backup().await;
+/// Uploads an existing tar archive to be stored as a historical snapshot of this device.
+///
+/// The archive is streamed to the server unchanged, the server detects gzip and zstd
+/// compression on its own. Content that is already stored on the server is deduplicated.
+///
+/// # Arguments
+///
+/// * `tar` - Path of the archive to import
+/// * `as_of` - Point in time the archive was taken (Unix timestamp, `YYYY-MM-DD` or RFC 3339)
+/// * `root` - Directory on this device that the paths inside the archive are relative to
+///
+/// # Returns
+///
+/// * `Ok(String)` - A summary of the imported files
+/// * `Err(CratisError)` - If the archive cannot be read, the request fails or the archive is invalid
+pub async fn import_archive(tar: &str, as_of: &str, root: &str) -> CratisResult<String> {
+    let as_of: u64 = parse_timestamp(as_of)?;
+    let file = tokio::fs::File::open(tar).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            CratisError::InvalidPath { path: PathBuf::from(tar), reason: "File not found".to_string() }
+        } else {
+            CratisError::IoError(e)
+        }
+    })?;
+    let body = reqwest::Body::wrap_stream(ReaderStream::new(file));
 
-    match status {
-        s if s.is_success() => Ok("Files backed up successfully!".to_string()),
-        http::status::StatusCode::NOT_FOUND => Err(CratisError::RequestError("Server not found")),
-        http::status::StatusCode::UNAUTHORIZED => Err(CratisError::RequestError("Unauthorized")),
-        _ => Err(CratisError::RequestError("Invalid response")),
+    let client: Client = client()?;
+    let config = get_config_cli()?;
+    let response: Response = authorize(client.post(format!("{}/import", config.server.address))).await?
+        .query(&[("as_of", as_of.to_string()), ("root", root.to_string())])
+        .body(body)
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    match response.status() {
+        s if s.is_success() => {}
+        _ => return Err(CratisError::from_response(response).await),
     }
+
+    let json_value: Value = response
+        .json()
+        .await
+        .map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    let summary: &Value = json_value.get("summary").ok_or(CratisError::invalid_response("Invalid response: Summary missing!"))?;
+    let field = |name: &str| summary.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+
+    Ok(format!(
+        "Imported {} files ({} new versions, {} of which {} deduplicated)",
+        field("files"),
+        field("versions"),
+        to_human_readable_size(field("bytes") as f64),
+        to_human_readable_size(field("deduplicated_bytes") as f64)
+    ))
 }
@@ -1,38 +1,120 @@
 use clap::{Parser};
-use cratis_core::error::{display_msg, CratisErrorLevel, CratisResult};
-use cratis_core::config::{update_config, load_config, TEMP_CONFIG_PATH};
-use crate::cli::{Commands, register, backup_now, ping_server};
+use cratis_core::error::{CratisError, CratisResult};
+use cratis_core::config::{AuthMethod, CratisConfig, update_config, load_config, find_config, default_init_path, write_starter_config, get_config_cli, render_config, migrate_config_file};
+use cratis_core::identity::{key_path, DeviceKey};
+use cratis_core::logging::init_logging;
+use cratis_core::validation::check_config;
+use crate::cli::{Commands, ConfigCommand, TokenCommand, pin_server_certificate, register, unregister, list_devices, create_token, backup_now, ping_server, export_snapshot, import_archive};
 use serde_yaml::Value;
+use tracing::{error, info};
+use std::path::PathBuf;
 
 mod cli;
 
 #[tokio::main]
 async fn main() {
-    load_config(TEMP_CONFIG_PATH, false);
     let cli_ = cli::Cli::parse();
 
+    // Init and check-config have to work without a valid config, they print to stderr as logging is not set up yet
     match cli_.command {
-        Commands::Register {} => {
-            display_msg(None, CratisErrorLevel::Info, Some("Registering...".to_string()));
-
-            match register().await {
-                Ok(token) => {
-                    display_msg(None, CratisErrorLevel::Info, Some("Registered successfully!".to_string()));
-                    match update_config("server.auth_token", TEMP_CONFIG_PATH, Value::String(token)) {
-                        Ok(_) => display_msg(None, CratisErrorLevel::Info, Some("Updated config successfully!".to_string())),
-                        Err(e) => display_msg(Some(&e), CratisErrorLevel::Warning, None),
+        Commands::Init { force } => {
+            match default_init_path(cli_.config.as_deref(), false).and_then(|path| write_starter_config(&path, false, force).map(|_| path)) {
+                Ok(path) => eprintln!("Created config at {}", path.display()),
+                Err(e) => fatal(&e),
+            }
+            return;
+        }
+        Commands::CheckConfig => {
+            match find_config(cli_.config.as_deref(), false).and_then(|path| check_config(&path, false).map(|issues| (path, issues))) {
+                Ok((path, issues)) if issues.is_empty() => eprintln!("{} is valid", path.display()),
+                Ok((path, issues)) => {
+                    for issue in &issues {
+                        eprintln!("Warning: {}", CratisError::ConfigError(issue.to_string()));
                     }
+                    let summary = CratisError::ConfigError(format!("{} problem(s) found in {}", issues.len(), path.display()));
+                    fatal(&summary);
                 }
-                Err(e) => display_msg(Some(&e), CratisErrorLevel::Warning, None),
+                Err(e) => fatal(&e),
             }
+            return;
         }
-        Commands::BackupNow {} => {
-            display_msg(None, CratisErrorLevel::Info, Some("Starting backup".to_string()));
+        Commands::Config { command: ConfigCommand::Migrate } => {
+            match find_config(cli_.config.as_deref(), false).and_then(|path| migrate_config_file(&path, false).map(|report| (path, report))) {
+                Ok((path, report)) => {
+                    for line in report.messages(&path) {
+                        eprintln!("{}", line);
+                    }
+                }
+                Err(e) => fatal(&e),
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let config_path: PathBuf = find_config(cli_.config.as_deref(), false).unwrap_or_else(|e| fatal(&e));
+    let config: &CratisConfig = load_config(&config_path, false).and_then(|_| get_config_cli()).unwrap_or_else(|e| fatal(&e));
+    if let Err(e) = init_logging(&config.log) {
+        fatal(&e);
+    }
 
-            let result: CratisResult<String> = backup_now().await;
-            match result {
-                Ok(_) => display_msg(None, CratisErrorLevel::Info, Some(result.unwrap())),
-                Err(e) => display_msg(Some(&e), CratisErrorLevel::Warning, None),
+    match cli_.command {
+        Commands::Init { .. } | Commands::CheckConfig | Commands::Config { .. } => unreachable!(),
+        Commands::Register { code, user, fingerprint } => {
+            let pinned: Option<String> = match pin_server_certificate(fingerprint.as_deref()).await {
+                Ok(pinned) => pinned,
+                Err(e) => fatal(&e),
+            };
+
+            info!("Registering...");
+
+            match register(code.as_deref(), user.as_deref()).await {
+                Ok(tokens) => {
+                    let registered: String = match key_path().and_then(|path| DeviceKey::load(&path)) {
+                        Ok(Some(key)) => format!("Registered successfully as {}!", key.device_id()),
+                        _ => "Registered successfully!".to_string(),
+                    };
+                    info!("{}", registered);
+                    // Devices using a client certificate keep no tokens in the config
+                    let (access_token, refresh_token) = match config.server.auth {
+                        AuthMethod::Certificate => (String::new(), String::new()),
+                        AuthMethod::Token => (tokens.access_token, tokens.refresh_token),
+                    };
+                    let result: CratisResult<()> = update_config("server.auth_token", &config_path, Value::String(access_token))
+                        .and_then(|_| update_config("server.refresh_token", &config_path, Value::String(refresh_token)))
+                        .and_then(|_| match pinned {
+                            Some(fingerprint) => update_config("server.tls_fingerprint", &config_path, Value::String(fingerprint)),
+                            None => Ok(()),
+                        });
+                    match result {
+                        Ok(_) => info!("Updated config successfully!"),
+                        Err(e) => fatal(&e),
+                    }
+                }
+                Err(e) => fatal(&e),
+            }
+        }
+        Commands::Unregister { delete_data } => {
+            info!("Unregistering...");
+
+            match unregister(delete_data).await {
+                Ok(msg) => {
+                    info!("{}", msg);
+                    let result: CratisResult<()> = update_config("server.auth_token", &config_path, Value::String(String::new()))
+                        .and_then(|_| update_config("server.refresh_token", &config_path, Value::String(String::new())));
+                    if let Err(e) = result {
+                        fatal(&e);
+                    }
+                }
+                Err(e) => fatal(&e),
+            }
+        }
+        Commands::BackupNow { job } => {
+            info!(job = job.as_deref(), "Starting backup");
+
+            match backup_now(job.as_deref()).await {
+                Ok(msg) => info!("{}", msg),
+                Err(e) => fatal(&e),
             }
         }
         Commands::RestoreSnapshot { from, to } => {
@@ -42,15 +124,58 @@ async fn main() {
             println!("List versions of {}", file);
         }
         Commands::PingServer {} => {
-            display_msg(None, CratisErrorLevel::Info, Some("Pinging server...".to_string()));
+            info!("Pinging server...");
 
             match ping_server().await {
-                Ok(msg) => display_msg(None, CratisErrorLevel::Info, Some(msg)),
-                Err(e) => display_msg(Some(&e), CratisErrorLevel::Fatal, None)
+                Ok(lines) => lines.into_iter().for_each(|line| println!("{}", line)),
+                Err(e) => fatal(&e)
             }
         }
         Commands::ShowConfig {} => {
-            println!("Getting Config");
+            match render_config(config) {
+                Ok(rendered) => println!("# Loaded from {}\n{}", config_path.display(), rendered),
+                Err(e) => fatal(&e),
+            }
+        }
+        Commands::Devices => {
+            match list_devices().await {
+                Ok(lines) => lines.into_iter().for_each(|line| println!("{}", line)),
+                Err(e) => fatal(&e),
+            }
+        }
+        Commands::Token { command: TokenCommand::Create { scopes } } => {
+            match create_token(&scopes).await {
+                Ok(lines) => lines.into_iter().for_each(|line| println!("{}", line)),
+                Err(e) => fatal(&e),
+            }
         }
+        Commands::Export { output, as_of, prefix, format, device } => {
+            info!("Exporting snapshot...");
+
+            match export_snapshot(&output, as_of, prefix, &format, device).await {
+                Ok(msg) => info!("{}", msg),
+                Err(e) => fatal(&e),
+            }
+        }
+        Commands::Import { tar, as_of, root } => {
+            info!("Importing {}...", tar);
+
+            match import_archive(&tar, &as_of, &root).await {
+                Ok(msg) => info!("{}", msg),
+                Err(e) => fatal(&e),
+            }
+        }
+    }
+}
+
+/// Reports a fatal error and exits, the library itself never terminates the process.
+///
+/// Errors before logging is initialized, e.g. about the config itself, are printed to stderr.
+fn fatal(error: &CratisError) -> ! {
+    if tracing::dispatcher::has_been_set() {
+        error!("{}", error);
+    } else {
+        eprintln!("Fatal error: {}", error);
     }
-}
\ No newline at end of file
+    std::process::exit(1)
+}
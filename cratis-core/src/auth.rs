@@ -0,0 +1,292 @@
+use crate::config::{get_config_cli, get_config_path, update_config, AuthMethod};
+use crate::error::{CratisError, CratisResult, ErrorCode};
+use crate::identity::{cert_path, challenge_message, key_path, save_certificate, DeviceKey};
+use crate::tls::{certificate_needs_renewal, client};
+use crate::utils::timestamp_now;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use once_cell::sync::Lazy;
+use reqwest::{Client, RequestBuilder, Response};
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+/// Access tokens expiring within this many seconds are refreshed before they are used.
+pub const REFRESH_MARGIN_SECONDS: u64 = 60;
+
+/// The token pair of this device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+// Response Structs
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: String,
+    refresh_token: String,
+    // Only issued by servers with client certificates enabled
+    #[serde(default)]
+    client_certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChallengeResponse {
+    challenge: String,
+}
+
+#[derive(Deserialize)]
+struct UnverifiedClaims {
+    device_id: Option<String>,
+    exp: Option<u64>,
+}
+
+// Tokens currently in use, initialized from the config and replaced on every refresh
+static SESSION: Lazy<Mutex<Option<Tokens>>> = Lazy::new(|| Mutex::new(None));
+
+/// Returns a valid access token for requests to the server.
+///
+/// If the current access token expires within [`REFRESH_MARGIN_SECONDS`], a new token pair is
+/// requested from `/token/refresh` and written back to the config file, so callers never have to
+/// deal with expired tokens. Concurrent callers wait for a single refresh.
+///
+/// Tokens without an expiry, or devices without a refresh token, are returned unchanged.
+/// If the refresh token was rejected, new tokens are requested with the device key, see [`login`].
+///
+/// # Returns
+///
+/// * `Ok(String)` - The access token to send as bearer token
+/// * `Err(CratisError::AuthFailure)` - If the server rejected the refresh token and the device key, the device has to register again
+/// * `Err(CratisError)` - If the server is not reachable or the new tokens cannot be saved
+///
+/// # Examples
+///
+/// ```ignore
+/// let response = client.get(url).bearer_auth(access_token().await?).send().await?;
+/// ```
+pub async fn access_token() -> CratisResult<String> {
+    let server = &get_config_cli()?.server;
+    let mut session = SESSION.lock().await;
+    let tokens: &mut Tokens = session.get_or_insert_with(|| {
+        Tokens { access_token: server.auth_token.clone(), refresh_token: server.refresh_token.clone() }
+    });
+
+    if tokens.refresh_token.is_empty() || !expires_soon(&tokens.access_token)? {
+        return Ok(tokens.access_token.clone());
+    }
+
+    let config_path: Option<&Path> = get_config_path(false);
+
+    // Another process may have rotated the tokens already, presenting the old refresh token again
+    // would make the server revoke all tokens of this device
+    if let Some(stored) = config_path.and_then(stored_tokens).filter(|stored| stored != tokens) {
+        *tokens = stored;
+        if !expires_soon(&tokens.access_token)? {
+            return Ok(tokens.access_token.clone());
+        }
+    }
+
+    *tokens = match refresh(&tokens.refresh_token).await {
+        Err(CratisError::AuthFailure(msg)) => login_with_token(&tokens.access_token).await.map_err(|e| match e {
+            // Devices without a key, e.g. registered by an earlier version, keep the original message
+            CratisError::AuthFailure(_) => CratisError::AuthFailure(msg),
+            e => e,
+        })?,
+        result => result?,
+    };
+
+    if let Some(path) = config_path {
+        update_config("server.auth_token", path, Value::String(tokens.access_token.clone()))?;
+        update_config("server.refresh_token", path, Value::String(tokens.refresh_token.clone()))?;
+    }
+
+    Ok(tokens.access_token.clone())
+}
+
+/// Adds the credentials of this device to a request to the server.
+///
+/// With `server.auth: certificate` the device is identified by its client certificate during
+/// the TLS handshake (see [`crate::tls::client`]) and the request is returned unchanged. The
+/// certificate is renewed by logging in once half of its validity has passed, requests use the
+/// new one from the next [`crate::tls::client`] on.
+/// Otherwise the access token from [`access_token`] is attached as bearer token.
+///
+/// # Errors
+///
+/// * `CratisError::AuthFailure` - If the device has no client certificate or no valid tokens
+/// * `CratisError` - If refreshing the tokens fails
+///
+/// # Examples
+///
+/// ```ignore
+/// let response = authorize(client.get(url)).await?.send().await?;
+/// ```
+pub async fn authorize(request: RequestBuilder) -> CratisResult<RequestBuilder> {
+    if get_config_cli()?.server.auth == AuthMethod::Certificate {
+        let path: PathBuf = cert_path()?;
+        if !path.exists() {
+            return Err(CratisError::AuthFailure("This device has no client certificate, please register it again".to_string()));
+        }
+        if certificate_needs_renewal(&path)? {
+            let key: DeviceKey = DeviceKey::load(&key_path()?)?.ok_or(CratisError::AuthFailure("This device has no key, please register it again".to_string()))?;
+            login(&key.device_id(), None).await?;
+        }
+        return Ok(request);
+    }
+
+    Ok(request.bearer_auth(access_token().await?))
+}
+
+/// Exchanges a refresh token for a new token pair.
+///
+/// # Errors
+///
+/// * `CratisError::ConnectionIssue` - If the server is not reachable
+/// * `CratisError::AuthFailure` - If the refresh token is unknown, expired or was already used
+/// * `CratisError::Api` - For any other error the server reports
+pub async fn refresh(refresh_token: &str) -> CratisResult<Tokens> {
+    let body: String = serde_json::json!({ "refresh_token": refresh_token }).to_string();
+
+    let client: Client = client()?;
+    let response: Response = client
+        .post(format!("{}/token/refresh", get_config_cli()?.server.address))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    if !response.status().is_success() {
+        return Err(auth_error(response, ErrorCode::Unauthorized).await);
+    }
+
+    let text: String = response.text().await.map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    let parsed: TokenResponse = serde_json::from_str(&text).map_err(|_| CratisError::invalid_response("Invalid response: Token missing!"))?;
+
+    Ok(Tokens { access_token: parsed.token, refresh_token: parsed.refresh_token })
+}
+
+/// Obtains a new token pair by signing a challenge from the server with the device key.
+///
+/// # Arguments
+///
+/// * `device_id` - The id the server derived from the device's public key
+/// * `label` - New display name of the device, the server keeps the current one if `None`
+///
+/// # Errors
+///
+/// * `CratisError::AuthFailure` - If there is no device key or the server does not know the device
+/// * `CratisError::ConnectionIssue` - If the server is not reachable
+/// * `CratisError::Api` - For any other error the server reports
+pub async fn login(device_id: &str, label: Option<&str>) -> CratisResult<Tokens> {
+    let key: DeviceKey = DeviceKey::load(&key_path()?)?.ok_or(CratisError::AuthFailure("This device has no key, please register it again".to_string()))?;
+    let server = &get_config_cli()?.server;
+    let address: &str = &server.address;
+    let client: Client = client()?;
+
+    let response: Response = client
+        .post(format!("{}/auth/challenge", address))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::json!({ "device_id": device_id }).to_string())
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    if !response.status().is_success() {
+        return Err(auth_error(response, ErrorCode::NotFound).await);
+    }
+    let text: String = response.text().await.map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    let challenge: String = serde_json::from_str::<ChallengeResponse>(&text)
+        .map_err(|_| CratisError::invalid_response("Invalid response: Challenge missing!"))?
+        .challenge;
+
+    let body = serde_json::json!({
+        "device_id": device_id,
+        "challenge": challenge,
+        "signature": key.sign(challenge_message(device_id, &challenge).as_bytes()),
+        "label": label,
+    });
+    let response: Response = client
+        .post(format!("{}/auth/token", address))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(CratisError::connection)?;
+
+    if !response.status().is_success() {
+        return Err(auth_error(response, ErrorCode::Unauthorized).await);
+    }
+    let text: String = response.text().await.map_err(|_| CratisError::invalid_response("Invalid response"))?;
+    let parsed: TokenResponse = serde_json::from_str(&text).map_err(|_| CratisError::invalid_response("Invalid response: Token missing!"))?;
+
+    if let Some(certificate) = parsed.client_certificate.filter(|_| server.auth == AuthMethod::Certificate) {
+        save_certificate(&certificate)?;
+    }
+
+    Ok(Tokens { access_token: parsed.token, refresh_token: parsed.refresh_token })
+}
+
+/// Turns an unsuccessful response into an error.
+///
+/// An error with the `rejected` code becomes `CratisError::AuthFailure`, keeping the message of
+/// the server and telling the user to register again. [`access_token`] relies on this to try
+/// the device key once the refresh token is rejected.
+async fn auth_error(response: Response, rejected: ErrorCode) -> CratisError {
+    let error: CratisError = CratisError::from_response(response).await;
+    match error.code() {
+        code if code == rejected => CratisError::AuthFailure(format!("{}, please register this device again", error.body().message)),
+        _ => error,
+    }
+}
+
+async fn login_with_token(access_token: &str) -> CratisResult<Tokens> {
+    let device_id: String = token_device_id(access_token).ok_or(CratisError::AuthFailure("This device is not registered".to_string()))?;
+    login(&device_id, None).await
+}
+
+/// Reads the expiry of a JWT without verifying its signature.
+///
+/// # Returns
+///
+/// * `Some(u64)` - The `exp` claim as Unix timestamp
+/// * `None` - If the token is malformed or has no expiry
+pub fn token_expiry(token: &str) -> Option<u64> {
+    unverified_claims(token)?.exp
+}
+
+/// Reads the device id a JWT was issued to without verifying its signature.
+///
+/// # Returns
+///
+/// * `Some(String)` - The `device_id` claim
+/// * `None` - If the token is malformed or has no device id
+pub fn token_device_id(token: &str) -> Option<String> {
+    unverified_claims(token)?.device_id
+}
+
+fn unverified_claims(token: &str) -> Option<UnverifiedClaims> {
+    let payload: &str = token.split('.').nth(1)?;
+    let decoded: Vec<u8> = BASE64_URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    serde_json::from_slice::<UnverifiedClaims>(&decoded).ok()
+}
+
+fn expires_soon(access_token: &str) -> CratisResult<bool> {
+    match token_expiry(access_token) {
+        Some(exp) => Ok(exp <= timestamp_now()? + REFRESH_MARGIN_SECONDS),
+        None => Ok(false),
+    }
+}
+
+/// Reads the tokens currently saved in the config file.
+fn stored_tokens(path: &Path) -> Option<Tokens> {
+    let config: Value = serde_yaml::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    let server: &Value = config.get("server")?;
+
+    Some(Tokens {
+        access_token: server.get("auth_token")?.as_str()?.to_string(),
+        refresh_token: server.get("refresh_token")?.as_str()?.to_string(),
+    })
+}
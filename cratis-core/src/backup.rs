@@ -1,29 +1,48 @@
-use crate::error::{display_msg, CratisErrorLevel, CratisResult};
-use crate::utils::{is_path_file, get_files_in_directory, load_file};
-use crate::config::get_config_cli;
+use crate::error::{CratisError, CratisResult};
+use crate::utils::{compile_patterns, is_path_file, get_files_in_directory, load_file};
+use crate::auth::authorize;
+use crate::config::{get_config_cli, BackupJob, Compression};
+use crate::tls::client;
+use async_compression::tokio::bufread::ZstdEncoder;
+use glob::Pattern;
 use reqwest::{Client};
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::fs::File as TokioFile;
+use tokio::io::BufReader;
 use tokio_util::io::ReaderStream;
 
-pub async fn backup() -> reqwest::StatusCode {
-    let watch_dirs = &get_config_cli().backup.watch_directories;
+/// Uploads all files of a backup job to the server.
+///
+/// The job name, its compression and its retention policy are sent along as query parameters,
+/// so the server can record which job produced each version and prune old versions of the job.
+///
+/// # Arguments
+///
+/// * `job` - The job to run, see [`crate::config::BackupConfig::effective_jobs`]
+///
+/// # Returns
+///
+/// * `Ok(StatusCode)` - The status code of the server's response, if it accepted the upload
+/// * `Err(CratisError::Api)` - If the server rejected the upload, with the reason it gave
+/// * `Err(CratisError::InvalidPattern)` - If an exclude pattern of the job is not a valid glob
+/// * `Err(CratisError::ConnectionIssue)` - If the server is not reachable
+/// * `Err(CratisError)` - If no valid access token can be obtained
+pub async fn backup(job: &BackupJob) -> CratisResult<reqwest::StatusCode> {
+    let exclude_patterns: Vec<Pattern> = compile_patterns(&job.exclude)?;
 
     let mut files_to_load: Vec<PathBuf> = Vec::new();
 
-    for dir in watch_dirs {
+    for dir in &job.directories {
         if is_path_file(dir) {
             files_to_load.push(PathBuf::from(dir));
         } else {
-            let files: CratisResult<Vec<PathBuf>> = get_files_in_directory(dir);
+            let files: CratisResult<Vec<PathBuf>> = get_files_in_directory(Path::new(dir), &exclude_patterns);
             match files {
                 Ok(files) => {
                     files_to_load.extend(files);
                 }
-                Err(e) => {
-                    display_msg(Some(&e), CratisErrorLevel::Warning, None)
-                }
+                Err(e) => tracing::warn!(directory = %dir, error = %e, "Skipping directory"),
             }
         }
     }
@@ -31,14 +50,13 @@ pub async fn backup() -> reqwest::StatusCode {
     let mut loaded_files: Vec<(File, String, String)> = Vec::new();
 
     for file in files_to_load {
+        let path: String = file.display().to_string();
         let loaded_file = load_file(file);
         match loaded_file {
             Ok(file) => {
-                loaded_files.push((file.0, file.1, file.2.unwrap()));
-            }
-            Err(e) => {
-                display_msg(Some(&e), CratisErrorLevel::Warning, None)
+                loaded_files.push((file.0, file.1, file.2.unwrap_or(path)));
             }
+            Err(e) => tracing::warn!(file = %path, error = %e, "Skipping file"),
         }
     }
 
@@ -47,25 +65,44 @@ pub async fn backup() -> reqwest::StatusCode {
 
     for (std_file, file_name, file_path) in loaded_files {
         let tokio_file: TokioFile = TokioFile::from_std(std_file);
-        let file_body_stream = ReaderStream::new(tokio_file);
-        let body = reqwest::Body::wrap_stream(file_body_stream);
-        let file_part = reqwest::multipart::Part::stream(body).file_name(file_name).mime_str("application/octet-stream").expect("Unable to send files");
+        let body = match job.compression {
+            Compression::None => reqwest::Body::wrap_stream(ReaderStream::new(tokio_file)),
+            Compression::Zstd => reqwest::Body::wrap_stream(ReaderStream::new(ZstdEncoder::new(BufReader::new(tokio_file)))),
+        };
+        let file_part = reqwest::multipart::Part::stream(body).file_name(file_name).mime_str("application/octet-stream")
+            .map_err(|e| CratisError::Internal(e.to_string()))?;
 
         form = form.part("files", file_part);
         form = form.text("paths", file_path);
     }
 
-    let client = Client::new();
-    let config = get_config_cli();
+    let client: Client = client()?;
+    let config = get_config_cli()?;
 
     // Send request
-    let response = client.post(format!("{}/backup", config.server.address))
-        .bearer_auth(config.server.auth_token.clone())
-        .multipart(form)
+    let mut query: Vec<(&str, String)> = vec![("job", job.name.clone())];
+    if job.compression == Compression::Zstd {
+        query.push(("compression", "zstd".to_string()));
+    }
+    if let Some(keep_versions) = job.retention.keep_versions {
+        query.push(("keep_versions", keep_versions.to_string()));
+    }
+    if let Some(keep_days) = job.retention.keep_days {
+        query.push(("keep_days", keep_days.to_string()));
+    }
+
+    let request = client.post(format!("{}/backup", config.server.address))
+        .query(&query)
+        .multipart(form);
+    let response = authorize(request).await?
         .send()
         .await
-        .expect("Invalid request");
+        .map_err(CratisError::connection)?;
+
+    if !response.status().is_success() {
+        return Err(CratisError::from_response(response).await);
+    }
 
     let status: reqwest::StatusCode = response.status().into();
-    status
+    Ok(status)
 }
\ No newline at end of file
@@ -1,124 +1,1005 @@
 #![allow(dead_code)]
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use once_cell::sync::OnceCell;
-use serde_yaml::{Value};
+use serde_yaml::{Mapping, Value};
+use std::env;
 use std::fs;
-use crate::error::{display_msg, CratisError, CratisErrorLevel, CratisResult};
+use std::path::{Path, PathBuf};
+use crate::error::{CratisError, CratisResult};
+use crate::utils::{generate_random_string, timestamp_now};
+use crate::validation::find_line;
 
-// TODO: Remove this later on when a proper .yml selection is implemented
-pub static TEMP_CONFIG_PATH: &str = "/home/raphael/Development/Cratis/cratis.yml";
-pub static TEMP_API_CONFIG_PATH: &str = "/home/raphael/Development/Cratis/cratis-api/config.yml";
+/// Environment variable pointing at the configuration file, overridden by `--config`.
+pub const CONFIG_ENV_VAR: &str = "CRATIS_CONFIG";
+/// Prefix of environment variables overriding configuration values.
+pub const ENV_OVERRIDE_PREFIX: &str = "CRATIS_";
+/// Keys of the client and server configuration holding strings, overrides of them are never parsed
+/// as numbers or booleans. Keep in sync with the `String` fields of the config structs.
+const STRING_KEYS: &[&str] = &[
+    "client.id", "client.name", "client.key_file", "client.cert_file",
+    "server.address", "server.auth_token", "server.refresh_token", "server.tls_fingerprint",
+    "log.level", "log.file",
+    "settings.db", "settings.jwt", "settings.storage", "settings.admin_token",
+    "settings.tls.cert", "settings.tls.key", "settings.tls.ca_cert", "settings.tls.ca_key",
+    "settings.log.level", "settings.log.file", "settings.metrics.token",
+];
+/// Keys holding lists of strings, the items of overrides are kept as strings.
+const STRING_LIST_KEYS: &[&str] = &["backup.watch_directories", "settings.tls.names"];
+/// File name of the client configuration inside the configuration directories.
+pub const CLI_CONFIG_FILE: &str = "cratis.yml";
+/// File name of the server configuration inside the configuration directories.
+pub const API_CONFIG_FILE: &str = "cratis-api.yml";
+/// System wide configuration directory, searched after the user's configuration directory.
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/cratis";
+/// Current version of the configuration format, configs without a `version` field are version 1.
+pub const CONFIG_VERSION: u32 = 3;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct CratisConfig {
+    #[serde(default = "current_config_version")]
+    pub version: u32,
     pub client: ClientConfig,
     pub backup: BackupConfig,
     pub server: ServerConfig,
+    #[serde(default)]
+    pub log: LogSettings,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ClientConfig {
-    pub id: String,
+    // No longer used, devices are identified by their key. Kept so older configs still load
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     pub name: String,
+    // Path of the device's private key, defaults to device.key next to the config file
+    #[serde(default)]
+    pub key_file: String,
+    // Path of the client certificate issued by the server, defaults to device.crt next to the config file
+    #[serde(default)]
+    pub cert_file: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct BackupConfig {
+    #[serde(default = "default_interval_seconds")]
     pub interval_seconds: u32,
+    #[serde(default)]
     pub watch_directories: Vec<String>,
     pub exclude: Option<Vec<String>>,
+    // Named jobs, if empty the settings above form a single job called `default`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<BackupJob>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackupJob {
+    pub name: String,
+    pub directories: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    // Seconds between two runs of this job, defaults to `backup.interval_seconds`
+    pub interval_seconds: Option<u32>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RetentionConfig {
+    // Number of versions kept per file, the latest version is always kept
+    pub keep_versions: Option<u32>,
+    // Versions older than this many days are removed, the latest version is always kept
+    pub keep_days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// Name of the job formed by the top-level backup settings when no jobs are configured.
+pub const DEFAULT_JOB_NAME: &str = "default";
+
+impl BackupConfig {
+    /// Returns the configured backup jobs.
+    ///
+    /// Configs without a `jobs` section are treated as a single job named [`DEFAULT_JOB_NAME`]
+    /// built from `watch_directories`, `exclude` and `interval_seconds`. Jobs without their own
+    /// interval inherit `interval_seconds`.
+    pub fn effective_jobs(&self) -> Vec<BackupJob> {
+        if self.jobs.is_empty() {
+            return vec![BackupJob {
+                name: DEFAULT_JOB_NAME.to_string(),
+                directories: self.watch_directories.clone(),
+                exclude: self.exclude.clone().unwrap_or_default(),
+                interval_seconds: Some(self.interval_seconds),
+                retention: RetentionConfig::default(),
+                compression: Compression::None,
+            }];
+        }
+
+        self.jobs
+            .iter()
+            .cloned()
+            .map(|mut job| {
+                job.interval_seconds.get_or_insert(self.interval_seconds);
+                job
+            })
+            .collect()
+    }
+
+    /// Looks up a backup job by name, see [`BackupConfig::effective_jobs`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::ConfigError` if no job with this name exists.
+    pub fn job(&self, name: &str) -> CratisResult<BackupJob> {
+        self.effective_jobs()
+            .into_iter()
+            .find(|job| job.name == name)
+            .ok_or_else(|| CratisError::ConfigError(format!("No backup job named '{}'", name)))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
     pub address: String,
-    pub auth_token: String
+    // Empty until the device is registered
+    #[serde(default)]
+    pub auth_token: String,
+    // Used to obtain a new auth_token once it expires, rotated on every use
+    #[serde(default)]
+    pub refresh_token: String,
+    // SHA-256 fingerprint of the server certificate, pinned by `cratis register`
+    #[serde(default)]
+    pub tls_fingerprint: String,
+    // How requests are authenticated, by access token or by client certificate
+    #[serde(default)]
+    pub auth: AuthMethod,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    #[default]
+    Token,
+    // No tokens are stored, the device presents the certificate the server issued to it
+    Certificate,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct CratisServerConfig {
+    #[serde(default = "current_config_version")]
+    pub version: u32,
     pub settings: CratisServerSettings,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct CratisServerSettings {
+    #[serde(default = "default_port")]
     pub port: u16,
     pub db: String,
+    // Database engine the metadata is kept in, see `DbBackend`
+    #[serde(default)]
+    pub db_backend: DbBackend,
     pub jwt: String,
+    pub storage: String,
+    // Lifetime of access tokens
+    #[serde(default = "default_access_token_ttl")]
+    pub access_token_ttl_seconds: u64,
+    // Lifetime of refresh tokens, a device that stays offline longer has to register again
+    #[serde(default = "default_refresh_token_ttl")]
+    pub refresh_token_ttl_seconds: u64,
+    // Grace period before the data of a removed device is deleted, if its deletion was requested
+    #[serde(default = "default_delete_data_after_days")]
+    pub delete_data_after_days: u32,
+    // Secret for the admin endpoints, empty disables them
+    #[serde(default)]
+    pub admin_token: String,
+    // Require an enrollment code issued by the admin to register a device
+    #[serde(default = "default_require_enrollment_code")]
+    pub require_enrollment_code: bool,
+    // HTTPS, plain HTTP is served if the section is missing
+    #[serde(default)]
+    pub tls: TlsSettings,
+    // Request limits per client IP and device, and the lockout after failed authentication
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub log: LogSettings,
+    // Prometheus endpoint at /metrics, disabled if the section is missing
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub health: HealthSettings,
+    // Time running requests get to finish after SIGTERM or Ctrl-C before they are cut off
+    #[serde(default = "default_drain_timeout_seconds")]
+    pub drain_timeout_seconds: u64,
+}
+
+/// Database engine the server keeps its metadata in, i.e. devices, file versions and tokens.
+///
+/// Both store the same data, switching the backend starts with an empty database. polodb
+/// cannot index a device's history by time or job, so listing snapshot times, applying
+/// retention, checking device revocations and computing storage statistics read the whole
+/// history. It suits small installations only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbBackend {
+    Polodb,
+    #[default]
+    Sqlite,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TlsSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    // PEM encoded certificate chain and private key
+    #[serde(default)]
+    pub cert: String,
+    #[serde(default)]
+    pub key: String,
+    // Generate a self-signed certificate on start if cert and key do not exist yet
+    #[serde(default)]
+    pub self_signed: bool,
+    // Additional host names and IP addresses the self-signed certificate is valid for
+    #[serde(default)]
+    pub names: Vec<String>,
+    // Issue client certificates to devices and accept them instead of access tokens
+    #[serde(default)]
+    pub client_auth: bool,
+    // Certificate and key of the CA signing client certificates, generated on start if missing
+    #[serde(default)]
+    pub ca_cert: String,
+    #[serde(default)]
+    pub ca_key: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogSettings {
+    // Minimum level of messages, or a filter like "info,cratis_api=debug"
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    // File messages are appended to, empty logs to stderr
+    #[serde(default)]
+    pub file: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings { level: default_log_level(), format: LogFormat::default(), file: String::new() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MetricsSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    // Bearer token the scraper has to send, empty allows anyone who can reach the server
+    #[serde(default)]
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HealthSettings {
+    // Free space below which /health/ready reports the storage as full, 0 disables the check
+    #[serde(default = "default_min_free_disk_mb")]
+    pub min_free_disk_mb: u64,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        HealthSettings { min_free_disk_mb: default_min_free_disk_mb() }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RateLimitSettings {
+    #[serde(default = "default_rate_limit_enabled")]
+    pub enabled: bool,
+    // Requests a single IP address may send per minute, short bursts up to this number are allowed
+    #[serde(default = "default_requests_per_minute_ip")]
+    pub requests_per_minute_ip: u32,
+    // Requests a single authenticated device may send per minute
+    #[serde(default = "default_requests_per_minute_device")]
+    pub requests_per_minute_device: u32,
+    // Failed authentication attempts from one IP address before it is locked out
+    #[serde(default = "default_lockout_after_failures")]
+    pub lockout_after_failures: u32,
+    // Length of the first lockout, doubled with every further failure
+    #[serde(default = "default_lockout_base_seconds")]
+    pub lockout_base_seconds: u64,
+    #[serde(default = "default_lockout_max_seconds")]
+    pub lockout_max_seconds: u64,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            enabled: default_rate_limit_enabled(),
+            requests_per_minute_ip: default_requests_per_minute_ip(),
+            requests_per_minute_device: default_requests_per_minute_device(),
+            lockout_after_failures: default_lockout_after_failures(),
+            lockout_base_seconds: default_lockout_base_seconds(),
+            lockout_max_seconds: default_lockout_max_seconds(),
+        }
+    }
+}
+
+fn current_config_version() -> u32 {
+    CONFIG_VERSION
+}
+
+fn default_interval_seconds() -> u32 {
+    3600
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+fn default_access_token_ttl() -> u64 {
+    15 * 60
+}
+
+fn default_refresh_token_ttl() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_delete_data_after_days() -> u32 {
+    7
+}
+
+fn default_require_enrollment_code() -> bool {
+    true
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_rate_limit_enabled() -> bool {
+    true
+}
+
+fn default_requests_per_minute_ip() -> u32 {
+    120
+}
+
+fn default_requests_per_minute_device() -> u32 {
+    600
+}
+
+fn default_lockout_after_failures() -> u32 {
+    5
+}
+
+fn default_lockout_base_seconds() -> u64 {
+    30
+}
+
+fn default_lockout_max_seconds() -> u64 {
+    60 * 60
+}
+
+fn default_min_free_disk_mb() -> u64 {
+    1024
+}
+
+fn default_drain_timeout_seconds() -> u64 {
+    30
 }
 
 static CONFIG_CLI: OnceCell<CratisConfig> = OnceCell::new();
 static CONFIG_API: OnceCell<CratisServerConfig> = OnceCell::new();
+static CONFIG_CLI_PATH: OnceCell<PathBuf> = OnceCell::new();
+static CONFIG_API_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// Returns the per-user configuration directory of Cratis.
+///
+/// This is `$XDG_CONFIG_HOME/cratis`, falling back to `$HOME/.config/cratis` and
+/// `%APPDATA%\cratis` on Windows.
+///
+/// # Returns
+///
+/// * `Some(PathBuf)` - The directory, which does not need to exist
+/// * `None` - If none of the environment variables above are set
+pub fn user_config_dir() -> Option<PathBuf> {
+    let base: PathBuf = env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").filter(|v| !v.is_empty()).map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").filter(|v| !v.is_empty()).map(PathBuf::from))?;
+
+    Some(base.join("cratis"))
+}
+
+/// Returns the locations searched for a configuration file, in order of precedence.
+///
+/// These are the user configuration directory (see [`user_config_dir`]) followed by
+/// [`SYSTEM_CONFIG_DIR`], each joined with the file name for the client or the server.
+///
+/// # Arguments
+///
+/// * `api` - If true, returns the locations of the server config; if false, of the client config
+pub fn config_search_paths(api: bool) -> Vec<PathBuf> {
+    let file_name: &str = if api { API_CONFIG_FILE } else { CLI_CONFIG_FILE };
+    let mut paths: Vec<PathBuf> = Vec::new();
+
+    if let Some(dir) = user_config_dir() {
+        paths.push(dir.join(file_name));
+    }
+    paths.push(Path::new(SYSTEM_CONFIG_DIR).join(file_name));
+
+    paths
+}
+
+/// Determines which configuration file to use.
+///
+/// The file is chosen in the following order:
+/// 1. The explicitly given path (the `--config` flag)
+/// 2. The path in the `CRATIS_CONFIG` environment variable
+/// 3. The first existing file of [`config_search_paths`]
+///
+/// An explicitly given path or `CRATIS_CONFIG` has to exist, there is no fallback to the
+/// search paths in that case.
+///
+/// # Arguments
+///
+/// * `explicit` - Path passed on the command line, if any
+/// * `api` - If true, searches for the server config; if false, for the client config
+///
+/// # Returns
+///
+/// * `Ok(PathBuf)` - The configuration file to load
+/// * `Err(CratisError::ConfigError)` - If no configuration file was found
+pub fn find_config(explicit: Option<&str>, api: bool) -> CratisResult<PathBuf> {
+    let requested: Option<PathBuf> = explicit
+        .map(PathBuf::from)
+        .or_else(|| env::var_os(CONFIG_ENV_VAR).filter(|v| !v.is_empty()).map(PathBuf::from));
+
+    if let Some(path) = requested {
+        return if path.is_file() {
+            Ok(path)
+        } else {
+            Err(CratisError::ConfigError(format!("Config file not found: {}", path.display())))
+        };
+    }
+
+    let candidates: Vec<PathBuf> = config_search_paths(api);
+    candidates.iter().find(|p| p.is_file()).cloned().ok_or_else(|| {
+        let searched: Vec<String> = candidates.iter().map(|p| p.display().to_string()).collect();
+        CratisError::ConfigError(format!(
+            "No config file found, pass --config, set {} or create one of: {} (see `init`)",
+            CONFIG_ENV_VAR,
+            searched.join(", ")
+        ))
+    })
+}
+
+/// Sets the value at the given key path, creating missing mappings along the way.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if the path runs through a value that is not a mapping.
+fn set_value(value: &mut Value, keys: &[&str], new_value: Value) -> CratisResult<()> {
+    if keys.is_empty() {
+        *value = new_value;
+        return Ok(());
+    }
+
+    if value.is_null() {
+        *value = Value::Mapping(Mapping::new());
+    }
+
+    match value {
+        Value::Mapping(map) => {
+            let key = Value::String(keys[0].to_string());
+            set_value(map.entry(key).or_insert(Value::Null), &keys[1..], new_value)
+        }
+        _ => Err(CratisError::ConfigError(format!("Error while updating config, '{}' is not a section", keys[0]))),
+    }
+}
+
+/// Applies `CRATIS_*` environment variables on top of a parsed configuration.
+///
+/// The variable name maps to a key path by stripping the `CRATIS_` prefix, lowercasing and
+/// splitting at double underscores, e.g. `CRATIS_SETTINGS__JWT` sets `settings.jwt` and
+/// `CRATIS_BACKUP__INTERVAL_SECONDS` sets `backup.interval_seconds`. See [`override_value`] for
+/// how the type of a value is chosen. `CRATIS_CONFIG` is not an override. Values are taken
+/// verbatim, `${VAR}` references in them are not expanded.
+///
+/// Variables whose name is not valid UTF-8 cannot be overrides and are skipped.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if a variable targets a key below a non-mapping value,
+/// or the value of an override is not valid UTF-8.
+pub fn apply_env_overrides(config: &mut Value) -> CratisResult<()> {
+    let mut overrides: Vec<(String, String)> = Vec::new();
+    for (name, raw) in env::vars_os() {
+        let name: String = match name.into_string() {
+            Ok(name) if name.starts_with(ENV_OVERRIDE_PREFIX) && name != CONFIG_ENV_VAR => name,
+            _ => continue,
+        };
+        let raw: String = raw
+            .into_string()
+            .map_err(|_| CratisError::ConfigError(format!("The value of {} is not valid UTF-8", name)))?;
+        overrides.push((name, raw));
+    }
+    // Apply parents before their children for a deterministic result
+    overrides.sort();
+
+    for (name, raw) in overrides {
+        let key_path: String = name[ENV_OVERRIDE_PREFIX.len()..].to_lowercase();
+        let keys: Vec<&str> = key_path.split("__").collect();
+        if keys.iter().any(|k| k.is_empty()) {
+            continue;
+        }
+
+        let value: Value = override_value(config, &keys, raw);
+        set_value(config, &keys, value)?;
+    }
+
+    Ok(())
+}
 
+/// Turns the raw value of an environment override into the value stored at `keys`.
+///
+/// Keys the config structs declare as strings stay strings, whether or not they are in the file,
+/// so secrets like `123456` are not turned into numbers. The same holds for keys that are strings
+/// in the file. Other values are parsed as YAML, so numbers, booleans and lists (`["/a", "/b"]`)
+/// keep their type.
+fn override_value(config: &Value, keys: &[&str], raw: String) -> Value {
+    let key_path: String = keys.join(".");
+    let existing: Option<&Value> = keys.iter().try_fold(config, |v, k| v.get(*k));
+    if STRING_KEYS.contains(&key_path.as_str()) || matches!(existing, Some(Value::String(_))) {
+        return Value::String(raw);
+    }
+
+    match serde_yaml::from_str::<Value>(&raw) {
+        Ok(Value::Sequence(items)) if STRING_LIST_KEYS.contains(&key_path.as_str()) => {
+            Value::Sequence(items.into_iter().map(|item| match item {
+                Value::Number(n) => Value::String(n.to_string()),
+                Value::Bool(b) => Value::String(b.to_string()),
+                other => other,
+            }).collect())
+        }
+        Ok(parsed @ (Value::Number(_) | Value::Bool(_) | Value::Sequence(_))) => parsed,
+        _ => Value::String(raw),
+    }
+}
+
+/// Expands `${VAR}` references and a leading `~` in every string of a configuration.
+///
+/// See [`expand_string`] for the supported syntax.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if a referenced variable is not set.
+pub fn expand_values(config: &mut Value) -> CratisResult<()> {
+    match config {
+        Value::String(s) => *s = expand_string(s)?,
+        Value::Sequence(items) => {
+            for item in items {
+                expand_values(item)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (_, item) in map.iter_mut() {
+                expand_values(item)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Expands environment variable references and the home directory in a string.
+///
+/// * `${VAR}` is replaced by the value of `VAR`
+/// * `${VAR:-default}` falls back to `default` if `VAR` is unset or empty
+/// * `$$` produces a literal `$`
+/// * `~` at the start of the string, alone or followed by `/`, is replaced by the home directory
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if a referenced variable is not set and has no default,
+/// a reference is not terminated, or `~` is used without a known home directory.
+///
+/// # Examples
+///
+/// ```ignore
+/// // HOME=/home/me, BACKUP_ROOT unset
+/// assert_eq!(expand_string("~/docs")?, "/home/me/docs");
+/// assert_eq!(expand_string("${BACKUP_ROOT:-/srv}/data")?, "/srv/data");
+/// ```
+pub fn expand_string(value: &str) -> CratisResult<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest: &str = value;
+
+    if rest == "~" || rest.starts_with("~/") {
+        let home = env::var("HOME")
+            .or_else(|_| env::var("USERPROFILE"))
+            .map_err(|_| CratisError::ConfigError("Unable to expand '~', HOME is not set".to_string()))?;
+        result.push_str(&home);
+        rest = &rest[1..];
+    }
+
+    while let Some(index) = rest.find('$') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end: usize = after
+                .find('}')
+                .ok_or_else(|| CratisError::ConfigError(format!("Unterminated variable reference in '{}'", value)))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+
+            match (env::var(name).ok().filter(|v| !v.is_empty()), default) {
+                (Some(v), _) => result.push_str(&v),
+                (None, Some(default)) => result.push_str(default),
+                (None, None) => {
+                    return Err(CratisError::ConfigError(format!("Environment variable '{}' referenced in config is not set", name)))
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
 
 /// Loads configuration from a YAML file into global static storage.
 ///
+/// Values are resolved with the following precedence, highest first:
+/// 1. `CRATIS_*` environment variables (see [`apply_env_overrides`]), taken verbatim
+/// 2. Values in the configuration file, with `${VAR}` references and `~` expanded (see [`expand_string`])
+///
 /// # Arguments
 ///
 /// * `path` - Path to the configuration file
 /// * `api` - If true, loads server config; if false, loads client config
 ///
-/// # Panics
+/// # Errors
 ///
-/// Panics if the file cannot be read or parsed.
-pub fn load_config(path: &str, api: bool) {
-    let contents = fs::read_to_string(path).expect("Failed to read config file");
-
+/// Returns `CratisError` if:
+/// * The file cannot be read
+/// * The file is not a valid configuration
+/// * A referenced environment variable is not set
+/// * A configuration has already been loaded
+pub fn load_config(path: &Path, api: bool) -> CratisResult<()> {
     if api {
-        let parsed: CratisServerConfig = serde_yaml::from_str(&contents).expect("Invalid config format");
-        CONFIG_API.set(parsed).expect("Config initialized");
-        return;
+        let parsed: CratisServerConfig = read_config(path, true)?;
+        CONFIG_API.set(parsed).map_err(|_| CratisError::ConfigError("Config already loaded".to_string()))?;
+        let _ = CONFIG_API_PATH.set(path.to_path_buf());
+        return Ok(());
     }
 
-    let parsed: CratisConfig = serde_yaml::from_str(&contents).expect("Invalid config format");
-    CONFIG_CLI.set(parsed).expect("Config initialized");
+    let parsed: CratisConfig = read_config(path, false)?;
+    CONFIG_CLI.set(parsed).map_err(|_| CratisError::ConfigError("Config already loaded".to_string()))?;
+    let _ = CONFIG_CLI_PATH.set(path.to_path_buf());
+    Ok(())
 }
 
-
-/// Returns a reference to the global CLI configuration.
+/// Reads a configuration file and resolves it to its effective value tree.
 ///
-/// Lazily loads the configuration from the default path if not already loaded.
-/// Terminates the program if configuration loading fails.
+/// Upgrades old config layouts (see [`migrate_value`]) and applies environment overrides and
+/// expansions like [`load_config`], without deserializing or storing the result.
+///
+/// # Arguments
+///
+/// * `path` - Path to the configuration file
+/// * `api` - If true, reads a server config; if false, a client config
 ///
 /// # Returns
 ///
-/// A static reference to the loaded `CratisConfig`
-pub fn get_config_cli() -> &'static CratisConfig {
-    if CONFIG_CLI.get().is_none() {
-        load_config(TEMP_CONFIG_PATH, false);
+/// * `Ok((String, Value))` - The raw file content and the effective configuration
+/// * `Err(CratisError)` - If the file cannot be read or parsed, or an expansion fails
+pub fn read_config_value(path: &Path, api: bool) -> CratisResult<(String, Value)> {
+    let contents: String = fs::read_to_string(path)
+        .map_err(|e| CratisError::ConfigError(format!("Unable to read {}: {}", path.display(), e)))?;
 
-        if CONFIG_CLI.get().is_none() {
-            display_msg(Some(&CratisError::ConfigError("Unable to load config".to_string())), CratisErrorLevel::Fatal, None);
-            unreachable!()
-        } else {
-            CONFIG_CLI.get().unwrap()
-        }
+    let mut value: Value = serde_yaml::from_str(&contents)?;
+    migrate_value(&mut value, api)?;
+    // Overrides are expanded by the shell already, a `$` in them is meant literally
+    expand_values(&mut value)?;
+    apply_env_overrides(&mut value)?;
+
+    Ok((contents, value))
+}
+
+/// Reads a configuration file into a configuration struct without storing it globally.
+///
+/// # Errors
+///
+/// Returns `CratisError` if the file cannot be read, is not a valid configuration,
+/// or a referenced environment variable is not set.
+pub fn read_config<T: DeserializeOwned>(path: &Path, api: bool) -> CratisResult<T> {
+    let (_, value) = read_config_value(path, api)?;
+    Ok(serde_yaml::from_value(value)?)
+}
+
+/// Returns the path of the configuration file that was loaded, if any.
+///
+/// # Arguments
+///
+/// * `api` - If true, returns the path of the server config; if false, of the client config
+pub fn get_config_path(api: bool) -> Option<&'static Path> {
+    if api {
+        CONFIG_API_PATH.get().map(PathBuf::as_path)
     } else {
-        CONFIG_CLI.get().unwrap()
+        CONFIG_CLI_PATH.get().map(PathBuf::as_path)
     }
 }
 
+/// Returns a reference to the global CLI configuration.
+///
+/// Lazily discovers and loads the configuration (see [`find_config`]) if not already loaded.
+///
+/// # Returns
+///
+/// * `Ok(&CratisConfig)` - A static reference to the loaded configuration
+/// * `Err(CratisError)` - If no configuration file is found or it cannot be loaded
+pub fn get_config_cli() -> CratisResult<&'static CratisConfig> {
+    if let Some(config) = CONFIG_CLI.get() {
+        return Ok(config);
+    }
+
+    load_config(&find_config(None, false)?, false)?;
+    CONFIG_CLI.get().ok_or_else(|| CratisError::ConfigError("The client configuration was not loaded".to_string()))
+}
+
 /// Returns a reference to the global API server configuration.
 ///
-/// Lazily loads the configuration from the default path if not already loaded.
-/// Terminates the program if configuration loading fails.
+/// Lazily discovers and loads the configuration (see [`find_config`]) if not already loaded.
 ///
 /// # Returns
 ///
-/// A static reference to the loaded `CratisServerConfig`
-pub fn get_config_api() -> &'static CratisServerConfig {
-    if CONFIG_API.get().is_none() {
-        load_config(TEMP_API_CONFIG_PATH, true);
+/// * `Ok(&CratisServerConfig)` - A static reference to the loaded configuration
+/// * `Err(CratisError)` - If no configuration file is found or it cannot be loaded
+pub fn get_config_api() -> CratisResult<&'static CratisServerConfig> {
+    if let Some(config) = CONFIG_API.get() {
+        return Ok(config);
+    }
 
-        if CONFIG_API.get().is_none() {
-            display_msg(Some(&CratisError::ConfigError("Unable to load config".to_string())), CratisErrorLevel::Fatal, None);
-            unreachable!()
-        } else {
-            CONFIG_API.get().unwrap()
+    load_config(&find_config(None, true)?, true)?;
+    CONFIG_API.get().ok_or_else(|| CratisError::ConfigError("The server configuration was not loaded".to_string()))
+}
+
+/// Keys whose values are replaced by [`REDACTED`] when a configuration is displayed.
+pub const SECRET_KEYS: [&str; 5] = ["auth_token", "refresh_token", "jwt", "admin_token", "token"];
+/// Placeholder for redacted secrets.
+pub const REDACTED: &str = "<redacted>";
+
+/// Renders a configuration as YAML with all secrets redacted.
+///
+/// Every non-empty value of a key in [`SECRET_KEYS`] is replaced by [`REDACTED`], empty values
+/// are kept to show that the secret is missing.
+///
+/// # Arguments
+///
+/// * `config` - The configuration to render, usually the effective configuration after overrides
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigParseError` if the configuration cannot be serialized.
+pub fn render_config<T: Serialize>(config: &T) -> CratisResult<String> {
+    fn redact(value: &mut Value) {
+        match value {
+            Value::Mapping(map) => {
+                for (key, item) in map.iter_mut() {
+                    let is_secret: bool = key.as_str().is_some_and(|k| SECRET_KEYS.contains(&k));
+                    if is_secret && item.as_str().is_some_and(|v| !v.is_empty()) {
+                        *item = Value::String(REDACTED.to_string());
+                    } else {
+                        redact(item);
+                    }
+                }
+            }
+            Value::Sequence(items) => items.iter_mut().for_each(redact),
+            _ => {}
         }
+    }
+
+    let mut value: Value = serde_yaml::to_value(config)?;
+    redact(&mut value);
+    Ok(serde_yaml::to_string(&value)?)
+}
+
+/// Returns a starter configuration with sensible defaults.
+///
+/// The server configuration contains a freshly generated JWT secret.
+///
+/// # Arguments
+///
+/// * `api` - If true, returns a server config; if false, a client config
+pub fn starter_config(api: bool) -> String {
+    if api {
+        format!(
+            r#"version: {}
+
+settings:
+  # Port the API listens on
+  port: 8080
+  # Path of the metadata database
+  db: "/var/lib/cratis/cratis.db"
+  # Engine of the metadata database, sqlite or polodb. Switching it starts with an empty database
+  db_backend: "sqlite"
+  # Secret used to sign access tokens, keep it private
+  jwt: "{}"
+  # Directory the backed up file contents are stored in
+  storage: "/var/lib/cratis/storage"
+  # Lifetime of access tokens and of refresh tokens in seconds
+  access_token_ttl_seconds: 900
+  refresh_token_ttl_seconds: 2592000
+  # Days the data of a device removed with `cratis unregister --delete-data` is kept
+  delete_data_after_days: 7
+  # Secret for admin commands like `cratis-api enroll create`, keep it private
+  admin_token: "{}"
+  # Devices need a code from `cratis-api enroll create` to register
+  require_enrollment_code: true
+  tls:
+    enabled: true
+    cert: "/var/lib/cratis/tls/cert.pem"
+    key: "/var/lib/cratis/tls/key.pem"
+    # Creates the certificate on first start, clients pin its fingerprint on `cratis register`
+    self_signed: true
+    # Host names and IP addresses clients use to reach the server, localhost is always included
+    names: []
+    # Issue client certificates on registration, devices with `server.auth: certificate` use them instead of tokens
+    client_auth: false
+    ca_cert: "/var/lib/cratis/tls/ca.pem"
+    ca_key: "/var/lib/cratis/tls/ca-key.pem"
+  rate_limit:
+    enabled: true
+    requests_per_minute_ip: 120
+    requests_per_minute_device: 600
+    # Locks out an IP address after this many failed authentications, doubling the lockout every time
+    lockout_after_failures: 5
+    lockout_base_seconds: 30
+    lockout_max_seconds: 3600
+  log:
+    # error, warn, info, debug or trace
+    level: "info"
+    # text or json
+    format: "text"
+    # Appended to instead of logging to stderr if set
+    file: ""
+  metrics:
+    # Serves Prometheus metrics at /metrics
+    enabled: false
+    # Bearer token Prometheus has to send, empty allows anyone who can reach the server
+    token: ""
+  health:
+    # /health/ready reports the server as not ready below this much free space in the storage directory
+    min_free_disk_mb: 1024
+  # Seconds running uploads get to finish on shutdown, new uploads are refused meanwhile
+  drain_timeout_seconds: 30
+"#,
+            CONFIG_VERSION,
+            generate_random_string(64),
+            generate_random_string(32)
+        )
     } else {
-        CONFIG_API.get().unwrap()
+        format!(
+            r#"version: {}
+
+client:
+  name: "my-device"
+  # Private key identifying this device, created by `cratis register`
+  # key_file: "device.key"
+  # Client certificate issued by the server, used with `server.auth: certificate`
+  # cert_file: "device.crt"
+
+backup:
+  interval_seconds: 3600
+  # Files and directories to back up
+  watch_directories: []
+  # Glob patterns of paths to skip
+  exclude: []
+  # Named jobs replace the settings above, run one with `cratis backup-now --job <name>`
+  # jobs:
+  #   - name: photos
+  #     directories: ["~/Pictures"]
+  #     exclude: ["*.tmp"]
+  #     interval_seconds: 86400
+  #     retention:
+  #       keep_versions: 10
+  #       keep_days: 365
+  #     compression: zstd
+
+server:
+  address: "https://localhost:8080"
+  # Filled in by `cratis register` and renewed automatically
+  auth_token: ""
+  refresh_token: ""
+  # Fingerprint of the server certificate, confirmed and stored by `cratis register`
+  tls_fingerprint: ""
+  # token, or certificate to authenticate with a client certificate instead of storing tokens
+  auth: token
+
+log:
+  # error, warn, info, debug or trace
+  level: "info"
+  # text or json
+  format: "text"
+  # Appended to instead of logging to stderr if set
+  file: ""
+"#,
+            CONFIG_VERSION
+        )
+    }
+}
+
+/// Writes a starter configuration (see [`starter_config`]) to the given path.
+///
+/// Missing parent directories are created.
+///
+/// # Arguments
+///
+/// * `path` - Where to write the configuration
+/// * `api` - If true, writes a server config; if false, a client config
+/// * `force` - Overwrite an existing file
+///
+/// # Errors
+///
+/// Returns `CratisError` if the file already exists and `force` is not set, or if it cannot be written.
+pub fn write_starter_config(path: &Path, api: bool, force: bool) -> CratisResult<()> {
+    if path.exists() && !force {
+        return Err(CratisError::ConfigError(format!("{} already exists, use --force to overwrite it", path.display())));
+    }
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
     }
+    fs::write(path, starter_config(api))?;
+
+    Ok(())
+}
+
+/// Returns the path `init` writes to when no path is given.
+///
+/// This is the explicitly given path, then `CRATIS_CONFIG`, then the file in [`user_config_dir`].
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if no user configuration directory can be determined.
+pub fn default_init_path(explicit: Option<&str>, api: bool) -> CratisResult<PathBuf> {
+    let file_name: &str = if api { API_CONFIG_FILE } else { CLI_CONFIG_FILE };
+
+    explicit
+        .map(PathBuf::from)
+        .or_else(|| env::var_os(CONFIG_ENV_VAR).filter(|v| !v.is_empty()).map(PathBuf::from))
+        .or_else(|| user_config_dir().map(|dir| dir.join(file_name)))
+        .ok_or_else(|| CratisError::ConfigError("Unable to determine a config directory, pass --config".to_string()))
 }
 
 /// Updates a configuration value in the YAML file using a dot-separated key path.
@@ -130,6 +1011,7 @@ pub fn get_config_api() -> &'static CratisServerConfig {
 /// # Arguments
 ///
 /// * `key_path` - Dot-separated path to the configuration key (e.g., "server.address")
+/// * `config_path` - Path of the configuration file to update
 /// * `new_value` - The new value to set, as a serde_yaml::Value
 ///
 /// # Returns
@@ -157,7 +1039,7 @@ pub fn get_config_api() -> &'static CratisServerConfig {
 /// * The configuration file cannot be read or written
 /// * The YAML parsing fails
 /// * The key path points to an invalid location in the configuration structure
-pub fn update_config(key_path: &str, config_path: &str, new_value: Value) -> CratisResult<()> {
+pub fn update_config(key_path: &str, config_path: &Path, new_value: Value) -> CratisResult<()> {
     // Read existing YAML file
     let file_content: String = fs::read_to_string(config_path)?;
     let mut yaml_value: Value = serde_yaml::from_str(&file_content)?;
@@ -166,31 +1048,541 @@ pub fn update_config(key_path: &str, config_path: &str, new_value: Value) -> Cra
     let keys: Vec<&str> = key_path.split('.').collect();
 
     // Traverse the Value tree and update the field
-    fn update_recursive(value: &mut Value, keys: &[&str], new_value: Value) -> CratisResult<()> {
-        if keys.is_empty() {
-            *value = new_value;
-            return Ok(());
-        }
-        match value {
-            Value::Mapping(map) => {
-                let key = Value::String(keys[0].to_string());
-                if let Some(v) = map.get_mut(&key) {
-                    update_recursive(v, &keys[1..], new_value)
-                } else {
-                    // If key doesn't exist, create it
-                    map.insert(key.clone(), Value::Null);
-                    update_recursive(map.get_mut(&key).unwrap(), &keys[1..], new_value)
-                }
-            },
-            _ => Err(CratisError::ConfigError("Error while updating config!".to_string())),
-        }
-    }
-
-    update_recursive(&mut yaml_value, &keys, new_value)?;
+    set_value(&mut yaml_value, &keys, new_value)?;
 
     // Write back to file
     let new_yaml_str: String = serde_yaml::to_string(&yaml_value)?;
     fs::write(config_path, new_yaml_str)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+/// A single upgrade step of the configuration format, from version `from` to `from + 1`.
+struct Migration {
+    from: u32,
+    // Shown by `config migrate`
+    description: &'static str,
+    // Upgrades the value tree, the second argument tells whether it is a server config
+    apply: fn(&mut Value, bool),
+    // Applies the same change to the file content, keeping comments, None if that is not possible
+    rewrite: fn(&str, bool) -> Option<String>,
+}
+
+/// All upgrade steps in order. Every format change bumps [`CONFIG_VERSION`] and adds a step here.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "Add the version field, and settings.storage next to settings.db for servers",
+        apply: migrate_v1_storage,
+        rewrite: rewrite_v1_storage,
+    },
+    Migration {
+        from: 2,
+        description: "Keep polodb as settings.db_backend for servers, the default is now sqlite",
+        apply: migrate_v2_db_backend,
+        rewrite: rewrite_v2_db_backend,
+    },
+];
+
+/// Summary of a config file migration, see [`migrate_config_file`].
+#[derive(Debug)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    // Descriptions of the applied steps
+    pub steps: Vec<&'static str>,
+    // Copy of the original file, None if the file was already up to date
+    pub backup: Option<PathBuf>,
+    // false if the file had to be re-serialized, dropping its comments
+    pub comments_preserved: bool,
+}
+
+impl MigrationReport {
+    /// Returns human readable lines describing the migration of the file at `path`.
+    pub fn messages(&self, path: &Path) -> Vec<String> {
+        let backup: &PathBuf = match &self.backup {
+            Some(backup) => backup,
+            None => return vec![format!("{} is already at version {}", path.display(), self.to)],
+        };
+
+        let mut lines: Vec<String> = vec![format!("Migrated {} from version {} to {}", path.display(), self.from, self.to)];
+        lines.extend(self.steps.iter().map(|step| format!("  - {}", step)));
+        lines.push(format!("Original saved as {}", backup.display()));
+        if !self.comments_preserved {
+            lines.push("Comments could not be preserved, see the backup for the original layout".to_string());
+        }
+        lines
+    }
+}
+
+/// Returns the format version of a configuration value tree.
+///
+/// # Returns
+///
+/// * `Ok(u32)` - The value of the top-level `version` field, 1 if it is missing
+/// * `Err(CratisError::ConfigError)` - If `version` is not a positive integer
+pub fn config_version(config: &Value) -> CratisResult<u32> {
+    match config.get("version") {
+        None | Some(Value::Null) => Ok(1),
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .filter(|v| *v > 0)
+            .ok_or_else(|| CratisError::ConfigError(format!("Invalid config version: {:?}", version))),
+    }
+}
+
+/// Upgrades a configuration value tree to [`CONFIG_VERSION`] in memory.
+///
+/// Applies every migration step between the version of the config and the current version
+/// and sets `version` accordingly. Values that are not a mapping are left untouched, so they
+/// fail deserialization with a meaningful error instead.
+///
+/// # Arguments
+///
+/// * `config` - The configuration value tree, as read from the file
+/// * `api` - If true, the value is a server config; if false, a client config
+///
+/// # Returns
+///
+/// * `Ok(Vec<&str>)` - Descriptions of the applied steps, empty if the config was up to date
+/// * `Err(CratisError::ConfigError)` - If the config is newer than this version of Cratis supports
+pub fn migrate_value(config: &mut Value, api: bool) -> CratisResult<Vec<&'static str>> {
+    if !config.is_mapping() {
+        return Ok(Vec::new());
+    }
+
+    let version: u32 = config_version(config)?;
+    if version > CONFIG_VERSION {
+        return Err(CratisError::ConfigError(format!(
+            "Config version {} is newer than the supported version {}, please update Cratis",
+            version, CONFIG_VERSION
+        )));
+    }
+
+    let mut steps: Vec<&'static str> = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= version) {
+        (migration.apply)(config, api);
+        set_version(config, migration.from + 1);
+        steps.push(migration.description);
+    }
+
+    Ok(steps)
+}
+
+/// Rewrites a configuration file in the current format.
+///
+/// The original file is copied to `<file>.v<version>-<timestamp>.bak` first. Migration steps
+/// are applied to the file content directly to keep comments and formatting; if that is not
+/// possible, the migrated configuration is written in plain YAML instead.
+///
+/// # Arguments
+///
+/// * `path` - Path of the configuration file
+/// * `api` - If true, migrates a server config; if false, a client config
+///
+/// # Returns
+///
+/// * `Ok(MigrationReport)` - What was done, nothing is written if the file is already up to date
+/// * `Err(CratisError)` - If the file cannot be read, parsed, backed up or written, or is too new
+pub fn migrate_config_file(path: &Path, api: bool) -> CratisResult<MigrationReport> {
+    let contents: String = fs::read_to_string(path)
+        .map_err(|e| CratisError::ConfigError(format!("Unable to read {}: {}", path.display(), e)))?;
+    let mut value: Value = serde_yaml::from_str(&contents)?;
+
+    let from: u32 = config_version(&value)?;
+    let steps: Vec<&'static str> = migrate_value(&mut value, api)?;
+
+    if steps.is_empty() {
+        return Ok(MigrationReport { from, to: from, steps, backup: None, comments_preserved: true });
+    }
+
+    // Apply the steps to the text and only keep the result if it means the same as the migrated value
+    let rewritten: Option<String> = MIGRATIONS
+        .iter()
+        .filter(|m| m.from >= from)
+        .try_fold(contents.clone(), |text, m| (m.rewrite)(&text, api).map(|text| set_version_line(&text, m.from + 1)))
+        .filter(|text| serde_yaml::from_str::<Value>(text).is_ok_and(|parsed| parsed == value));
+
+    let comments_preserved: bool = rewritten.is_some();
+    let new_contents: String = match rewritten {
+        Some(text) => text,
+        None => serde_yaml::to_string(&value)?,
+    };
+
+    let file_name: String = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let backup: PathBuf = path.with_file_name(format!("{}.v{}-{}.bak", file_name, from, timestamp_now()?));
+    fs::copy(path, &backup)?;
+    fs::write(path, new_contents)?;
+
+    Ok(MigrationReport { from, to: CONFIG_VERSION, steps, backup: Some(backup), comments_preserved })
+}
+
+/// Sets the top-level `version` field, keeping it as the first key of the mapping.
+fn set_version(config: &mut Value, version: u32) {
+    if let Value::Mapping(mapping) = config {
+        let mut upgraded: Mapping = Mapping::new();
+        upgraded.insert(Value::from("version"), Value::from(version));
+
+        for (key, value) in std::mem::take(mapping) {
+            if key.as_str() != Some("version") {
+                upgraded.insert(key, value);
+            }
+        }
+        *mapping = upgraded;
+    }
+}
+
+/// Sets the top-level `version` line of a YAML document.
+///
+/// Replaces an existing `version:` line, or inserts one before the first key, after any
+/// leading comments.
+fn set_version_line(source: &str, version: u32) -> String {
+    let line: String = format!("version: {}", version);
+    let mut lines: Vec<String> = source.lines().map(str::to_string).collect();
+
+    if let Some(existing) = lines.iter_mut().find(|l| l.starts_with("version:")) {
+        *existing = line;
+    } else {
+        let first_key: usize = lines
+            .iter()
+            .position(|l| !l.trim().is_empty() && !l.starts_with('#') && l.as_str() != "---")
+            .unwrap_or(lines.len());
+        lines.insert(first_key, line);
+    }
+
+    let mut result: String = lines.join("\n");
+    if source.ends_with('\n') || source.is_empty() {
+        result.push('\n');
+    }
+    result
+}
+
+/// Storage directory used for version 1 server configs, which had no `settings.storage`.
+///
+/// This is a `storage` directory next to the database.
+fn v1_storage_path(db: &str) -> String {
+    match Path::new(db).parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(dir) => dir.join("storage").to_string_lossy().to_string(),
+        None => String::from("storage"),
+    }
+}
+
+fn migrate_v1_storage(config: &mut Value, api: bool) {
+    let settings = match config.get_mut("settings").filter(|_| api) {
+        Some(Value::Mapping(settings)) => settings,
+        _ => return,
+    };
+
+    if settings.contains_key("storage") {
+        return;
+    }
+    if let Some(db) = settings.get("db").and_then(Value::as_str) {
+        let storage: String = v1_storage_path(db);
+        settings.insert(Value::from("storage"), Value::from(storage));
+    }
+}
+
+fn rewrite_v1_storage(source: &str, api: bool) -> Option<String> {
+    let config: Value = serde_yaml::from_str(source).ok()?;
+    let settings = match config.get("settings") {
+        Some(settings) if api => settings,
+        _ => return Some(source.to_string()),
+    };
+    if settings.get("storage").is_some() {
+        return Some(source.to_string());
+    }
+    let db: &str = match settings.get("db").and_then(Value::as_str) {
+        Some(db) => db,
+        None => return Some(source.to_string()),
+    };
+
+    insert_after_db(source, "storage", &Value::from(v1_storage_path(db)))
+}
+
+fn migrate_v2_db_backend(config: &mut Value, api: bool) {
+    let settings = match config.get_mut("settings").filter(|_| api) {
+        Some(Value::Mapping(settings)) => settings,
+        _ => return,
+    };
+
+    if settings.contains_key("db") && !settings.contains_key("db_backend") {
+        settings.insert(Value::from("db_backend"), Value::from("polodb"));
+    }
+}
+
+fn rewrite_v2_db_backend(source: &str, api: bool) -> Option<String> {
+    let config: Value = serde_yaml::from_str(source).ok()?;
+    match config.get("settings") {
+        Some(settings) if api && settings.get("db").is_some() && settings.get("db_backend").is_none() => {
+            insert_after_db(source, "db_backend", &Value::from("polodb"))
+        }
+        _ => Some(source.to_string()),
+    }
+}
+
+/// Inserts a `key: value` line into `settings` after the `db` line, with the same indentation.
+///
+/// Returns None if the `db` line cannot be found, e.g. in a flow mapping.
+fn insert_after_db(source: &str, key: &str, value: &Value) -> Option<String> {
+    let db_line: usize = find_line(source, &["settings", "db"], None)?;
+    let mut lines: Vec<&str> = source.lines().collect();
+    let indent: &str = &lines[db_line - 1][..lines[db_line - 1].len() - lines[db_line - 1].trim_start().len()];
+
+    let value: String = serde_yaml::to_string(value).ok()?;
+    let line: String = format!("{}{}: {}", indent, key, value.trim_end());
+    lines.insert(db_line, &line);
+
+    let mut result: String = lines.join("\n");
+    if source.ends_with('\n') {
+        result.push('\n');
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(source: &str) -> Value {
+        serde_yaml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn override_of_string_key_missing_from_file_stays_string() {
+        let config: Value = yaml("settings:\n  port: 8080\n");
+        assert_eq!(override_value(&config, &["settings", "jwt"], "123456".to_string()), Value::String("123456".to_string()));
+        assert_eq!(override_value(&config, &["server", "auth_token"], "true".to_string()), Value::String("true".to_string()));
+    }
+
+    #[test]
+    fn override_of_other_keys_keeps_yaml_type() {
+        let config: Value = yaml("settings:\n  port: 8080\n");
+        assert_eq!(override_value(&config, &["settings", "port"], "9090".to_string()), yaml("9090"));
+        assert_eq!(override_value(&config, &["settings", "tls", "enabled"], "false".to_string()), yaml("false"));
+        assert_eq!(override_value(&config, &["backup", "watch_directories"], "[\"/a\", 2024]".to_string()), yaml("[\"/a\", \"2024\"]"));
+    }
+
+    #[test]
+    fn override_of_key_that_is_string_in_file_stays_string() {
+        let config: Value = yaml("custom:\n  value: \"x\"\n");
+        assert_eq!(override_value(&config, &["custom", "value"], "42".to_string()), Value::String("42".to_string()));
+    }
+
+    #[test]
+    fn overrides_are_not_expanded_again() {
+        let path: PathBuf = temp_config("verbatim", "settings:\n  admin_token: \"x$$y\"\n");
+        // Only read by this test, other configs just gain an unknown key
+        unsafe { env::set_var("CRATIS_VERBATIM_TEST__SECRET", "ab$$cd${"); }
+
+        let (_, value) = read_config_value(&path, true).unwrap();
+        unsafe { env::remove_var("CRATIS_VERBATIM_TEST__SECRET"); }
+
+        assert_eq!(value["settings"]["admin_token"], yaml("x$y"));
+        assert_eq!(value["verbatim_test"]["secret"], Value::String("ab$$cd${".to_string()));
+    }
+
+    const SERVER_V1: &str = "\
+# Cratis server
+settings:
+  # Metadata database
+  db: /var/lib/cratis/cratis.db   # moved in 2023
+  port: 8080
+";
+
+    const CLIENT_V1: &str = "\
+client:
+  id: laptop
+  name: laptop
+backup:
+  jobs:
+    - name: photos
+      directories: [/home/me/Pictures, \"/mnt/photos\"]
+      retention:
+        keep_versions: 10
+    - name: documents
+      directories:
+        - /home/me/Documents
+      exclude: ['*.tmp']
+server:
+  address: https://backup.example.com
+";
+
+    /// Writes `contents` to a file of its own in the temp directory and returns its path.
+    fn temp_config(name: &str, contents: &str) -> PathBuf {
+        let dir: PathBuf = std::env::temp_dir().join(format!("cratis-config-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let path: PathBuf = dir.join("config.yaml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn migrate_value_adds_storage_next_to_db() {
+        let mut config: Value = yaml(SERVER_V1);
+        let steps: Vec<&str> = migrate_value(&mut config, true).unwrap();
+
+        assert_eq!(steps.len(), 2);
+        assert_eq!(config["version"], yaml("3"));
+        assert_eq!(config["settings"]["storage"], yaml("/var/lib/cratis/storage"));
+        assert_eq!(config["settings"]["db_backend"], yaml("polodb"));
+        assert_eq!(config["settings"]["db"], yaml("/var/lib/cratis/cratis.db"));
+    }
+
+    #[test]
+    fn migrate_value_keeps_existing_storage_and_quoted_keys() {
+        let mut config: Value = yaml("\"settings\":\n  'db': cratis.db\n  \"storage\": /data\n");
+        migrate_value(&mut config, true).unwrap();
+
+        assert_eq!(config["settings"]["storage"], yaml("/data"));
+        assert_eq!(config["version"], yaml("3"));
+    }
+
+    #[test]
+    fn migrate_value_leaves_client_jobs_alone() {
+        let original: Value = yaml(CLIENT_V1);
+        let mut config: Value = original.clone();
+        migrate_value(&mut config, false).unwrap();
+
+        let mapping: &Mapping = config.as_mapping().unwrap();
+        assert_eq!(mapping.keys().next(), Some(&Value::from("version")));
+        assert_eq!(config["backup"], original["backup"]);
+        assert!(config.get("settings").is_none());
+    }
+
+    #[test]
+    fn migrate_value_rejects_newer_and_invalid_versions() {
+        let mut newer: Value = yaml(&format!("version: {}\n", CONFIG_VERSION + 1));
+        assert!(migrate_value(&mut newer, true).is_err());
+
+        let mut invalid: Value = yaml("version: zero\n");
+        assert!(migrate_value(&mut invalid, true).is_err());
+
+        let mut current: Value = yaml(&format!("version: {}\nsettings:\n  db: cratis.db\n", CONFIG_VERSION));
+        assert!(migrate_value(&mut current, true).unwrap().is_empty());
+        assert!(current["settings"].get("storage").is_none());
+    }
+
+    #[test]
+    fn set_version_line_inserts_after_leading_comments() {
+        assert_eq!(set_version_line("# Cratis\n\nclient:\n  id: a\n", 2), "# Cratis\n\nversion: 2\nclient:\n  id: a\n");
+        assert_eq!(set_version_line("---\nclient: {}\n", 2), "---\nversion: 2\nclient: {}\n");
+        assert_eq!(set_version_line("", 2), "version: 2\n");
+    }
+
+    #[test]
+    fn set_version_line_replaces_only_the_top_level_version() {
+        let source: &str = "version: 1\njobs:\n  - version: 1\n";
+        assert_eq!(set_version_line(source, 2), "version: 2\njobs:\n  - version: 1\n");
+        assert_eq!(set_version_line("version: 1", 2), "version: 2");
+    }
+
+    #[test]
+    fn rewrite_v1_storage_keeps_comments() {
+        let rewritten: String = rewrite_v1_storage(SERVER_V1, true).unwrap();
+
+        assert_eq!(
+            rewritten,
+            "# Cratis server\nsettings:\n  # Metadata database\n  db: /var/lib/cratis/cratis.db   # moved in 2023\n  storage: /var/lib/cratis/storage\n  port: 8080\n"
+        );
+    }
+
+    #[test]
+    fn rewrite_v1_storage_handles_quoted_keys() {
+        let rewritten: String = rewrite_v1_storage("\"settings\":\n    'db': \"cratis.db\"\n", true).unwrap();
+        assert_eq!(rewritten, "\"settings\":\n    'db': \"cratis.db\"\n    storage: storage\n");
+    }
+
+    #[test]
+    fn rewrite_v1_storage_leaves_client_configs_alone() {
+        assert_eq!(rewrite_v1_storage(CLIENT_V1, false).as_deref(), Some(CLIENT_V1));
+    }
+
+    #[test]
+    fn rewrite_v1_storage_gives_up_on_flow_settings() {
+        assert_eq!(rewrite_v1_storage("settings: {db: cratis.db, port: 8080}\n", true), None);
+    }
+
+    #[test]
+    fn migrate_value_keeps_the_chosen_db_backend() {
+        let mut chosen: Value = yaml("version: 2\nsettings:\n  db: cratis.db\n  db_backend: sqlite\n");
+        assert_eq!(migrate_value(&mut chosen, true).unwrap().len(), 1);
+        assert_eq!(chosen["settings"]["db_backend"], yaml("sqlite"));
+
+        let mut implicit: Value = yaml("version: 2\nsettings:\n  db: cratis.db\n");
+        migrate_value(&mut implicit, true).unwrap();
+        assert_eq!(implicit["settings"]["db_backend"], yaml("polodb"));
+    }
+
+    #[test]
+    fn rewrite_v2_db_backend_keeps_comments() {
+        let source: &str = "version: 2\nsettings:\n  db: /srv/cratis.db   # metadata\n  port: 8080\n";
+        assert_eq!(
+            rewrite_v2_db_backend(source, true).unwrap(),
+            "version: 2\nsettings:\n  db: /srv/cratis.db   # metadata\n  db_backend: polodb\n  port: 8080\n"
+        );
+        assert_eq!(rewrite_v2_db_backend(CLIENT_V1, false).as_deref(), Some(CLIENT_V1));
+    }
+
+    #[test]
+    fn migrate_config_file_preserves_comments() {
+        let path: PathBuf = temp_config("comments", SERVER_V1);
+        let report: MigrationReport = migrate_config_file(&path, true).unwrap();
+
+        assert!(report.comments_preserved);
+        assert_eq!((report.from, report.to), (1, CONFIG_VERSION));
+        let migrated: String = fs::read_to_string(&path).unwrap();
+        assert!(migrated.starts_with("# Cratis server\nversion: 3\nsettings:\n  # Metadata database\n"));
+        assert!(migrated.contains("  db_backend: polodb\n  storage: /var/lib/cratis/storage\n"));
+        assert_eq!(fs::read_to_string(report.backup.unwrap()).unwrap(), SERVER_V1);
+
+        // Already migrated, nothing is written
+        assert!(migrate_config_file(&path, true).unwrap().backup.is_none());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn migrate_config_file_preserves_client_jobs() {
+        let path: PathBuf = temp_config("client", CLIENT_V1);
+        let report: MigrationReport = migrate_config_file(&path, false).unwrap();
+
+        assert!(report.comments_preserved);
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("version: 3\n{}", CLIENT_V1));
+
+        let config: CratisConfig = read_config(&path, false).unwrap();
+        assert_eq!(config.backup.jobs[0].directories, ["/home/me/Pictures", "/mnt/photos"]);
+        assert_eq!(config.backup.jobs[1].exclude, ["*.tmp"]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn migrate_config_file_falls_back_to_plain_yaml() {
+        // The flow mapping cannot be rewritten in place
+        let source: &str = "# Cratis server\nsettings: {db: /srv/cratis.db, port: 8080}\n";
+        let path: PathBuf = temp_config("fallback", source);
+        let report: MigrationReport = migrate_config_file(&path, true).unwrap();
+
+        assert!(!report.comments_preserved);
+        let migrated: String = fs::read_to_string(&path).unwrap();
+        assert!(!migrated.contains("# Cratis server"));
+
+        let value: Value = yaml(&migrated);
+        assert_eq!(value["version"], yaml("3"));
+        assert_eq!(value["settings"]["storage"], yaml("/srv/storage"));
+        assert_eq!(value["settings"]["port"], yaml("8080"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn migrate_config_file_falls_back_when_the_rewrite_changes_the_meaning() {
+        // A folded db value continues on the next line, inserting storage after the key splits it
+        let source: &str = "settings:\n  db: >-\n    /srv/cratis.db\n";
+        let path: PathBuf = temp_config("round-trip", source);
+        let report: MigrationReport = migrate_config_file(&path, true).unwrap();
+
+        assert!(!report.comments_preserved);
+        let value: Value = yaml(&fs::read_to_string(&path).unwrap());
+        assert_eq!(value["settings"]["db"], yaml("/srv/cratis.db"));
+        assert_eq!(value["settings"]["storage"], yaml("/srv/storage"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+
 #[derive(Debug, thiserror::Error)]
 pub enum CratisError {
     #[error("Failed read/write file: {0}")]
@@ -10,31 +14,35 @@ pub enum CratisError {
     ConfigParseError(#[from] serde_yaml::Error),
 
     #[error("Invalid input provided: {0}")]
-    InvalidInput(&'static str),
+    InvalidInput(String),
+
+    #[error("Invalid path {}: {reason}", path.display())]
+    InvalidPath { path: PathBuf, reason: String },
 
-    #[error("Invalid path: {0}")]
-    InvalidPath(String),
+    #[error("Invalid exclude pattern '{pattern}': {reason}")]
+    InvalidPattern { pattern: String, reason: String },
 
-    #[error("Network or connection error: {0}")]
-    ConnectionIssue(&'static str),
+    #[error("Unable to reach {url}: {reason}")]
+    ConnectionIssue { url: String, reason: String },
 
     #[error("Authentication failed: {0}")]
-    AuthFailure(&'static str),
+    AuthFailure(String),
 
     #[error("Operation timed out")]
     Timeout,
 
     #[error("Backup process failed: {0}")]
-    BackupFailure(&'static str),
+    BackupFailure(String),
 
     #[error("Unsupported operation: {0}")]
-    Unsupported(&'static str),
+    Unsupported(String),
 
     #[error("Internal error: {0}")]
-    Internal(&'static str),
+    Internal(String),
 
-    #[error("Request error: {0}")]
-    RequestError(&'static str),
+    // The status is missing if no response was received or its body is invalid
+    #[error("Request error{}: {message}", status.map(|s| format!(" (HTTP {})", s)).unwrap_or_default())]
+    RequestError { status: Option<u16>, message: String },
 
     #[error("Database error: {0}")]
     DatabaseError(String),
@@ -45,52 +53,237 @@ pub enum CratisError {
     #[error("Environment error: {0}")]
     EnvError(String),
 
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    // An error the server reported in its response body, see `ErrorBody`
+    #[error("{message}")]
+    Api { code: ErrorCode, message: String, details: Value },
+
     #[error("Unknown error")]
     Unknown,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum CratisErrorLevel {
-    // An info message for the user.
-    Info,
-    // An error occurred, but it is not fatal.
-    Warning,
-    // A fatal error occurred, exit program immediately!
-    Fatal,
-}
+impl CratisError {
+    /// Creates a `RequestError` for an unexpected response of the server.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// s => return Err(CratisError::request(s, "Invalid response")),
+    /// ```
+    pub fn request(status: reqwest::StatusCode, message: impl Into<String>) -> Self {
+        CratisError::RequestError { status: Some(status.as_u16()), message: message.into() }
+    }
 
-pub type CratisResult<T> = Result<T, CratisError>;
+    /// Creates a `RequestError` for a response body that is not what the server should send.
+    pub fn invalid_response(message: impl Into<String>) -> Self {
+        CratisError::RequestError { status: None, message: message.into() }
+    }
 
-/// Displays a Cratis message to standard error (stderr).
-///
-/// # Arguments
-///
-/// * `error` - Optional reference to a CratisError to be displayed (used for Warning/Fatal).
-/// * `level` - Info, Warning, or Fatal.
-/// * `msg` - Optional message string for Info messages.
+    /// Creates a `ConnectionIssue` from a failed HTTP request, with the URL and the underlying
+    /// cause, e.g. "connection refused", as reason.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let response = client.get(url).send().await.map_err(CratisError::connection)?;
+    /// ```
+    pub fn connection(error: reqwest::Error) -> Self {
+        let url: String = error.url().map(|url| url.to_string()).unwrap_or_default();
+        let mut cause: &dyn std::error::Error = &error;
+        while let Some(source) = cause.source() {
+            cause = source;
+        }
+
+        CratisError::ConnectionIssue { url, reason: cause.to_string() }
+    }
+
+    /// Creates the error a handler returns to the client.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// return Err(CratisError::api(ErrorCode::NotFound, "Device not found"));
+    /// ```
+    pub fn api(code: ErrorCode, message: impl Into<String>) -> Self {
+        CratisError::Api { code, message: message.into(), details: Value::Null }
+    }
+
+    /// Attaches machine readable details to an `Api` error, other errors are returned unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// CratisError::api(ErrorCode::AlreadyExists, "Device already registered").with_details(json!({ "device_id": device_id }))
+    /// ```
+    pub fn with_details(self, details: Value) -> Self {
+        match self {
+            CratisError::Api { code, message, .. } => CratisError::Api { code, message, details },
+            other => other,
+        }
+    }
+
+    /// Returns the error code the error is reported with.
+    ///
+    /// Errors caused by the request map to `InvalidRequest` and `Unauthorized`, everything
+    /// else is a problem of the server and maps to `Internal`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CratisError::Api { code, .. } => *code,
+            CratisError::InvalidInput(_) | CratisError::InvalidPath { .. } | CratisError::InvalidPattern { .. } => ErrorCode::InvalidRequest,
+            CratisError::AuthFailure(_) | CratisError::TokenError(_) => ErrorCode::Unauthorized,
+            CratisError::RequestError { status: Some(status), .. } => ErrorCode::from_status(*status),
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// Returns the body sent to the client for this error.
+    ///
+    /// Internal errors are reported with a generic message, their details may contain paths or
+    /// database errors that are only meant for the server log.
+    pub fn body(&self) -> ErrorBody {
+        let (message, details): (String, Value) = match self {
+            CratisError::Api { message, details, .. } => (message.clone(), details.clone()),
+            CratisError::InvalidInput(message) | CratisError::AuthFailure(message) => (message.clone(), Value::Null),
+            _ if self.code() == ErrorCode::Internal => ("Internal Server Error".to_string(), Value::Null),
+            _ => (self.to_string(), Value::Null),
+        };
+
+        ErrorBody { code: self.code(), message, details }
+    }
+
+    /// Turns an unsuccessful response of the server into an error.
+    ///
+    /// The error body of the server is parsed into an `Api` error, so the message of the server
+    /// is shown, e.g. "Device already registered on 2025-01-03 00:00:00 UTC". Responses without
+    /// one, e.g. from a proxy, become a `RequestError` with the status.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// match response.status() {
+    ///     StatusCode::OK => {}
+    ///     _ => return Err(CratisError::from_response(response).await),
+    /// }
+    /// ```
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let status: reqwest::StatusCode = response.status();
+        let body = response.bytes().await.unwrap_or_default();
+        CratisError::from_body(status, &body)
+    }
+
+    /// Same as [`CratisError::from_response`], for a body that was already read.
+    pub fn from_body(status: reqwest::StatusCode, body: &[u8]) -> Self {
+        match serde_json::from_slice::<ErrorResponse>(body) {
+            Ok(ErrorResponse { error }) => CratisError::Api { code: error.code, message: error.message, details: error.details },
+            Err(_) => CratisError::request(status, status.canonical_reason().unwrap_or("Invalid response")),
+        }
+    }
+}
+
+/// Stable, machine readable code of an error response.
 ///
-/// Behavior:
-/// - Info: prints "Info: {msg}"
-/// - Warning: prints "Warning: {error}"
-/// - Fatal: prints "Fatal error: {error}" and exits with code 1.
+/// Clients should match on the code rather than on the message, which may change. Codes a
+/// client does not know yet are read as `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The request is malformed or a value is invalid
+    InvalidRequest,
+    // The credentials are missing, invalid or expired
+    Unauthorized,
+    // The credentials are valid but do not allow the request
+    Forbidden,
+    // The token lacks the scope the route requires, the scope is in the details
+    MissingScope,
+    // The enrollment code is missing, unknown, expired or used up
+    EnrollmentCodeInvalid,
+    NotFound,
+    AlreadyExists,
+    // Too many requests, the seconds to wait are in the details as `retry_after`
+    RateLimited,
+    Internal,
+    // The server is shutting down and does not take new uploads, retry against it once it is back
+    Unavailable,
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Returns the HTTP status an error with this code is sent with.
+    pub fn status(self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest => 400,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::Forbidden | ErrorCode::MissingScope | ErrorCode::EnrollmentCodeInvalid => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::AlreadyExists => 409,
+            ErrorCode::RateLimited => 429,
+            ErrorCode::Internal | ErrorCode::Unknown => 500,
+            ErrorCode::Unavailable => 503,
+        }
+    }
+
+    /// Returns the code matching an HTTP status, for responses without an error body.
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            400 | 422 => ErrorCode::InvalidRequest,
+            401 => ErrorCode::Unauthorized,
+            403 => ErrorCode::Forbidden,
+            404 => ErrorCode::NotFound,
+            409 => ErrorCode::AlreadyExists,
+            429 => ErrorCode::RateLimited,
+            503 => ErrorCode::Unavailable,
+            500..=599 => ErrorCode::Internal,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+/// Error reported by the server.
 ///
 /// # Examples
-/// ```ignore
-/// use cratis_core::error::{display_msg, CratisError, CratisErrorLevel};
 ///
-/// display_msg(None, CratisErrorLevel::Info, Some("Starting backup".into()));
-/// display_msg(Some(&CratisError::InvalidInput("Invalid configuration")), CratisErrorLevel::Warning, None);
+/// ```json
+/// {
+///   "error": {
+///     "code": "already_exists",
+///     "message": "Device already registered on 2025-01-03 00:00:00 UTC",
+///     "details": { "device_id": "6f1c2b1e-...", "registered_at": 1735862400 }
+///   }
+/// }
 /// ```
-pub fn display_msg(error: Option<&CratisError>, level: CratisErrorLevel, msg: Option<String> /* msg is for info messages only */) {
-    let error = error.unwrap_or(&CratisError::Unknown);
-    let msg = msg.unwrap_or("".to_string());
-
-    if level == CratisErrorLevel::Info {
-        eprintln!("Info: {msg}");
-    } else if level == CratisErrorLevel::Warning {
-        eprintln!("Warning: {error}");
-    } else if level == CratisErrorLevel::Fatal {
-        eprintln!("Fatal error: {error}");
-        std::process::exit(1);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    // Human readable description, meant to be shown to the user
+    pub message: String,
+    // Machine readable context, depends on the code
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub details: Value,
+}
+
+/// Body of every error response, wrapping [`ErrorBody`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[cfg(feature = "server")]
+impl axum::response::IntoResponse for CratisError {
+    /// Sends the error as [`ErrorResponse`] with the status of its code.
+    ///
+    /// Internal errors are logged, since the client only gets a generic message.
+    fn into_response(self) -> axum::response::Response {
+        let body: ErrorBody = self.body();
+        if body.code == ErrorCode::Internal && !matches!(self, CratisError::Api { .. }) {
+            tracing::warn!(error = %self);
+        }
+
+        let status = axum::http::StatusCode::from_u16(body.code.status()).unwrap_or(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+        (status, axum::Json(ErrorResponse { error: body })).into_response()
     }
 }
+
+pub type CratisResult<T> = Result<T, CratisError>;
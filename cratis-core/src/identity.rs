@@ -0,0 +1,220 @@
+use crate::config::{get_config_cli, get_config_path};
+use crate::error::{CratisError, CratisResult};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// File name of the device key when `client.key_file` is not set, relative to the config file.
+pub const DEFAULT_KEY_FILE: &str = "device.key";
+/// File name of the client certificate when `client.cert_file` is not set, relative to the config file.
+pub const DEFAULT_CERT_FILE: &str = "device.crt";
+
+/// PKCS#8 header of an Ed25519 private key (RFC 8410), followed by the 32 byte secret key.
+const PKCS8_ED25519_PREFIX: [u8; 16] = [0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+
+/// The Ed25519 keypair identifying this device.
+///
+/// The device ID is derived from the public key by the server, the private key never leaves the device.
+pub struct DeviceKey {
+    signing_key: SigningKey,
+}
+
+impl DeviceKey {
+    /// Generates a new random keypair.
+    pub fn generate() -> Self {
+        let seed: [u8; 32] = rand::rng().random();
+        DeviceKey { signing_key: SigningKey::from_bytes(&seed) }
+    }
+
+    /// Reads a keypair from a file holding the base64 encoded 32 byte secret key.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(DeviceKey))` - The stored keypair
+    /// * `Ok(None)` - If the file does not exist
+    /// * `Err(CratisError)` - If the file cannot be read or holds no valid key
+    pub fn load(path: &Path) -> CratisResult<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let invalid = || CratisError::ConfigError(format!("{} does not contain a valid device key", path.display()));
+        let decoded: Vec<u8> = BASE64_STANDARD.decode(fs::read_to_string(path)?.trim()).map_err(|_| invalid())?;
+        let secret: [u8; 32] = decoded.try_into().map_err(|_| invalid())?;
+
+        Ok(Some(DeviceKey { signing_key: SigningKey::from_bytes(&secret) }))
+    }
+
+    /// Writes the secret key to a file that only the current user can read.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CratisError::IoError` if the file cannot be written.
+    pub fn save(&self, path: &Path) -> CratisResult<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // The key is never readable by others, not even before it is written
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file: fs::File = options.open(path)?;
+        // A key file that already existed keeps its mode on open
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(BASE64_STANDARD.encode(self.signing_key.to_bytes()).as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Returns the base64 encoded public key, as sent to the server.
+    pub fn public_key(&self) -> String {
+        BASE64_STANDARD.encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Signs a message and returns the base64 encoded signature.
+    pub fn sign(&self, message: &[u8]) -> String {
+        BASE64_STANDARD.encode(self.signing_key.sign(message).to_bytes())
+    }
+
+    /// Returns the id the server derives from the public key, see [`device_id`].
+    pub fn device_id(&self) -> String {
+        device_id(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// Returns the secret key PKCS#8 encoded, as TLS libraries expect it for client certificates.
+    pub fn to_pkcs8_der(&self) -> Vec<u8> {
+        [PKCS8_ED25519_PREFIX.as_slice(), self.signing_key.as_bytes()].concat()
+    }
+}
+
+/// Generates the device ID belonging to a public key.
+///
+/// Creates a deterministic UUID v5 by hashing the raw public key with SHA-256 and
+/// generating a UUID using the URL namespace, so a device keeps its ID as long as it keeps its key.
+/// Server and client derive it the same way.
+///
+/// # Arguments
+///
+/// * `public_key` - The raw 32 byte Ed25519 public key
+///
+/// # Returns
+///
+/// A string representation of the generated UUID
+///
+/// # Examples
+///
+/// ```ignore
+/// let device_id = device_id(public_key.as_bytes());
+/// assert_eq!(device_id.len(), 36); // UUID string length
+/// ```
+pub fn device_id(public_key: &[u8]) -> String {
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, &Sha256::digest(public_key)).to_string()
+}
+
+/// Returns the path of the device key of the client.
+///
+/// `client.key_file` is used if set, relative paths and the default are resolved against the
+/// directory of the loaded config file.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if no config file was loaded.
+pub fn key_path() -> CratisResult<PathBuf> {
+    resolve_path(&get_config_cli()?.client.key_file, DEFAULT_KEY_FILE)
+}
+
+/// Returns the path of the client certificate, resolved like [`key_path`] from `client.cert_file`.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if no config file was loaded.
+pub fn cert_path() -> CratisResult<PathBuf> {
+    resolve_path(&get_config_cli()?.client.cert_file, DEFAULT_CERT_FILE)
+}
+
+fn resolve_path(configured: &str, default: &str) -> CratisResult<PathBuf> {
+    let file: &Path = Path::new(if configured.is_empty() { default } else { configured });
+    if file.is_absolute() {
+        return Ok(file.to_path_buf());
+    }
+
+    let config_path: &Path = get_config_path(false).ok_or(CratisError::ConfigError("No config file loaded".to_string()))?;
+    Ok(config_path.parent().unwrap_or(Path::new(".")).join(file))
+}
+
+/// Loads the device key of the client, generating and saving a new one if there is none yet.
+///
+/// # Errors
+///
+/// Returns `CratisError` if the key file cannot be read or written.
+pub fn load_or_create_key() -> CratisResult<DeviceKey> {
+    let path: PathBuf = key_path()?;
+    if let Some(key) = DeviceKey::load(&path)? {
+        return Ok(key);
+    }
+
+    let key: DeviceKey = DeviceKey::generate();
+    key.save(&path)?;
+    Ok(key)
+}
+
+/// Stores the client certificate the server issued to this device at [`cert_path`].
+///
+/// # Errors
+///
+/// Returns `CratisError` if the path cannot be resolved or the file cannot be written.
+pub fn save_certificate(pem: &str) -> CratisResult<()> {
+    let path: PathBuf = cert_path()?;
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, pem)?;
+    Ok(())
+}
+
+/// Message a device signs to prove it holds the key it registers with.
+pub fn registration_message(public_key: &str, timestamp: u64) -> String {
+    format!("cratis-register:{}:{}", public_key, timestamp)
+}
+
+/// Message a device signs to answer a login challenge.
+pub fn challenge_message(device_id: &str, challenge: &str) -> String {
+    format!("cratis-login:{}:{}", device_id, challenge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_keys_are_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir: PathBuf = std::env::temp_dir().join(format!("cratis-identity-{}", std::process::id()));
+        let path: PathBuf = dir.join("device.key");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let key = DeviceKey::generate();
+        key.save(&path).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(DeviceKey::load(&path).unwrap().unwrap().public_key(), key.public_key());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
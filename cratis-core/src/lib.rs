@@ -1,4 +1,9 @@
 pub mod config;
 pub mod error;
 pub mod utils;
-pub mod backup;
\ No newline at end of file
+pub mod backup;
+pub mod validation;
+pub mod auth;
+pub mod identity;
+pub mod tls;
+pub mod logging;
\ No newline at end of file
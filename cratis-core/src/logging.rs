@@ -0,0 +1,72 @@
+use crate::config::{LogFormat, LogSettings};
+use crate::error::{CratisError, CratisResult};
+use std::fs::{File, OpenOptions};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+/// Parses the `level` of the log settings.
+///
+/// Accepts a plain level like `info` as well as per-module directives like
+/// `info,cratis_api=debug`.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if the level is not a valid filter.
+pub fn log_filter(level: &str) -> CratisResult<EnvFilter> {
+    EnvFilter::try_new(level).map_err(|e| CratisError::ConfigError(format!("Invalid log level '{}': {}", level, e)))
+}
+
+/// Installs the global `tracing` subscriber according to the log settings.
+///
+/// Messages are written as text or as one JSON object per line, to stderr or appended to
+/// `file`. Commands that run before the config is loaded print plain messages to stderr instead.
+///
+/// # Arguments
+///
+/// * `settings` - The `log` section of the client config or `settings.log` of the server config
+///
+/// # Errors
+///
+/// * `CratisError::ConfigError` - If the level is invalid or a subscriber is already installed
+/// * `CratisError::IoError` - If the log file cannot be opened
+///
+/// # Examples
+///
+/// ```ignore
+/// load_config(&path, true)?;
+/// init_logging(&get_config_api()?.settings.log)?;
+/// tracing::info!(port = 8080, "Server started");
+/// ```
+pub fn init_logging(settings: &LogSettings) -> CratisResult<()> {
+    let filter: EnvFilter = log_filter(&settings.level)?;
+
+    let (writer, ansi) = if settings.file.is_empty() {
+        (BoxMakeWriter::new(std::io::stderr), std::io::stderr().is_terminal())
+    } else {
+        (BoxMakeWriter::new(Mutex::new(open_log_file(Path::new(&settings.file))?)), false)
+    };
+
+    let layer: Box<dyn Layer<Registry> + Send + Sync> = match settings.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi).with_target(false).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().with_current_span(true).with_span_list(false).with_writer(writer).boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(filter)
+        .try_init()
+        .map_err(|e| CratisError::ConfigError(format!("Unable to initialize logging: {}", e)))
+}
+
+fn open_log_file(path: &Path) -> CratisResult<File> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
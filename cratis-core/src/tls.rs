@@ -0,0 +1,246 @@
+use crate::config::{get_config_cli, AuthMethod};
+use crate::error::{CratisError, CratisResult};
+use crate::identity::{cert_path, key_path, DeviceKey};
+use crate::utils::timestamp_now;
+use once_cell::sync::OnceCell;
+use reqwest::Client;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Fingerprint pinned during this run, takes precedence over `server.tls_fingerprint`.
+static PINNED: OnceCell<String> = OnceCell::new();
+
+/// Returns the SHA-256 fingerprint of a DER encoded certificate, as colon separated hex pairs.
+///
+/// # Examples
+///
+/// ```ignore
+/// let fingerprint: String = fingerprint(cert.as_ref());
+/// // "3A:F1:09:...:7C"
+/// ```
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(":")
+}
+
+/// Normalizes a fingerprint to uppercase colon separated hex pairs.
+///
+/// Fingerprints are accepted with or without colons and in any case.
+///
+/// # Errors
+///
+/// Returns `CratisError::InvalidInput` if the value is not a SHA-256 fingerprint.
+pub fn normalize_fingerprint(value: &str) -> CratisResult<String> {
+    let hex: String = value.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect::<String>().to_uppercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(CratisError::InvalidInput("A certificate fingerprint has to be 64 hex digits".to_string()));
+    }
+
+    Ok(hex.as_bytes().chunks(2).map(|c| String::from_utf8_lossy(c).to_string()).collect::<Vec<String>>().join(":"))
+}
+
+/// Pins a fingerprint for the rest of this run, e.g. right after the user accepted it on `register`.
+pub fn pin(fingerprint: String) {
+    let _ = PINNED.set(fingerprint);
+}
+
+/// Returns the HTTP client to talk to the Cratis server with.
+///
+/// If a server certificate fingerprint is pinned, through [`pin`] or `server.tls_fingerprint`,
+/// only a server presenting exactly that certificate is accepted. Otherwise the certificate is
+/// checked against the system's trusted CAs as usual. With `server.auth: certificate`, the
+/// client certificate issued by the server is presented once it exists.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConfigError` if the pinned fingerprint is invalid, or if a client
+/// certificate is to be used without a pinned fingerprint.
+pub fn client() -> CratisResult<Client> {
+    let server = &get_config_cli()?.server;
+    let configured: &str = match PINNED.get() {
+        Some(pinned) => pinned,
+        None => &server.tls_fingerprint,
+    };
+    let identity: Option<ClientIdentity> = match server.auth {
+        AuthMethod::Certificate => load_identity()?,
+        AuthMethod::Token => None,
+    };
+
+    if configured.is_empty() {
+        if identity.is_some() {
+            return Err(CratisError::ConfigError("server.auth: certificate needs server.tls_fingerprint, run `cratis register` to pin it".to_string()));
+        }
+        return Ok(Client::new());
+    }
+
+    let expected: String = normalize_fingerprint(configured)
+        .map_err(|_| CratisError::ConfigError("server.tls_fingerprint is not a valid SHA-256 fingerprint".to_string()))?;
+    build_client(PinnedCertVerifier::new(Some(expected)), identity)
+}
+
+/// Returns an HTTP client that only accepts a server presenting the certificate with the given
+/// fingerprint, regardless of the CA that issued it or the names it is valid for.
+///
+/// # Errors
+///
+/// Returns `CratisError::InvalidInput` if the fingerprint is invalid.
+pub fn pinned_client(fingerprint: &str) -> CratisResult<Client> {
+    build_client(PinnedCertVerifier::new(Some(normalize_fingerprint(fingerprint)?)), None)
+}
+
+/// Connects to the server and returns the fingerprint of the certificate it presents.
+///
+/// The certificate is not verified, the fingerprint has to be confirmed by the user or
+/// compared to the one printed by the server before it is pinned.
+///
+/// # Errors
+///
+/// Returns `CratisError::ConnectionIssue` if the server is not reachable over HTTPS.
+pub async fn fetch_fingerprint(address: &str) -> CratisResult<String> {
+    let verifier: PinnedCertVerifier = PinnedCertVerifier::new(None);
+    let seen: Arc<Mutex<Option<String>>> = verifier.seen.clone();
+
+    // Only the handshake matters, the status of the response is irrelevant
+    let _ = build_client(verifier, None)?.get(format!("{}/ping", address.trim_end_matches('/'))).send().await;
+
+    seen.lock()
+        .map_err(|_| CratisError::Internal("Certificate lock poisoned".to_string()))?
+        .clone()
+        .ok_or_else(|| CratisError::ConnectionIssue { url: address.to_string(), reason: "The server did not present a certificate".to_string() })
+}
+
+/// Client certificate chain and private key presented during the handshake.
+type ClientIdentity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// Loads the client certificate issued by the server together with the device key.
+///
+/// # Returns
+///
+/// * `Ok(Some(ClientIdentity))` - The certificate and key to present
+/// * `Ok(None)` - If no certificate was issued yet, e.g. before registering
+/// * `Err(CratisError)` - If the certificate or the key cannot be read
+fn load_identity() -> CratisResult<Option<ClientIdentity>> {
+    let path: PathBuf = cert_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let certs: Vec<CertificateDer<'static>> = CertificateDer::pem_file_iter(&path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| CratisError::TlsError(format!("Unable to read client certificate {}: {}", path.display(), e)))?;
+    // The server refuses the handshake if an expired certificate is presented, even to log in again
+    if certs.first().and_then(|cert| certificate_validity(cert.as_ref())).is_some_and(|(_, not_after)| not_after <= timestamp_now().unwrap_or(0)) {
+        return Ok(None);
+    }
+    let key: DeviceKey = DeviceKey::load(&key_path()?)?.ok_or(CratisError::AuthFailure("This device has no key, please register it again".to_string()))?;
+
+    Ok(Some((certs, PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.to_pkcs8_der())))))
+}
+
+/// Returns the validity period of a certificate as Unix timestamps, `(not_before, not_after)`.
+fn certificate_validity(der: &[u8]) -> Option<(u64, u64)> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(der).ok()?;
+    let validity = parsed.validity();
+    Some((u64::try_from(validity.not_before.timestamp()).ok()?, u64::try_from(validity.not_after.timestamp()).ok()?))
+}
+
+/// Checks whether the client certificate at `path` is due for renewal.
+///
+/// Certificates are renewed once half of their validity has passed, so devices that connect
+/// regularly never present an expired one.
+///
+/// # Returns
+///
+/// * `Ok(true)` - If the certificate expired or passed half of its validity
+/// * `Ok(false)` - If it is still fresh, or its validity cannot be read
+/// * `Err(CratisError::TlsError)` - If the file cannot be read
+pub fn certificate_needs_renewal(path: &Path) -> CratisResult<bool> {
+    let der: CertificateDer<'static> = CertificateDer::from_pem_file(path)
+        .map_err(|e| CratisError::TlsError(format!("Unable to read client certificate {}: {}", path.display(), e)))?;
+
+    Ok(match certificate_validity(&der) {
+        Some((not_before, not_after)) => timestamp_now()? >= not_before + (not_after.saturating_sub(not_before)) / 2,
+        None => false,
+    })
+}
+
+fn build_client(verifier: PinnedCertVerifier, identity: Option<ClientIdentity>) -> CratisResult<Client> {
+    let provider: Arc<CryptoProvider> = verifier.provider.clone();
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| CratisError::TlsError(e.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+
+    let config: ClientConfig = match identity {
+        Some((certs, key)) => builder.with_client_auth_cert(certs, key).map_err(|e| CratisError::TlsError(e.to_string()))?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Client::builder()
+        .use_preconfigured_tls(config)
+        .build()
+        .map_err(|e| CratisError::TlsError(e.to_string()))
+}
+
+/// Accepts exactly one server certificate, identified by its fingerprint.
+///
+/// Without an expected fingerprint every certificate is accepted and only recorded, which is
+/// used to show the fingerprint to the user before pinning it. Handshake signatures are always
+/// verified, so the server has to hold the certificate's private key.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    expected: Option<String>,
+    seen: Arc<Mutex<Option<String>>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    fn new(expected: Option<String>) -> Self {
+        PinnedCertVerifier {
+            expected,
+            seen: Arc::new(Mutex::new(None)),
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let presented: String = fingerprint(end_entity.as_ref());
+        if let Ok(mut seen) = self.seen.lock() {
+            *seen = Some(presented.clone());
+        }
+
+        match &self.expected {
+            Some(expected) if *expected != presented => Err(rustls::Error::General(format!(
+                "Certificate fingerprint {} does not match the pinned fingerprint",
+                presented
+            ))),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
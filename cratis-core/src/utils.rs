@@ -4,11 +4,10 @@ use std::fs::File;
 use std::io::{BufReader, Read};
 use std::time::{SystemTime, UNIX_EPOCH};
 use blake3::Hasher;
-use crate::error::{display_msg, CratisError, CratisResult, CratisErrorLevel};
-use crate::config::{CratisConfig};
+use crate::error::{CratisError, CratisResult};
 use glob::Pattern;
 use rand::distr::{Alphanumeric, SampleString};
-use rand::Rng;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
 
 /// Verifies that a given path exists and is a directory in the filesystem.
 ///
@@ -38,18 +37,15 @@ use rand::Rng;
 /// ```
 ///
 /// # Errors
-/// Returns `CratisError::InvalidPath` with:
-/// - The path string if the path doesn't exist
-/// - A formatted message including the path if it points to a file
+/// Returns `CratisError::InvalidPath` with the path and whether it does not exist or
+/// points to a file
 pub fn ensure_path_exists(path: &Path) -> CratisResult<()> {
     if !path.exists() {
-        let path_str = path.to_string_lossy().into_owned();
-        return Err(CratisError::InvalidPath(path_str));
+        return Err(CratisError::InvalidPath { path: path.to_path_buf(), reason: "The path does not exist".to_string() });
     }
 
     if path.is_file() {
-        let path_str = path.to_string_lossy().into_owned();
-        return Err(CratisError::InvalidPath(format!("The path has to point to a folder: {}", path_str)));
+        return Err(CratisError::InvalidPath { path: path.to_path_buf(), reason: "The path has to point to a folder".to_string() });
     }
 
     Ok(())
@@ -100,10 +96,124 @@ pub fn to_human_readable_size(bytes: f64) -> String {
 pub fn timestamp_now() -> CratisResult<u64> {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .map_err(|_| CratisError::Internal("Failed to get system time."))
+        .map_err(|_| CratisError::Internal("Failed to get system time.".to_string()))
         .map(|duration| duration.as_secs())
 }
 
+/// Parses a user supplied point in time into a Unix timestamp in seconds.
+///
+/// Accepted formats are a plain Unix timestamp, an RFC 3339 date-time
+/// (`2025-01-03T12:00:00+01:00`), a date-time without offset (`2025-01-03 12:00:00`)
+/// and a plain date (`2025-01-03`). Values without an offset are interpreted as UTC,
+/// plain dates as midnight.
+///
+/// # Arguments
+///
+/// * `value` - The string to parse
+///
+/// # Returns
+///
+/// * `CratisResult<u64>` - The Unix timestamp in seconds on success
+///
+/// # Errors
+///
+/// Returns `CratisError::InvalidInput` if the value matches none of the accepted formats
+/// or lies before the Unix epoch.
+///
+/// # Examples
+///
+/// ```ignore
+/// assert_eq!(parse_timestamp("1735862400")?, 1735862400);
+/// assert_eq!(parse_timestamp("2025-01-03")?, 1735862400);
+/// ```
+pub fn parse_timestamp(value: &str) -> CratisResult<u64> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(seconds);
+    }
+
+    let seconds: i64 = if let Ok(date_time) = DateTime::parse_from_rfc3339(value) {
+        date_time.timestamp()
+    } else if let Ok(date_time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        date_time.and_utc().timestamp()
+    } else if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        date.and_time(NaiveTime::MIN).and_utc().timestamp()
+    } else {
+        return Err(CratisError::InvalidInput("Invalid date, expected a Unix timestamp, YYYY-MM-DD or RFC 3339".to_string()));
+    };
+
+    u64::try_from(seconds).map_err(|_| CratisError::InvalidInput("Dates before 1970 are not supported".to_string()))
+}
+
+/// Parses a user supplied duration into seconds.
+///
+/// Accepts plain seconds or a number followed by `s`, `m`, `h`, `d` or `w`, e.g. `90`, `30m` or `7d`.
+///
+/// # Errors
+///
+/// Returns `CratisError::InvalidInput` if the value is not a valid duration.
+///
+/// # Examples
+///
+/// ```ignore
+/// assert_eq!(parse_duration("90")?, 90);
+/// assert_eq!(parse_duration("2h")?, 7200);
+/// ```
+pub fn parse_duration(value: &str) -> CratisResult<u64> {
+    let value = value.trim();
+    let invalid = CratisError::InvalidInput("Invalid duration, expected seconds or a number followed by s, m, h, d or w".to_string());
+
+    let (number, unit): (&str, u64) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 60 * 60),
+        Some((i, 'd')) => (&value[..i], 24 * 60 * 60),
+        Some((i, 'w')) => (&value[..i], 7 * 24 * 60 * 60),
+        Some(_) => (value, 1),
+        None => return Err(invalid),
+    };
+
+    number.trim().parse::<u64>().ok().and_then(|n| n.checked_mul(unit)).ok_or(invalid)
+}
+
+/// Formats a duration in seconds for display, with the two largest units.
+///
+/// # Examples
+///
+/// ```ignore
+/// assert_eq!(format_duration(93784), "1d 2h");
+/// assert_eq!(format_duration(754), "12m 34s");
+/// assert_eq!(format_duration(9), "9s");
+/// ```
+pub fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, _) => format!("{}m {}s", minutes, seconds % 60),
+        (0, _, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+/// Formats a Unix timestamp as `YYYY-MM-DD HH:MM:SS UTC` for display.
+///
+/// Timestamps that cannot be represented are returned as plain number.
+///
+/// # Examples
+///
+/// ```ignore
+/// assert_eq!(format_timestamp(1735862400), "2025-01-03 00:00:00 UTC");
+/// ```
+pub fn format_timestamp(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+        .map(|date_time| date_time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
 /// Sanitizes a filename by removing or replacing invalid characters.
 ///
 /// This function removes control characters and replaces common invalid characters
@@ -170,6 +280,25 @@ pub fn hash_file(path: &str) -> CratisResult<String> {
     Ok(hasher.finalize().to_hex().to_string())
 }
 
+/// Compiles the exclude patterns of a backup job.
+///
+/// # Errors
+///
+/// Returns `CratisError::InvalidPattern` with the first pattern that is not a valid glob.
+///
+/// # Examples
+///
+/// ```ignore
+/// let patterns: Vec<Pattern> = compile_patterns(&job.exclude)?;
+/// let files = get_files_in_directory(Path::new("/home/user/docs"), &patterns)?;
+/// ```
+pub fn compile_patterns(patterns: &[String]) -> CratisResult<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(|e| CratisError::InvalidPattern { pattern: pattern.clone(), reason: e.to_string() }))
+        .collect()
+}
+
 /// Checks if a path matches any of the provided exclusion patterns.
 ///
 /// # Arguments
@@ -234,12 +363,13 @@ pub fn is_path_file(dir: &str) -> bool {
 /// Recursively collects all files in a directory, respecting exclusion patterns.
 ///
 /// This function traverses the specified directory and all its subdirectories,
-/// collecting paths to all files while applying exclusion patterns from the
-/// application configuration.
+/// collecting paths to all files while skipping paths that match one of the
+/// exclusion patterns.
 ///
 /// # Arguments
 ///
-/// * `dir` - A reference to a String containing the directory path to scan
+/// * `dir` - The directory to scan
+/// * `exclude_patterns` - Patterns of files and directories to skip, see [`compile_patterns`]
 ///
 /// # Returns
 ///
@@ -257,7 +387,7 @@ pub fn is_path_file(dir: &str) -> bool {
 /// # Examples
 ///
 /// ```ignore
-/// match get_files_in_directory(&String::from("/path/to/directory")) {
+/// match get_files_in_directory(Path::new("/path/to/directory"), &[]) {
 ///     Ok(files) => {
 ///         println!("Found {} files", files.len());
 ///         for file in files {
@@ -267,45 +397,27 @@ pub fn is_path_file(dir: &str) -> bool {
 ///     Err(e) => println!("Error: {}", e),
 /// }
 /// ```
-pub fn get_files_in_directory(dir: &String) -> CratisResult<Vec<PathBuf>> {
+pub fn get_files_in_directory(dir: &Path, exclude_patterns: &[Pattern]) -> CratisResult<Vec<PathBuf>> {
     // Check if directory is a file (Just in case)
-    if is_path_file(&dir) {
-        // Warning
-        return Err(CratisError::InvalidPath(format!("The path has to point to a folder: {}", dir)));
+    if dir.is_file() {
+        return Err(CratisError::InvalidPath { path: dir.to_path_buf(), reason: "The path has to point to a folder".to_string() });
     }
 
     // Check if directory exists
-    if !Path::new(&dir).exists() {
-        // Warning
-        return Err(CratisError::InvalidPath(format!("The path does not exist: {}", dir)));
-    }
-
-    let config: &CratisConfig = crate::config::get_config_cli();
-
-    let exclude_dirs: &Vec<String> = &config.backup.exclude.clone().unwrap_or_default();
-
-    let mut exclude_patterns: Vec<Pattern> = Vec::new();
-
-    if !exclude_dirs.is_empty() {
-        for pattern in exclude_dirs.iter() {
-            match Pattern::new(pattern) {
-                Ok(p) => exclude_patterns.push(p),
-                Err(e) => display_msg(Some(&CratisError::ConfigError(format!("Invalid exclusion pattern '{}': {}", pattern, e))), CratisErrorLevel::Fatal, None)
-            }
-        }
+    if !dir.exists() {
+        return Err(CratisError::InvalidPath { path: dir.to_path_buf(), reason: "The path does not exist".to_string() });
     }
 
-    let path = Path::new(&dir);
     let mut file_paths: Vec<PathBuf> = Vec::new();
 
-    for entry in fs::read_dir(path)? {
+    for entry in fs::read_dir(dir)? {
         let entry = entry?;
         let path = entry.path();
 
-        if is_excluded(&path, &exclude_patterns) { continue; }
+        if is_excluded(&path, exclude_patterns) { continue; }
 
         if path.is_dir() {
-            let sub_dir_files = get_files_in_directory(&path.to_str().unwrap().to_string())?;
+            let sub_dir_files = get_files_in_directory(&path, exclude_patterns)?;
             file_paths.extend(sub_dir_files);
         } else {
             file_paths.push(path);
@@ -347,14 +459,13 @@ pub fn get_files_in_directory(dir: &String) -> CratisResult<Vec<PathBuf>> {
 pub fn load_file(file_path: PathBuf) -> CratisResult<(File, String, Option<String>)> {
     let file = File::open(&file_path).map_err(|e| {
         if e.kind() == std::io::ErrorKind::NotFound {
-            // Warning
-            CratisError::InvalidPath(format!("File not found: {}", &file_path.to_str().unwrap().to_string()))
+            CratisError::InvalidPath { path: file_path.clone(), reason: "File not found".to_string() }
         } else {
-            CratisError::IoError(e.into())
+            CratisError::IoError(e)
         }
     })?;
 
-    Ok((file, get_file_name(file_path.clone()), Some(file_path.to_str().unwrap().to_string())))
+    Ok((file, get_file_name(file_path.clone()), Some(file_path.to_string_lossy().into_owned())))
 }
 
 /// Extracts the filename from a path.
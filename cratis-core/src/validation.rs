@@ -0,0 +1,511 @@
+use crate::config::{read_config_value, AuthMethod, BackupJob, CratisConfig, CratisServerConfig};
+use crate::error::CratisResult;
+use crate::logging::log_filter;
+use crate::tls::normalize_fingerprint;
+use glob::Pattern;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+/// Smallest accepted backup interval in seconds.
+pub const MIN_INTERVAL_SECONDS: u32 = 60;
+/// Largest accepted backup interval in seconds (31 days).
+pub const MAX_INTERVAL_SECONDS: u32 = 31 * 24 * 60 * 60;
+
+/// A single problem found while validating a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    // Dot-separated key path, e.g. `backup.watch_directories[1]`
+    pub key: String,
+    // 1-based line in the config file, None if the value came from the environment or a default
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}: {}", line, self.key, self.message),
+            None => write!(f, "{}: {}", self.key, self.message),
+        }
+    }
+}
+
+/// Collects issues together with their location in the config file.
+struct Issues<'a> {
+    source: &'a str,
+    issues: Vec<ConfigIssue>,
+}
+
+impl Issues<'_> {
+    fn push(&mut self, keys: &[&str], item: Option<usize>, message: String) {
+        let mut key: String = keys.join(".");
+        if let Some(index) = item {
+            key.push_str(&format!("[{}]", index));
+        }
+
+        let line: Option<usize> = find_line(self.source, keys, item);
+        self.issues.push(ConfigIssue { key, line, message });
+    }
+
+    /// Records an issue with a field of a mapping inside a sequence, e.g. `backup.jobs[1].name`.
+    fn push_field(&mut self, keys: &[&str], item: usize, field: &str, message: String) {
+        let key: String = format!("{}[{}].{}", keys.join("."), item, field);
+        let item_line: Option<usize> = find_line(self.source, keys, Some(item));
+        let line: Option<usize> = item_line.map(|l| find_field_line(self.source, l, field).unwrap_or(l));
+        self.issues.push(ConfigIssue { key, line, message });
+    }
+}
+
+/// Validates a configuration file and reports every problem found.
+///
+/// The file is resolved like [`crate::config::load_config`] does, including environment overrides.
+/// If it cannot be deserialized, the deserialization error is the only issue reported.
+///
+/// # Arguments
+///
+/// * `path` - Path of the configuration file
+/// * `api` - If true, validates a server config; if false, a client config
+///
+/// # Returns
+///
+/// * `Ok(Vec<ConfigIssue>)` - All issues found, empty if the configuration is valid
+/// * `Err(CratisError)` - If the file cannot be read or a referenced environment variable is not set
+pub fn check_config(path: &Path, api: bool) -> CratisResult<Vec<ConfigIssue>> {
+    let (source, value) = read_config_value(path, api)?;
+    Ok(check_value(&source, value, api))
+}
+
+fn check_value(source: &str, value: Value, api: bool) -> Vec<ConfigIssue> {
+    if api {
+        match serde_yaml::from_value::<CratisServerConfig>(value) {
+            Ok(config) => validate_api_config(&config, source),
+            Err(e) => vec![parse_issue::<CratisServerConfig>(source, e)],
+        }
+    } else {
+        match serde_yaml::from_value::<CratisConfig>(value) {
+            Ok(config) => validate_cli_config(&config, source),
+            Err(e) => vec![parse_issue::<CratisConfig>(source, e)],
+        }
+    }
+}
+
+/// Turns a deserialization error into an issue.
+///
+/// Errors of the resolved value tree carry no position, so the raw file is deserialized again
+/// to locate the error. If the raw file deserializes fine, the error stems from an environment
+/// override and is reported without a line.
+fn parse_issue<T: DeserializeOwned>(source: &str, error: serde_yaml::Error) -> ConfigIssue {
+    let error: serde_yaml::Error = serde_yaml::from_str::<T>(source).err().unwrap_or(error);
+
+    ConfigIssue {
+        key: String::from("(file)"),
+        line: error.location().map(|l| l.line()),
+        message: error.to_string(),
+    }
+}
+
+/// Validates a client configuration.
+///
+/// Checks that the server address is an http(s) URL, that all watched paths exist, that all
+/// exclude patterns compile and that the backup interval lies between [`MIN_INTERVAL_SECONDS`]
+/// and [`MAX_INTERVAL_SECONDS`]. Named backup jobs are checked the same way.
+///
+/// # Arguments
+///
+/// * `config` - The configuration to validate
+/// * `source` - Content of the config file, used to look up line numbers
+///
+/// # Returns
+///
+/// All issues found, empty if the configuration is valid
+pub fn validate_cli_config(config: &CratisConfig, source: &str) -> Vec<ConfigIssue> {
+    let mut issues = Issues { source, issues: Vec::new() };
+
+    if config.client.name.trim().is_empty() {
+        issues.push(&["client", "name"], None, "Must not be empty".to_string());
+    }
+
+    match Url::parse(&config.server.address) {
+        Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+            issues.push(&["server", "address"], None, format!("Unsupported scheme '{}', expected http or https", url.scheme()))
+        }
+        Ok(url) if url.host_str().is_none() => {
+            issues.push(&["server", "address"], None, "URL has no host".to_string())
+        }
+        Ok(_) => {}
+        Err(e) => issues.push(&["server", "address"], None, format!("Invalid URL '{}': {}", config.server.address, e)),
+    }
+
+    if !config.server.tls_fingerprint.is_empty() && normalize_fingerprint(&config.server.tls_fingerprint).is_err() {
+        issues.push(&["server", "tls_fingerprint"], None, "Must be a SHA-256 fingerprint of 64 hex digits".to_string());
+    }
+    if config.server.auth == AuthMethod::Certificate && !config.server.address.starts_with("https://") {
+        issues.push(&["server", "auth"], None, "Client certificates need an https:// server address".to_string());
+    }
+
+    if let Err(e) = log_filter(&config.log.level) {
+        issues.push(&["log", "level"], None, e.to_string());
+    }
+
+    let interval: u32 = config.backup.interval_seconds;
+    if !(MIN_INTERVAL_SECONDS..=MAX_INTERVAL_SECONDS).contains(&interval) {
+        issues.push(
+            &["backup", "interval_seconds"],
+            None,
+            format!("{} is out of range, expected {} to {} seconds", interval, MIN_INTERVAL_SECONDS, MAX_INTERVAL_SECONDS),
+        );
+    }
+
+    for (index, dir) in config.backup.watch_directories.iter().enumerate() {
+        if !Path::new(dir).exists() {
+            issues.push(&["backup", "watch_directories"], Some(index), format!("Path does not exist: {}", dir));
+        }
+    }
+
+    for (index, pattern) in config.backup.exclude.iter().flatten().enumerate() {
+        if let Err(e) = Pattern::new(pattern) {
+            issues.push(&["backup", "exclude"], Some(index), format!("Invalid exclusion pattern '{}': {}", pattern, e));
+        }
+    }
+
+    validate_jobs(&config.backup.jobs, &mut issues);
+
+    issues.issues
+}
+
+/// Validates the named backup jobs of a client configuration.
+///
+/// Job names have to be unique and non-empty, every job needs at least one existing directory,
+/// valid exclude patterns and, if set, an interval within the accepted range.
+fn validate_jobs(jobs: &[BackupJob], issues: &mut Issues) {
+    const JOBS: [&str; 2] = ["backup", "jobs"];
+    let mut names: HashSet<&str> = HashSet::new();
+
+    for (index, job) in jobs.iter().enumerate() {
+        if job.name.trim().is_empty() {
+            issues.push_field(&JOBS, index, "name", "Must not be empty".to_string());
+        } else if !names.insert(job.name.as_str()) {
+            issues.push_field(&JOBS, index, "name", format!("Duplicate job name '{}'", job.name));
+        }
+
+        if job.directories.is_empty() {
+            issues.push_field(&JOBS, index, "directories", "At least one directory is required".to_string());
+        }
+        for dir in job.directories.iter().filter(|d| !Path::new(d).exists()) {
+            issues.push_field(&JOBS, index, "directories", format!("Path does not exist: {}", dir));
+        }
+
+        for pattern in &job.exclude {
+            if let Err(e) = Pattern::new(pattern) {
+                issues.push_field(&JOBS, index, "exclude", format!("Invalid exclusion pattern '{}': {}", pattern, e));
+            }
+        }
+
+        if let Some(interval) = job.interval_seconds.filter(|i| !(MIN_INTERVAL_SECONDS..=MAX_INTERVAL_SECONDS).contains(i)) {
+            issues.push_field(
+                &JOBS,
+                index,
+                "interval_seconds",
+                format!("{} is out of range, expected {} to {} seconds", interval, MIN_INTERVAL_SECONDS, MAX_INTERVAL_SECONDS),
+            );
+        }
+
+        if job.retention.keep_versions == Some(0) {
+            issues.push_field(&JOBS, index, "retention", "keep_versions must be at least 1".to_string());
+        }
+    }
+}
+
+/// Validates a server configuration.
+///
+/// Checks that the port is set, that the JWT secret is not empty, that the directory of the
+/// database exists, that the storage directory exists, that refresh tokens outlive access tokens,
+/// that enrollment codes can be issued if they are required, that the TLS certificate and key
+/// exist or can be generated and that client certificates are only issued over TLS.
+///
+/// # Arguments
+///
+/// * `config` - The configuration to validate
+/// * `source` - Content of the config file, used to look up line numbers
+///
+/// # Returns
+///
+/// All issues found, empty if the configuration is valid
+pub fn validate_api_config(config: &CratisServerConfig, source: &str) -> Vec<ConfigIssue> {
+    let mut issues = Issues { source, issues: Vec::new() };
+    let settings = &config.settings;
+
+    if settings.port == 0 {
+        issues.push(&["settings", "port"], None, "Must not be 0".to_string());
+    }
+
+    if settings.jwt.trim().is_empty() {
+        issues.push(&["settings", "jwt"], None, "Must not be empty".to_string());
+    }
+
+    let db_dir = Path::new(&settings.db).parent().filter(|p| !p.as_os_str().is_empty());
+    if let Some(dir) = db_dir.filter(|d| !d.is_dir()) {
+        issues.push(&["settings", "db"], None, format!("Directory does not exist: {}", dir.display()));
+    }
+
+    if !Path::new(&settings.storage).is_dir() {
+        issues.push(&["settings", "storage"], None, format!("Directory does not exist: {}", settings.storage));
+    }
+
+    if settings.access_token_ttl_seconds == 0 {
+        issues.push(&["settings", "access_token_ttl_seconds"], None, "Must not be 0".to_string());
+    }
+    if settings.require_enrollment_code && settings.admin_token.trim().is_empty() {
+        issues.push(
+            &["settings", "admin_token"],
+            None,
+            "Must be set to issue enrollment codes, or set require_enrollment_code to false".to_string(),
+        );
+    }
+
+    if settings.tls.enabled {
+        for (field, path) in [("cert", &settings.tls.cert), ("key", &settings.tls.key)] {
+            if path.trim().is_empty() {
+                issues.push(&["settings", "tls", field], None, "Must be set if TLS is enabled".to_string());
+            } else if !settings.tls.self_signed && !Path::new(path).is_file() {
+                issues.push(&["settings", "tls", field], None, format!("File does not exist: {}", path));
+            }
+        }
+    }
+    if settings.tls.client_auth {
+        if !settings.tls.enabled {
+            issues.push(&["settings", "tls", "client_auth"], None, "Needs TLS to be enabled".to_string());
+        }
+        for (field, path) in [("ca_cert", &settings.tls.ca_cert), ("ca_key", &settings.tls.ca_key)] {
+            if path.trim().is_empty() {
+                issues.push(&["settings", "tls", field], None, "Must be set if client_auth is enabled".to_string());
+            }
+        }
+    }
+
+    if let Err(e) = log_filter(&settings.log.level) {
+        issues.push(&["settings", "log", "level"], None, e.to_string());
+    }
+
+    let limits = &settings.rate_limit;
+    if limits.enabled {
+        for (field, value) in [
+            ("requests_per_minute_ip", limits.requests_per_minute_ip),
+            ("requests_per_minute_device", limits.requests_per_minute_device),
+            ("lockout_after_failures", limits.lockout_after_failures),
+        ] {
+            if value == 0 {
+                issues.push(&["settings", "rate_limit", field], None, "Must not be 0".to_string());
+            }
+        }
+        if limits.lockout_max_seconds < limits.lockout_base_seconds {
+            issues.push(&["settings", "rate_limit", "lockout_max_seconds"], None, "Must not be shorter than lockout_base_seconds".to_string());
+        }
+    }
+
+    if settings.refresh_token_ttl_seconds <= settings.access_token_ttl_seconds {
+        issues.push(&["settings", "refresh_token_ttl_seconds"], None, "Must be longer than access_token_ttl_seconds".to_string());
+    }
+
+    issues.issues
+}
+
+/// Finds the line of a key, or of an item of a sequence, in a YAML document.
+///
+/// This is a lightweight, indentation based lookup that covers block style documents as
+/// written by hand or by `init`. Keys set only through the environment are not found.
+///
+/// # Returns
+///
+/// The 1-based line number, or `None` if the key cannot be found
+pub(crate) fn find_line(source: &str, keys: &[&str], item: Option<usize>) -> Option<usize> {
+    let lines: Vec<&str> = source.lines().collect();
+    let indent_of = |line: &str| line.len() - line.trim_start().len();
+    let is_content = |line: &str| !line.trim().is_empty() && !line.trim_start().starts_with('#');
+
+    let mut start: usize = 0;
+    let mut parent_indent: Option<usize> = None;
+    let mut found: Option<(usize, usize)> = None;
+
+    for key in keys {
+        found = None;
+        // Keys nested deeper, e.g. inside sequence items, are not children of the section
+        let mut child_indent: Option<usize> = None;
+
+        for (index, line) in lines.iter().enumerate().skip(start) {
+            if !is_content(line) {
+                continue;
+            }
+
+            let indent: usize = indent_of(line);
+            if parent_indent.is_some_and(|p| indent <= p) {
+                // Left the parent section
+                break;
+            }
+            if *child_indent.get_or_insert(indent) != indent {
+                continue;
+            }
+
+            let trimmed: &str = line.trim_start();
+            let key_matches = [*key, &format!("\"{}\"", key), &format!("'{}'", key)]
+                .iter()
+                .any(|k| trimmed.strip_prefix(k).is_some_and(|rest| rest.trim_start().starts_with(':')));
+
+            if key_matches {
+                found = Some((index, indent));
+                break;
+            }
+        }
+
+        let (index, indent) = found?;
+        start = index + 1;
+        parent_indent = Some(indent);
+    }
+
+    let (key_line, key_indent) = found?;
+    let item: usize = match item {
+        Some(item) => item,
+        None => return Some(key_line + 1),
+    };
+
+    // Flow sequences like `[a, b]` live on the key line itself
+    let after_colon: &str = lines[key_line].split_once(':').map(|(_, rest)| rest.trim()).unwrap_or("");
+    if after_colon.starts_with('[') {
+        return Some(key_line + 1);
+    }
+
+    let mut seen: usize = 0;
+    let mut item_indent: Option<usize> = None;
+    for (index, line) in lines.iter().enumerate().skip(key_line + 1) {
+        if !is_content(line) {
+            continue;
+        }
+
+        let trimmed: &str = line.trim_start();
+        let is_item: bool = trimmed == "-" || trimmed.starts_with("- ");
+        let indent: usize = indent_of(line);
+
+        if indent < key_indent || (indent == key_indent && !is_item) {
+            break;
+        }
+        // Only count items of this sequence, not of sequences nested inside its items
+        if is_item && *item_indent.get_or_insert(indent) == indent {
+            if seen == item {
+                return Some(index + 1);
+            }
+            seen += 1;
+        }
+    }
+
+    None
+}
+
+/// Finds the line of a field of the sequence item starting at `item_line` (1-based).
+///
+/// The field may be on the item line itself (`- name: photos`) or on one of the following lines
+/// that are indented deeper than the item.
+fn find_field_line(source: &str, item_line: usize, field: &str) -> Option<usize> {
+    let lines: Vec<&str> = source.lines().collect();
+    let first: &str = lines.get(item_line - 1)?;
+    let item_indent: usize = first.len() - first.trim_start().len();
+    let is_field = |text: &str| text.strip_prefix(field).is_some_and(|rest| rest.trim_start().starts_with(':'));
+
+    if is_field(first.trim_start().trim_start_matches('-').trim_start()) {
+        return Some(item_line);
+    }
+
+    for (index, line) in lines.iter().enumerate().skip(item_line) {
+        let trimmed: &str = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if line.len() - trimmed.len() <= item_indent {
+            break;
+        }
+        if is_field(trimmed) {
+            return Some(index + 1);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+version: 2
+# client: commented out
+client:
+  id: \"abc\"
+  'name': laptop # trailing comment
+backup:
+  watch_directories: [\"/a\", \"/b\"]
+  jobs:
+    - name: docs
+      directories:
+        - /home/me/docs
+        - /home/me/notes
+      # exclude: [\"*.tmp\"]
+      exclude: [\"*.bak\"]
+    -
+      name: photos
+      directories: [/home/me/photos]
+  \"interval_seconds\": 60
+server:
+  address: \"https://example.com\"
+";
+
+    #[test]
+    fn finds_nested_and_quoted_keys() {
+        assert_eq!(find_line(CONFIG, &["client"], None), Some(3));
+        assert_eq!(find_line(CONFIG, &["client", "id"], None), Some(4));
+        assert_eq!(find_line(CONFIG, &["client", "name"], None), Some(5));
+        assert_eq!(find_line(CONFIG, &["backup", "interval_seconds"], None), Some(18));
+        assert_eq!(find_line(CONFIG, &["server", "address"], None), Some(20));
+    }
+
+    #[test]
+    fn ignores_comments_and_keys_outside_the_section() {
+        assert_eq!(find_line(CONFIG, &["client", "address"], None), None);
+        // Fields of job items are no keys of the backup section
+        assert_eq!(find_line(CONFIG, &["backup", "exclude"], None), None);
+        assert_eq!(find_line(CONFIG, &["missing"], None), None);
+    }
+
+    #[test]
+    fn finds_items_of_block_and_flow_sequences() {
+        // Flow sequences point at the key line
+        assert_eq!(find_line(CONFIG, &["backup", "watch_directories"], Some(1)), Some(7));
+        // Block sequences point at the item, items of nested sequences are not counted
+        assert_eq!(find_line(CONFIG, &["backup", "jobs"], Some(0)), Some(9));
+        assert_eq!(find_line(CONFIG, &["backup", "jobs"], Some(1)), Some(15));
+        assert_eq!(find_line(CONFIG, &["backup", "jobs"], Some(2)), None);
+    }
+
+    #[test]
+    fn finds_items_at_the_indent_of_their_key() {
+        let source: &str = "jobs:\n- name: a\n- name: b\nother: 1\n";
+        assert_eq!(find_line(source, &["jobs"], Some(1)), Some(3));
+        assert_eq!(find_line(source, &["jobs"], Some(2)), None);
+    }
+
+    #[test]
+    fn finds_fields_of_job_items() {
+        // On the item line itself
+        assert_eq!(find_field_line(CONFIG, 9, "name"), Some(9));
+        assert_eq!(find_field_line(CONFIG, 9, "directories"), Some(10));
+        // Commented out fields are skipped
+        assert_eq!(find_field_line(CONFIG, 9, "exclude"), Some(14));
+        // Fields of the next item are not found
+        assert_eq!(find_field_line(CONFIG, 9, "retention"), None);
+        // Items starting with a bare `-`
+        assert_eq!(find_field_line(CONFIG, 15, "name"), Some(16));
+        assert_eq!(find_field_line(CONFIG, 15, "directories"), Some(17));
+    }
+}
\ No newline at end of file